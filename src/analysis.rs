@@ -0,0 +1,169 @@
+// Metrics computed from the samples of an activity
+use uuid::Uuid;
+
+use fit::{Recording, Sample};
+use models::summaries::Summary;
+
+pub const CYCLING: &'static str = "cycling";
+pub const RUNNING: &'static str = "running";
+pub const OTHER: &'static str = "other";
+
+pub const SPORTS: [&'static str; 6] = [
+    CYCLING,
+    RUNNING,
+    "swimming",
+    "walking",
+    "hiking",
+    OTHER,
+];
+
+// Mean radius of the earth in metres
+const EARTH_RADIUS: f64 = 6371008.8;
+
+// Samples further apart than this are treated as a pause. Each sample
+// counts for at most this many seconds.
+pub const MAX_SAMPLE_GAP: f64 = 10.0;
+
+// Slowest speed, in metres per second, at which pace is counted. Below
+// this the athlete is treated as standing still.
+pub const MIN_PACE_SPEED: f64 = 0.5;
+
+// The sport chosen on upload takes precedence over the sport recorded by
+// the device
+pub fn sport(requested: Option<&str>, recording: &Recording) -> String {
+    let known = |s: &str| SPORTS.iter().any(|k| *k == s);
+    match requested.map(|s| s.trim().to_lowercase()) {
+        Some(ref s) if known(s.as_str()) => s.clone(),
+        _ => match recording.sport {
+            Some(ref s) if known(s.as_str()) => s.clone(),
+            _ => OTHER.to_string(),
+        },
+    }
+}
+
+pub fn summarize(activity_id: &Uuid, user_id: &Uuid, sport: &str, recording: &Recording) -> Summary {
+    let samples = &recording.samples;
+    let (avg_heart_rate, max_heart_rate) = avg_max(samples, |s| s.heart_rate);
+    let (avg_power, max_power) = avg_max(samples, |s| s.power);
+    let (avg_cadence, _) = avg_max(samples, |s| s.cadence);
+    let (_, max_speed) = avg_max(samples, |s| s.speed);
+    let elapsed_time = samples.last().map(|s| s.time).unwrap_or(0.0);
+    let distance = total_distance(samples);
+    Summary {
+        activity_id: *activity_id,
+        user_id: *user_id,
+        sport: sport.to_string(),
+        start_time: recording.start_time,
+        elapsed_time: elapsed_time,
+        distance: distance,
+        avg_speed: distance.and_then(|d| {
+            if elapsed_time > 0.0 { Some(d / elapsed_time) } else { None }
+        }),
+        max_speed: max_speed,
+        avg_heart_rate: avg_heart_rate,
+        max_heart_rate: max_heart_rate,
+        avg_power: avg_power,
+        max_power: max_power,
+        avg_cadence: avg_cadence,
+    }
+}
+
+// Distance recorded by the device, or measured along the track when the
+// device did not record distance
+pub fn total_distance(samples: &[Sample]) -> Option<f64> {
+    if let Some(d) = samples.iter().filter_map(|s| s.distance).last() {
+        return Some(d);
+    }
+    let points = track(samples);
+    if points.len() < 2 {
+        return None;
+    }
+    Some(points.windows(2).map(|w| haversine(w[0], w[1])).sum())
+}
+
+// Distance from the start at each sample, recorded by the device or
+// measured along the track. Samples without a value take the distance of
+// the sample before them.
+pub fn distances(samples: &[Sample]) -> Vec<f64> {
+    let recorded = samples.iter().any(|s| s.distance.is_some());
+    let mut result = Vec::with_capacity(samples.len());
+    let mut distance = 0.0;
+    let mut last_point = None;
+    for s in samples {
+        if recorded {
+            if let Some(d) = s.distance {
+                distance = d;
+            }
+        } else if let (Some(lat), Some(lng)) = (s.lat, s.lng) {
+            if let Some(p) = last_point {
+                distance += haversine(p, (lat, lng));
+            }
+            last_point = Some((lat, lng));
+        }
+        result.push(distance);
+    }
+    result
+}
+
+// Positions of the samples that have one
+pub fn track(samples: &[Sample]) -> Vec<(f64, f64)> {
+    samples.iter()
+        .filter_map(|s| match (s.lat, s.lng) {
+            (Some(lat), Some(lng)) => Some((lat, lng)),
+            _ => None,
+        })
+        .collect()
+}
+
+// Great circle distance in metres between two (lat, lng) points in degrees
+pub fn haversine(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (a.0.to_radians(), b.0.to_radians());
+    let dlat = lat2 - lat1;
+    let dlng = (b.1 - a.1).to_radians();
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().min(1.0).asin()
+}
+
+// Pace in seconds per kilometre
+pub fn pace(sample: &Sample) -> Option<f64> {
+    match sample.speed {
+        Some(speed) if speed >= MIN_PACE_SPEED => Some(1000.0 / speed),
+        _ => None,
+    }
+}
+
+// Values resampled to one per second from the start of the recording.
+// Each second takes the value of the last sample at or before it, and is
+// None while paused or when the value was not recorded.
+pub fn per_second<F>(samples: &[Sample], value: F) -> Vec<Option<f64>>
+    where F: Fn(&Sample) -> Option<f64>
+{
+    let end = match samples.last() {
+        Some(s) => s.time as usize,
+        None => return Vec::new(),
+    };
+    let mut series = Vec::with_capacity(end + 1);
+    let mut i = 0;
+    for t in 0..end + 1 {
+        let t = t as f64;
+        while i + 1 < samples.len() && samples[i + 1].time <= t {
+            i += 1;
+        }
+        let sample = &samples[i];
+        series.push(if t - sample.time <= MAX_SAMPLE_GAP { value(sample) } else { None });
+    }
+    series
+}
+
+// Average and maximum of the values the samples have
+pub fn avg_max<F>(samples: &[Sample], value: F) -> (Option<f64>, Option<f64>)
+    where F: Fn(&Sample) -> Option<f64>
+{
+    let values: Vec<f64> = samples.iter().filter_map(|s| value(s)).collect();
+    if values.is_empty() {
+        return (None, None);
+    }
+    let avg = values.iter().sum::<f64>() / values.len() as f64;
+    let max = values.iter().fold(0.0f64, |m, v| m.max(*v));
+    (Some(avg), Some(max))
+}
@@ -0,0 +1,147 @@
+// Audit log of security relevant events such as logins, token issuance
+// and account changes. Events are stored in the database, where users can
+// query their own events and admins can query all events, and are
+// optionally appended to a JSON lines file.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use serde_json;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use client::ClientInfo;
+use config::AuditConfig;
+use models::audit::{self, AuditEvent};
+
+pub const REGISTER: &'static str = "register";
+pub const LOGIN: &'static str = "login";
+pub const LOGIN_SECOND_FACTOR: &'static str = "login_second_factor";
+pub const PASSWORD_CHANGE: &'static str = "password_change";
+pub const TOKEN_ISSUED: &'static str = "token_issued";
+pub const ACCOUNT_DELETION_REQUESTED: &'static str = "account_deletion_requested";
+pub const ACCOUNT_DELETION_CANCELLED: &'static str = "account_deletion_cancelled";
+pub const ACCOUNT_PURGED: &'static str = "account_purged";
+pub const DATA_EXPORT_REQUESTED: &'static str = "data_export_requested";
+pub const DATA_EXPORT_READY: &'static str = "data_export_ready";
+pub const DATA_EXPORT_FAILED: &'static str = "data_export_failed";
+pub const DATA_EXPORT_DOWNLOADED: &'static str = "data_export_downloaded";
+pub const EMAIL_CHANGED: &'static str = "email_changed";
+pub const ACTIVITY_IMPORTED: &'static str = "activity_imported";
+pub const ACTIVITY_DELETED: &'static str = "activity_deleted";
+pub const TOTP_ENABLED: &'static str = "totp_enabled";
+pub const TOTP_DISABLED: &'static str = "totp_disabled";
+pub const RECOVERY_CODES_REGENERATED: &'static str = "recovery_codes_regenerated";
+pub const API_KEY_CREATED: &'static str = "api_key_created";
+pub const API_KEY_REVOKED: &'static str = "api_key_revoked";
+pub const OAUTH_CLIENT_CREATED: &'static str = "oauth_client_created";
+pub const OAUTH_CLIENT_DELETED: &'static str = "oauth_client_deleted";
+pub const OAUTH_AUTHORIZATION: &'static str = "oauth_authorization";
+pub const OAUTH_TOKEN_ISSUED: &'static str = "oauth_token_issued";
+pub const ADMIN_ACTION: &'static str = "admin_action";
+
+// Clones share the same file, so the log can be used outside of requests
+#[derive(Clone)]
+pub struct AuditLog {
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig) -> Result<AuditLog, io::Error> {
+        if config.file.is_empty() {
+            return Ok(AuditLog { file: None });
+        }
+        let path = Path::new(&config.file);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(AuditLog { file: Some(Arc::new(Mutex::new(file))) })
+    }
+
+    // Failing to record an event does not fail the request it belongs to
+    pub fn record(&self, event: Event, conn: &PlatformConnection) {
+        let event = event.0;
+        if !audit::create(&event, conn) {
+            eprintln!("Error storing audit event {}", event.event);
+        }
+        if let Some(ref file) = self.file {
+            let line = match serde_json::to_string(&event) {
+                Ok(l) => l,
+                Err(e) => {
+                    eprintln!("Error serializing audit event {}: {}", event.event, e);
+                    return;
+                }
+            };
+            let mut f = match file.lock() {
+                Ok(f) => f,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if let Err(e) = writeln!(f, "{}", line) {
+                eprintln!("Error writing audit event {}: {}", event.event, e);
+            }
+        }
+    }
+}
+
+pub struct Event(AuditEvent);
+
+impl Event {
+    pub fn success(event: &str, client: &ClientInfo) -> Event {
+        Event::new(event, "success", client)
+    }
+
+    pub fn failure(event: &str, client: &ClientInfo) -> Event {
+        Event::new(event, "failure", client)
+    }
+
+    // Event recorded by hapi itself rather than in response to a request
+    pub fn system(event: &str) -> Event {
+        Event(AuditEvent {
+            id: None,
+            created_on: Utc::now(),
+            event: event.to_string(),
+            outcome: "success".to_string(),
+            user_id: None,
+            username: None,
+            ip: None,
+            user_agent: None,
+            details: None,
+        })
+    }
+
+    fn new(event: &str, outcome: &str, client: &ClientInfo) -> Event {
+        Event(AuditEvent {
+            id: None,
+            created_on: Utc::now(),
+            event: event.to_string(),
+            outcome: outcome.to_string(),
+            user_id: None,
+            username: None,
+            ip: client.ip.map(|ip| ip.to_string()),
+            user_agent: client.user_agent.clone(),
+            details: None,
+        })
+    }
+
+    pub fn user(mut self, user_id: &Uuid) -> Event {
+        self.0.user_id = Some(*user_id);
+        self
+    }
+
+    pub fn username(mut self, username: &str) -> Event {
+        self.0.username = Some(username.to_string());
+        self
+    }
+
+    pub fn details<S: Into<String>>(mut self, details: S) -> Event {
+        self.0.details = Some(details.into());
+        self
+    }
+}
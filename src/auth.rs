@@ -1,35 +1,266 @@
-use chrono::{Duration, Utc};
+use chrono::{Duration, TimeZone, Utc};
 
 use rocket::http::Status;
-use rocket::request::{self, FromRequest};
+use rocket::request::{self, FromRequest, State};
 use rocket::{Request, Outcome};
 
-use rand::{self, Rng};
+use std::cell::Cell;
+use std::str;
+
+use uuid::Uuid;
+
+use rand::{OsRng, Rng};
+use argon2::{self, ThreadMode, Version};
 use argon2rs::defaults::{KIB, LANES, PASSES};
 use argon2rs::verifier::Encoded;
 use argon2rs::{Argon2, Variant};
+use base64;
+
+use ring::{constant_time, digest};
+
+use hdb::platform::PlatformConnection;
+
+use config::PasswordConfig;
+use db::Conn;
+use keys::{KeySet, TokenError};
+use models::{api_keys, oauth, sessions};
+use scope;
+use session;
 
-use jwt::{self, encode, decode, Header, Validation};
+// Lifetime of access tokens in seconds
+pub const ACCESS_TOKEN_LIFETIME: i64 = 3600;
+// Lifetime of second factor challenge tokens in seconds
+const CHALLENGE_TOKEN_LIFETIME: i64 = 300;
+// Length of the random id identifying each challenge token
+const CHALLENGE_ID_LENGTH: usize = 32;
 
-#[derive(Serialize)]
-pub struct AccessToken(pub String);
+// Header carrying an API key, as an alternative to the Authorization header
+pub const API_KEY_HEADER: &'static str = "X-API-Key";
+
+// Credentials sent with a request: an access token or an API key. Use
+// Principal in routes, which also validates the credentials.
+pub struct AccessToken {
+    pub token: String,
+    // Read from the session cookie rather than a header
+    pub from_cookie: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CredentialError {
+    // No credentials were sent
+    Missing,
+    // Credentials were malformed, or were sent in more than one way
+    Malformed,
+}
 
 impl<'a, 'r> FromRequest<'a, 'r> for AccessToken {
-    type Error = ();
+    type Error = CredentialError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AccessToken, CredentialError> {
+        match AccessToken::read(request) {
+            Ok(token) => Outcome::Success(token),
+            Err(CredentialError::Missing) => {
+                record_failure(AuthFailure::Missing);
+                Outcome::Failure((Status::Unauthorized, CredentialError::Missing))
+            },
+            Err(CredentialError::Malformed) => {
+                record_failure(AuthFailure::Malformed);
+                Outcome::Failure((Status::BadRequest, CredentialError::Malformed))
+            },
+        }
+    }
+}
+
+// Why a request failed authentication. Guards record the failure and the
+// error catchers describe it in the response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthFailure {
+    // No credentials were sent
+    Missing,
+    // Credentials were malformed, or were sent in more than one way
+    Malformed,
+    // Access token is invalid, expired or revoked, or API key is unknown,
+    // expired or revoked
+    InvalidToken,
+    // Credentials are valid but were issued for another user
+    WrongUser,
+    // Credentials do not grant the scope the route requires
+    InsufficientScope(&'static str),
+    // Request authenticated by the session cookie without a valid CSRF
+    // token
+    Csrf,
+    // Route requires an access token issued by login
+    LoginRequired,
+    // Route requires the admin role
+    AdminRequired,
+}
 
-    fn from_request(request: &'a Request<'r>) -> request::Outcome<AccessToken, ()> {
-        let header_map = request.headers();
-        let value = header_map.get_one("Authorization");
-        match value {
-            Some(val) => {
-                // Bearer should proceed the access token
-                // If the token does not start at index 7, an error
-                // will be returned when trying to validate the token
-                let (_, v) = val.split_at(7);
-                Outcome::Success(AccessToken(v.to_string()))
+impl AuthFailure {
+    pub fn status(&self) -> Status {
+        match *self {
+            AuthFailure::Missing | AuthFailure::InvalidToken | AuthFailure::WrongUser => {
+                Status::Unauthorized
             },
-            None => Outcome::Failure((Status::Unauthorized, ()))
+            AuthFailure::Malformed => Status::BadRequest,
+            _ => Status::Forbidden,
+        }
+    }
+}
+
+// Rocket has no request-local state, but each request is handled from
+// start to finish, including its error catcher, on one worker thread. The
+// failure is cleared when a request arrives (see clear_failure).
+thread_local! {
+    static FAILURE: Cell<Option<AuthFailure>> = Cell::new(None);
+}
+
+pub fn record_failure(failure: AuthFailure) {
+    FAILURE.with(|f| f.set(Some(failure)));
+}
+
+// The failure recorded by a guard while handling the current request
+pub fn take_failure() -> Option<AuthFailure> {
+    FAILURE.with(|f| f.take())
+}
+
+pub fn clear_failure() {
+    FAILURE.with(|f| f.set(None));
+}
+
+// Fail a guard, recording why for the error catchers
+pub fn fail<T>(failure: AuthFailure) -> request::Outcome<T, ()> {
+    record_failure(failure);
+    Outcome::Failure((failure.status(), ()))
+}
+
+impl AccessToken {
+    // Reads credentials from a bearer Authorization header (RFC 6750), the
+    // X-API-Key header or the session cookie. Clients must only use one of
+    // these in a request.
+    pub fn read(request: &Request) -> Result<AccessToken, CredentialError> {
+        let headers = request.headers();
+        let mut sources = Vec::new();
+        if let Some(value) = headers.get_one("Authorization") {
+            let token = parse_bearer(value).ok_or(CredentialError::Malformed)?;
+            sources.push(AccessToken { token: token, from_cookie: false });
+        }
+        if let Some(value) = headers.get_one(API_KEY_HEADER) {
+            let key = value.trim();
+            if !key.starts_with(API_KEY_PREFIX) {
+                return Err(CredentialError::Malformed);
+            }
+            sources.push(AccessToken { token: key.to_string(), from_cookie: false });
+        }
+        if let Some(cookie) = request.cookies().get_private(session::SESSION_COOKIE) {
+            sources.push(AccessToken { token: cookie.value().to_string(), from_cookie: true });
+        }
+        match sources.len() {
+            0 => Err(CredentialError::Missing),
+            1 => Ok(sources.remove(0)),
+            _ => Err(CredentialError::Malformed),
+        }
+    }
+
+    // Returns what the token or API key grants access to. API keys are
+    // recorded as used when they are accepted.
+    pub fn grant(&self, keys: &KeySet, conn: &PlatformConnection) -> Option<Grant> {
+        if self.is_api_key() {
+            ApiKey::grant(&self.token, conn)
+        } else {
+            UserToken::active_grant(&self.token, keys, conn)
+        }
+    }
+
+    pub fn is_api_key(&self) -> bool {
+        self.token.starts_with(API_KEY_PREFIX)
+    }
+}
+
+// Returns the token from an Authorization header value of the form
+// "Bearer <token>". The scheme is case insensitive and the token must be
+// a b64token as defined by RFC 6750.
+fn parse_bearer(value: &str) -> Option<String> {
+    let mut parts = value.trim().splitn(2, ' ');
+    let scheme = parts.next()?;
+    if !scheme.eq_ignore_ascii_case("Bearer") {
+        return None;
+    }
+    let token = parts.next()?.trim_left_matches(' ');
+    let body = token.trim_right_matches('=');
+    let valid = !body.is_empty() && body.chars().all(|c| {
+        c.is_ascii_alphanumeric() || "-._~+/".contains(c)
+    });
+    if valid {
+        Some(token.to_string())
+    } else {
+        None
+    }
+}
+
+// User authenticated by the access token or API key sent with a request.
+// Fails with 401 Unauthorized when the credentials are missing or invalid,
+// and 400 Bad Request when they are malformed. Requests authenticated by
+// the session cookie fail with 403 Forbidden when they change state without
+// a valid CSRF token.
+pub struct Principal {
+    pub user_id: Uuid,
+    pub scopes: Vec<String>,
+    // OAuth client the access token was issued to
+    pub client_id: Option<String>,
+    // Authenticated with an API key rather than an access token
+    pub api_key: bool,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Principal {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Principal, ()> {
+        let token = match request.guard::<AccessToken>() {
+            Outcome::Success(t) => t,
+            Outcome::Failure((status, _)) => return Outcome::Failure((status, ())),
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+        if token.from_cookie && session::requires_csrf(request) && !session::csrf_valid(request) {
+            return fail(AuthFailure::Csrf);
         }
+        let keys = request.guard::<State<KeySet>>()?;
+        let db = request.guard::<Conn>()?;
+        let grant = match token.grant(&keys, &db) {
+            Some(g) => g,
+            None => return fail(AuthFailure::InvalidToken),
+        };
+        let user_id = match Uuid::parse_str(&grant.sub) {
+            Ok(id) => id,
+            Err(_) => return fail(AuthFailure::InvalidToken),
+        };
+        Outcome::Success(Principal {
+            user_id: user_id,
+            scopes: grant.scopes,
+            client_id: grant.client_id,
+            api_key: token.is_api_key(),
+        })
+    }
+}
+
+impl Principal {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+// The user and scopes a valid access token or API key was issued for
+pub struct Grant {
+    pub sub: String,
+    pub scopes: Vec<String>,
+    // Time the token or API key was issued
+    pub issued_at: i64,
+    // OAuth client the token was issued to
+    pub client_id: Option<String>,
+}
+
+impl Grant {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
     }
 }
 
@@ -41,6 +272,19 @@ struct Claim {
     iat: i64,
     // Time token expires.
     exp: i64,
+    // Space separated scopes granted to an access token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    // Set for access tokens issued to OAuth clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    // Set for tokens that are not access tokens, such as second factor
+    // challenges. Access tokens never carry a purpose.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    purpose: Option<String>,
+    // Unique id of a challenge token, recorded when it is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jti: Option<String>,
 }
 
 impl Claim {
@@ -49,35 +293,272 @@ impl Claim {
             sub: sub.to_string(),
             iat: iat,
             exp: exp,
+            scope: None,
+            client_id: None,
+            purpose: None,
+            jti: None,
         }
     }
+
+    // Claims are valid if they have not expired and were issued for sub
+    fn valid_for(&self, sub: &str) -> bool {
+        self.sub == sub && self.exp > Utc::now().timestamp()
+    }
 }
 
 pub struct UserToken;
 
 impl UserToken {
-    pub fn new(sub: &str, secret: &str) -> Result<String, jwt::errors::Error> {
+    pub fn new(sub: &str, scopes: &[&str], keys: &KeySet) -> Result<String, TokenError> {
         let now = Utc::now();
-        let expires = now + Duration::seconds(3600);
-        let claim = Claim::new(sub, now.timestamp(), expires.timestamp());
-        encode(&Header::default(), &claim, secret.as_bytes())
+        let expires = now + Duration::seconds(ACCESS_TOKEN_LIFETIME);
+        let mut claim = Claim::new(sub, now.timestamp(), expires.timestamp());
+        claim.scope = Some(scope::join(scopes));
+        keys.encode(&claim)
     }
 
-    pub fn validate(token: &str, secret: &str, sub: &str) -> bool {
-        let validation = Validation {
-            sub: Some(sub.to_string()),
-            ..Default::default()
-        };
-        match decode::<Claim>(&token, secret.as_bytes(), &validation) {
-            Ok(_) => true,
-            Err(_) => false,
+    // Access token issued to an OAuth client on behalf of sub
+    pub fn for_client(sub: &str,
+                      scopes: &[String],
+                      client_id: &str,
+                      keys: &KeySet) -> Result<String, TokenError> {
+        let now = Utc::now();
+        let expires = now + Duration::seconds(ACCESS_TOKEN_LIFETIME);
+        let mut claim = Claim::new(sub, now.timestamp(), expires.timestamp());
+        claim.scope = Some(scope::join(scopes));
+        claim.client_id = Some(client_id.to_string());
+        keys.encode(&claim)
+    }
+
+    pub fn grant(token: &str, keys: &KeySet) -> Option<Grant> {
+        match keys.decode::<Claim>(&token) {
+            Ok(claim) => {
+                if claim.purpose.is_some() || !claim.valid_for(&claim.sub) {
+                    return None;
+                }
+                let scopes = claim.scope.as_ref().map_or(Vec::new(), |s| scope::parse(s));
+                Some(Grant {
+                    sub: claim.sub,
+                    scopes: scopes,
+                    issued_at: claim.iat,
+                    client_id: claim.client_id,
+                })
+            },
+            Err(_) => None,
+        }
+    }
+
+    // Like grant, but also rejects tokens issued before the user's sessions
+    // were revoked, and tokens issued to OAuth clients that were deleted
+    pub fn active_grant(token: &str,
+                        keys: &KeySet,
+                        conn: &PlatformConnection) -> Option<Grant> {
+        let grant = UserToken::grant(token, keys)?;
+        let user_id = Uuid::parse_str(&grant.sub).ok()?;
+        if let Some(ref client_id) = grant.client_id {
+            if !oauth::client_exists(client_id, conn) {
+                return None;
+            }
+        }
+        match sessions::revoked_before(&user_id, conn) {
+            // Tokens issued in the same second as the revocation are
+            // accepted so a user can login again immediately.
+            Some(revoked) if grant.issued_at < revoked.timestamp() => None,
+            _ => Some(grant),
+        }
+    }
+
+    pub fn validate(token: &str, keys: &KeySet, sub: &str) -> bool {
+        UserToken::grant(token, keys).map_or(false, |g| g.sub == sub)
+    }
+}
+
+// Prefix identifying API keys so they can be told apart from JWTs
+pub const API_KEY_PREFIX: &'static str = "hapi_";
+// Number of random characters following the prefix
+const API_KEY_LENGTH: usize = 40;
+// Characters of the key stored in the clear so users can identify keys
+pub const API_KEY_DISPLAY_LENGTH: usize = 12;
+
+// Long lived keys created by users for scripts and integrations. Only a
+// SHA-256 digest of the key is stored; the key itself is shown once when
+// it is created.
+pub struct ApiKey;
+
+impl ApiKey {
+    pub fn new() -> String {
+        format!("{}{}", API_KEY_PREFIX, random_token(API_KEY_LENGTH))
+    }
+
+    pub fn grant(key: &str, conn: &PlatformConnection) -> Option<Grant> {
+        match api_keys::get_by_hash(&hash_token(key), conn) {
+            Ok(api_key) => {
+                if !api_key.is_valid() {
+                    return None;
+                }
+                api_keys::touch(&api_key.id, conn);
+                Some(Grant {
+                    sub: api_key.user_id.to_string(),
+                    scopes: api_key.scopes,
+                    issued_at: api_key.created_on.timestamp(),
+                    client_id: None,
+                })
+            },
+            Err(_) => None,
+        }
+    }
+}
+
+// Challenge for a second factor when two factor authentication is enabled
+pub const SECOND_FACTOR: &'static str = "second_factor";
+// Challenge for a new password when a password reset is required
+pub const PASSWORD_CHANGE: &'static str = "password_change";
+// Challenge for a second factor when cancelling an account deletion
+pub const RESTORE: &'static str = "restore";
+
+// Short lived token returned by login when another step is needed before
+// an access token is issued, such as entering a two factor code. The
+// purpose names the step the token can be used for.
+pub struct ChallengeToken;
+
+impl ChallengeToken {
+    pub fn new(sub: &str, purpose: &str, keys: &KeySet) -> Result<String, TokenError> {
+        let now = Utc::now();
+        let expires = now + Duration::seconds(CHALLENGE_TOKEN_LIFETIME);
+        let mut claim = Claim::new(sub, now.timestamp(), expires.timestamp());
+        claim.purpose = Some(purpose.to_string());
+        claim.jti = Some(random_token(CHALLENGE_ID_LENGTH));
+        keys.encode(&claim)
+    }
+
+    // Returns the challenge if the token is valid for the purpose and has
+    // not been used
+    pub fn validate(token: &str,
+                    purpose: &str,
+                    keys: &KeySet,
+                    conn: &PlatformConnection) -> Option<Challenge> {
+        let claim = keys.decode::<Claim>(&token).ok()?;
+        let matches = claim.purpose.as_ref().map(|p| p.as_str()) == Some(purpose);
+        if !matches || !claim.valid_for(&claim.sub) {
+            return None;
+        }
+        let id = claim.jti?;
+        if sessions::challenge_used(&id, conn) {
+            return None;
         }
+        Some(Challenge {
+            sub: claim.sub,
+            id: id,
+            expires: claim.exp,
+        })
     }
 }
 
+// A valid challenge token
+pub struct Challenge {
+    // User id the challenge was issued for
+    pub sub: String,
+    id: String,
+    expires: i64,
+}
+
+impl Challenge {
+    // Record the challenge as used once it has been exchanged. Returns
+    // false if it was already used.
+    pub fn redeem(&self, conn: &PlatformConnection) -> bool {
+        sessions::use_challenge(&self.id, &Utc.timestamp(self.expires, 0), conn)
+    }
+}
 
 // Functions for hashing user passwords
-pub fn generate_hash(pass: String, salt: &Vec<u8>) -> Vec<u8> {
+//
+// Passwords are hashed with Argon2id and stored as PHC strings, which
+// carry the algorithm parameters and salt along with the hash:
+//
+//   $argon2id$v=19$m=65536,t=3,p=1$<salt>$<hash>
+//
+// Hashes created by earlier versions of hapi used Argon2d with library
+// defaults and a separately stored salt. These are still accepted and
+// are replaced with a new hash on the next successful login.
+
+pub enum PasswordMatch {
+    // Password does not match the stored hash
+    Invalid,
+    // Password matches the stored hash
+    Valid,
+    // Password matches, but the stored hash is weaker than the current
+    // policy and should be replaced
+    Rehash,
+}
+
+pub fn hash_password(pass: &str, policy: &PasswordConfig) -> Result<String, argon2::Error> {
+    let salt = random_bytes(16);
+    let config = argon2_config(policy.memory_cost,
+                               policy.time_cost,
+                               policy.parallelism,
+                               Version::Version13,
+                               32);
+    argon2::hash_encoded(pass.as_bytes(), &salt, &config)
+}
+
+pub fn verify_password(pass: &str,
+                       stored: &[u8],
+                       salt: &[u8],
+                       policy: &PasswordConfig) -> PasswordMatch {
+    let encoded = match str::from_utf8(stored) {
+        Ok(e) => e,
+        Err(_) => return PasswordMatch::Invalid,
+    };
+    if !encoded.starts_with("$argon2id$") {
+        // Legacy Argon2d hash with separately stored salt. Always rehash.
+        let hash = legacy_hash(pass, salt);
+        return match constant_time::verify_slices_are_equal(&hash, stored) {
+            Ok(_) => PasswordMatch::Rehash,
+            Err(_) => PasswordMatch::Invalid,
+        };
+    }
+    let phc = match PhcHash::parse(encoded) {
+        Some(p) => p,
+        None => return PasswordMatch::Invalid,
+    };
+    let config = argon2_config(phc.memory_cost,
+                               phc.time_cost,
+                               phc.parallelism,
+                               phc.version,
+                               phc.hash.len() as u32);
+    let hash = match argon2::hash_raw(pass.as_bytes(), &phc.salt, &config) {
+        Ok(h) => h,
+        Err(_) => return PasswordMatch::Invalid,
+    };
+    if constant_time::verify_slices_are_equal(&hash, &phc.hash).is_err() {
+        return PasswordMatch::Invalid;
+    }
+    if phc.weaker_than(policy) {
+        PasswordMatch::Rehash
+    } else {
+        PasswordMatch::Valid
+    }
+}
+
+fn argon2_config(memory_cost: u32,
+                 time_cost: u32,
+                 parallelism: u32,
+                 version: Version,
+                 hash_length: u32) -> argon2::Config<'static> {
+    argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        version: version,
+        mem_cost: memory_cost,
+        time_cost: time_cost,
+        lanes: parallelism,
+        thread_mode: ThreadMode::Sequential,
+        secret: &[],
+        ad: &[],
+        hash_length: hash_length,
+    }
+}
+
+fn legacy_hash(pass: &str, salt: &[u8]) -> Vec<u8> {
     let a2 = Argon2::new(PASSES,
                          LANES,
                          KIB,
@@ -89,13 +570,150 @@ pub fn generate_hash(pass: String, salt: &Vec<u8>) -> Vec<u8> {
                  b"").to_u8()
 }
 
-pub fn generate_salt() -> Vec<u8> {
-    random(32).as_bytes().to_vec()
+// Decoded Argon2id PHC string
+struct PhcHash {
+    version: Version,
+    memory_cost: u32,
+    time_cost: u32,
+    parallelism: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PhcHash {
+    fn parse(encoded: &str) -> Option<PhcHash> {
+        // Leading '$' produces an empty first field
+        let fields: Vec<&str> = encoded.split('$').collect();
+        // Version field is optional and defaults to 0x10
+        let (version, rest) = match fields.len() {
+            6 => {
+                let version = match fields[2] {
+                    "v=19" => Version::Version13,
+                    "v=16" => Version::Version10,
+                    _ => return None,
+                };
+                (version, &fields[3..])
+            },
+            5 => (Version::Version10, &fields[2..]),
+            _ => return None,
+        };
+        if fields[1] != "argon2id" {
+            return None;
+        }
+        let mut memory_cost = None;
+        let mut time_cost = None;
+        let mut parallelism = None;
+        for param in rest[0].split(',') {
+            let mut kv = param.splitn(2, '=');
+            let key = kv.next();
+            let value = match kv.next().and_then(|v| v.parse::<u32>().ok()) {
+                Some(v) => v,
+                None => return None,
+            };
+            match key {
+                Some("m") => memory_cost = Some(value),
+                Some("t") => time_cost = Some(value),
+                Some("p") => parallelism = Some(value),
+                _ => return None,
+            }
+        }
+        Some(PhcHash {
+            version: version,
+            memory_cost: memory_cost?,
+            time_cost: time_cost?,
+            parallelism: parallelism?,
+            salt: decode_b64(rest[1])?,
+            hash: decode_b64(rest[2])?,
+        })
+    }
+
+    fn weaker_than(&self, policy: &PasswordConfig) -> bool {
+        self.version != Version::Version13 ||
+            self.memory_cost < policy.memory_cost ||
+            self.time_cost < policy.time_cost ||
+            self.parallelism < policy.parallelism ||
+            self.salt.len() < 16 ||
+            self.hash.len() < 32
+    }
+}
+
+// PHC strings use standard base64 without padding
+fn decode_b64(value: &str) -> Option<Vec<u8>> {
+    let mut padded = value.to_string();
+    while padded.len() % 4 != 0 {
+        padded.push('=');
+    }
+    base64::decode(&padded).ok()
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    OsRng::new()
+        .expect("unable to access OS random number generator")
+        .fill_bytes(&mut bytes);
+    bytes
 }
 
-fn random(take: usize) -> String {
-    rand::thread_rng()
+// Generate a random token suitable for use in emails and URLs
+pub fn random_token(take: usize) -> String {
+    OsRng::new()
+        .expect("unable to access OS random number generator")
         .gen_ascii_chars()
         .take(take)
         .collect::<String>()
 }
+
+// Tokens handed out to users (verification links, etc.) are stored as a
+// SHA-256 hex digest so a database leak does not expose usable tokens.
+pub fn hash_token(token: &str) -> String {
+    let d = digest::digest(&digest::SHA256, token.as_bytes());
+    to_hex(d.as_ref())
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use keys::KeySet;
+    use scope;
+    use super::*;
+
+    fn keys() -> KeySet {
+        KeySet::load(&[], "secret", ACCESS_TOKEN_LIFETIME).unwrap()
+    }
+
+    #[test]
+    fn grant_accepts_current_access_tokens() {
+        let keys = keys();
+        let token = UserToken::new("user", &[scope::ACTIVITIES_READ], &keys).unwrap();
+        let grant = UserToken::grant(&token, &keys).unwrap();
+        assert_eq!(grant.sub, "user");
+        assert!(grant.allows(scope::ACTIVITIES_READ));
+        assert!(!grant.allows(scope::ACTIVITIES_WRITE));
+    }
+
+    #[test]
+    fn grant_rejects_expired_access_tokens() {
+        let keys = keys();
+        let now = Utc::now();
+        let mut claim = Claim::new("user",
+                                   (now - Duration::hours(2)).timestamp(),
+                                   (now - Duration::hours(1)).timestamp());
+        claim.scope = Some(scope::ACTIVITIES_READ.to_string());
+        let token = keys.encode(&claim).unwrap();
+        assert!(UserToken::grant(&token, &keys).is_none());
+    }
+
+    #[test]
+    fn grant_rejects_challenge_tokens() {
+        let keys = keys();
+        let token = ChallengeToken::new("user", SECOND_FACTOR, &keys).unwrap();
+        assert!(UserToken::grant(&token, &keys).is_none());
+    }
+}
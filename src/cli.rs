@@ -1,4 +1,4 @@
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 
 pub fn new<'a, 'b>() -> App<'a, 'b> {
     App::new("hapi")
@@ -11,4 +11,32 @@ pub fn new<'a, 'b>() -> App<'a, 'b> {
              .value_name("FILE")
              .help("Sets custom configuration file")
              .takes_value(true))
+        .subcommand(SubCommand::with_name("unlock")
+                    .about("Clears failed login attempts for a username or address")
+                    .arg(Arg::with_name("username")
+                         .long("username")
+                         .value_name("USERNAME")
+                         .help("Username to unlock")
+                         .takes_value(true)
+                         .required_unless("address")
+                         .conflicts_with("address"))
+                    .arg(Arg::with_name("address")
+                         .long("address")
+                         .value_name("ADDRESS")
+                         .help("IP address to unlock")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("admin")
+                    .about("Grants or revokes the admin role for a user")
+                    .arg(Arg::with_name("grant")
+                         .long("grant")
+                         .value_name("USERNAME")
+                         .help("Username to grant the admin role to")
+                         .takes_value(true)
+                         .required_unless("revoke")
+                         .conflicts_with("revoke"))
+                    .arg(Arg::with_name("revoke")
+                         .long("revoke")
+                         .value_name("USERNAME")
+                         .help("Username to revoke the admin role from")
+                         .takes_value(true)))
 }
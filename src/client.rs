@@ -0,0 +1,35 @@
+use std::net::IpAddr;
+
+use rocket::request::{self, FromRequest, State};
+use rocket::{Request, Outcome};
+
+use config::ServerConfig;
+
+// Information about the client making a request
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientInfo {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ClientInfo, ()> {
+        let conf = request.guard::<State<ServerConfig>>()?;
+        let headers = request.headers();
+        // When running behind a reverse proxy the remote address is the
+        // proxy. Only trust X-Forwarded-For when configured to.
+        let forwarded = if conf.trust_forwarded_for {
+            headers.get_one("X-Forwarded-For")
+                .and_then(|v| v.split(',').next())
+                .and_then(|v| v.trim().parse::<IpAddr>().ok())
+        } else {
+            None
+        };
+        let ip = forwarded.or_else(|| request.remote().map(|addr| addr.ip()));
+        Outcome::Success(ClientInfo {
+            ip: ip,
+            user_agent: headers.get_one("User-Agent").map(|v| v.to_string()),
+        })
+    }
+}
@@ -16,6 +16,15 @@ pub fn default() -> Config {
     Config {
         server: default_server_config(),
         database: DatabaseConfig::default(),
+        accounts: default_accounts_config(),
+        mail: default_mail_config(),
+        passwords: default_password_config(),
+        usernames: default_username_config(),
+        login_throttle: default_throttle_config(),
+        keys: Vec::new(),
+        audit: default_audit_config(),
+        exports: default_export_config(),
+        tracks: default_track_config(),
     }
 }
 
@@ -26,6 +35,33 @@ pub struct Config {
 
     #[serde(default = "DatabaseConfig::default")]
     pub database: DatabaseConfig,
+
+    #[serde(default = "default_accounts_config")]
+    pub accounts: AccountsConfig,
+
+    #[serde(default = "default_mail_config")]
+    pub mail: MailConfig,
+
+    #[serde(default = "default_password_config")]
+    pub passwords: PasswordConfig,
+
+    #[serde(default = "default_username_config")]
+    pub usernames: UsernameConfig,
+
+    #[serde(default = "default_throttle_config")]
+    pub login_throttle: ThrottleConfig,
+
+    #[serde(default = "Vec::new")]
+    pub keys: Vec<KeyConfig>,
+
+    #[serde(default = "default_audit_config")]
+    pub audit: AuditConfig,
+
+    #[serde(default = "default_export_config")]
+    pub exports: ExportConfig,
+
+    #[serde(default = "default_track_config")]
+    pub tracks: TrackConfig,
 }
 
 #[derive(Debug, Deserialize)]
@@ -41,6 +77,16 @@ pub struct ServerConfig {
 
     #[serde(default = "default_file_dir")]
     pub file_dir: String,
+
+    // Use the first address in X-Forwarded-For as the client address.
+    // Only enable when running behind a reverse proxy that sets it.
+    #[serde(default = "default_trust_forwarded_for")]
+    pub trust_forwarded_for: bool,
+
+    // Only send session cookies over HTTPS. Disable for local development
+    // over plain HTTP.
+    #[serde(default = "default_secure_cookies")]
+    pub secure_cookies: bool,
 }
 
 fn default_server_config() -> ServerConfig {
@@ -49,6 +95,8 @@ fn default_server_config() -> ServerConfig {
         port: default_server_port(),
         secret: default_secret(),
         file_dir: default_file_dir(),
+        trust_forwarded_for: default_trust_forwarded_for(),
+        secure_cookies: default_secure_cookies(),
     }
 }
 
@@ -67,3 +115,472 @@ fn default_secret() -> String {
 fn default_file_dir() -> String {
     "/tmp".to_string()
 }
+
+fn default_trust_forwarded_for() -> bool {
+    false
+}
+
+fn default_secure_cookies() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccountsConfig {
+    // Require an email address when registering a new user
+    #[serde(default = "default_require_email")]
+    pub require_email: bool,
+
+    // Allow users that have not verified their email address to login
+    #[serde(default = "default_allow_unverified_login")]
+    pub allow_unverified_login: bool,
+
+    // Allow users that have not verified their email address to import
+    // activities
+    #[serde(default = "default_allow_unverified_upload")]
+    pub allow_unverified_upload: bool,
+
+    // Number of hours a verification token is valid for
+    #[serde(default = "default_verification_ttl")]
+    pub verification_ttl: i64,
+
+    // Public URL of the verify endpoint. The verification token is appended
+    // as a query parameter to this URL in verification emails.
+    #[serde(default = "default_verification_url")]
+    pub verification_url: String,
+
+    // Issuer shown in authenticator apps for two factor authentication
+    #[serde(default = "default_totp_issuer")]
+    pub totp_issuer: String,
+
+    // Days between a user deleting their account and their data being
+    // purged, during which the deletion can be cancelled
+    #[serde(default = "default_deletion_grace_days")]
+    pub deletion_grace_days: i64,
+
+    // Seconds between checks for accounts to purge
+    #[serde(default = "default_purge_interval")]
+    pub purge_interval: u64,
+}
+
+fn default_accounts_config() -> AccountsConfig {
+    AccountsConfig {
+        require_email: default_require_email(),
+        allow_unverified_login: default_allow_unverified_login(),
+        allow_unverified_upload: default_allow_unverified_upload(),
+        verification_ttl: default_verification_ttl(),
+        verification_url: default_verification_url(),
+        totp_issuer: default_totp_issuer(),
+        deletion_grace_days: default_deletion_grace_days(),
+        purge_interval: default_purge_interval(),
+    }
+}
+
+fn default_require_email() -> bool {
+    false
+}
+
+fn default_allow_unverified_login() -> bool {
+    true
+}
+
+fn default_allow_unverified_upload() -> bool {
+    true
+}
+
+fn default_verification_ttl() -> i64 {
+    48
+}
+
+fn default_verification_url() -> String {
+    "http://127.0.0.1:8000/users/verify".to_string()
+}
+
+fn default_totp_issuer() -> String {
+    "hapi".to_string()
+}
+
+fn default_deletion_grace_days() -> i64 {
+    30
+}
+
+fn default_purge_interval() -> u64 {
+    3600
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailBackend {
+    // Write messages to files in spool_dir
+    File,
+    // Pipe messages to a sendmail compatible binary
+    Sendmail,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MailConfig {
+    #[serde(default = "default_mail_backend")]
+    pub backend: MailBackend,
+
+    #[serde(default = "default_mail_from")]
+    pub from: String,
+
+    #[serde(default = "default_spool_dir")]
+    pub spool_dir: String,
+
+    #[serde(default = "default_sendmail")]
+    pub sendmail: String,
+}
+
+fn default_mail_config() -> MailConfig {
+    MailConfig {
+        backend: default_mail_backend(),
+        from: default_mail_from(),
+        spool_dir: default_spool_dir(),
+        sendmail: default_sendmail(),
+    }
+}
+
+fn default_mail_backend() -> MailBackend {
+    MailBackend::File
+}
+
+fn default_mail_from() -> String {
+    "hapi@localhost".to_string()
+}
+
+fn default_spool_dir() -> String {
+    "/tmp/hapi/mail".to_string()
+}
+
+fn default_sendmail() -> String {
+    "/usr/sbin/sendmail".to_string()
+}
+
+// Argon2id parameters used when hashing passwords, and the policy new
+// passwords must meet. Stored hashes using weaker parameters are rehashed
+// on the next successful login.
+#[derive(Debug, Deserialize)]
+pub struct PasswordConfig {
+    // Memory in KiB
+    #[serde(default = "default_memory_cost")]
+    pub memory_cost: u32,
+
+    // Number of passes over memory
+    #[serde(default = "default_time_cost")]
+    pub time_cost: u32,
+
+    // Number of lanes
+    #[serde(default = "default_parallelism")]
+    pub parallelism: u32,
+
+    // Length limits in characters
+    #[serde(default = "default_password_min_length")]
+    pub min_length: usize,
+
+    #[serde(default = "default_password_max_length")]
+    pub max_length: usize,
+
+    // Minimum estimated strength in bits
+    #[serde(default = "default_min_entropy")]
+    pub min_entropy: f64,
+
+    // Directory of breached password hashes split by the first five
+    // characters of their SHA-1 hash. Empty disables the check.
+    #[serde(default = "default_breached_passwords")]
+    pub breached_passwords: String,
+}
+
+fn default_password_config() -> PasswordConfig {
+    PasswordConfig {
+        memory_cost: default_memory_cost(),
+        time_cost: default_time_cost(),
+        parallelism: default_parallelism(),
+        min_length: default_password_min_length(),
+        max_length: default_password_max_length(),
+        min_entropy: default_min_entropy(),
+        breached_passwords: default_breached_passwords(),
+    }
+}
+
+fn default_memory_cost() -> u32 {
+    65536
+}
+
+fn default_time_cost() -> u32 {
+    3
+}
+
+fn default_parallelism() -> u32 {
+    1
+}
+
+fn default_password_min_length() -> usize {
+    10
+}
+
+fn default_password_max_length() -> usize {
+    128
+}
+
+fn default_min_entropy() -> f64 {
+    40.0
+}
+
+fn default_breached_passwords() -> String {
+    "".to_string()
+}
+
+// Rules for usernames chosen at registration. Usernames are stored in
+// lowercase and may contain letters, digits, '.', '_' and '-'.
+#[derive(Debug, Deserialize)]
+pub struct UsernameConfig {
+    #[serde(default = "default_username_min_length")]
+    pub min_length: usize,
+
+    #[serde(default = "default_username_max_length")]
+    pub max_length: usize,
+
+    // Names that cannot be registered
+    #[serde(default = "default_reserved_usernames")]
+    pub reserved: Vec<String>,
+}
+
+fn default_username_config() -> UsernameConfig {
+    UsernameConfig {
+        min_length: default_username_min_length(),
+        max_length: default_username_max_length(),
+        reserved: default_reserved_usernames(),
+    }
+}
+
+fn default_username_min_length() -> usize {
+    3
+}
+
+fn default_username_max_length() -> usize {
+    32
+}
+
+fn default_reserved_usernames() -> Vec<String> {
+    ["admin", "administrator", "api", "hapi", "hydra", "login", "logout", "oauth",
+     "root", "support", "system", "users"].iter().map(|s| s.to_string()).collect()
+}
+
+// Limits on failed login attempts. All durations are in seconds.
+#[derive(Debug, Deserialize)]
+pub struct ThrottleConfig {
+    // Failed attempts allowed before delays are applied
+    #[serde(default = "default_free_attempts")]
+    pub free_attempts: i64,
+
+    // Delay after the first attempt beyond free_attempts. Doubles with
+    // each further failed attempt.
+    #[serde(default = "default_base_delay")]
+    pub base_delay: i64,
+
+    #[serde(default = "default_max_delay")]
+    pub max_delay: i64,
+
+    // Failed attempts for a username before it is locked out
+    #[serde(default = "default_lockout_threshold")]
+    pub lockout_threshold: i64,
+
+    // Failed attempts from an address before it is locked out
+    #[serde(default = "default_ip_lockout_threshold")]
+    pub ip_lockout_threshold: i64,
+
+    #[serde(default = "default_lockout_duration")]
+    pub lockout_duration: i64,
+
+    // Failed attempts are forgotten after this long without a failure
+    #[serde(default = "default_reset_after")]
+    pub reset_after: i64,
+}
+
+fn default_throttle_config() -> ThrottleConfig {
+    ThrottleConfig {
+        free_attempts: default_free_attempts(),
+        base_delay: default_base_delay(),
+        max_delay: default_max_delay(),
+        lockout_threshold: default_lockout_threshold(),
+        ip_lockout_threshold: default_ip_lockout_threshold(),
+        lockout_duration: default_lockout_duration(),
+        reset_after: default_reset_after(),
+    }
+}
+
+fn default_free_attempts() -> i64 {
+    3
+}
+
+fn default_base_delay() -> i64 {
+    1
+}
+
+fn default_max_delay() -> i64 {
+    300
+}
+
+fn default_lockout_threshold() -> i64 {
+    10
+}
+
+fn default_ip_lockout_threshold() -> i64 {
+    100
+}
+
+fn default_lockout_duration() -> i64 {
+    900
+}
+
+fn default_reset_after() -> i64 {
+    3600
+}
+
+#[derive(Debug, Deserialize)]
+pub enum KeyAlgorithm {
+    RS256,
+    ES256,
+    EdDSA,
+}
+
+// Key used to sign access tokens. Keys are rotated by adding a new key
+// with an activate_at time in the future; it is published immediately and
+// used for signing once active.
+#[derive(Debug, Deserialize)]
+pub struct KeyConfig {
+    // Key id included in tokens signed with this key
+    pub kid: String,
+
+    pub algorithm: KeyAlgorithm,
+
+    // Path to a PEM encoded private key. PKCS#8 for all algorithms, or
+    // PKCS#1 for RS256.
+    pub private_key: String,
+
+    // RFC 3339 time the key starts being used for signing. Defaults to
+    // immediately.
+    #[serde(default)]
+    pub activate_at: Option<String>,
+
+    // RFC 3339 time the key stops being used for signing. Tokens signed
+    // with the key are accepted until they expire.
+    #[serde(default)]
+    pub retire_at: Option<String>,
+}
+
+// Security relevant events are always stored in the database. They can
+// also be appended to a file as JSON lines for ingestion by a SIEM.
+#[derive(Debug, Deserialize)]
+pub struct AuditConfig {
+    // Path of the JSON lines file. Events are not written to a file when
+    // empty.
+    #[serde(default = "default_audit_file")]
+    pub file: String,
+}
+
+fn default_audit_config() -> AuditConfig {
+    AuditConfig {
+        file: default_audit_file(),
+    }
+}
+
+fn default_audit_file() -> String {
+    "".to_string()
+}
+
+// Personal data exports
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExportConfig {
+    // Directory export archives are written to
+    #[serde(default = "default_export_dir")]
+    pub dir: String,
+
+    // Hours a download link is valid for once the export is ready
+    #[serde(default = "default_export_link_ttl")]
+    pub link_ttl: i64,
+
+    // Public URL of the download endpoint. The download token is appended
+    // to this URL in notification emails.
+    #[serde(default = "default_export_download_url")]
+    pub download_url: String,
+
+    // Minutes an export may stay pending before it is treated as failed,
+    // such as when the server building it stopped
+    #[serde(default = "default_export_build_timeout")]
+    pub build_timeout: i64,
+}
+
+fn default_export_config() -> ExportConfig {
+    ExportConfig {
+        dir: default_export_dir(),
+        link_ttl: default_export_link_ttl(),
+        download_url: default_export_download_url(),
+        build_timeout: default_export_build_timeout(),
+    }
+}
+
+fn default_export_dir() -> String {
+    "/tmp/hapi/exports".to_string()
+}
+
+fn default_export_link_ttl() -> i64 {
+    48
+}
+
+fn default_export_download_url() -> String {
+    "http://127.0.0.1:8000/exports".to_string()
+}
+
+fn default_export_build_timeout() -> i64 {
+    60
+}
+
+// Cleanup applied to GPS tracks when activities are analysed
+#[derive(Clone, Debug, Deserialize)]
+pub struct TrackConfig {
+    // Positions implying a faster speed, in metres per second, from the
+    // previous position are dropped as outliers
+    #[serde(default = "default_max_speed")]
+    pub max_speed: f64,
+
+    // Number of points averaged when smoothing positions and altitude. 1
+    // disables smoothing.
+    #[serde(default = "default_smoothing_window")]
+    pub smoothing_window: usize,
+
+    // Slower than this, in metres per second, counts as paused when
+    // computing moving time
+    #[serde(default = "default_pause_speed")]
+    pub pause_speed: f64,
+
+    // Directory of SRTM .hgt tiles used to correct altitude. Empty keeps
+    // the altitude recorded by the device.
+    #[serde(default = "default_dem_dir")]
+    pub dem_dir: String,
+}
+
+fn default_track_config() -> TrackConfig {
+    TrackConfig {
+        max_speed: default_max_speed(),
+        smoothing_window: default_smoothing_window(),
+        pause_speed: default_pause_speed(),
+        dem_dir: default_dem_dir(),
+    }
+}
+
+fn default_max_speed() -> f64 {
+    50.0
+}
+
+fn default_smoothing_window() -> usize {
+    5
+}
+
+fn default_pause_speed() -> f64 {
+    0.5
+}
+
+fn default_dem_dir() -> String {
+    "".to_string()
+}
@@ -0,0 +1,82 @@
+// Accounts are deleted in two steps. Deleting an account deactivates it
+// and revokes its sessions straight away, then a background worker
+// purges everything stored for the user once the grace period has ended.
+// Until then the user can cancel the deletion by logging in through
+// /users/restore.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use hdb::platform::{Pool, PlatformConnection};
+use hdb::platform::models::tokens;
+
+use audit::{self, AuditLog, Event};
+use export;
+use models::{deletions, users};
+use throttle::Subject;
+
+// Start a thread that purges accounts whose grace period has ended every
+// interval seconds
+pub fn spawn_worker(pool: Pool,
+                    file_dir: String,
+                    export_dir: String,
+                    interval: u64,
+                    audit_log: AuditLog) {
+    thread::spawn(move || {
+        loop {
+            match pool.get() {
+                Ok(conn) => purge_due(&conn, &file_dir, &export_dir, &audit_log),
+                Err(e) => eprintln!("Error connecting to database to purge accounts: {}", e),
+            }
+            thread::sleep(Duration::from_secs(interval));
+        }
+    });
+}
+
+pub fn purge_due(conn: &PlatformConnection,
+                 file_dir: &str,
+                 export_dir: &str,
+                 audit_log: &AuditLog) {
+    let due = match deletions::due(conn) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Error finding accounts to purge: {}", e);
+            return;
+        }
+    };
+    for user_id in due {
+        if let Err(e) = purge(&user_id, conn, file_dir, export_dir, audit_log) {
+            eprintln!("Error purging account {}: {}", user_id, e);
+        }
+    }
+}
+
+// Files are removed first so a failure leaves the account in place to be
+// retried on the next run
+fn purge(user_id: &Uuid,
+         conn: &PlatformConnection,
+         file_dir: &str,
+         export_dir: &str,
+         audit_log: &AuditLog) -> Result<(), String> {
+    let user = users::get(user_id, conn).map_err(|e| e.to_string())?;
+    export::remove_all(user_id, export_dir, conn)?;
+    let dir = Path::new(file_dir).join(user_id.to_string());
+    match fs::remove_dir_all(&dir) {
+        Ok(_) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(format!("removing {}: {}", dir.display(), e)),
+    }
+    if let Ok(token) = tokens::get_by_user_id(user_id, conn) {
+        if !tokens::delete(&token.id, conn) {
+            return Err("deleting access token".to_string());
+        }
+    }
+    let failures_key = Subject::Username(user.username).key();
+    deletions::purge(user_id, &failures_key, conn).map_err(|e| e.to_string())?;
+    audit_log.record(Event::system(audit::ACCOUNT_PURGED).user(user_id), conn);
+    Ok(())
+}
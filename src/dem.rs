@@ -0,0 +1,143 @@
+// Elevations from a directory of SRTM .hgt tiles. Each tile covers one
+// degree of latitude and longitude and is named after its south west
+// corner, e.g. N37W123.hgt. Tiles are grids of big endian 16 bit heights
+// in metres, from the north west corner row by row, with 1201 (3 arc
+// second) or 3601 (1 arc second) points per side.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Height of points with no data
+const VOID: i16 = -32768;
+
+struct Tile {
+    size: usize,
+    heights: Vec<i16>,
+}
+
+impl Tile {
+    fn height(&self, row: usize, col: usize) -> Option<f64> {
+        match self.heights[row * self.size + col] {
+            VOID => None,
+            h => Some(h as f64),
+        }
+    }
+}
+
+// Tiles are read the first time they are needed and kept until the Dem is
+// dropped
+pub struct Dem<'a> {
+    dir: &'a str,
+    tiles: HashMap<(i32, i32), Option<Tile>>,
+}
+
+impl<'a> Dem<'a> {
+    pub fn new(dir: &'a str) -> Dem<'a> {
+        Dem { dir: dir, tiles: HashMap::new() }
+    }
+
+    // Elevation in metres interpolated from the four surrounding points,
+    // or None when the tile is missing or a surrounding point has no data
+    pub fn elevation(&mut self, lat: f64, lng: f64) -> Option<f64> {
+        let key = (lat.floor() as i32, lng.floor() as i32);
+        let dir = self.dir;
+        let tile = match *self.tiles.entry(key).or_insert_with(|| read_tile(dir, key)) {
+            Some(ref t) => t,
+            None => return None,
+        };
+        let last = (tile.size - 1) as f64;
+        let y = (key.0 as f64 + 1.0 - lat) * last;
+        let x = (lng - key.1 as f64) * last;
+        let row = (y.floor() as usize).min(tile.size - 2);
+        let col = (x.floor() as usize).min(tile.size - 2);
+        let (dy, dx) = (y - row as f64, x - col as f64);
+        let top = tile.height(row, col)? * (1.0 - dx) + tile.height(row, col + 1)? * dx;
+        let bottom = tile.height(row + 1, col)? * (1.0 - dx) + tile.height(row + 1, col + 1)? * dx;
+        Some(top * (1.0 - dy) + bottom * dy)
+    }
+}
+
+fn tile_name(lat: i32, lng: i32) -> String {
+    format!("{}{:02}{}{:03}.hgt",
+            if lat < 0 { 'S' } else { 'N' },
+            lat.abs(),
+            if lng < 0 { 'W' } else { 'E' },
+            lng.abs())
+}
+
+// Missing and malformed tiles are treated as having no data
+fn read_tile(dir: &str, key: (i32, i32)) -> Option<Tile> {
+    let path = Path::new(dir).join(tile_name(key.0, key.1));
+    let mut buffer = Vec::new();
+    if File::open(&path).and_then(|mut f| f.read_to_end(&mut buffer)).is_err() {
+        return None;
+    }
+    let size = ((buffer.len() / 2) as f64).sqrt() as usize;
+    if size < 2 || size * size * 2 != buffer.len() {
+        eprintln!("Ignoring elevation tile {}: unexpected size", path.display());
+        return None;
+    }
+    let heights = buffer.chunks(2).map(|b| ((b[0] as u16) << 8 | b[1] as u16) as i16).collect();
+    Some(Tile { size: size, heights: heights })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use super::*;
+
+    // A directory holding one tile with its south west corner at lat, lng
+    fn tile_dir(name: &str, lat: i32, lng: i32, heights: &[i16]) -> String {
+        let dir = env::temp_dir().join(format!("hapi-dem-test-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        let mut data = Vec::new();
+        for h in heights {
+            data.push((*h as u16 >> 8) as u8);
+            data.push(*h as u8);
+        }
+        File::create(dir.join(tile_name(lat, lng))).unwrap().write_all(&data).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    fn close(a: Option<f64>, b: f64) -> bool {
+        a.map(|a| (a - b).abs() < 1e-9).unwrap_or(false)
+    }
+
+    #[test]
+    fn tiles_are_named_after_their_south_west_corner() {
+        assert_eq!(tile_name(37, -123), "N37W123.hgt");
+        assert_eq!(tile_name(-9, 7), "S09E007.hgt");
+        assert_eq!(tile_name(0, 0), "N00E000.hgt");
+    }
+
+    #[test]
+    fn elevation_is_interpolated_between_the_surrounding_points() {
+        // North west, north east, south west, south east
+        let dir = tile_dir("interpolation", 45, 6, &[100, 200, 300, 400]);
+        let mut dem = Dem::new(&dir);
+        assert!(close(dem.elevation(45.0, 6.0), 300.0));
+        assert!(close(dem.elevation(45.5, 6.5), 250.0));
+        // 125 along the north edge, 325 along the south edge
+        assert!(close(dem.elevation(45.75, 6.25), 175.0));
+        // 175 along the north edge, 375 along the south edge
+        assert!(close(dem.elevation(45.25, 6.75), 325.0));
+    }
+
+    #[test]
+    fn voids_and_missing_tiles_have_no_elevation() {
+        let dir = tile_dir("void", 45, 6, &[100, 200, 300, VOID]);
+        let mut dem = Dem::new(&dir);
+        assert!(dem.elevation(45.9, 6.1).is_none());
+        assert!(dem.elevation(44.5, 6.5).is_none());
+    }
+
+    #[test]
+    fn malformed_tiles_are_ignored() {
+        let dir = tile_dir("malformed", 45, 6, &[100, 200, 300]);
+        assert!(Dem::new(&dir).elevation(45.5, 6.5).is_none());
+    }
+}
@@ -0,0 +1,359 @@
+// Personal data exports. Exporting builds a zip archive of everything
+// stored for the user in a background thread:
+//
+//   profile.json        account details, email address, roles, API keys,
+//                       OAuth clients, privacy zones, segments created
+//                       by the user and the athlete profile with its
+//                       physiological history and training zones
+//   activities.json     activity metadata
+//   analyses.json       activity summaries, raw and corrected track
+//                       metrics, polylines with points inside privacy
+//                       zones removed, device laps, time in zones,
+//                       training stress, mean-maximal curves, best
+//                       efforts and segment efforts
+//   audit_events.json   the user's audit events
+//   files/              original activity files, as uploaded and not
+//                       trimmed to privacy zones
+//
+// The archive is downloaded with a random token that is only stored
+// hashed, and the user is emailed the download link when it is ready.
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use serde_json;
+use uuid::Uuid;
+use zip::ZipWriter;
+use zip::write::FileOptions;
+
+use hdb::platform::{Pool, PlatformConnection};
+
+use audit::{self, AuditLog, Event};
+use config::ExportConfig;
+use mail::{Mailer, Message};
+use models::{activities, api_keys, curves, emails, exports, laps, oauth, polylines, privacy,
+             profiles, roles, segments, summaries, totp, tracks, training_load, training_zones,
+             users};
+use models::audit::{self as audit_events, AuditEvent, Filter};
+use polyline;
+use processing::Analysis;
+
+// Audit events are read in pages of this size
+const AUDIT_PAGE_SIZE: i64 = 1000;
+
+#[derive(Serialize)]
+struct Profile {
+    user: users::UserSummary,
+    email: Option<emails::UserEmail>,
+    roles: Vec<String>,
+    two_factor_enabled: bool,
+    api_keys: Vec<api_keys::ApiKey>,
+    oauth_clients: Vec<oauth::Client>,
+    privacy_zones: Vec<privacy::PrivacyZone>,
+    segments: Vec<segments::Segment>,
+    athlete: profiles::Profile,
+    physiology: BTreeMap<&'static str, Vec<profiles::SettingValue>>,
+    training_zones: Vec<training_zones::ManualZones>,
+    exported_on: DateTime<Utc>,
+}
+
+pub fn archive_path(dir: &str, export_id: &Uuid) -> PathBuf {
+    Path::new(dir).join(format!("{}.zip", export_id))
+}
+
+// Build the export in a background thread. The download token is only
+// held in memory so it can be sent in the notification email.
+pub fn start(export_id: Uuid,
+             user_id: Uuid,
+             token: String,
+             pool: Pool,
+             file_dir: String,
+             config: ExportConfig,
+             mailer: Mailer,
+             audit_log: AuditLog) {
+    thread::spawn(move || {
+        let conn = match pool.get() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Error connecting to database to export {}: {}", export_id, e);
+                return;
+            }
+        };
+        let path = archive_path(&config.dir, &export_id);
+        let fail = || {
+            remove_archive(&config.dir, &export_id);
+            if !exports::set_status(&export_id, exports::FAILED, &conn) {
+                eprintln!("Error marking export {} as failed", export_id);
+            }
+            audit_log.record(Event::system(audit::DATA_EXPORT_FAILED).user(&user_id), &conn);
+        };
+        // A panic while building fails the export rather than leaving it
+        // pending
+        let built = panic::catch_unwind(AssertUnwindSafe(|| build(&user_id, &path, &file_dir, &conn)));
+        let bytes = match built {
+            Ok(Ok(b)) => b,
+            Ok(Err(e)) => {
+                eprintln!("Error building export {}: {}", export_id, e);
+                fail();
+                return;
+            },
+            Err(_) => {
+                eprintln!("Error building export {}: panicked", export_id);
+                fail();
+                return;
+            },
+        };
+        let expires_on = Utc::now() + Duration::hours(config.link_ttl);
+        if !exports::complete(&export_id, bytes as i64, &expires_on, &conn) {
+            eprintln!("Error completing export {}", export_id);
+            fail();
+            return;
+        }
+        audit_log.record(Event::system(audit::DATA_EXPORT_READY)
+                             .user(&user_id)
+                             .details(export_id.to_string()),
+                         &conn);
+        notify(&user_id, &token, &expires_on, &config, &mailer, &conn);
+    });
+}
+
+// Write the archive to a temporary file and move it into place once it is
+// complete. Returns the size of the archive.
+fn build(user_id: &Uuid,
+         path: &Path,
+         file_dir: &str,
+         conn: &PlatformConnection) -> Result<u64, String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    let partial = path.with_extension("zip.part");
+    let file = File::create(&partial).map_err(|e| e.to_string())?;
+    let mut zip = ZipWriter::new(file);
+
+    let history = profiles::history(user_id, conn).map_err(|e| e.to_string())?;
+    let profile = Profile {
+        user: users::get(user_id, conn).map_err(|e| e.to_string())?,
+        email: emails::get_by_user_id(user_id, conn).ok(),
+        roles: roles::get_by_user_id(user_id, conn).map_err(|e| e.to_string())?,
+        two_factor_enabled: totp::is_enabled(user_id, conn),
+        api_keys: api_keys::get_by_user_id(user_id, conn).map_err(|e| e.to_string())?,
+        oauth_clients: oauth::get_clients_by_owner(user_id, conn).map_err(|e| e.to_string())?,
+        privacy_zones: privacy::get_zones(user_id, conn).map_err(|e| e.to_string())?,
+        segments: segments::get_by_user_id(user_id, conn).map_err(|e| e.to_string())?,
+        athlete: profiles::get(user_id, conn).map_err(|e| e.to_string())?,
+        physiology: profiles::SETTINGS.iter().map(|s| (*s, history.values(s))).collect(),
+        training_zones: training_zones::get_by_user_id(user_id, conn).map_err(|e| e.to_string())?,
+        exported_on: Utc::now(),
+    };
+    add_json(&mut zip, "profile.json", &profile)?;
+    let user_activities = activities::get_by_user_id(user_id, conn).map_err(|e| e.to_string())?;
+    add_json(&mut zip, "activities.json", &user_activities)?;
+    add_json(&mut zip, "analyses.json", &analyses(user_id, conn)?)?;
+    add_json(&mut zip, "audit_events.json", &user_audit_events(user_id, conn)?)?;
+    add_files(&mut zip, &Path::new(file_dir).join(user_id.to_string()), "files")?;
+
+    zip.finish().map_err(|e| e.to_string())?;
+    fs::rename(&partial, path).map_err(|e| e.to_string())?;
+    fs::metadata(path).map(|m| m.len()).map_err(|e| e.to_string())
+}
+
+fn analyses(user_id: &Uuid, conn: &PlatformConnection) -> Result<Vec<Analysis>, String> {
+    let user_summaries = summaries::get_by_user_id(user_id, conn).map_err(|e| e.to_string())?;
+    let privacy_zones = privacy::get_zones(user_id, conn).map_err(|e| e.to_string())?;
+    let mut all = Vec::with_capacity(user_summaries.len());
+    for summary in user_summaries {
+        let activity_laps = laps::get_by_activity_id(&summary.activity_id, conn)
+            .map_err(|e| e.to_string())?;
+        let activity_polylines = polylines::get_by_activity_id(&summary.activity_id, conn)
+            .map_err(|e| e.to_string())?
+            .iter()
+            .map(|p| polyline::trim(p, &privacy_zones))
+            .collect();
+        let zones = training_zones::get_activity_times(&summary.activity_id, conn)
+            .map_err(|e| e.to_string())?;
+        let load = training_load::get_activity_load(&summary.activity_id, conn).ok();
+        let activity_curves = curves::get_activity_curves(&summary.activity_id, conn)
+            .map_err(|e| e.to_string())?;
+        let efforts = curves::get_activity_efforts(&summary.activity_id, conn)
+            .map_err(|e| e.to_string())?;
+        let segment_efforts = segments::get_activity_efforts(&summary.activity_id, conn)
+            .map_err(|e| e.to_string())?;
+        all.push(Analysis {
+            track: tracks::get(&summary.activity_id, conn).ok(),
+            summary: summary,
+            polylines: activity_polylines,
+            laps: activity_laps,
+            zones: zones,
+            load: load,
+            curves: activity_curves,
+            best_efforts: efforts,
+            segment_efforts: segment_efforts,
+            records: Vec::new(),
+        });
+    }
+    Ok(all)
+}
+
+fn user_audit_events(user_id: &Uuid, conn: &PlatformConnection) -> Result<Vec<AuditEvent>, String> {
+    let mut events = Vec::new();
+    loop {
+        let filter = Filter {
+            user_id: Some(*user_id),
+            event: None,
+            limit: AUDIT_PAGE_SIZE,
+            offset: events.len() as i64,
+        };
+        let page = audit_events::query(&filter, conn).map_err(|e| e.to_string())?;
+        let done = (page.len() as i64) < AUDIT_PAGE_SIZE;
+        events.extend(page);
+        if done {
+            return Ok(events);
+        }
+    }
+}
+
+fn add_json<T: Serialize>(zip: &mut ZipWriter<File>, name: &str, value: &T) -> Result<(), String> {
+    let json = serde_json::to_vec_pretty(value).map_err(|e| e.to_string())?;
+    zip.start_file(name, FileOptions::default()).map_err(|e| e.to_string())?;
+    zip.write_all(&json).map_err(|e| e.to_string())
+}
+
+// Add the files under dir to the archive under prefix
+fn add_files(zip: &mut ZipWriter<File>, dir: &Path, prefix: &str) -> Result<(), String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        // Users that have not imported any activities have no directory
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.to_string()),
+    };
+    for entry in entries {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let name = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        let path = entry.path();
+        if path.is_dir() {
+            add_files(zip, &path, &name)?;
+            continue;
+        }
+        let mut contents = Vec::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_end(&mut contents))
+            .map_err(|e| format!("reading {}: {}", path.display(), e))?;
+        zip.start_file(name, FileOptions::default()).map_err(|e| e.to_string())?;
+        zip.write_all(&contents).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Email the download link to users with a verified email address
+fn notify(user_id: &Uuid,
+          token: &str,
+          expires_on: &DateTime<Utc>,
+          config: &ExportConfig,
+          mailer: &Mailer,
+          conn: &PlatformConnection) {
+    let email = match emails::get_by_user_id(user_id, conn) {
+        Ok(ref e) if e.verified => e.email.clone(),
+        _ => return,
+    };
+    let body = format!("The export of your hapi data is ready. Download it from:\n\n\
+                        {}/{}\n\n\
+                        The link expires on {}.\n",
+                       config.download_url, token, expires_on.to_rfc2822());
+    let message = Message::new(email, "Your hapi data export is ready".to_string(), body);
+    if let Err(e) = mailer.send(&message) {
+        eprintln!("Error sending export notification to {}: {}", user_id, e);
+    }
+}
+
+// Start a thread that deletes archives whose download link has expired
+// every interval seconds
+// Exports requested before this time that are still pending are assumed
+// to have failed
+pub fn pending_cutoff(config: &ExportConfig) -> DateTime<Utc> {
+    Utc::now() - Duration::minutes(config.build_timeout)
+}
+
+// Expired and stale exports are removed every interval seconds
+pub fn spawn_cleanup(pool: Pool, config: ExportConfig, interval: u64) {
+    thread::spawn(move || {
+        loop {
+            match pool.get() {
+                Ok(conn) => {
+                    remove_stale(&conn, &config);
+                    remove_expired(&conn, &config.dir);
+                },
+                Err(e) => eprintln!("Error connecting to database to remove exports: {}", e),
+            }
+            thread::sleep(StdDuration::from_secs(interval));
+        }
+    });
+}
+
+// Exports that have been pending for longer than the build timeout were
+// left by a server that stopped while building them. Exports still being
+// built by other instances are not affected.
+fn remove_stale(conn: &PlatformConnection, config: &ExportConfig) {
+    match exports::fail_stale(&pending_cutoff(config), conn) {
+        Ok(stale) => {
+            for id in stale {
+                remove_archive(&config.dir, &id);
+            }
+        },
+        Err(e) => eprintln!("Error failing stale exports: {}", e),
+    }
+}
+
+// Remove the archive of a failed export and any partly written archive
+fn remove_archive(dir: &str, export_id: &Uuid) {
+    let path = archive_path(dir, export_id);
+    for p in &[path.with_extension("zip.part"), path] {
+        match fs::remove_file(p) {
+            Ok(_) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => eprintln!("Error removing export {}: {}", export_id, e),
+        }
+    }
+}
+
+fn remove_expired(conn: &PlatformConnection, dir: &str) {
+    let expired = match exports::expired(conn) {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("Error finding expired exports: {}", e);
+            return;
+        }
+    };
+    for id in expired {
+        match fs::remove_file(archive_path(dir, &id)) {
+            Ok(_) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => {
+                eprintln!("Error removing export {}: {}", id, e);
+                continue;
+            }
+        }
+        exports::set_status(&id, exports::EXPIRED, conn);
+    }
+}
+
+// Remove every archive for the user. Used when the account is purged.
+pub fn remove_all(user_id: &Uuid, dir: &str, conn: &PlatformConnection) -> Result<(), String> {
+    let user_exports = exports::get_by_user_id(user_id, conn).map_err(|e| e.to_string())?;
+    for export in user_exports {
+        for path in &[archive_path(dir, &export.id),
+                      archive_path(dir, &export.id).with_extension("zip.part")] {
+            match fs::remove_file(path) {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(format!("removing {}: {}", path.display(), e)),
+            }
+        }
+    }
+    Ok(())
+}
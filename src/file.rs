@@ -1,5 +1,8 @@
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
 
 use rocket::{Request, Data, Outcome};
 use rocket::data::{self, FromData};
@@ -115,6 +118,11 @@ impl FromData for ActivityRequest {
     }
 }
 
+// Activity files are stored under a directory for each user
+pub fn activity_path(file_dir: &str, user_id: &Uuid, filename: &str) -> PathBuf {
+    Path::new(file_dir).join(user_id.to_string()).join(filename)
+}
+
 pub fn create_dir(path: &Path) {
     let _ = fs::create_dir_all(path);
 }
@@ -123,3 +131,30 @@ pub fn remove_file(file: SavedFile) {
     println!("Removing file: {}", file.path.to_str().unwrap());
     let _ = fs::remove_file(&file.path);
 }
+
+// Disk space used by the files under a directory
+#[derive(Serialize, Default)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+pub fn usage(path: &Path) -> io::Result<Usage> {
+    let mut total = Usage::default();
+    if !path.exists() {
+        return Ok(total);
+    }
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            let sub = usage(&entry.path())?;
+            total.bytes += sub.bytes;
+            total.files += sub.files;
+        } else {
+            total.bytes += metadata.len();
+            total.files += 1;
+        }
+    }
+    Ok(total)
+}
@@ -0,0 +1,471 @@
+// Decoder for the parts of the FIT file format used for analysis. FIT
+// files are a sequence of definition messages, which describe the layout
+// of the data messages that follow them, and data messages. Only record
+// messages (the samples), session and sport messages (the sport) and lap
+// messages are decoded; everything else is skipped.
+use std::fmt;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+// Seconds between the Unix epoch and the FIT epoch, 1989-12-31T00:00:00Z
+const FIT_EPOCH: i64 = 631065600;
+// Degrees per semicircle
+const SEMICIRCLE: f64 = 180.0 / 2147483648.0;
+
+const MESG_SPORT: u16 = 12;
+const MESG_SESSION: u16 = 18;
+const MESG_LAP: u16 = 19;
+const MESG_RECORD: u16 = 20;
+
+const FIELD_TIMESTAMP: u8 = 253;
+
+// Seconds. Longer recordings are assumed to be corrupt; analysis works on
+// one value per second of the recording.
+const MAX_DURATION: u32 = 7 * 24 * 3600;
+
+#[derive(Debug)]
+pub enum Error {
+    Header,
+    Truncated,
+    UndefinedMessage(u8),
+    NoRecords,
+    TooLong,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Header => write!(f, "not a FIT file"),
+            Error::Truncated => write!(f, "file is truncated"),
+            Error::UndefinedMessage(t) => write!(f, "data message {} has no definition", t),
+            Error::NoRecords => write!(f, "file has no records"),
+            Error::TooLong => write!(f, "recording is longer than {} days", MAX_DURATION / 86400),
+        }
+    }
+}
+
+// A single sample. time is seconds since the start of the recording.
+#[derive(Clone, Default)]
+pub struct Sample {
+    pub time: f64,
+    pub lat: Option<f64>,
+    pub lng: Option<f64>,
+    // Metres
+    pub altitude: Option<f64>,
+    pub heart_rate: Option<f64>,
+    pub cadence: Option<f64>,
+    pub power: Option<f64>,
+    // Metres per second
+    pub speed: Option<f64>,
+    // Metres from the start
+    pub distance: Option<f64>,
+}
+
+// A lap recorded by the device, in seconds since the start of the recording
+#[derive(Clone)]
+pub struct Lap {
+    pub start: f64,
+    pub end: f64,
+}
+
+pub struct Recording {
+    pub sport: Option<String>,
+    pub start_time: DateTime<Utc>,
+    pub samples: Vec<Sample>,
+    pub laps: Vec<Lap>,
+}
+
+struct FieldDefinition {
+    number: u8,
+    size: usize,
+    base_type: u8,
+}
+
+struct Definition {
+    global: u16,
+    big_endian: bool,
+    fields: Vec<FieldDefinition>,
+    // Developer fields are skipped
+    developer_size: usize,
+}
+
+// Field values of a data message, as raw integers or floats with invalid
+// values removed
+struct Message {
+    global: u16,
+    values: Vec<(u8, f64)>,
+}
+
+impl Message {
+    fn get(&self, number: u8) -> Option<f64> {
+        self.values.iter().find(|&&(n, _)| n == number).map(|&(_, v)| v)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.pos + n > self.data.len() {
+            return Err(Error::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+}
+
+fn uint(bytes: &[u8], big_endian: bool) -> u64 {
+    let mut value = 0u64;
+    if big_endian {
+        for b in bytes {
+            value = (value << 8) | *b as u64;
+        }
+    } else {
+        for b in bytes.iter().rev() {
+            value = (value << 8) | *b as u64;
+        }
+    }
+    value
+}
+
+// Decode the first value of a field. Arrays, strings and byte fields are
+// not needed and are skipped.
+fn value(bytes: &[u8], base_type: u8, big_endian: bool) -> Option<f64> {
+    let (size, signed, zero_invalid) = match base_type & 0x1F {
+        0x00 | 0x02 => (1, false, false),
+        0x01 => (1, true, false),
+        0x03 => (2, true, false),
+        0x04 => (2, false, false),
+        0x05 => (4, true, false),
+        0x06 => (4, false, false),
+        0x0A => (1, false, true),
+        0x0B => (2, false, true),
+        0x0C => (4, false, true),
+        0x08 => {
+            if bytes.len() < 4 {
+                return None;
+            }
+            let bits = uint(&bytes[..4], big_endian) as u32;
+            if bits == 0xFFFFFFFF {
+                return None;
+            }
+            return Some(f32::from_bits(bits) as f64);
+        },
+        0x09 => {
+            if bytes.len() < 8 {
+                return None;
+            }
+            let bits = uint(&bytes[..8], big_endian);
+            if bits == 0xFFFFFFFFFFFFFFFF {
+                return None;
+            }
+            return Some(f64::from_bits(bits));
+        },
+        _ => return None,
+    };
+    if bytes.len() < size {
+        return None;
+    }
+    let raw = uint(&bytes[..size], big_endian);
+    let bits = size as u32 * 8;
+    let invalid = if zero_invalid {
+        0
+    } else if signed {
+        (1u64 << (bits - 1)) - 1
+    } else {
+        (1u64 << bits) - 1
+    };
+    if raw == invalid {
+        return None;
+    }
+    if signed && raw & (1u64 << (bits - 1)) != 0 {
+        Some(raw as i64 as f64 - (1u64 << bits) as f64)
+    } else {
+        Some(raw as f64)
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Recording, Error> {
+    if data.len() < 12 || &data[8..12] != b".FIT" {
+        return Err(Error::Header);
+    }
+    let header_size = data[0] as usize;
+    let data_size = uint(&data[4..8], false) as usize;
+    if header_size < 12 || header_size + data_size > data.len() {
+        return Err(Error::Header);
+    }
+    let mut reader = Reader {
+        data: &data[..header_size + data_size],
+        pos: header_size,
+    };
+    let mut definitions: Vec<Option<Definition>> = (0..16).map(|_| None).collect();
+    let mut last_timestamp = 0u32;
+    let mut sport = None;
+    let mut records = Vec::new();
+    let mut laps = Vec::new();
+
+    while reader.pos < reader.data.len() {
+        let header = reader.u8()?;
+        // Compressed timestamp headers carry the low five bits of the
+        // timestamp of a data message
+        let (local, time_offset) = if header & 0x80 != 0 {
+            ((header >> 5) & 0x03, Some((header & 0x1F) as u32))
+        } else if header & 0x40 != 0 {
+            let local = header & 0x0F;
+            let developer = header & 0x20 != 0;
+            reader.bytes(1)?;
+            let big_endian = reader.u8()? == 1;
+            let global = uint(reader.bytes(2)?, big_endian) as u16;
+            let count = reader.u8()?;
+            let mut fields = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let field = reader.bytes(3)?;
+                fields.push(FieldDefinition {
+                    number: field[0],
+                    size: field[1] as usize,
+                    base_type: field[2],
+                });
+            }
+            let mut developer_size = 0;
+            if developer {
+                let count = reader.u8()?;
+                for _ in 0..count {
+                    developer_size += reader.bytes(3)?[1] as usize;
+                }
+            }
+            definitions[local as usize] = Some(Definition {
+                global: global,
+                big_endian: big_endian,
+                fields: fields,
+                developer_size: developer_size,
+            });
+            continue;
+        } else {
+            (header & 0x0F, None)
+        };
+
+        let message = match definitions[local as usize] {
+            Some(ref definition) => {
+                let mut values = Vec::new();
+                for field in &definition.fields {
+                    let bytes = reader.bytes(field.size)?;
+                    if let Some(v) = value(bytes, field.base_type, definition.big_endian) {
+                        values.push((field.number, v));
+                    }
+                }
+                reader.bytes(definition.developer_size)?;
+                Message { global: definition.global, values: values }
+            },
+            None => return Err(Error::UndefinedMessage(local)),
+        };
+
+        let timestamp = match time_offset {
+            Some(offset) => {
+                let mut timestamp = (last_timestamp & !0x1F).wrapping_add(offset);
+                if offset < last_timestamp & 0x1F {
+                    timestamp = timestamp.wrapping_add(0x20);
+                }
+                Some(timestamp)
+            },
+            None => message.get(FIELD_TIMESTAMP).map(|t| t as u32),
+        };
+        if let Some(t) = timestamp {
+            last_timestamp = t;
+        }
+
+        match message.global {
+            MESG_RECORD => {
+                if let Some(t) = timestamp {
+                    records.push((t, message));
+                }
+            },
+            MESG_LAP => {
+                // The message timestamp is the end of the lap. Laps without
+                // a start time are ignored.
+                if let (Some(start), Some(end)) = (message.get(2), timestamp) {
+                    laps.push((start as u32, end));
+                }
+            },
+            MESG_SESSION | MESG_SPORT => {
+                let field = if message.global == MESG_SESSION { 5 } else { 0 };
+                if let Some(s) = message.get(field) {
+                    sport = Some(sport_name(s as u8));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    if records.is_empty() {
+        return Err(Error::NoRecords);
+    }
+    // Devices occasionally write records out of order. Analysis expects
+    // samples in time order; the sort is stable so records with the same
+    // timestamp keep the order they were written in.
+    records.sort_by_key(|&(t, _)| t);
+    let start = records[0].0;
+    if records[records.len() - 1].0 - start > MAX_DURATION {
+        return Err(Error::TooLong);
+    }
+    let samples = records.iter().map(|&(t, ref m)| record_sample(t, start, m)).collect();
+    let laps = laps.iter()
+        .filter(|&&(lap_start, lap_end)| lap_end > lap_start)
+        .map(|&(lap_start, lap_end)| Lap {
+            start: lap_start.saturating_sub(start) as f64,
+            end: lap_end.saturating_sub(start) as f64,
+        })
+        .collect();
+    Ok(Recording {
+        sport: sport,
+        start_time: Utc.timestamp(FIT_EPOCH + start as i64, 0),
+        samples: samples,
+        laps: laps,
+    })
+}
+
+fn record_sample(timestamp: u32, start: u32, message: &Message) -> Sample {
+    Sample {
+        time: timestamp.saturating_sub(start) as f64,
+        lat: message.get(0).map(|v| v * SEMICIRCLE),
+        lng: message.get(1).map(|v| v * SEMICIRCLE),
+        // Enhanced fields have a wider range and are preferred when present
+        altitude: message.get(78).or(message.get(2)).map(|v| v / 5.0 - 500.0),
+        heart_rate: message.get(3),
+        cadence: message.get(4),
+        power: message.get(7),
+        speed: message.get(73).or(message.get(6)).map(|v| v / 1000.0),
+        distance: message.get(5).map(|v| v / 100.0),
+    }
+}
+
+fn sport_name(sport: u8) -> String {
+    match sport {
+        1 => "running",
+        2 => "cycling",
+        5 => "swimming",
+        11 => "walking",
+        17 => "hiking",
+        _ => "other",
+    }.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u32 = 900000000;
+
+    // A FIT file with a definition for record messages with a timestamp
+    // and heart rate, followed by one record for each (timestamp, heart
+    // rate) pair. The CRC is not checked and is left out.
+    fn file(records: &[(u32, u8)]) -> Vec<u8> {
+        let mut body = vec![
+            0x40, 0, 0, MESG_RECORD as u8, 0, 2,
+            FIELD_TIMESTAMP, 4, 0x86,
+            3, 1, 0x02,
+        ];
+        for &(timestamp, heart_rate) in records {
+            body.push(0x00);
+            body.extend_from_slice(&[timestamp as u8,
+                                     (timestamp >> 8) as u8,
+                                     (timestamp >> 16) as u8,
+                                     (timestamp >> 24) as u8]);
+            body.push(heart_rate);
+        }
+        with_header(body)
+    }
+
+    fn with_header(body: Vec<u8>) -> Vec<u8> {
+        let size = body.len() as u32;
+        let mut data = vec![12, 0x10, 0, 0,
+                            size as u8, (size >> 8) as u8, (size >> 16) as u8, (size >> 24) as u8];
+        data.extend_from_slice(b".FIT");
+        data.extend(body);
+        data
+    }
+
+    #[test]
+    fn decodes_records() {
+        let recording = decode(&file(&[(START, 120), (START + 1, 125), (START + 10, 130)])).unwrap();
+        assert_eq!(recording.start_time, Utc.timestamp(FIT_EPOCH + START as i64, 0));
+        let times: Vec<f64> = recording.samples.iter().map(|s| s.time).collect();
+        assert_eq!(times, vec![0.0, 1.0, 10.0]);
+        let heart_rates: Vec<Option<f64>> = recording.samples.iter().map(|s| s.heart_rate).collect();
+        assert_eq!(heart_rates, vec![Some(120.0), Some(125.0), Some(130.0)]);
+        assert!(recording.samples[0].lat.is_none());
+    }
+
+    #[test]
+    fn sorts_records_written_out_of_order() {
+        let recording = decode(&file(&[(START + 5, 130), (START, 120), (START + 2, 125)])).unwrap();
+        assert_eq!(recording.start_time, Utc.timestamp(FIT_EPOCH + START as i64, 0));
+        let samples: Vec<(f64, Option<f64>)> = recording.samples.iter()
+            .map(|s| (s.time, s.heart_rate))
+            .collect();
+        assert_eq!(samples, vec![(0.0, Some(120.0)), (2.0, Some(125.0)), (5.0, Some(130.0))]);
+    }
+
+    #[test]
+    fn decodes_compressed_timestamps() {
+        let mut body = file(&[(START + 30, 120)]).split_off(12);
+        // Record messages with only a heart rate, timed by the header
+        body.extend_from_slice(&[0x41, 0, 0, MESG_RECORD as u8, 0, 1, 3, 1, 0x02]);
+        // Offsets 31 and then 2, which rolls over into the next 32 seconds
+        body.extend_from_slice(&[0x80 | 0x20 | 31, 125, 0x80 | 0x20 | 2, 130]);
+        let recording = decode(&with_header(body)).unwrap();
+        let samples: Vec<(f64, Option<f64>)> = recording.samples.iter()
+            .map(|s| (s.time, s.heart_rate))
+            .collect();
+        assert_eq!(samples, vec![(0.0, Some(120.0)), (1.0, Some(125.0)), (4.0, Some(130.0))]);
+    }
+
+    #[test]
+    fn rejects_files_without_a_header() {
+        assert!(match decode(b"not a FIT file") { Err(Error::Header) => true, _ => false });
+        let mut data = file(&[(START, 120)]);
+        data[8] = b'x';
+        assert!(match decode(&data) { Err(Error::Header) => true, _ => false });
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        // Shorter than the data size in the header
+        let mut data = file(&[(START, 120), (START + 1, 125)]);
+        data.pop();
+        assert!(match decode(&data) { Err(Error::Header) => true, _ => false });
+
+        // A message cut short within the data
+        let mut body = file(&[(START, 120), (START + 1, 125)]).split_off(12);
+        body.pop();
+        assert!(match decode(&with_header(body)) { Err(Error::Truncated) => true, _ => false });
+    }
+
+    #[test]
+    fn rejects_data_messages_without_a_definition() {
+        let body = vec![0x01, 0, 0, 0, 0, 0];
+        assert!(match decode(&with_header(body)) {
+            Err(Error::UndefinedMessage(1)) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn rejects_files_without_records() {
+        assert!(match decode(&file(&[])) { Err(Error::NoRecords) => true, _ => false });
+    }
+
+    #[test]
+    fn rejects_recordings_longer_than_the_maximum() {
+        assert!(decode(&file(&[(START, 120), (START + MAX_DURATION, 125)])).is_ok());
+        let data = file(&[(START, 120), (START + MAX_DURATION + 1, 125)]);
+        assert!(match decode(&data) { Err(Error::TooLong) => true, _ => false });
+    }
+}
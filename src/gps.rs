@@ -0,0 +1,276 @@
+// Cleanup of the GPS track before an activity is analysed. Positions that
+// would mean moving faster than the configured maximum speed are dropped,
+// positions and altitude are smoothed with a moving average, and altitude
+// is replaced with elevations from SRTM tiles when they cover the whole
+// track. Distance is then measured along the cleaned track. The metrics
+// of the recording as it was and after cleanup are both kept.
+use analysis::{self, MAX_SAMPLE_GAP};
+use config::TrackConfig;
+use dem::Dem;
+use fit::{Recording, Sample};
+use models::tracks::TrackMetrics;
+
+pub const DEM: &'static str = "dem";
+pub const DEVICE: &'static str = "device";
+
+// After this many positions in a row are dropped the next one is accepted,
+// so a bad first fix does not remove the rest of the track
+const MAX_DROPPED: usize = 10;
+
+pub struct Cleaned {
+    pub recording: Recording,
+    // Where altitude came from, dem or device, or None without altitude
+    pub elevation_source: Option<&'static str>,
+}
+
+pub fn clean(recording: &Recording, config: &TrackConfig) -> Cleaned {
+    let mut samples = recording.samples.clone();
+    remove_outliers(&mut samples, config.max_speed);
+    smooth_positions(&mut samples, config.smoothing_window);
+
+    let mut source = if samples.iter().any(|s| s.altitude.is_some()) { Some(DEVICE) } else { None };
+    if !config.dem_dir.is_empty() && correct_elevation(&mut samples, &config.dem_dir) {
+        source = Some(DEM);
+    } else {
+        smooth_altitude(&mut samples, config.smoothing_window);
+    }
+
+    if analysis::track(&samples).len() >= 2 {
+        for s in samples.iter_mut() {
+            s.distance = None;
+        }
+        let distances = analysis::distances(&samples);
+        for (s, d) in samples.iter_mut().zip(distances) {
+            s.distance = Some(d);
+        }
+    }
+    Cleaned {
+        recording: Recording {
+            sport: recording.sport.clone(),
+            start_time: recording.start_time,
+            samples: samples,
+            laps: recording.laps.clone(),
+        },
+        elevation_source: source,
+    }
+}
+
+pub fn metrics(samples: &[Sample], pause_speed: f64) -> TrackMetrics {
+    TrackMetrics {
+        distance: analysis::total_distance(samples),
+        elevation_gain: elevation_gain(samples),
+        moving_time: moving_time(samples, pause_speed),
+    }
+}
+
+// Sum of every climb between samples
+pub fn elevation_gain(samples: &[Sample]) -> Option<f64> {
+    let altitudes: Vec<f64> = samples.iter().filter_map(|s| s.altitude).collect();
+    if altitudes.is_empty() {
+        return None;
+    }
+    Some(altitudes.windows(2).map(|w| (w[1] - w[0]).max(0.0)).sum())
+}
+
+// Time spent moving at or above pause_speed. Gaps between samples longer
+// than MAX_SAMPLE_GAP are pauses, and without speed or distance every
+// other second counts as moving.
+pub fn moving_time(samples: &[Sample], pause_speed: f64) -> f64 {
+    let distances = analysis::distances(samples);
+    let measured = samples.iter().any(|s| s.distance.is_some() || s.lat.is_some());
+    let mut moving = 0.0;
+    for i in 1..samples.len() {
+        let dt = samples[i].time - samples[i - 1].time;
+        if dt <= 0.0 || dt > MAX_SAMPLE_GAP {
+            continue;
+        }
+        let speed = match samples[i].speed {
+            Some(v) => Some(v),
+            None if measured => Some((distances[i] - distances[i - 1]) / dt),
+            None => None,
+        };
+        if speed.map(|v| v >= pause_speed).unwrap_or(true) {
+            moving += dt;
+        }
+    }
+    moving
+}
+
+fn remove_outliers(samples: &mut [Sample], max_speed: f64) {
+    let mut last: Option<(f64, (f64, f64))> = None;
+    let mut dropped = 0;
+    for s in samples.iter_mut() {
+        let point = match (s.lat, s.lng) {
+            (Some(lat), Some(lng)) => (lat, lng),
+            _ => continue,
+        };
+        // Devices report 0, 0 when they have no fix
+        let invalid = point == (0.0, 0.0) || point.0.abs() > 90.0 || point.1.abs() > 180.0;
+        let too_fast = match last {
+            Some((time, previous)) if dropped < MAX_DROPPED => {
+                analysis::haversine(previous, point) / (s.time - time).max(1.0) > max_speed
+            },
+            _ => false,
+        };
+        if invalid || too_fast {
+            s.lat = None;
+            s.lng = None;
+            dropped += 1;
+        } else {
+            last = Some((s.time, point));
+            dropped = 0;
+        }
+    }
+}
+
+fn smooth_positions(samples: &mut [Sample], window: usize) {
+    let indices: Vec<usize> = (0..samples.len())
+        .filter(|i| samples[*i].lat.is_some() && samples[*i].lng.is_some())
+        .collect();
+    let lats: Vec<f64> = indices.iter().filter_map(|i| samples[*i].lat).collect();
+    let lngs: Vec<f64> = indices.iter().filter_map(|i| samples[*i].lng).collect();
+    let lats = moving_average(&lats, window);
+    let lngs = moving_average(&lngs, window);
+    for (k, i) in indices.iter().enumerate() {
+        samples[*i].lat = Some(lats[k]);
+        samples[*i].lng = Some(lngs[k]);
+    }
+}
+
+fn smooth_altitude(samples: &mut [Sample], window: usize) {
+    let indices: Vec<usize> = (0..samples.len()).filter(|i| samples[*i].altitude.is_some()).collect();
+    let altitudes: Vec<f64> = indices.iter().filter_map(|i| samples[*i].altitude).collect();
+    let altitudes = moving_average(&altitudes, window);
+    for (k, i) in indices.iter().enumerate() {
+        samples[*i].altitude = Some(altitudes[k]);
+    }
+}
+
+// Replace altitude with elevations from the tiles in dir. Nothing is
+// changed unless every position has an elevation, so the altitude of an
+// activity never mixes sources.
+fn correct_elevation(samples: &mut [Sample], dir: &str) -> bool {
+    let mut dem = Dem::new(dir);
+    let mut elevations = Vec::with_capacity(samples.len());
+    for s in samples.iter() {
+        elevations.push(match (s.lat, s.lng) {
+            (Some(lat), Some(lng)) => match dem.elevation(lat, lng) {
+                Some(e) => Some(e),
+                None => return false,
+            },
+            _ => None,
+        });
+    }
+    if elevations.iter().all(|e| e.is_none()) {
+        return false;
+    }
+    for (s, e) in samples.iter_mut().zip(elevations) {
+        s.altitude = e;
+    }
+    true
+}
+
+// Average of each value and the window / 2 values either side of it
+fn moving_average(values: &[f64], window: usize) -> Vec<f64> {
+    let half = window / 2;
+    (0..values.len()).map(|i| {
+        let from = i.saturating_sub(half);
+        let to = (i + half + 1).min(values.len());
+        values[from..to].iter().sum::<f64>() / (to - from) as f64
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    fn config(dem_dir: &str) -> TrackConfig {
+        TrackConfig {
+            max_speed: 30.0,
+            smoothing_window: 1,
+            pause_speed: 0.5,
+            dem_dir: dem_dir.to_string(),
+        }
+    }
+
+    // North along a meridian at about 11 metres a second, climbing a metre
+    // a second
+    fn recording() -> Recording {
+        Recording {
+            sport: None,
+            start_time: Utc.ymd(2018, 3, 1).and_hms(8, 0, 0),
+            samples: (0..100).map(|t| Sample {
+                time: t as f64,
+                lat: Some(45.1 + t as f64 * 0.0001),
+                lng: Some(6.5),
+                altitude: Some(1000.0 + t as f64),
+                ..Default::default()
+            }).collect(),
+            laps: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn elevation_gain_sums_climbs() {
+        let samples: Vec<Sample> = [100.0, 110.0, 105.0, 120.0, 120.0, 90.0].iter()
+            .map(|a| Sample { altitude: Some(*a), ..Default::default() })
+            .collect();
+        assert_eq!(elevation_gain(&samples), Some(25.0));
+        assert_eq!(elevation_gain(&[Sample::default()]), None);
+    }
+
+    #[test]
+    fn moving_time_skips_pauses_and_gaps() {
+        let speeds = [(0.0, 5.0), (1.0, 5.0), (2.0, 0.1), (3.0, 0.2), (4.0, 5.0), (60.0, 5.0), (61.0, 5.0)];
+        let samples: Vec<Sample> = speeds.iter()
+            .map(|&(t, v)| Sample { time: t, speed: Some(v), ..Default::default() })
+            .collect();
+        // 0-1, 3-4 and 60-61; 1-3 is paused and 4-60 is a gap
+        assert_eq!(moving_time(&samples, 0.5), 3.0);
+    }
+
+    #[test]
+    fn clean_drops_positions_moving_too_fast() {
+        let mut recording = recording();
+        recording.samples[40].lat = Some(45.2);
+        recording.samples[60].lat = Some(0.0);
+        recording.samples[60].lng = Some(0.0);
+        let cleaned = clean(&recording, &config(""));
+        let samples = &cleaned.recording.samples;
+        assert!(samples[40].lat.is_none());
+        assert!(samples[60].lat.is_none());
+        assert_eq!(samples[41].lat, recording.samples[41].lat);
+        assert_eq!(cleaned.elevation_source, Some(DEVICE));
+        // Distance is measured along the cleaned track
+        let expected = analysis::haversine((45.1, 6.5), (45.1 + 99.0 * 0.0001, 6.5));
+        assert!((samples[99].distance.unwrap() - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn clean_takes_altitude_from_tiles_covering_the_track() {
+        let dir = env::temp_dir().join("hapi-gps-test-dem");
+        fs::create_dir_all(&dir).unwrap();
+        // 800 metres everywhere
+        let mut tile = File::create(dir.join("N45E006.hgt")).unwrap();
+        tile.write_all(&[0x03, 0x20, 0x03, 0x20, 0x03, 0x20, 0x03, 0x20]).unwrap();
+        let cleaned = clean(&recording(), &config(dir.to_str().unwrap()));
+        assert_eq!(cleaned.elevation_source, Some(DEM));
+        assert!(cleaned.recording.samples.iter().all(|s| s.altitude == Some(800.0)));
+
+        // A track leaving the tiles keeps the device altitude throughout
+        let mut recording = recording();
+        for (t, s) in recording.samples.iter_mut().enumerate() {
+            s.lat = Some(45.1);
+            s.lng = Some(6.995 + t as f64 * 0.0001);
+        }
+        let cleaned = clean(&recording, &config(dir.to_str().unwrap()));
+        assert_eq!(cleaned.elevation_source, Some(DEVICE));
+        assert_eq!(cleaned.recording.samples[0].altitude, Some(1000.0));
+    }
+}
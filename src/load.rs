@@ -0,0 +1,323 @@
+// Training load. Each activity is given a training stress score (TSS),
+// from power when the athlete has an FTP and the activity has power, from
+// pace for runs when the athlete has a threshold pace (rTSS), or from heart
+// rate when the athlete has threshold, maximum and resting heart rates
+// (hrTSS). One hour at threshold scores 100.
+//
+// Daily TSS is turned into fitness (CTL) and fatigue (ATL), exponentially
+// weighted averages over 42 and 7 days. Form (TSB) is the previous day's
+// fitness less its fatigue.
+use chrono::{Duration, NaiveDate};
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use analysis::{self, RUNNING};
+use fit::Sample;
+use models::Error;
+use models::profiles::Physiology;
+use models::summaries::Summary;
+use models::training_load::{self, ActivityLoad, DailyLoad};
+
+pub const POWER: &'static str = "power";
+pub const PACE: &'static str = "pace";
+pub const HEART_RATE: &'static str = "heart_rate";
+
+const CTL_DAYS: f64 = 42.0;
+const ATL_DAYS: f64 = 7.0;
+// Normalized power is based on a 30 second rolling average
+const NP_WINDOW: usize = 30;
+
+#[derive(Serialize)]
+pub struct DayForm {
+    pub day: NaiveDate,
+    pub tss: f64,
+    pub ctl: f64,
+    pub atl: f64,
+    pub tsb: f64,
+}
+
+pub fn activity_load(summary: &Summary,
+                     samples: &[Sample],
+                     physiology: &Physiology,
+                     sex: Option<&str>) -> Option<ActivityLoad> {
+    let load = |method: &str, normalized_power: Option<f64>, intensity: f64, tss: f64| {
+        ActivityLoad {
+            activity_id: summary.activity_id,
+            user_id: summary.user_id,
+            day: summary.start_time.naive_utc().date(),
+            method: method.to_string(),
+            normalized_power: normalized_power,
+            intensity_factor: intensity,
+            tss: tss,
+        }
+    };
+    if let Some(ftp) = physiology.ftp_watts {
+        let power = moving(analysis::per_second(samples, |s| s.power));
+        if let Some(np) = normalized_power(&power) {
+            let intensity = np / ftp;
+            let tss = hours(power.len()) * intensity * intensity * 100.0;
+            return Some(load(POWER, Some(np), intensity, tss));
+        }
+    }
+    if let (RUNNING, Some(threshold_pace)) = (summary.sport.as_str(), physiology.threshold_pace) {
+        let speed: Vec<f64> = moving(analysis::per_second(samples, |s| s.speed))
+            .into_iter()
+            .filter(|s| *s >= analysis::MIN_PACE_SPEED)
+            .collect();
+        if !speed.is_empty() {
+            let avg = speed.iter().sum::<f64>() / speed.len() as f64;
+            let intensity = avg / (1000.0 / threshold_pace);
+            let tss = hours(speed.len()) * intensity * intensity * 100.0;
+            return Some(load(PACE, None, intensity, tss));
+        }
+    }
+    if let (Some(lthr), Some(max), Some(rest)) = (physiology.threshold_hr,
+                                                 physiology.max_hr,
+                                                 physiology.resting_hr) {
+        let heart_rate = moving(analysis::per_second(samples, |s| s.heart_rate));
+        if !heart_rate.is_empty() && max > rest {
+            let threshold = trimp(lthr, max, rest, sex) * 3600.0;
+            let total: f64 = heart_rate.iter().map(|hr| trimp(*hr, max, rest, sex)).sum();
+            let tss = total / threshold * 100.0;
+            let avg = heart_rate.iter().sum::<f64>() / heart_rate.len() as f64;
+            return Some(load(HEART_RATE, None, avg / lthr, tss));
+        }
+    }
+    None
+}
+
+// Drop the seconds without a value
+fn moving(series: Vec<Option<f64>>) -> Vec<f64> {
+    series.into_iter().filter_map(|v| v).collect()
+}
+
+fn hours(seconds: usize) -> f64 {
+    seconds as f64 / 3600.0
+}
+
+// Fourth root of the mean of the fourth powers of the 30 second rolling
+// average
+pub fn normalized_power(power: &[f64]) -> Option<f64> {
+    if power.len() < NP_WINDOW {
+        return None;
+    }
+    let mut sum: f64 = power[..NP_WINDOW].iter().sum();
+    let mut total = (sum / NP_WINDOW as f64).powi(4);
+    for i in NP_WINDOW..power.len() {
+        sum += power[i] - power[i - NP_WINDOW];
+        total += (sum / NP_WINDOW as f64).powi(4);
+    }
+    let count = (power.len() - NP_WINDOW + 1) as f64;
+    Some((total / count).powf(0.25))
+}
+
+// Banister's training impulse for one second at heart rate hr
+fn trimp(hr: f64, max: f64, rest: f64, sex: Option<&str>) -> f64 {
+    let reserve = ((hr - rest) / (max - rest)).max(0.0).min(1.0);
+    let (a, b) = match sex {
+        Some("female") => (0.86, 1.67),
+        _ => (0.64, 1.92),
+    };
+    reserve * a * (b * reserve).exp() / 60.0
+}
+
+// Recompute the daily series from day onwards after an activity on day was
+// added, changed or removed. Days before it are unchanged.
+pub fn update_from(user_id: &Uuid, day: &NaiveDate, conn: &PlatformConnection) -> Result<(), String> {
+    let previous = training_load::day_before(user_id, day, conn).map_err(|e| e.to_string())?;
+    let daily = training_load::daily_tss(user_id, day, conn).map_err(|e| e.to_string())?;
+    // Days skipped between the previous row and day are filled in
+    let start = previous.as_ref().map(|p| p.day + Duration::days(1)).unwrap_or(*day);
+    let mut end = training_load::last_day(user_id, conn)
+        .map_err(|e| e.to_string())?
+        .unwrap_or(start);
+    if let Some(&(last, _)) = daily.last() {
+        if last > end {
+            end = last;
+        }
+    }
+    let (mut ctl, mut atl) = previous.map(|p| (p.ctl, p.atl)).unwrap_or((0.0, 0.0));
+    let mut days = Vec::new();
+    let mut current = start;
+    let mut next = daily.iter().peekable();
+    while current <= end {
+        let trained = match next.peek() {
+            Some(&&(d, tss)) if d == current => Some(tss),
+            _ => None,
+        };
+        if trained.is_some() {
+            next.next();
+        }
+        let tss = trained.unwrap_or(0.0);
+        ctl += (tss - ctl) / CTL_DAYS;
+        atl += (tss - atl) / ATL_DAYS;
+        days.push(DailyLoad { day: current, tss: tss, ctl: ctl, atl: atl });
+        current = current + Duration::days(1);
+    }
+    if training_load::set_days(user_id, &days, conn) {
+        Ok(())
+    } else {
+        Err("storing training load".to_string())
+    }
+}
+
+// Daily fitness, fatigue and form from from to to. Days after the last
+// stored day decay without training.
+pub fn series(user_id: &Uuid,
+              from: &NaiveDate,
+              to: &NaiveDate,
+              conn: &PlatformConnection) -> Result<Vec<DayForm>, Error> {
+    // The day before from is included for the form on from
+    let first = *from - Duration::days(1);
+    let (mut ctl, mut atl) = match training_load::day_before(user_id, &first, conn)? {
+        Some(ref p) => decay(p, &(first - Duration::days(1))),
+        None => (0.0, 0.0),
+    };
+    let mut stored = training_load::get_days(user_id, &first, to, conn)?.into_iter().peekable();
+    let mut series = Vec::new();
+    let mut current = first;
+    while current <= *to {
+        let form = ctl - atl;
+        let stored_day = match stored.peek() {
+            Some(d) if d.day == current => Some(d.clone()),
+            _ => None,
+        };
+        let tss = match stored_day {
+            Some(d) => {
+                stored.next();
+                ctl = d.ctl;
+                atl = d.atl;
+                d.tss
+            },
+            None => {
+                ctl -= ctl / CTL_DAYS;
+                atl -= atl / ATL_DAYS;
+                0.0
+            },
+        };
+        if current >= *from {
+            series.push(DayForm {
+                day: current,
+                tss: tss,
+                ctl: ctl,
+                atl: atl,
+                tsb: form,
+            });
+        }
+        current = current + Duration::days(1);
+    }
+    Ok(series)
+}
+
+// Fitness and fatigue on day, decayed without training from an earlier row
+fn decay(load: &DailyLoad, day: &NaiveDate) -> (f64, f64) {
+    let days = (*day - load.day).num_days() as i32;
+    (load.ctl * (1.0 - 1.0 / CTL_DAYS).powi(days),
+     load.atl * (1.0 - 1.0 / ATL_DAYS).powi(days))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use uuid::Uuid;
+
+    use analysis::CYCLING;
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn summary(sport: &str) -> Summary {
+        Summary {
+            activity_id: Uuid::nil(),
+            user_id: Uuid::nil(),
+            sport: sport.to_string(),
+            start_time: Utc.ymd(2018, 3, 1).and_hms(8, 0, 0),
+            elapsed_time: 3599.0,
+            distance: None,
+            avg_speed: None,
+            max_speed: None,
+            avg_heart_rate: None,
+            max_heart_rate: None,
+            avg_power: None,
+            max_power: None,
+            avg_cadence: None,
+        }
+    }
+
+    // One sample a second for an hour
+    fn hour<F: Fn(&mut Sample)>(set: F) -> Vec<Sample> {
+        (0..3600).map(|t| {
+            let mut sample = Sample { time: t as f64, ..Default::default() };
+            set(&mut sample);
+            sample
+        }).collect()
+    }
+
+    #[test]
+    fn normalized_power_of_constant_power_is_that_power() {
+        let np = normalized_power(&vec![250.0; 600]).unwrap();
+        assert!(close(np, 250.0));
+    }
+
+    #[test]
+    fn normalized_power_averages_rolling_windows() {
+        // Windows average 100 and then (29 * 100 + 400) / 30 = 110
+        let mut power = vec![100.0; 30];
+        power.push(400.0);
+        let expected = ((100f64.powi(4) + 110f64.powi(4)) / 2.0).powf(0.25);
+        assert!(close(normalized_power(&power).unwrap(), expected));
+    }
+
+    #[test]
+    fn normalized_power_needs_a_full_window() {
+        assert!(normalized_power(&vec![250.0; NP_WINDOW - 1]).is_none());
+    }
+
+    #[test]
+    fn hour_at_ftp_scores_100() {
+        let physiology = Physiology { ftp_watts: Some(250.0), ..Default::default() };
+        let samples = hour(|s| s.power = Some(250.0));
+        let load = activity_load(&summary(CYCLING), &samples, &physiology, None).unwrap();
+        assert_eq!(load.method, POWER);
+        assert!(close(load.normalized_power.unwrap(), 250.0));
+        assert!(close(load.intensity_factor, 1.0));
+        assert!(close(load.tss, 100.0));
+    }
+
+    #[test]
+    fn hour_at_threshold_pace_scores_100() {
+        // Five minutes a kilometre
+        let physiology = Physiology { threshold_pace: Some(300.0), ..Default::default() };
+        let samples = hour(|s| s.speed = Some(1000.0 / 300.0));
+        let load = activity_load(&summary(RUNNING), &samples, &physiology, None).unwrap();
+        assert_eq!(load.method, PACE);
+        assert!(close(load.intensity_factor, 1.0));
+        assert!(close(load.tss, 100.0));
+    }
+
+    #[test]
+    fn hour_at_threshold_heart_rate_scores_100() {
+        let physiology = Physiology {
+            threshold_hr: Some(165.0),
+            max_hr: Some(190.0),
+            resting_hr: Some(50.0),
+            ..Default::default()
+        };
+        let samples = hour(|s| s.heart_rate = Some(165.0));
+        for sex in &[None, Some("female")] {
+            let load = activity_load(&summary(CYCLING), &samples, &physiology, *sex).unwrap();
+            assert_eq!(load.method, HEART_RATE);
+            assert!(close(load.intensity_factor, 1.0));
+            assert!(close(load.tss, 100.0));
+        }
+    }
+
+    #[test]
+    fn no_load_without_thresholds() {
+        let samples = hour(|s| s.power = Some(250.0));
+        assert!(activity_load(&summary(CYCLING), &samples, &Physiology::default(), None).is_none());
+    }
+}
@@ -0,0 +1,105 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use chrono::Utc;
+
+use auth;
+use config::{MailBackend, MailConfig};
+
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Message {
+    pub fn new<S>(to: S, subject: S, body: S) -> Message
+    where
+        S: Into<String>
+    {
+        Message {
+            to: to.into(),
+            subject: subject.into(),
+            body: body.into(),
+        }
+    }
+}
+
+// Mailer delivers messages using the backend configured in the mail
+// section of the configuration file. The file backend writes each message
+// to the spool directory so mail can be inspected or relayed when the
+// server has no access to a mail server.
+#[derive(Clone)]
+pub struct Mailer {
+    config: MailConfig,
+}
+
+impl Mailer {
+    pub fn new(config: MailConfig) -> Mailer {
+        Mailer { config: config }
+    }
+
+    pub fn send(&self, message: &Message) -> Result<(), io::Error> {
+        let rendered = self.render(message);
+        match self.config.backend {
+            MailBackend::File => self.spool(&rendered),
+            MailBackend::Sendmail => self.sendmail(&rendered),
+        }
+    }
+
+    fn render(&self, message: &Message) -> String {
+        let now = Utc::now();
+        format!("From: {}\r\n\
+                 To: {}\r\n\
+                 Subject: {}\r\n\
+                 Date: {}\r\n\
+                 Message-ID: <{}.{}@hapi>\r\n\
+                 MIME-Version: 1.0\r\n\
+                 Content-Type: text/plain; charset=utf-8\r\n\
+                 \r\n\
+                 {}\r\n",
+                header_value(&self.config.from),
+                header_value(&message.to),
+                header_value(&message.subject),
+                now.to_rfc2822(),
+                now.timestamp(),
+                auth::random_token(16),
+                message.body.replace("\n", "\r\n"))
+    }
+
+    fn spool(&self, rendered: &str) -> Result<(), io::Error> {
+        let dir = Path::new(&self.config.spool_dir);
+        fs::create_dir_all(dir)?;
+        let filename = format!("{}-{}.eml", Utc::now().timestamp(), auth::random_token(8));
+        let mut f = File::create(dir.join(filename))?;
+        f.write_all(rendered.as_bytes())
+    }
+
+    fn sendmail(&self, rendered: &str) -> Result<(), io::Error> {
+        let mut child = Command::new(&self.config.sendmail)
+            .arg("-t")
+            .arg("-i")
+            .stdin(Stdio::piped())
+            .spawn()?;
+        // stdin is dropped at the end of this block, closing the pipe so
+        // sendmail sees the end of the message.
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(rendered.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               format!("sendmail exited with {}", status)))
+        }
+    }
+}
+
+// Strip line breaks from values placed in message headers so user
+// supplied values cannot inject additional headers.
+fn header_value(value: &str) -> String {
+    value.replace("\r", "").replace("\n", "")
+}
@@ -3,35 +3,76 @@
 // hapi is the API server.
 
 // external libs
+extern crate argon2;
 extern crate argon2rs;
+extern crate base64;
 extern crate chrono;
 extern crate clap;
-extern crate jsonwebtoken as jwt;
 extern crate multipart;
+extern crate postgres;
 extern crate rand;
+extern crate ring;
 extern crate rocket;
 #[macro_use] extern crate rocket_contrib;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
+extern crate serde_json;
 extern crate toml;
+extern crate untrusted;
 extern crate uuid;
+extern crate zip;
 
 // Platform libs
 extern crate hdb;
 
+mod analysis;
+mod audit;
 mod auth;
 mod cli;
+mod client;
 mod config;
 mod db;
+mod deletion;
+mod dem;
+mod export;
 mod file;
+mod fit;
+mod gps;
+mod keys;
+mod load;
+mod mail;
+mod matching;
+mod mean_max;
+mod models;
+mod otp;
+mod policy;
+mod polyline;
+mod processing;
 mod routes;
+mod scope;
+mod session;
+mod splits;
+mod streams;
+mod throttle;
+mod zones;
 
 use std::fs;
 use std::path::Path;
+use std::process;
 
+use hdb::platform::models::users;
+
+use audit::AuditLog;
+use auth::ACCESS_TOKEN_LIFETIME;
 use config::Config;
+use keys::KeySet;
+use mail::Mailer;
+use models::admin_actions::{self, NewAdminAction};
+use models::roles;
+use throttle::Subject;
 use rocket::config::Config as RocketConfig;
 use rocket::config::Environment;
+use rocket::fairing::AdHoc;
 
 fn main() {
     //let mut config = config::default();
@@ -59,23 +100,201 @@ fn main() {
     // TODO: Configure permissions on directory
     fs::create_dir_all(Path::new(&config.server.file_dir)).unwrap();
 
+    // Load keys used to sign access tokens
+    let keys = match KeySet::load(&config.keys, &config.server.secret, ACCESS_TOKEN_LIFETIME) {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("Error loading signing keys: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // Open the audit log file, if one is configured
+    let audit_log = match AuditLog::new(&config.audit) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("Error opening audit log {}: {}", config.audit.file, e);
+            process::exit(1);
+        }
+    };
+
     // Create database connection pool
     let pool = db::init_pool(config.database);
 
+    // Create tables for hapi models
+    models::migrate(&pool.get().unwrap()).unwrap();
+
+    // Clear failed login attempts and exit
+    if let Some(m) = matches.subcommand_matches("unlock") {
+        let subject = match m.value_of("username") {
+            Some(username) => Subject::Username(username.to_string()),
+            None => match m.value_of("address").unwrap().parse() {
+                Ok(ip) => Subject::Address(ip),
+                Err(_) => {
+                    eprintln!("Invalid address {}", m.value_of("address").unwrap());
+                    process::exit(1);
+                }
+            },
+        };
+        if throttle::unlock(&subject, &pool.get().unwrap()) {
+            println!("Unlocked {}", subject.key());
+        } else {
+            println!("No failed logins recorded for {}", subject.key());
+        }
+        return;
+    }
+
+    // Grant or revoke the admin role and exit
+    if let Some(m) = matches.subcommand_matches("admin") {
+        let conn = pool.get().unwrap();
+        let (username, grant) = match m.value_of("grant") {
+            Some(username) => (username, true),
+            None => (m.value_of("revoke").unwrap(), false),
+        };
+        let user = match users::get_by_username(username, &conn) {
+            Ok(u) => u,
+            Err(_) => {
+                eprintln!("User {} not found", username);
+                process::exit(1);
+            }
+        };
+        let (changed, action) = if grant {
+            (roles::grant(&user.id, roles::ADMIN, &conn), "grant_admin")
+        } else {
+            (roles::revoke(&user.id, roles::ADMIN, &conn), "revoke_admin")
+        };
+        if !changed {
+            eprintln!("Admin role was not changed for {}", username);
+            process::exit(1);
+        }
+        admin_actions::record(NewAdminAction {
+            admin_id: None,
+            action: action,
+            target_user_id: Some(user.id),
+            details: None,
+        }, &conn);
+        println!("{} {}", if grant { "Granted admin role to" } else { "Revoked admin role from" },
+                 username);
+        return;
+    }
+
+    // Purge deleted accounts once their grace period has ended
+    deletion::spawn_worker(pool.clone(),
+                           config.server.file_dir.clone(),
+                           config.exports.dir.clone(),
+                           config.accounts.purge_interval,
+                           audit_log.clone());
+
+    // Expired exports, and exports left pending by a server that stopped
+    // while building them, are removed on the same schedule as account
+    // purges
+    export::spawn_cleanup(pool.clone(), config.exports.clone(), config.accounts.purge_interval);
+
     // Configure and start Rocket
-    let server_config = RocketConfig::build(Environment::Development)
+    let mut server_config = RocketConfig::build(Environment::Development)
         .address(config.server.address.clone())
-        .port(config.server.port)
-        .unwrap();
+        .port(config.server.port);
+    // Key for encrypting session cookies
+    match session::cookie_key(&config.server.secret) {
+        Some(key) => server_config = server_config.secret_key(key),
+        None => eprintln!("No server secret configured. Sessions will not survive a restart."),
+    }
+    let server_config = server_config.unwrap();
     rocket::custom(server_config, true)
+        // Forget authentication failures recorded while handling the
+        // previous request on the worker thread
+        .attach(AdHoc::on_request(|_, _| auth::clear_failure()))
         .manage(pool)
+        .manage(config.exports)
+        .manage(config.tracks)
         .manage(config.server)
-        .mount("/", routes![routes::index])
+        .manage(keys)
+        .manage(config.accounts)
+        .manage(config.passwords)
+        .manage(config.usernames)
+        .manage(config.login_throttle)
+        .manage(Mailer::new(config.mail))
+        .manage(audit_log)
+        .mount("/", routes![routes::index,
+                            routes::jwks])
         .mount("/users", routes![routes::user::register,
+                                routes::user::verify,
                                 routes::user::login,
+                                routes::user::login_second_factor,
+                                routes::user::login_password_change,
+                                routes::user::logout,
+                                routes::user::restore,
+                                routes::user::restore_second_factor,
                                 routes::user::delete,
-                                routes::user::import])
+                                routes::user::update_email,
+                                routes::user::resend_verification,
+                                routes::user::import,
+                                routes::activities::list,
+                                routes::activities::view,
+                                routes::activities::update,
+                                routes::activities::delete,
+                                routes::activities::streams,
+                                routes::activities::default_streams,
+                                routes::activities::laps,
+                                routes::activities::splits,
+                                routes::activities::km_splits,
+                                routes::training_load::series,
+                                routes::training_load::recent,
+                                routes::curves::best_curve,
+                                routes::curves::all_time_curve,
+                                routes::curves::best_efforts,
+                                routes::curves::all_time_best_efforts,
+                                routes::api_keys::create,
+                                routes::api_keys::list,
+                                routes::api_keys::revoke,
+                                routes::audit::events,
+                                routes::audit::recent_events,
+                                routes::exports::create,
+                                routes::exports::list,
+                                routes::exports::view,
+                                routes::profile::view_on,
+                                routes::profile::view,
+                                routes::profile::update,
+                                routes::profile::delete_value,
+                                routes::zones::list,
+                                routes::zones::update,
+                                routes::zones::delete,
+                                routes::privacy::list,
+                                routes::privacy::create,
+                                routes::privacy::delete,
+                                routes::segments::create,
+                                routes::segments::list,
+                                routes::segments::view,
+                                routes::segments::delete,
+                                routes::segments::efforts,
+                                routes::segments::leaderboard,
+                                routes::oauth::create_client,
+                                routes::oauth::list_clients,
+                                routes::oauth::delete_client,
+                                routes::totp::enroll,
+                                routes::totp::confirm,
+                                routes::totp::regenerate_recovery_codes,
+                                routes::totp::disable])
+        .mount("/activities", routes![routes::activities::shared])
+        .mount("/exports", routes![routes::exports::download])
+        .mount("/oauth", routes![routes::oauth::consent,
+                                routes::oauth::authorize,
+                                routes::oauth::token])
+        .mount("/admin", routes![routes::admin::search,
+                                routes::admin::list,
+                                routes::admin::view,
+                                routes::admin::deactivate,
+                                routes::admin::reactivate,
+                                routes::admin::force_password_reset,
+                                routes::admin::revoke_user_sessions,
+                                routes::admin::storage,
+                                routes::admin::actions,
+                                routes::admin::recent_actions,
+                                routes::admin::events,
+                                routes::admin::recent_events])
         .catch(errors![routes::error::bad_request,
+                       routes::error::unauthorized,
+                       routes::error::forbidden,
                        routes::error::length_required,
                        routes::error::payload_too_large])
         .launch();
@@ -0,0 +1,224 @@
+// Matching of activity tracks against segments. An activity rides a
+// segment when its track passes within a few metres of the segment's
+// start, then of each point along the segment in order, and finally of
+// its end. Points must be reached in order, so riding a segment the wrong
+// way does not match, and a track may ride a segment several times.
+use std::collections::HashSet;
+
+use chrono::Duration;
+use uuid::Uuid;
+
+use analysis;
+use fit::Recording;
+use models::segments::{self, Segment, SegmentEffort};
+use polyline;
+
+// Metres the track may be from the start and end of a segment
+const ENDPOINT_RADIUS: f64 = 25.0;
+// Metres the track may be from points along a segment
+const MATCH_RADIUS: f64 = 35.0;
+// The track may cover this many times the distance between two segment
+// points, plus DETOUR_ALLOWANCE metres, to get from one to the next. Any
+// further and the activity has left the segment.
+const MAX_DETOUR: f64 = 1.5;
+const DETOUR_ALLOWANCE: f64 = 100.0;
+
+struct TrackPoint {
+    time: f64,
+    point: (f64, f64),
+    // Metres from the start of the activity
+    distance: f64,
+}
+
+// Cells segments matching the activity could start in
+pub fn track_cells(recording: &Recording) -> Vec<String> {
+    let mut cells = HashSet::new();
+    for (lat, lng) in analysis::track(&recording.samples) {
+        if cells.insert(segments::cell(lat, lng)) {
+            for c in segments::cells_around(lat, lng) {
+                cells.insert(c);
+            }
+        }
+    }
+    cells.into_iter().collect()
+}
+
+pub fn efforts(activity_id: &Uuid,
+               user_id: &Uuid,
+               recording: &Recording,
+               candidates: &[Segment]) -> Vec<SegmentEffort> {
+    let distances = analysis::distances(&recording.samples);
+    let points: Vec<TrackPoint> = recording.samples.iter().zip(distances)
+        .filter_map(|(s, d)| match (s.lat, s.lng) {
+            (Some(lat), Some(lng)) => Some(TrackPoint { time: s.time, point: (lat, lng), distance: d }),
+            _ => None,
+        })
+        .collect();
+    let mut efforts = Vec::new();
+    for segment in candidates {
+        let route = polyline::decode(&segment.polyline);
+        if route.len() < 2 {
+            continue;
+        }
+        let mut from = 0;
+        while let Some((start, end)) = find(&points, from, &route) {
+            let offset = points[start].time;
+            efforts.push(SegmentEffort {
+                segment_id: segment.id,
+                name: segment.name.clone(),
+                activity_id: *activity_id,
+                user_id: *user_id,
+                start: offset,
+                start_time: recording.start_time + Duration::milliseconds((offset * 1000.0) as i64),
+                elapsed_time: points[end].time - offset,
+            });
+            from = end + 1;
+        }
+    }
+    efforts.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+    efforts
+}
+
+// Track indices of the start and end of the first ride of the route
+// starting at or after from
+fn find(points: &[TrackPoint], from: usize, route: &[(f64, f64)]) -> Option<(usize, usize)> {
+    let near_start = |i: usize| analysis::haversine(points[i].point, route[0]);
+    let mut i = from;
+    while i < points.len() {
+        if near_start(i) > ENDPOINT_RADIUS {
+            i += 1;
+            continue;
+        }
+        // Start from the closest of the points passing the start
+        let mut start = i;
+        while i + 1 < points.len() && near_start(i + 1) <= ENDPOINT_RADIUS {
+            i += 1;
+            if near_start(i) < near_start(start) {
+                start = i;
+            }
+        }
+        if let Some(end) = follow(points, start, route) {
+            return Some((start, end));
+        }
+        i += 1;
+    }
+    None
+}
+
+// Follow the route from the track point at start, reaching each route
+// point in turn. Returns the index of the track point at the end.
+fn follow(points: &[TrackPoint], start: usize, route: &[(f64, f64)]) -> Option<usize> {
+    let mut j = start;
+    for k in 1..route.len() {
+        let radius = if k == route.len() - 1 { ENDPOINT_RADIUS } else { MATCH_RADIUS };
+        let leg = analysis::haversine(route[k - 1], route[k]);
+        let limit = points[j].distance + leg * MAX_DETOUR + DETOUR_ALLOWANCE;
+        // The closest track point to the route point while the track is
+        // near it
+        let mut best: Option<(usize, f64)> = None;
+        let mut m = j;
+        while m < points.len() && points[m].distance <= limit {
+            let d = analysis::haversine(points[m].point, route[k]);
+            if d <= radius {
+                if best.map(|(_, b)| d < b).unwrap_or(true) {
+                    best = Some((m, d));
+                }
+            } else if best.is_some() {
+                break;
+            }
+            m += 1;
+        }
+        j = best?.0;
+    }
+    Some(j)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use fit::Sample;
+    use super::*;
+
+    // About 1.1 km north from 45, 6
+    fn route() -> Vec<(f64, f64)> {
+        (0..5).map(|i| (45.0 + i as f64 * 0.0025, 6.0)).collect()
+    }
+
+    // One point a second at about 11 metres a second through the latitudes
+    fn track(lats: &[f64]) -> Vec<TrackPoint> {
+        let mut points: Vec<TrackPoint> = Vec::new();
+        for (t, lat) in lats.iter().enumerate() {
+            let point = (*lat, 6.0);
+            let distance = points.last()
+                .map(|p| p.distance + analysis::haversine(p.point, point))
+                .unwrap_or(0.0);
+            points.push(TrackPoint { time: t as f64, point: point, distance: distance });
+        }
+        points
+    }
+
+    fn lats(from: f64, to: f64) -> Vec<f64> {
+        let steps = ((to - from) / 0.0001).abs().round() as usize;
+        let step = if to > from { 0.0001 } else { -0.0001 };
+        (0..steps + 1).map(|i| from + i as f64 * step).collect()
+    }
+
+    #[test]
+    fn finds_a_ride_of_the_segment() {
+        let points = track(&lats(44.999, 45.011));
+        let (start, end) = find(&points, 0, &route()).unwrap();
+        assert_eq!(start, 10);
+        assert_eq!(end, 110);
+    }
+
+    #[test]
+    fn rejects_the_segment_ridden_in_reverse() {
+        let points = track(&lats(45.011, 44.999));
+        assert!(find(&points, 0, &route()).is_none());
+    }
+
+    #[test]
+    fn rejects_a_track_leaving_the_segment() {
+        // Turns back before the end
+        let mut lats = lats(44.999, 45.007);
+        lats.extend(self::lats(45.007, 44.999));
+        assert!(find(&track(&lats), 0, &route()).is_none());
+    }
+
+    #[test]
+    fn finds_every_ride_of_the_segment() {
+        // Up, back down past the start, and up again
+        let mut lats = lats(44.999, 45.011);
+        lats.extend(self::lats(45.011, 44.99).into_iter().skip(1));
+        lats.extend(self::lats(44.99, 45.011).into_iter().skip(1));
+        let recording = Recording {
+            sport: None,
+            start_time: Utc.ymd(2018, 3, 1).and_hms(8, 0, 0),
+            samples: lats.iter().enumerate().map(|(t, lat)| Sample {
+                time: t as f64,
+                lat: Some(*lat),
+                lng: Some(6.0),
+                ..Default::default()
+            }).collect(),
+            laps: Vec::new(),
+        };
+        let segment = Segment {
+            id: Uuid::nil(),
+            user_id: Uuid::nil(),
+            name: "Climb".to_string(),
+            sport: "cycling".to_string(),
+            distance: 1112.0,
+            start_lat: 45.0,
+            start_lng: 6.0,
+            end_lat: 45.01,
+            end_lng: 6.0,
+            polyline: polyline::encode(&route()),
+            created_at: Utc::now(),
+        };
+        let efforts = efforts(&Uuid::nil(), &Uuid::nil(), &recording, &[segment]);
+        let found: Vec<(f64, f64)> = efforts.iter().map(|e| (e.start, e.elapsed_time)).collect();
+        assert_eq!(found, vec![(10.0, 100.0), (430.0, 100.0)]);
+        assert_eq!(efforts[1].start_time, recording.start_time + Duration::seconds(430));
+    }
+}
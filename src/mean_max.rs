@@ -0,0 +1,287 @@
+// Mean-maximal curves and best efforts. A mean-maximal curve holds the
+// highest average of a stream over each duration, and best efforts the
+// fastest time over each distance. Both are computed from the samples
+// resampled to one per second, with paused seconds counted as zero so
+// pauses never raise an average.
+//
+// An activity sets a personal record when it beats the user's other
+// activities of the same sport for 5 second, 1, 5 or 20 minute power, or
+// for the fastest 1k, 5k, 10k, half marathon or marathon on a run.
+use analysis;
+use fit::Sample;
+use models::curves::{BestEffort, BestTime, BestValue, Curve, CurvePoint};
+
+pub const POWER: &'static str = "power";
+pub const HEART_RATE: &'static str = "heart_rate";
+// Pace curves are stored as speed in metres per second, so higher is
+// better for every curve
+pub const SPEED: &'static str = "speed";
+
+pub const KINDS: [&'static str; 3] = [POWER, HEART_RATE, SPEED];
+
+// Durations in seconds the curves are computed for
+pub const DURATIONS: [i64; 24] = [
+    1, 2, 5, 10, 15, 20, 30, 45, 60, 90, 120, 180, 300, 420, 600, 900, 1200,
+    1800, 2700, 3600, 5400, 7200, 10800, 14400,
+];
+
+// Distances in metres best efforts are computed for: 1k, 5k, 10k, half
+// marathon and marathon
+pub const DISTANCES: [f64; 5] = [1000.0, 5000.0, 10000.0, 21097.5, 42195.0];
+
+// Durations checked for power records
+pub const RECORD_DURATIONS: [i64; 4] = [5, 60, 300, 1200];
+
+// A new best set by an activity. previous is missing when there was no
+// earlier value.
+#[derive(Serialize)]
+pub struct PersonalRecord {
+    pub kind: String,
+    pub duration: Option<i64>,
+    pub distance: Option<f64>,
+    pub value: f64,
+    pub previous: Option<f64>,
+}
+
+pub fn value(kind: &str, sample: &Sample) -> Option<f64> {
+    match kind {
+        POWER => sample.power,
+        HEART_RATE => sample.heart_rate,
+        SPEED => sample.speed,
+        _ => None,
+    }
+}
+
+// Curves for the streams the samples have
+pub fn mean_max_curves(samples: &[Sample]) -> Vec<Curve> {
+    KINDS.iter()
+        .filter(|kind| samples.iter().any(|s| value(kind, s).is_some()))
+        .map(|kind| {
+            let series: Vec<f64> = analysis::per_second(samples, |s| value(kind, s))
+                .into_iter()
+                .map(|v| v.unwrap_or(0.0))
+                .collect();
+            Curve {
+                kind: kind.to_string(),
+                points: mean_max(&series),
+            }
+        })
+        .collect()
+}
+
+pub fn mean_max(series: &[f64]) -> Vec<CurvePoint> {
+    let mut prefix = Vec::with_capacity(series.len() + 1);
+    prefix.push(0.0);
+    for v in series {
+        let last = prefix[prefix.len() - 1];
+        prefix.push(last + v);
+    }
+    DURATIONS.iter()
+        .filter(|d| **d as usize <= series.len())
+        .map(|d| {
+            let d = *d as usize;
+            let best = (d..prefix.len())
+                .map(|end| prefix[end] - prefix[end - d])
+                .fold(0.0f64, |m, sum| m.max(sum));
+            CurvePoint { duration: d as i64, value: best / d as f64 }
+        })
+        .collect()
+}
+
+// Fastest time over each distance covered by the samples
+pub fn best_efforts(samples: &[Sample]) -> Vec<BestEffort> {
+    // Distance at each second, carried forward through gaps
+    let mut distance = Vec::new();
+    let mut last = 0.0;
+    for d in analysis::per_second(samples, |s| s.distance) {
+        if let Some(d) = d {
+            last = d;
+        }
+        distance.push(last);
+    }
+    let mut efforts = Vec::new();
+    for target in DISTANCES.iter() {
+        let mut best: Option<usize> = None;
+        let mut start = 0;
+        for end in 0..distance.len() {
+            if distance[end] - distance[start] < *target {
+                continue;
+            }
+            while start + 1 < end && distance[end] - distance[start + 1] >= *target {
+                start += 1;
+            }
+            let seconds = end - start;
+            if best.map(|b| seconds < b).unwrap_or(true) {
+                best = Some(seconds);
+            }
+        }
+        match best {
+            Some(seconds) => efforts.push(BestEffort {
+                distance: *target,
+                seconds: seconds as f64,
+            }),
+            // Longer distances cannot be covered either
+            None => break,
+        }
+    }
+    efforts
+}
+
+// Records set by the curves and efforts of an activity, compared with the
+// best values of the user's other activities
+pub fn records(curves: &[Curve],
+               efforts: &[BestEffort],
+               best_power: &[BestValue],
+               best_efforts: &[BestTime]) -> Vec<PersonalRecord> {
+    let mut records = Vec::new();
+    for curve in curves.iter().filter(|c| c.kind == POWER) {
+        for point in curve.points.iter().filter(|p| RECORD_DURATIONS.contains(&p.duration)) {
+            let previous = best_power.iter()
+                .find(|b| b.duration == point.duration)
+                .map(|b| b.value);
+            if previous.map(|p| point.value > p).unwrap_or(true) {
+                records.push(PersonalRecord {
+                    kind: POWER.to_string(),
+                    duration: Some(point.duration),
+                    distance: None,
+                    value: point.value,
+                    previous: previous,
+                });
+            }
+        }
+    }
+    for effort in efforts {
+        let previous = best_efforts.iter()
+            .find(|b| b.distance == effort.distance)
+            .map(|b| b.seconds);
+        if previous.map(|p| effort.seconds < p).unwrap_or(true) {
+            records.push(PersonalRecord {
+                kind: "distance".to_string(),
+                duration: None,
+                distance: Some(effort.distance),
+                value: effort.seconds,
+                previous: previous,
+            });
+        }
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use uuid::Uuid;
+
+    use super::*;
+
+    // A run at 4 metres a second, 250 seconds a kilometre
+    fn run(seconds: i64) -> Vec<Sample> {
+        (0..seconds + 1).map(|t| Sample {
+            time: t as f64,
+            distance: Some(t as f64 * 4.0),
+            ..Default::default()
+        }).collect()
+    }
+
+    fn power_curve(values: &[(i64, f64)]) -> Curve {
+        Curve {
+            kind: POWER.to_string(),
+            points: values.iter()
+                .map(|&(duration, value)| CurvePoint { duration: duration, value: value })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn constant_power_has_a_flat_curve() {
+        let points = mean_max(&vec![200.0; 600]);
+        let durations: Vec<i64> = points.iter().map(|p| p.duration).collect();
+        let expected: Vec<i64> = DURATIONS.iter().cloned().filter(|d| *d <= 600).collect();
+        assert_eq!(durations, expected);
+        assert!(points.iter().all(|p| p.value == 200.0));
+    }
+
+    #[test]
+    fn curve_takes_the_best_window() {
+        let mut series = vec![100.0; 120];
+        for v in series[30..40].iter_mut() {
+            *v = 400.0;
+        }
+        let points = mean_max(&series);
+        let value = |d| points.iter().find(|p| p.duration == d).unwrap().value;
+        assert_eq!(value(10), 400.0);
+        assert_eq!(value(20), 250.0);
+        assert_eq!(value(120), 125.0);
+    }
+
+    #[test]
+    fn best_1k_at_a_known_pace() {
+        let efforts = best_efforts(&run(1200));
+        assert_eq!(efforts.len(), 1);
+        assert_eq!(efforts[0].distance, 1000.0);
+        assert_eq!(efforts[0].seconds, 250.0);
+    }
+
+    #[test]
+    fn best_effort_finds_the_fastest_stretch() {
+        // 250 seconds a kilometre, then 200 seconds a kilometre
+        let samples: Vec<Sample> = (0..501).map(|t| Sample {
+            time: t as f64,
+            distance: Some(if t <= 250 {
+                t as f64 * 4.0
+            } else {
+                1000.0 + (t - 250) as f64 * 5.0
+            }),
+            ..Default::default()
+        }).collect();
+        let efforts = best_efforts(&samples);
+        assert_eq!(efforts[0].seconds, 200.0);
+    }
+
+    #[test]
+    fn best_efforts_stop_at_distances_not_covered() {
+        let efforts = best_efforts(&run(1300));
+        let distances: Vec<f64> = efforts.iter().map(|e| e.distance).collect();
+        assert_eq!(distances, vec![1000.0, 5000.0]);
+        assert!(best_efforts(&run(200)).is_empty());
+    }
+
+    #[test]
+    fn records_beat_earlier_bests() {
+        let day = NaiveDate::from_ymd(2018, 3, 1);
+        let curves = vec![power_curve(&[(5, 500.0), (60, 300.0), (90, 280.0), (300, 250.0)])];
+        let best_power = vec![
+            BestValue { duration: 5, value: 550.0, activity_id: Uuid::nil(), day: day },
+            BestValue { duration: 60, value: 290.0, activity_id: Uuid::nil(), day: day },
+            BestValue { duration: 300, value: 250.0, activity_id: Uuid::nil(), day: day },
+        ];
+        let efforts = vec![BestEffort { distance: 1000.0, seconds: 240.0 },
+                           BestEffort { distance: 5000.0, seconds: 1300.0 }];
+        let best_times = vec![
+            BestTime { distance: 1000.0, seconds: 250.0, activity_id: Uuid::nil(), day: day },
+            BestTime { distance: 5000.0, seconds: 1250.0, activity_id: Uuid::nil(), day: day },
+        ];
+        let records = records(&curves, &efforts, &best_power, &best_times);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].kind, POWER);
+        assert_eq!(records[0].duration, Some(60));
+        assert_eq!(records[0].value, 300.0);
+        assert_eq!(records[0].previous, Some(290.0));
+        assert_eq!(records[1].kind, "distance");
+        assert_eq!(records[1].distance, Some(1000.0));
+        assert_eq!(records[1].value, 240.0);
+        assert_eq!(records[1].previous, Some(250.0));
+    }
+
+    #[test]
+    fn first_activity_sets_records_without_previous() {
+        let curves = vec![power_curve(&[(5, 500.0), (10, 450.0), (60, 300.0)])];
+        let efforts = vec![BestEffort { distance: 1000.0, seconds: 240.0 }];
+        let records = records(&curves, &efforts, &[], &[]);
+        let found: Vec<(Option<i64>, Option<f64>)> = records.iter()
+            .map(|r| (r.duration, r.distance))
+            .collect();
+        assert_eq!(found, vec![(Some(5), None), (Some(60), None), (None, Some(1000.0))]);
+        assert!(records.iter().all(|r| r.previous.is_none()));
+    }
+}
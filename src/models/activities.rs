@@ -0,0 +1,78 @@
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// Lookups against the platform activities table that are not provided by
+// hdb
+
+#[derive(Serialize)]
+pub struct ActivitySummary {
+    pub id: Uuid,
+    pub filename: String,
+    pub activity_type: Option<String>,
+    pub name: Option<String>,
+}
+
+pub fn get_by_user_id(user_id: &Uuid,
+                      conn: &PlatformConnection) -> Result<Vec<ActivitySummary>, Error> {
+    let rows = conn.query("SELECT id, filename, activity_type, name
+                           FROM activities
+                           WHERE user_id = $1
+                           ORDER BY filename",
+                          &[user_id])?;
+    Ok(rows.iter().map(|row| ActivitySummary {
+        id: row.get(0),
+        filename: row.get(1),
+        activity_type: row.get(2),
+        name: row.get(3),
+    }).collect())
+}
+
+pub fn get(id: &Uuid, user_id: &Uuid, conn: &PlatformConnection) -> Result<ActivitySummary, Error> {
+    let rows = conn.query("SELECT id, filename, activity_type, name
+                           FROM activities
+                           WHERE id = $1 AND user_id = $2",
+                          &[id, user_id])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let row = rows.get(0);
+    Ok(ActivitySummary {
+        id: row.get(0),
+        filename: row.get(1),
+        activity_type: row.get(2),
+        name: row.get(3),
+    })
+}
+
+pub fn update(id: &Uuid,
+              user_id: &Uuid,
+              name: &Option<String>,
+              activity_type: &Option<String>,
+              conn: &PlatformConnection) -> bool {
+    match conn.execute("UPDATE activities SET name = $3, activity_type = $4
+                        WHERE id = $1 AND user_id = $2",
+                       &[id, user_id, name, activity_type]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+pub fn delete(id: &Uuid, user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    match conn.execute("DELETE FROM activities WHERE id = $1 AND user_id = $2",
+                       &[id, user_id]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+// Owner of an activity, for reads by users other than the owner
+pub fn owner(id: &Uuid, conn: &PlatformConnection) -> Result<Uuid, Error> {
+    let rows = conn.query("SELECT user_id FROM activities WHERE id = $1", &[id])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(rows.get(0).get(0))
+}
@@ -0,0 +1,66 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS admin_actions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    admin_id UUID,
+    action STRING NOT NULL,
+    target_user_id UUID,
+    details STRING,
+    created_on TIMESTAMPTZ NOT NULL,
+    INDEX admin_actions_created_on_idx (created_on)
+);
+";
+
+#[derive(Serialize)]
+pub struct AdminAction {
+    pub id: Uuid,
+    // Not set for actions taken from the command line
+    pub admin_id: Option<Uuid>,
+    pub action: String,
+    pub target_user_id: Option<Uuid>,
+    pub details: Option<String>,
+    pub created_on: DateTime<Utc>,
+}
+
+pub struct NewAdminAction<'a> {
+    pub admin_id: Option<Uuid>,
+    pub action: &'a str,
+    pub target_user_id: Option<Uuid>,
+    pub details: Option<String>,
+}
+
+pub fn record(action: NewAdminAction, conn: &PlatformConnection) -> bool {
+    conn.execute("INSERT INTO admin_actions
+                  (admin_id, action, target_user_id, details, created_on)
+                  VALUES ($1, $2, $3, $4, $5)",
+                 &[&action.admin_id,
+                   &action.action,
+                   &action.target_user_id,
+                   &action.details,
+                   &Utc::now()]).is_ok()
+}
+
+// Most recent actions first
+pub fn recent(limit: i64,
+              offset: i64,
+              conn: &PlatformConnection) -> Result<Vec<AdminAction>, Error> {
+    let rows = conn.query("SELECT id, admin_id, action, target_user_id, details, created_on
+                           FROM admin_actions
+                           ORDER BY created_on DESC
+                           LIMIT $1 OFFSET $2",
+                          &[&limit, &offset])?;
+    Ok(rows.iter().map(|row| AdminAction {
+        id: row.get(0),
+        admin_id: row.get(1),
+        action: row.get(2),
+        target_user_id: row.get(3),
+        details: row.get(4),
+        created_on: row.get(5),
+    }).collect())
+}
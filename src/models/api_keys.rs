@@ -0,0 +1,139 @@
+use chrono::{DateTime, Duration, Utc};
+use postgres::rows::Row;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use scope;
+use super::Error;
+
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS api_keys (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL,
+    name STRING NOT NULL,
+    prefix STRING NOT NULL,
+    key_hash STRING NOT NULL UNIQUE,
+    scopes STRING NOT NULL DEFAULT '',
+    created_on TIMESTAMPTZ NOT NULL,
+    expires_on TIMESTAMPTZ,
+    last_used_on TIMESTAMPTZ,
+    revoked_on TIMESTAMPTZ,
+    INDEX api_keys_user_id_idx (user_id)
+);
+";
+
+// Only record a key as used once per interval to avoid a write on every
+// request.
+const LAST_USED_INTERVAL: i64 = 60;
+
+#[derive(Serialize)]
+pub struct ApiKey {
+    pub id: Uuid,
+    #[serde(skip_serializing)]
+    pub user_id: Uuid,
+    pub name: String,
+    // First characters of the key so users can tell keys apart
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_on: DateTime<Utc>,
+    pub expires_on: Option<DateTime<Utc>>,
+    pub last_used_on: Option<DateTime<Utc>>,
+    #[serde(skip_serializing)]
+    pub revoked_on: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn is_valid(&self) -> bool {
+        self.revoked_on.is_none() &&
+            self.expires_on.map_or(true, |e| e > Utc::now())
+    }
+}
+
+pub struct NewApiKey {
+    pub user_id: Uuid,
+    pub name: String,
+    pub prefix: String,
+    // SHA-256 hex digest of the key
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub expires_on: Option<DateTime<Utc>>,
+}
+
+const COLUMNS: &'static str = "id, user_id, name, prefix, scopes, created_on, \
+                               expires_on, last_used_on, revoked_on";
+
+pub fn create(key: NewApiKey, conn: &PlatformConnection) -> Result<ApiKey, Error> {
+    let rows = conn.query(&format!("INSERT INTO api_keys
+                                    (user_id, name, prefix, key_hash, scopes,
+                                     created_on, expires_on)
+                                    VALUES ($1, $2, $3, $4, $5, $6, $7)
+                                    RETURNING {}", COLUMNS),
+                          &[&key.user_id,
+                            &key.name,
+                            &key.prefix,
+                            &key.key_hash,
+                            &scope::join(&key.scopes),
+                            &Utc::now(),
+                            &key.expires_on])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(from_row(&rows.get(0)))
+}
+
+pub fn get_by_hash(key_hash: &str, conn: &PlatformConnection) -> Result<ApiKey, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM api_keys WHERE key_hash = $1", COLUMNS),
+                          &[&key_hash])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(from_row(&rows.get(0)))
+}
+
+// Keys for a user that have not been revoked, including expired keys
+pub fn get_by_user_id(user_id: &Uuid, conn: &PlatformConnection) -> Result<Vec<ApiKey>, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM api_keys
+                                    WHERE user_id = $1 AND revoked_on IS NULL
+                                    ORDER BY created_on", COLUMNS),
+                          &[user_id])?;
+    Ok(rows.iter().map(|row| from_row(&row)).collect())
+}
+
+pub fn revoke(id: &Uuid, user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    match conn.execute("UPDATE api_keys SET revoked_on = $3
+                        WHERE id = $1 AND user_id = $2 AND revoked_on IS NULL",
+                       &[id, user_id, &Utc::now()]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+pub fn revoke_all(user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("UPDATE api_keys SET revoked_on = $2
+                  WHERE user_id = $1 AND revoked_on IS NULL",
+                 &[user_id, &Utc::now()]).is_ok()
+}
+
+pub fn touch(id: &Uuid, conn: &PlatformConnection) {
+    let now = Utc::now();
+    let before = now - Duration::seconds(LAST_USED_INTERVAL);
+    let _ = conn.execute("UPDATE api_keys SET last_used_on = $2
+                          WHERE id = $1
+                          AND (last_used_on IS NULL OR last_used_on < $3)",
+                         &[id, &now, &before]);
+}
+
+fn from_row(row: &Row) -> ApiKey {
+    ApiKey {
+        id: row.get(0),
+        user_id: row.get(1),
+        name: row.get(2),
+        prefix: row.get(3),
+        scopes: scope::parse(&row.get::<_, String>(4)),
+        created_on: row.get(5),
+        expires_on: row.get(6),
+        last_used_on: row.get(7),
+        revoked_on: row.get(8),
+    }
+}
@@ -0,0 +1,102 @@
+use chrono::{DateTime, Utc};
+use postgres::rows::Row;
+use postgres::types::ToSql;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// Events are only ever inserted. There are deliberately no functions to
+// update or delete them.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS audit_events (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    created_on TIMESTAMPTZ NOT NULL,
+    event STRING NOT NULL,
+    outcome STRING NOT NULL,
+    user_id UUID,
+    username STRING,
+    ip STRING,
+    user_agent STRING,
+    details STRING,
+    INDEX audit_events_user_id_idx (user_id, created_on),
+    INDEX audit_events_created_on_idx (created_on)
+);
+";
+
+#[derive(Serialize)]
+pub struct AuditEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    pub created_on: DateTime<Utc>,
+    pub event: String,
+    // success or failure
+    pub outcome: String,
+    pub user_id: Option<Uuid>,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Option<String>,
+}
+
+pub struct Filter {
+    pub user_id: Option<Uuid>,
+    pub event: Option<String>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+pub fn create(event: &AuditEvent, conn: &PlatformConnection) -> bool {
+    conn.execute("INSERT INTO audit_events
+                  (created_on, event, outcome, user_id, username, ip, user_agent, details)
+                  VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                 &[&event.created_on,
+                   &event.event,
+                   &event.outcome,
+                   &event.user_id,
+                   &event.username,
+                   &event.ip,
+                   &event.user_agent,
+                   &event.details]).is_ok()
+}
+
+// Events matching filter, most recent first
+pub fn query(filter: &Filter, conn: &PlatformConnection) -> Result<Vec<AuditEvent>, Error> {
+    let mut conditions = Vec::new();
+    let mut params: Vec<&ToSql> = vec![&filter.limit, &filter.offset];
+    if let Some(ref user_id) = filter.user_id {
+        params.push(user_id);
+        conditions.push(format!("user_id = ${}", params.len()));
+    }
+    if let Some(ref event) = filter.event {
+        params.push(event);
+        conditions.push(format!("event = ${}", params.len()));
+    }
+    let filter_sql = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    let rows = conn.query(&format!("SELECT id, created_on, event, outcome, user_id, username,
+                                           ip, user_agent, details
+                                    FROM audit_events {}
+                                    ORDER BY created_on DESC
+                                    LIMIT $1 OFFSET $2", filter_sql),
+                          &params)?;
+    Ok(rows.iter().map(|row| from_row(&row)).collect())
+}
+
+fn from_row(row: &Row) -> AuditEvent {
+    AuditEvent {
+        id: Some(row.get(0)),
+        created_on: row.get(1),
+        event: row.get(2),
+        outcome: row.get(3),
+        user_id: row.get(4),
+        username: row.get(5),
+        ip: row.get(6),
+        user_agent: row.get(7),
+        details: row.get(8),
+    }
+}
@@ -0,0 +1,203 @@
+use chrono::NaiveDate;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// Mean-maximal curves and best efforts of each activity. The sport and day
+// are copied from the activity summary so bests can be found for a sport
+// and date range without a join.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS activity_curves (
+    activity_id UUID NOT NULL,
+    user_id UUID NOT NULL,
+    sport STRING NOT NULL,
+    day DATE NOT NULL,
+    kind STRING NOT NULL,
+    duration INT NOT NULL,
+    value FLOAT NOT NULL,
+    PRIMARY KEY (activity_id, kind, duration),
+    INDEX activity_curves_user_id_idx (user_id, kind, day)
+);
+CREATE TABLE IF NOT EXISTS activity_best_efforts (
+    activity_id UUID NOT NULL,
+    user_id UUID NOT NULL,
+    sport STRING NOT NULL,
+    day DATE NOT NULL,
+    distance FLOAT NOT NULL,
+    seconds FLOAT NOT NULL,
+    PRIMARY KEY (activity_id, distance),
+    INDEX activity_best_efforts_user_id_idx (user_id, day)
+);
+";
+
+#[derive(Serialize, Clone)]
+pub struct CurvePoint {
+    pub duration: i64,
+    pub value: f64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BestEffort {
+    pub distance: f64,
+    pub seconds: f64,
+}
+
+#[derive(Serialize)]
+pub struct Curve {
+    pub kind: String,
+    pub points: Vec<CurvePoint>,
+}
+
+// The highest value for a duration and the activity it came from
+#[derive(Serialize)]
+pub struct BestValue {
+    pub duration: i64,
+    pub value: f64,
+    pub activity_id: Uuid,
+    pub day: NaiveDate,
+}
+
+// The fastest time over a distance and the activity it came from
+#[derive(Serialize)]
+pub struct BestTime {
+    pub distance: f64,
+    pub seconds: f64,
+    pub activity_id: Uuid,
+    pub day: NaiveDate,
+}
+
+// Which activities to find bests in. Bounds are inclusive and every
+// activity matches a bound that is not given.
+pub struct Range<'a> {
+    pub sport: Option<&'a str>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+pub fn set(activity_id: &Uuid,
+           user_id: &Uuid,
+           sport: &str,
+           day: &NaiveDate,
+           curves: &[Curve],
+           efforts: &[BestEffort],
+           conn: &PlatformConnection) -> bool {
+    let trans = match conn.transaction() {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    for curve in curves {
+        for point in &curve.points {
+            if trans.execute("INSERT INTO activity_curves
+                              (activity_id, user_id, sport, day, kind, duration, value)
+                              VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                             &[activity_id, user_id, &sport, day, &curve.kind,
+                               &point.duration, &point.value]).is_err() {
+                return false;
+            }
+        }
+    }
+    for effort in efforts {
+        if trans.execute("INSERT INTO activity_best_efforts
+                          (activity_id, user_id, sport, day, distance, seconds)
+                          VALUES ($1, $2, $3, $4, $5, $6)",
+                         &[activity_id, user_id, &sport, day,
+                           &effort.distance, &effort.seconds]).is_err() {
+            return false;
+        }
+    }
+    trans.commit().is_ok()
+}
+
+pub fn delete_activity(activity_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("DELETE FROM activity_curves WHERE activity_id = $1", &[activity_id]).is_ok()
+        && conn.execute("DELETE FROM activity_best_efforts WHERE activity_id = $1",
+                        &[activity_id]).is_ok()
+}
+
+pub fn get_activity_curves(activity_id: &Uuid,
+                           conn: &PlatformConnection) -> Result<Vec<Curve>, Error> {
+    let rows = conn.query("SELECT kind, duration, value FROM activity_curves
+                           WHERE activity_id = $1
+                           ORDER BY kind, duration",
+                          &[activity_id])?;
+    let mut curves: Vec<Curve> = Vec::new();
+    for row in rows.iter() {
+        let kind: String = row.get(0);
+        let point = CurvePoint { duration: row.get(1), value: row.get(2) };
+        let new_kind = curves.last().map(|c| c.kind != kind).unwrap_or(true);
+        if new_kind {
+            curves.push(Curve { kind: kind, points: Vec::new() });
+        }
+        if let Some(c) = curves.last_mut() {
+            c.points.push(point);
+        }
+    }
+    Ok(curves)
+}
+
+pub fn get_activity_efforts(activity_id: &Uuid,
+                            conn: &PlatformConnection) -> Result<Vec<BestEffort>, Error> {
+    let rows = conn.query("SELECT distance, seconds FROM activity_best_efforts
+                           WHERE activity_id = $1
+                           ORDER BY distance",
+                          &[activity_id])?;
+    Ok(rows.iter().map(|row| BestEffort { distance: row.get(0), seconds: row.get(1) }).collect())
+}
+
+// Highest value for each duration in the range
+pub fn best_curve(user_id: &Uuid,
+                  kind: &str,
+                  range: &Range,
+                  conn: &PlatformConnection) -> Result<Vec<BestValue>, Error> {
+    let rows = conn.query("SELECT duration, value, activity_id, day FROM activity_curves
+                           WHERE user_id = $1 AND kind = $2
+                           AND ($3::DATE IS NULL OR day >= $3)
+                           AND ($4::DATE IS NULL OR day <= $4)
+                           AND ($5::STRING IS NULL OR sport = $5)
+                           ORDER BY duration, value DESC, day",
+                          &[user_id, &kind, &range.from, &range.to, &range.sport])?;
+    let mut bests: Vec<BestValue> = Vec::new();
+    for row in rows.iter() {
+        let duration: i64 = row.get(0);
+        if bests.last().map(|b| b.duration == duration).unwrap_or(false) {
+            continue;
+        }
+        bests.push(BestValue {
+            duration: duration,
+            value: row.get(1),
+            activity_id: row.get(2),
+            day: row.get(3),
+        });
+    }
+    Ok(bests)
+}
+
+// Fastest time for each distance in the range
+pub fn best_efforts(user_id: &Uuid,
+                    range: &Range,
+                    conn: &PlatformConnection) -> Result<Vec<BestTime>, Error> {
+    let rows = conn.query("SELECT distance, seconds, activity_id, day
+                           FROM activity_best_efforts
+                           WHERE user_id = $1
+                           AND ($2::DATE IS NULL OR day >= $2)
+                           AND ($3::DATE IS NULL OR day <= $3)
+                           AND ($4::STRING IS NULL OR sport = $4)
+                           ORDER BY distance, seconds, day",
+                          &[user_id, &range.from, &range.to, &range.sport])?;
+    let mut bests: Vec<BestTime> = Vec::new();
+    for row in rows.iter() {
+        let distance: f64 = row.get(0);
+        if bests.last().map(|b| b.distance == distance).unwrap_or(false) {
+            continue;
+        }
+        bests.push(BestTime {
+            distance: distance,
+            seconds: row.get(1),
+            activity_id: row.get(2),
+            day: row.get(3),
+        });
+    }
+    Ok(bests)
+}
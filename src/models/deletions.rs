@@ -0,0 +1,132 @@
+use chrono::{DateTime, Utc};
+use postgres;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// A row is kept after the account is purged as confirmation that the
+// user's data was deleted
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS account_deletions (
+    user_id UUID PRIMARY KEY,
+    requested_on TIMESTAMPTZ NOT NULL,
+    purge_after TIMESTAMPTZ NOT NULL,
+    purged_on TIMESTAMPTZ,
+    INDEX account_deletions_purge_after_idx (purge_after)
+);
+";
+
+#[derive(Serialize)]
+pub struct AccountDeletion {
+    pub user_id: Uuid,
+    pub requested_on: DateTime<Utc>,
+    pub purge_after: DateTime<Utc>,
+    pub purged_on: Option<DateTime<Utc>>,
+}
+
+pub fn schedule(user_id: &Uuid,
+                purge_after: &DateTime<Utc>,
+                conn: &PlatformConnection) -> Result<AccountDeletion, Error> {
+    let rows = conn.query("UPSERT INTO account_deletions
+                           (user_id, requested_on, purge_after, purged_on)
+                           VALUES ($1, $2, $3, NULL)
+                           RETURNING user_id, requested_on, purge_after, purged_on",
+                          &[user_id, &Utc::now(), purge_after])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let row = rows.get(0);
+    Ok(AccountDeletion {
+        user_id: row.get(0),
+        requested_on: row.get(1),
+        purge_after: row.get(2),
+        purged_on: row.get(3),
+    })
+}
+
+pub fn is_pending(user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    match conn.query("SELECT 1 FROM account_deletions
+                      WHERE user_id = $1 AND purged_on IS NULL",
+                     &[user_id]) {
+        Ok(rows) => !rows.is_empty(),
+        Err(_) => false,
+    }
+}
+
+// Cancel a pending deletion. Returns false if there was nothing to cancel.
+pub fn cancel(user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    match conn.execute("DELETE FROM account_deletions
+                        WHERE user_id = $1 AND purged_on IS NULL",
+                       &[user_id]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+// Users whose grace period has ended. Accounts that are active again are
+// never purged.
+pub fn due(conn: &PlatformConnection) -> Result<Vec<Uuid>, Error> {
+    let rows = conn.query("SELECT d.user_id FROM account_deletions d
+                           JOIN users u ON u.id = d.user_id
+                           WHERE d.purged_on IS NULL AND d.purge_after <= $1
+                           AND u.active = false",
+                          &[&Utc::now()])?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+// Delete everything stored for the user in the database and record that
+// the account was purged. Files, including data exports, and the access
+// token stored by hdb are removed separately. Admin actions and audit
+// events are kept as a record of what happened to the account.
+pub fn purge(user_id: &Uuid,
+             login_failures_key: &str,
+             conn: &PlatformConnection) -> Result<(), postgres::Error> {
+    let trans = conn.transaction()?;
+    trans.execute("DELETE FROM oauth_codes
+                   WHERE user_id = $1
+                   OR client_id IN (SELECT id FROM oauth_clients WHERE owner_id = $1)",
+                  &[user_id])?;
+    trans.execute("DELETE FROM oauth_clients WHERE owner_id = $1", &[user_id])?;
+    // Efforts by other users on segments the user created go with them
+    trans.execute("DELETE FROM segment_efforts
+                   WHERE user_id = $1
+                   OR segment_id IN (SELECT id FROM segments WHERE user_id = $1)",
+                  &[user_id])?;
+    for table in ["user_emails",
+                  "user_totp",
+                  "recovery_codes",
+                  "api_keys",
+                  "user_roles",
+                  "session_revocations",
+                  "password_resets",
+                  "data_exports",
+                  "athlete_profiles",
+                  "athlete_settings",
+                  "activity_summaries",
+                  "training_zones",
+                  "activity_zone_times",
+                  "activity_loads",
+                  "training_load",
+                  "activity_curves",
+                  "activity_best_efforts",
+                  "activity_laps",
+                  "activity_tracks",
+                  "activity_polylines",
+                  "privacy_zones",
+                  "activity_privacy",
+                  "segments",
+                  "activities"].iter() {
+        trans.execute(&format!("DELETE FROM {} WHERE user_id = $1", table), &[user_id])?;
+    }
+    trans.execute("DELETE FROM login_failures WHERE key = $1", &[&login_failures_key])?;
+    // Free the username for new registrations
+    trans.execute("DELETE FROM usernames
+                   WHERE username = (SELECT lower(username) FROM users WHERE id = $1)",
+                  &[user_id])?;
+    trans.execute("DELETE FROM users WHERE id = $1", &[user_id])?;
+    trans.execute("UPDATE account_deletions SET purged_on = $2 WHERE user_id = $1",
+                  &[user_id, &Utc::now()])?;
+    trans.commit()
+}
@@ -0,0 +1,104 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS user_emails (
+    user_id UUID PRIMARY KEY,
+    email STRING NOT NULL UNIQUE,
+    verified BOOL NOT NULL DEFAULT false,
+    verification_hash STRING,
+    verification_expires TIMESTAMPTZ,
+    verified_on TIMESTAMPTZ
+);
+CREATE INDEX IF NOT EXISTS user_emails_verification_hash_idx
+    ON user_emails (verification_hash);
+";
+
+#[derive(Serialize)]
+pub struct UserEmail {
+    pub user_id: Uuid,
+    pub email: String,
+    pub verified: bool,
+    pub verified_on: Option<DateTime<Utc>>,
+}
+
+pub struct NewUserEmail {
+    pub user_id: Uuid,
+    pub email: String,
+    // SHA-256 hex digest of the verification token sent to the user
+    pub verification_hash: String,
+    pub verification_expires: DateTime<Utc>,
+}
+
+pub fn exists(email: &str, conn: &PlatformConnection) -> bool {
+    match conn.query("SELECT 1 FROM user_emails WHERE email = $1", &[&email]) {
+        Ok(rows) => !rows.is_empty(),
+        Err(_) => false,
+    }
+}
+
+// Create or replace the email address for a user. Replacing an address
+// resets its verification state.
+pub fn upsert(email: NewUserEmail, conn: &PlatformConnection) -> bool {
+    conn.execute("UPSERT INTO user_emails
+                  (user_id, email, verified, verification_hash,
+                   verification_expires, verified_on)
+                  VALUES ($1, $2, false, $3, $4, NULL)",
+                 &[&email.user_id,
+                   &email.email,
+                   &email.verification_hash,
+                   &email.verification_expires]).is_ok()
+}
+
+pub fn get_by_user_id(user_id: &Uuid,
+                      conn: &PlatformConnection) -> Result<UserEmail, Error> {
+    let rows = conn.query("SELECT user_id, email, verified, verified_on
+                           FROM user_emails WHERE user_id = $1",
+                          &[user_id])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let row = rows.get(0);
+    Ok(UserEmail {
+        user_id: row.get(0),
+        email: row.get(1),
+        verified: row.get(2),
+        verified_on: row.get(3),
+    })
+}
+
+// Store a new verification token for an unverified email address
+pub fn set_verification(user_id: &Uuid,
+                        hash: &str,
+                        expires: &DateTime<Utc>,
+                        conn: &PlatformConnection) -> bool {
+    match conn.execute("UPDATE user_emails
+                        SET verification_hash = $2, verification_expires = $3
+                        WHERE user_id = $1 AND verified = false",
+                       &[user_id, &hash, expires]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+// Mark the email address matching the verification token hash as verified.
+// Returns the id of the user owning the address.
+pub fn verify(hash: &str, conn: &PlatformConnection) -> Result<Uuid, Error> {
+    let now = Utc::now();
+    let rows = conn.query("UPDATE user_emails
+                           SET verified = true, verified_on = $2,
+                               verification_hash = NULL,
+                               verification_expires = NULL
+                           WHERE verification_hash = $1
+                           AND verification_expires > $2
+                           RETURNING user_id",
+                          &[&hash, &now])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(rows.get(0).get(0))
+}
@@ -0,0 +1,146 @@
+use chrono::{DateTime, Utc};
+use postgres::rows::Row;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS data_exports (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL,
+    status STRING NOT NULL,
+    token_hash STRING NOT NULL UNIQUE,
+    requested_on TIMESTAMPTZ NOT NULL,
+    completed_on TIMESTAMPTZ,
+    expires_on TIMESTAMPTZ,
+    bytes INT,
+    INDEX data_exports_user_id_idx (user_id)
+);
+";
+
+pub const PENDING: &'static str = "pending";
+pub const READY: &'static str = "ready";
+pub const FAILED: &'static str = "failed";
+pub const EXPIRED: &'static str = "expired";
+
+#[derive(Serialize)]
+pub struct DataExport {
+    pub id: Uuid,
+    #[serde(skip_serializing)]
+    pub user_id: Uuid,
+    pub status: String,
+    pub requested_on: DateTime<Utc>,
+    pub completed_on: Option<DateTime<Utc>>,
+    // The download link stops working after this time
+    pub expires_on: Option<DateTime<Utc>>,
+    pub bytes: Option<i64>,
+}
+
+impl DataExport {
+    pub fn is_downloadable(&self) -> bool {
+        self.status == READY && self.expires_on.map_or(false, |e| e > Utc::now())
+    }
+}
+
+const COLUMNS: &'static str = "id, user_id, status, requested_on, completed_on, expires_on, bytes";
+
+// token_hash is the SHA-256 hex digest of the token in the download link
+pub fn create(user_id: &Uuid, token_hash: &str, conn: &PlatformConnection) -> Result<DataExport, Error> {
+    let rows = conn.query(&format!("INSERT INTO data_exports
+                                    (user_id, status, token_hash, requested_on)
+                                    VALUES ($1, $2, $3, $4)
+                                    RETURNING {}", COLUMNS),
+                          &[user_id, &PENDING, &token_hash, &Utc::now()])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(from_row(&rows.get(0)))
+}
+
+pub fn get(id: &Uuid, user_id: &Uuid, conn: &PlatformConnection) -> Result<DataExport, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM data_exports
+                                    WHERE id = $1 AND user_id = $2", COLUMNS),
+                          &[id, user_id])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(from_row(&rows.get(0)))
+}
+
+pub fn get_by_token_hash(token_hash: &str, conn: &PlatformConnection) -> Result<DataExport, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM data_exports WHERE token_hash = $1", COLUMNS),
+                          &[&token_hash])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(from_row(&rows.get(0)))
+}
+
+pub fn get_by_user_id(user_id: &Uuid, conn: &PlatformConnection) -> Result<Vec<DataExport>, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM data_exports
+                                    WHERE user_id = $1
+                                    ORDER BY requested_on DESC", COLUMNS),
+                          &[user_id])?;
+    Ok(rows.iter().map(|row| from_row(&row)).collect())
+}
+
+// Exports requested before requested_after that are still pending are
+// not counted
+pub fn has_pending(user_id: &Uuid,
+                   requested_after: &DateTime<Utc>,
+                   conn: &PlatformConnection) -> bool {
+    match conn.query("SELECT 1 FROM data_exports
+                      WHERE user_id = $1 AND status = $2 AND requested_on > $3",
+                     &[user_id, &PENDING, requested_after]) {
+        Ok(rows) => !rows.is_empty(),
+        // Err on the side of not starting another export
+        Err(_) => true,
+    }
+}
+
+pub fn complete(id: &Uuid,
+                bytes: i64,
+                expires_on: &DateTime<Utc>,
+                conn: &PlatformConnection) -> bool {
+    conn.execute("UPDATE data_exports
+                  SET status = $2, completed_on = $3, expires_on = $4, bytes = $5
+                  WHERE id = $1",
+                 &[id, &READY, &Utc::now(), expires_on, &bytes]).is_ok()
+}
+
+pub fn set_status(id: &Uuid, status: &str, conn: &PlatformConnection) -> bool {
+    conn.execute("UPDATE data_exports SET status = $2 WHERE id = $1",
+                 &[id, &status]).is_ok()
+}
+
+// Ready exports whose download link has expired
+pub fn expired(conn: &PlatformConnection) -> Result<Vec<Uuid>, Error> {
+    let rows = conn.query("SELECT id FROM data_exports WHERE status = $1 AND expires_on <= $2",
+                          &[&READY, &Utc::now()])?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+// Mark exports still pending that were requested before requested_before
+// as failed. Returns their ids.
+pub fn fail_stale(requested_before: &DateTime<Utc>,
+                  conn: &PlatformConnection) -> Result<Vec<Uuid>, Error> {
+    let rows = conn.query("UPDATE data_exports SET status = $2
+                           WHERE status = $1 AND requested_on <= $3
+                           RETURNING id",
+                          &[&PENDING, &FAILED, requested_before])?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+fn from_row(row: &Row) -> DataExport {
+    DataExport {
+        id: row.get(0),
+        user_id: row.get(1),
+        status: row.get(2),
+        requested_on: row.get(3),
+        completed_on: row.get(4),
+        expires_on: row.get(5),
+        bytes: row.get(6),
+    }
+}
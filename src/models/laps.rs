@@ -0,0 +1,107 @@
+use postgres::rows::Row;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// Laps recorded by the device, with metrics computed from the samples in
+// each lap. start is seconds from the start of the activity.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS activity_laps (
+    activity_id UUID NOT NULL,
+    user_id UUID NOT NULL,
+    lap_index INT NOT NULL,
+    start FLOAT NOT NULL,
+    elapsed_time FLOAT NOT NULL,
+    distance FLOAT,
+    avg_speed FLOAT,
+    max_speed FLOAT,
+    avg_heart_rate FLOAT,
+    max_heart_rate FLOAT,
+    avg_power FLOAT,
+    max_power FLOAT,
+    avg_cadence FLOAT,
+    PRIMARY KEY (activity_id, lap_index),
+    INDEX activity_laps_user_id_idx (user_id)
+);
+";
+
+const COLUMNS: &'static str = "lap_index, start, elapsed_time, distance, avg_speed, max_speed,
+                               avg_heart_rate, max_heart_rate, avg_power, max_power, avg_cadence";
+
+// A device lap or an automatic split
+#[derive(Serialize, Clone)]
+pub struct Lap {
+    pub index: i64,
+    pub start: f64,
+    pub elapsed_time: f64,
+    pub distance: Option<f64>,
+    pub avg_speed: Option<f64>,
+    pub max_speed: Option<f64>,
+    pub avg_heart_rate: Option<f64>,
+    pub max_heart_rate: Option<f64>,
+    pub avg_power: Option<f64>,
+    pub max_power: Option<f64>,
+    pub avg_cadence: Option<f64>,
+}
+
+// Replace the laps of an activity
+pub fn set(activity_id: &Uuid, user_id: &Uuid, laps: &[Lap], conn: &PlatformConnection) -> bool {
+    let trans = match conn.transaction() {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    if trans.execute("DELETE FROM activity_laps WHERE activity_id = $1", &[activity_id]).is_err() {
+        return false;
+    }
+    for lap in laps {
+        if trans.execute(&format!("INSERT INTO activity_laps (activity_id, user_id, {})
+                                   VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+                                  COLUMNS),
+                         &[activity_id,
+                           user_id,
+                           &lap.index,
+                           &lap.start,
+                           &lap.elapsed_time,
+                           &lap.distance,
+                           &lap.avg_speed,
+                           &lap.max_speed,
+                           &lap.avg_heart_rate,
+                           &lap.max_heart_rate,
+                           &lap.avg_power,
+                           &lap.max_power,
+                           &lap.avg_cadence]).is_err() {
+            return false;
+        }
+    }
+    trans.commit().is_ok()
+}
+
+pub fn get_by_activity_id(activity_id: &Uuid, conn: &PlatformConnection) -> Result<Vec<Lap>, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM activity_laps
+                                    WHERE activity_id = $1
+                                    ORDER BY lap_index", COLUMNS),
+                          &[activity_id])?;
+    Ok(rows.iter().map(|row| from_row(&row)).collect())
+}
+
+pub fn delete_activity(activity_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("DELETE FROM activity_laps WHERE activity_id = $1", &[activity_id]).is_ok()
+}
+
+fn from_row(row: &Row) -> Lap {
+    Lap {
+        index: row.get(0),
+        start: row.get(1),
+        elapsed_time: row.get(2),
+        distance: row.get(3),
+        avg_speed: row.get(4),
+        max_speed: row.get(5),
+        avg_heart_rate: row.get(6),
+        max_heart_rate: row.get(7),
+        avg_power: row.get(8),
+        max_power: row.get(9),
+        avg_cadence: row.get(10),
+    }
+}
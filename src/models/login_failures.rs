@@ -0,0 +1,63 @@
+use chrono::{DateTime, Utc};
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS login_failures (
+    key STRING PRIMARY KEY,
+    failures INT NOT NULL,
+    last_failure TIMESTAMPTZ NOT NULL,
+    INDEX login_failures_last_failure_idx (last_failure)
+);
+";
+
+pub struct LoginFailures {
+    pub key: String,
+    pub failures: i64,
+    pub last_failure: DateTime<Utc>,
+}
+
+pub fn get(key: &str, conn: &PlatformConnection) -> Result<LoginFailures, Error> {
+    let rows = conn.query("SELECT key, failures, last_failure
+                           FROM login_failures WHERE key = $1",
+                          &[&key])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let row = rows.get(0);
+    Ok(LoginFailures {
+        key: row.get(0),
+        failures: row.get(1),
+        last_failure: row.get(2),
+    })
+}
+
+// Record a failure for key. The count starts over if the last failure was
+// before reset_before.
+pub fn increment(key: &str,
+                 reset_before: &DateTime<Utc>,
+                 conn: &PlatformConnection) -> bool {
+    conn.execute("INSERT INTO login_failures (key, failures, last_failure)
+                  VALUES ($1, 1, $2)
+                  ON CONFLICT (key) DO UPDATE SET
+                      failures = CASE
+                          WHEN login_failures.last_failure < $3 THEN 1
+                          ELSE login_failures.failures + 1
+                      END,
+                      last_failure = $2",
+                 &[&key, &Utc::now(), reset_before]).is_ok()
+}
+
+pub fn delete(key: &str, conn: &PlatformConnection) -> bool {
+    match conn.execute("DELETE FROM login_failures WHERE key = $1", &[&key]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+// Remove failures last recorded before the time
+pub fn delete_before(before: &DateTime<Utc>, conn: &PlatformConnection) -> bool {
+    conn.execute("DELETE FROM login_failures WHERE last_failure < $1", &[before]).is_ok()
+}
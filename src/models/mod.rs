@@ -0,0 +1,87 @@
+// Models for data owned by hapi. The platform models (users, tokens,
+// activities) live in hdb; tables created here share the same database
+// and are keyed by the platform user id.
+use std::fmt;
+
+use postgres;
+
+use hdb::platform::PlatformConnection;
+
+pub mod activities;
+pub mod admin_actions;
+pub mod api_keys;
+pub mod audit;
+pub mod curves;
+pub mod deletions;
+pub mod emails;
+pub mod exports;
+pub mod laps;
+pub mod login_failures;
+pub mod oauth;
+pub mod passwords;
+pub mod polylines;
+pub mod privacy;
+pub mod profiles;
+pub mod roles;
+pub mod segments;
+pub mod sessions;
+pub mod summaries;
+pub mod totp;
+pub mod tracks;
+pub mod training_load;
+pub mod training_zones;
+pub mod users;
+
+#[derive(Debug)]
+pub enum Error {
+    NotFound,
+    Database(postgres::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFound => write!(f, "record not found"),
+            Error::Database(ref e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<postgres::Error> for Error {
+    fn from(e: postgres::Error) -> Error {
+        Error::Database(e)
+    }
+}
+
+// Create any missing tables. Called once at startup.
+pub fn migrate(conn: &PlatformConnection) -> Result<(), postgres::Error> {
+    let schemas = [
+        emails::SCHEMA,
+        totp::SCHEMA,
+        login_failures::SCHEMA,
+        api_keys::SCHEMA,
+        roles::SCHEMA,
+        sessions::SCHEMA,
+        passwords::SCHEMA,
+        admin_actions::SCHEMA,
+        oauth::SCHEMA,
+        audit::SCHEMA,
+        deletions::SCHEMA,
+        exports::SCHEMA,
+        profiles::SCHEMA,
+        summaries::SCHEMA,
+        training_zones::SCHEMA,
+        training_load::SCHEMA,
+        curves::SCHEMA,
+        laps::SCHEMA,
+        tracks::SCHEMA,
+        polylines::SCHEMA,
+        privacy::SCHEMA,
+        segments::SCHEMA,
+        users::SCHEMA,
+    ];
+    for schema in schemas.iter() {
+        conn.batch_execute(schema)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use chrono::{DateTime, Utc};
+use postgres::rows::Row;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use scope;
+use super::Error;
+
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS oauth_clients (
+    id STRING PRIMARY KEY,
+    owner_id UUID NOT NULL,
+    name STRING NOT NULL,
+    secret_hash STRING,
+    redirect_uris STRING NOT NULL,
+    created_on TIMESTAMPTZ NOT NULL,
+    INDEX oauth_clients_owner_id_idx (owner_id)
+);
+CREATE TABLE IF NOT EXISTS oauth_codes (
+    code_hash STRING PRIMARY KEY,
+    client_id STRING NOT NULL,
+    user_id UUID NOT NULL,
+    redirect_uri STRING NOT NULL,
+    scopes STRING NOT NULL,
+    code_challenge STRING NOT NULL,
+    expires_on TIMESTAMPTZ NOT NULL,
+    used BOOL NOT NULL DEFAULT false
+);
+";
+
+#[derive(Serialize)]
+pub struct Client {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub owner_id: Uuid,
+    pub name: String,
+    // SHA-256 hex digest of the client secret. Not set for public clients.
+    #[serde(skip_serializing)]
+    pub secret_hash: Option<String>,
+    pub redirect_uris: Vec<String>,
+    pub created_on: DateTime<Utc>,
+}
+
+impl Client {
+    // Confidential clients authenticate with a secret at the token endpoint
+    pub fn is_confidential(&self) -> bool {
+        self.secret_hash.is_some()
+    }
+}
+
+pub struct NewClient {
+    pub id: String,
+    pub owner_id: Uuid,
+    pub name: String,
+    pub secret_hash: Option<String>,
+    pub redirect_uris: Vec<String>,
+}
+
+pub struct AuthorizationCode {
+    pub client_id: String,
+    pub user_id: Uuid,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    // Base64url encoded SHA-256 digest of the PKCE code verifier
+    pub code_challenge: String,
+    pub expires_on: DateTime<Utc>,
+}
+
+const CLIENT_COLUMNS: &'static str = "id, owner_id, name, secret_hash, redirect_uris, created_on";
+
+pub fn create_client(client: NewClient, conn: &PlatformConnection) -> Result<Client, Error> {
+    let rows = conn.query(&format!("INSERT INTO oauth_clients
+                                    (id, owner_id, name, secret_hash, redirect_uris, created_on)
+                                    VALUES ($1, $2, $3, $4, $5, $6)
+                                    RETURNING {}", CLIENT_COLUMNS),
+                          &[&client.id,
+                            &client.owner_id,
+                            &client.name,
+                            &client.secret_hash,
+                            &client.redirect_uris.join(" "),
+                            &Utc::now()])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(client_from_row(&rows.get(0)))
+}
+
+pub fn get_client(id: &str, conn: &PlatformConnection) -> Result<Client, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM oauth_clients WHERE id = $1",
+                                   CLIENT_COLUMNS),
+                          &[&id])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(client_from_row(&rows.get(0)))
+}
+
+pub fn client_exists(id: &str, conn: &PlatformConnection) -> bool {
+    match conn.query("SELECT 1 FROM oauth_clients WHERE id = $1", &[&id]) {
+        Ok(rows) => !rows.is_empty(),
+        Err(_) => false,
+    }
+}
+
+pub fn get_clients_by_owner(owner_id: &Uuid,
+                            conn: &PlatformConnection) -> Result<Vec<Client>, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM oauth_clients
+                                    WHERE owner_id = $1 ORDER BY created_on",
+                                   CLIENT_COLUMNS),
+                          &[owner_id])?;
+    Ok(rows.iter().map(|row| client_from_row(&row)).collect())
+}
+
+// Delete a client and any authorization codes issued to it
+pub fn delete_client(id: &str, owner_id: &Uuid, conn: &PlatformConnection) -> bool {
+    let trans = match conn.transaction() {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    let deleted = match trans.execute("DELETE FROM oauth_clients WHERE id = $1 AND owner_id = $2",
+                                      &[&id, owner_id]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    };
+    if !deleted {
+        return false;
+    }
+    if trans.execute("DELETE FROM oauth_codes WHERE client_id = $1", &[&id]).is_err() {
+        return false;
+    }
+    trans.commit().is_ok()
+}
+
+pub fn create_code(code_hash: &str,
+                   code: AuthorizationCode,
+                   conn: &PlatformConnection) -> bool {
+    conn.execute("INSERT INTO oauth_codes
+                  (code_hash, client_id, user_id, redirect_uri, scopes,
+                   code_challenge, expires_on)
+                  VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                 &[&code_hash,
+                   &code.client_id,
+                   &code.user_id,
+                   &code.redirect_uri,
+                   &scope::join(&code.scopes),
+                   &code.code_challenge,
+                   &code.expires_on]).is_ok()
+}
+
+// Mark an unexpired code as used and return it. Each code can only be
+// exchanged once.
+pub fn use_code(code_hash: &str, conn: &PlatformConnection) -> Result<AuthorizationCode, Error> {
+    let rows = conn.query("UPDATE oauth_codes SET used = true
+                           WHERE code_hash = $1 AND used = false AND expires_on > $2
+                           RETURNING client_id, user_id, redirect_uri, scopes,
+                                     code_challenge, expires_on",
+                          &[&code_hash, &Utc::now()])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let row = rows.get(0);
+    Ok(AuthorizationCode {
+        client_id: row.get(0),
+        user_id: row.get(1),
+        redirect_uri: row.get(2),
+        scopes: scope::parse(&row.get::<_, String>(3)),
+        code_challenge: row.get(4),
+        expires_on: row.get(5),
+    })
+}
+
+fn client_from_row(row: &Row) -> Client {
+    let redirect_uris: String = row.get(4);
+    Client {
+        id: row.get(0),
+        owner_id: row.get(1),
+        name: row.get(2),
+        secret_hash: row.get(3),
+        redirect_uris: redirect_uris.split_whitespace().map(|s| s.to_string()).collect(),
+        created_on: row.get(5),
+    }
+}
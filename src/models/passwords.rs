@@ -0,0 +1,39 @@
+use chrono::Utc;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS password_resets (
+    user_id UUID PRIMARY KEY,
+    required_on TIMESTAMPTZ NOT NULL
+);
+";
+
+// Replace the password hash stored for a user in the platform users table.
+// The salt column is cleared since the salt is encoded in the hash.
+pub fn update(user_id: &Uuid, hash: &[u8], conn: &PlatformConnection) -> bool {
+    let salt: Vec<u8> = Vec::new();
+    match conn.execute("UPDATE users SET password = $2, salt = $3 WHERE id = $1",
+                       &[user_id, &hash, &salt]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+// Require the user to choose a new password on their next login
+pub fn require_reset(user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("UPSERT INTO password_resets (user_id, required_on) VALUES ($1, $2)",
+                 &[user_id, &Utc::now()]).is_ok()
+}
+
+pub fn reset_required(user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    match conn.query("SELECT 1 FROM password_resets WHERE user_id = $1", &[user_id]) {
+        Ok(rows) => !rows.is_empty(),
+        Err(_) => false,
+    }
+}
+
+pub fn clear_reset(user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("DELETE FROM password_resets WHERE user_id = $1", &[user_id]).is_ok()
+}
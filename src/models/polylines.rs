@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use postgres::rows::Row;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// Encoded polylines of the simplified track of each activity, one for
+// each level of simplification. tolerance is in metres.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS activity_polylines (
+    activity_id UUID NOT NULL,
+    user_id UUID NOT NULL,
+    level STRING NOT NULL,
+    tolerance FLOAT NOT NULL,
+    points INT NOT NULL,
+    polyline STRING NOT NULL,
+    PRIMARY KEY (activity_id, level),
+    INDEX activity_polylines_user_id_idx (user_id, level)
+);
+";
+
+const COLUMNS: &'static str = "level, tolerance, points, polyline";
+
+#[derive(Serialize, Clone)]
+pub struct Polyline {
+    pub level: String,
+    pub tolerance: f64,
+    pub points: i64,
+    pub polyline: String,
+}
+
+// Replace the polylines of an activity
+pub fn set(activity_id: &Uuid,
+           user_id: &Uuid,
+           polylines: &[Polyline],
+           conn: &PlatformConnection) -> bool {
+    let trans = match conn.transaction() {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    if trans.execute("DELETE FROM activity_polylines WHERE activity_id = $1",
+                     &[activity_id]).is_err() {
+        return false;
+    }
+    for polyline in polylines {
+        if trans.execute(&format!("INSERT INTO activity_polylines (activity_id, user_id, {})
+                                   VALUES ($1, $2, $3, $4, $5, $6)", COLUMNS),
+                         &[activity_id,
+                           user_id,
+                           &polyline.level,
+                           &polyline.tolerance,
+                           &polyline.points,
+                           &polyline.polyline]).is_err() {
+            return false;
+        }
+    }
+    trans.commit().is_ok()
+}
+
+pub fn get(activity_id: &Uuid, level: &str, conn: &PlatformConnection) -> Result<Polyline, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM activity_polylines
+                                    WHERE activity_id = $1 AND level = $2", COLUMNS),
+                          &[activity_id, &level])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(from_row(&rows.get(0)))
+}
+
+pub fn get_by_activity_id(activity_id: &Uuid,
+                          conn: &PlatformConnection) -> Result<Vec<Polyline>, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM activity_polylines
+                                    WHERE activity_id = $1
+                                    ORDER BY tolerance DESC", COLUMNS),
+                          &[activity_id])?;
+    Ok(rows.iter().map(|row| from_row(&row)).collect())
+}
+
+// The polyline at level of each of the user's activities that has one,
+// by activity id
+pub fn get_by_user_id(user_id: &Uuid,
+                      level: &str,
+                      conn: &PlatformConnection) -> Result<HashMap<Uuid, String>, Error> {
+    let rows = conn.query("SELECT activity_id, polyline FROM activity_polylines
+                           WHERE user_id = $1 AND level = $2",
+                          &[user_id, &level])?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+pub fn delete_activity(activity_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("DELETE FROM activity_polylines WHERE activity_id = $1",
+                 &[activity_id]).is_ok()
+}
+
+fn from_row(row: &Row) -> Polyline {
+    Polyline {
+        level: row.get(0),
+        tolerance: row.get(1),
+        points: row.get(2),
+        polyline: row.get(3),
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use postgres::rows::Row;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use analysis;
+use super::Error;
+
+// Privacy zones are circles, radius in metres, inside which a user's
+// tracks are hidden from everyone else. Each activity has a privacy level
+// deciding who else can see it; activities without one are private.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS privacy_zones (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    lat FLOAT NOT NULL,
+    lng FLOAT NOT NULL,
+    radius FLOAT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    INDEX privacy_zones_user_id_idx (user_id)
+);
+CREATE TABLE IF NOT EXISTS activity_privacy (
+    activity_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    level STRING NOT NULL,
+    INDEX activity_privacy_user_id_idx (user_id)
+);
+";
+
+pub const PRIVATE: &'static str = "private";
+pub const FOLLOWERS: &'static str = "followers";
+pub const PUBLIC: &'static str = "public";
+
+pub const LEVELS: [&'static str; 3] = [PRIVATE, FOLLOWERS, PUBLIC];
+
+#[derive(Serialize)]
+pub struct PrivacyZone {
+    pub id: Uuid,
+    #[serde(skip_serializing)]
+    pub user_id: Uuid,
+    pub lat: f64,
+    pub lng: f64,
+    pub radius: f64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl PrivacyZone {
+    pub fn contains(&self, point: (f64, f64)) -> bool {
+        analysis::haversine((self.lat, self.lng), point) <= self.radius
+    }
+}
+
+pub fn get_zones(user_id: &Uuid, conn: &PlatformConnection) -> Result<Vec<PrivacyZone>, Error> {
+    let rows = conn.query("SELECT id, user_id, lat, lng, radius, created_at FROM privacy_zones
+                           WHERE user_id = $1
+                           ORDER BY created_at",
+                          &[user_id])?;
+    Ok(rows.iter().map(|row| from_row(&row)).collect())
+}
+
+pub fn create_zone(zone: &PrivacyZone, conn: &PlatformConnection) -> bool {
+    conn.execute("INSERT INTO privacy_zones (id, user_id, lat, lng, radius, created_at)
+                  VALUES ($1, $2, $3, $4, $5, $6)",
+                 &[&zone.id, &zone.user_id, &zone.lat, &zone.lng, &zone.radius,
+                   &zone.created_at]).is_ok()
+}
+
+pub fn delete_zone(id: &Uuid, user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    match conn.execute("DELETE FROM privacy_zones WHERE id = $1 AND user_id = $2",
+                       &[id, user_id]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+pub fn get_level(activity_id: &Uuid, conn: &PlatformConnection) -> Result<String, Error> {
+    let rows = conn.query("SELECT level FROM activity_privacy WHERE activity_id = $1",
+                          &[activity_id])?;
+    if rows.is_empty() {
+        return Ok(PRIVATE.to_string());
+    }
+    Ok(rows.get(0).get(0))
+}
+
+// Levels of the user's activities that have one, by activity id
+pub fn get_levels(user_id: &Uuid, conn: &PlatformConnection) -> Result<HashMap<Uuid, String>, Error> {
+    let rows = conn.query("SELECT activity_id, level FROM activity_privacy WHERE user_id = $1",
+                          &[user_id])?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+pub fn set_level(activity_id: &Uuid, user_id: &Uuid, level: &str, conn: &PlatformConnection) -> bool {
+    conn.execute("UPSERT INTO activity_privacy (activity_id, user_id, level) VALUES ($1, $2, $3)",
+                 &[activity_id, user_id, &level]).is_ok()
+}
+
+pub fn delete_level(activity_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("DELETE FROM activity_privacy WHERE activity_id = $1", &[activity_id]).is_ok()
+}
+
+fn from_row(row: &Row) -> PrivacyZone {
+    PrivacyZone {
+        id: row.get(0),
+        user_id: row.get(1),
+        lat: row.get(2),
+        lng: row.get(3),
+        radius: row.get(4),
+        created_at: row.get(5),
+    }
+}
@@ -0,0 +1,176 @@
+use chrono::{NaiveDate, Utc};
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// Physiological values change over time, so each value is stored with the
+// date it takes effect. Analyses of an activity use the values in effect
+// on the day of the activity.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS athlete_profiles (
+    user_id UUID PRIMARY KEY,
+    display_name STRING,
+    birth_year INT,
+    sex STRING,
+    height_cm FLOAT,
+    units STRING NOT NULL DEFAULT 'metric',
+    updated_on TIMESTAMPTZ NOT NULL
+);
+CREATE TABLE IF NOT EXISTS athlete_settings (
+    user_id UUID NOT NULL,
+    setting STRING NOT NULL,
+    effective_from DATE NOT NULL,
+    value FLOAT NOT NULL,
+    PRIMARY KEY (user_id, setting, effective_from)
+);
+";
+
+pub const WEIGHT: &'static str = "weight_kg";
+pub const FTP: &'static str = "ftp_watts";
+pub const MAX_HR: &'static str = "max_hr";
+pub const RESTING_HR: &'static str = "resting_hr";
+pub const THRESHOLD_HR: &'static str = "threshold_hr";
+// Seconds per kilometre
+pub const THRESHOLD_PACE: &'static str = "threshold_pace";
+
+pub const SETTINGS: [&'static str; 6] = [
+    WEIGHT,
+    FTP,
+    MAX_HR,
+    RESTING_HR,
+    THRESHOLD_HR,
+    THRESHOLD_PACE,
+];
+
+pub const METRIC: &'static str = "metric";
+pub const IMPERIAL: &'static str = "imperial";
+
+#[derive(Serialize, Default)]
+pub struct Profile {
+    pub display_name: Option<String>,
+    pub birth_year: Option<i32>,
+    pub sex: Option<String>,
+    pub height_cm: Option<f64>,
+    pub units: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct SettingValue {
+    pub effective_from: NaiveDate,
+    pub value: f64,
+}
+
+// Physiological values in effect on a given day
+#[derive(Serialize, Default, Clone)]
+pub struct Physiology {
+    pub weight_kg: Option<f64>,
+    pub ftp_watts: Option<f64>,
+    pub max_hr: Option<f64>,
+    pub resting_hr: Option<f64>,
+    pub threshold_hr: Option<f64>,
+    pub threshold_pace: Option<f64>,
+}
+
+// Every value ever set for the user, oldest first for each setting
+pub struct History {
+    values: Vec<(String, SettingValue)>,
+}
+
+impl History {
+    pub fn values(&self, setting: &str) -> Vec<SettingValue> {
+        self.values.iter()
+            .filter(|&&(ref s, _)| s == setting)
+            .map(|&(_, ref v)| v.clone())
+            .collect()
+    }
+
+    // Value of setting in effect on date
+    pub fn value_on(&self, setting: &str, date: &NaiveDate) -> Option<f64> {
+        self.values.iter()
+            .filter(|&&(ref s, ref v)| s == setting && v.effective_from <= *date)
+            .map(|&(_, ref v)| v)
+            .last()
+            .map(|v| v.value)
+    }
+
+    pub fn physiology_on(&self, date: &NaiveDate) -> Physiology {
+        Physiology {
+            weight_kg: self.value_on(WEIGHT, date),
+            ftp_watts: self.value_on(FTP, date),
+            max_hr: self.value_on(MAX_HR, date),
+            resting_hr: self.value_on(RESTING_HR, date),
+            threshold_hr: self.value_on(THRESHOLD_HR, date),
+            threshold_pace: self.value_on(THRESHOLD_PACE, date),
+        }
+    }
+}
+
+pub fn get(user_id: &Uuid, conn: &PlatformConnection) -> Result<Profile, Error> {
+    let rows = conn.query("SELECT display_name, birth_year, sex, height_cm, units
+                           FROM athlete_profiles WHERE user_id = $1",
+                          &[user_id])?;
+    if rows.is_empty() {
+        return Ok(Profile { units: METRIC.to_string(), ..Profile::default() });
+    }
+    let row = rows.get(0);
+    let birth_year: Option<i64> = row.get(1);
+    Ok(Profile {
+        display_name: row.get(0),
+        birth_year: birth_year.map(|y| y as i32),
+        sex: row.get(2),
+        height_cm: row.get(3),
+        units: row.get(4),
+    })
+}
+
+pub fn upsert(user_id: &Uuid, profile: &Profile, conn: &PlatformConnection) -> bool {
+    let birth_year = profile.birth_year.map(|y| y as i64);
+    conn.execute("UPSERT INTO athlete_profiles
+                  (user_id, display_name, birth_year, sex, height_cm, units, updated_on)
+                  VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                 &[user_id,
+                   &profile.display_name,
+                   &birth_year,
+                   &profile.sex,
+                   &profile.height_cm,
+                   &profile.units,
+                   &Utc::now()]).is_ok()
+}
+
+pub fn history(user_id: &Uuid, conn: &PlatformConnection) -> Result<History, Error> {
+    let rows = conn.query("SELECT setting, effective_from, value
+                           FROM athlete_settings
+                           WHERE user_id = $1
+                           ORDER BY setting, effective_from",
+                          &[user_id])?;
+    Ok(History {
+        values: rows.iter().map(|row| {
+            (row.get(0), SettingValue { effective_from: row.get(1), value: row.get(2) })
+        }).collect(),
+    })
+}
+
+// Set a value taking effect on effective_from, replacing any value set
+// for the same day
+pub fn set_value(user_id: &Uuid,
+                 setting: &str,
+                 value: &SettingValue,
+                 conn: &PlatformConnection) -> bool {
+    conn.execute("UPSERT INTO athlete_settings (user_id, setting, effective_from, value)
+                  VALUES ($1, $2, $3, $4)",
+                 &[user_id, &setting, &value.effective_from, &value.value]).is_ok()
+}
+
+pub fn delete_value(user_id: &Uuid,
+                    setting: &str,
+                    effective_from: &NaiveDate,
+                    conn: &PlatformConnection) -> bool {
+    match conn.execute("DELETE FROM athlete_settings
+                        WHERE user_id = $1 AND setting = $2 AND effective_from = $3",
+                       &[user_id, &setting, effective_from]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
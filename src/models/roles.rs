@@ -0,0 +1,44 @@
+use chrono::Utc;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS user_roles (
+    user_id UUID NOT NULL,
+    role STRING NOT NULL,
+    granted_on TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (user_id, role)
+);
+";
+
+pub const ADMIN: &'static str = "admin";
+
+pub fn has_role(user_id: &Uuid, role: &str, conn: &PlatformConnection) -> bool {
+    match conn.query("SELECT 1 FROM user_roles WHERE user_id = $1 AND role = $2",
+                     &[user_id, &role]) {
+        Ok(rows) => !rows.is_empty(),
+        Err(_) => false,
+    }
+}
+
+pub fn get_by_user_id(user_id: &Uuid, conn: &PlatformConnection) -> Result<Vec<String>, Error> {
+    let rows = conn.query("SELECT role FROM user_roles WHERE user_id = $1 ORDER BY role",
+                          &[user_id])?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+pub fn grant(user_id: &Uuid, role: &str, conn: &PlatformConnection) -> bool {
+    conn.execute("UPSERT INTO user_roles (user_id, role, granted_on) VALUES ($1, $2, $3)",
+                 &[user_id, &role, &Utc::now()]).is_ok()
+}
+
+pub fn revoke(user_id: &Uuid, role: &str, conn: &PlatformConnection) -> bool {
+    match conn.execute("DELETE FROM user_roles WHERE user_id = $1 AND role = $2",
+                       &[user_id, &role]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
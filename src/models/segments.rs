@@ -0,0 +1,283 @@
+use chrono::{DateTime, Utc};
+use postgres::rows::Row;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+use super::privacy::PUBLIC;
+
+// Segments are stretches of road or trail created by any user and matched
+// against everyone's activities of the same sport. The geometry is an
+// encoded polyline. Segments are found by the grid cell of their start
+// (see cell) so only segments near an activity are matched against it.
+// Efforts are the times taken over a segment in each activity.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS segments (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    name STRING NOT NULL,
+    sport STRING NOT NULL,
+    distance FLOAT NOT NULL,
+    start_lat FLOAT NOT NULL,
+    start_lng FLOAT NOT NULL,
+    end_lat FLOAT NOT NULL,
+    end_lng FLOAT NOT NULL,
+    start_cell STRING NOT NULL,
+    polyline STRING NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    INDEX segments_user_id_idx (user_id),
+    INDEX segments_start_cell_idx (start_cell, sport)
+);
+CREATE TABLE IF NOT EXISTS segment_efforts (
+    segment_id UUID NOT NULL,
+    activity_id UUID NOT NULL,
+    user_id UUID NOT NULL,
+    start FLOAT NOT NULL,
+    start_time TIMESTAMPTZ NOT NULL,
+    elapsed_time FLOAT NOT NULL,
+    PRIMARY KEY (segment_id, activity_id, start),
+    INDEX segment_efforts_activity_id_idx (activity_id),
+    INDEX segment_efforts_user_id_idx (user_id),
+    INDEX segment_efforts_time_idx (segment_id, elapsed_time)
+);
+";
+
+const COLUMNS: &'static str = "id, user_id, name, sport, distance, start_lat, start_lng,
+                               end_lat, end_lng, polyline, created_at";
+
+// Size in degrees of the grid cells segments are found by
+const CELL_SIZE: f64 = 0.01;
+
+#[derive(Serialize)]
+pub struct Segment {
+    pub id: Uuid,
+    // The user who created the segment
+    pub user_id: Uuid,
+    pub name: String,
+    pub sport: String,
+    // Metres along the segment
+    pub distance: f64,
+    pub start_lat: f64,
+    pub start_lng: f64,
+    pub end_lat: f64,
+    pub end_lng: f64,
+    pub polyline: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// start is seconds from the start of the activity
+#[derive(Serialize)]
+pub struct SegmentEffort {
+    pub segment_id: Uuid,
+    pub name: String,
+    pub activity_id: Uuid,
+    #[serde(skip_serializing)]
+    pub user_id: Uuid,
+    pub start: f64,
+    pub start_time: DateTime<Utc>,
+    pub elapsed_time: f64,
+}
+
+// The fastest effort of a user
+#[derive(Serialize)]
+pub struct LeaderboardEntry {
+    pub rank: i64,
+    pub user_id: Uuid,
+    pub display_name: Option<String>,
+    pub activity_id: Uuid,
+    pub start_time: DateTime<Utc>,
+    pub elapsed_time: f64,
+}
+
+// Key of the grid cell containing a point
+pub fn cell(lat: f64, lng: f64) -> String {
+    format!("{}:{}", (lat / CELL_SIZE).floor() as i64, (lng / CELL_SIZE).floor() as i64)
+}
+
+// Keys of the cell containing a point and the eight cells around it
+pub fn cells_around(lat: f64, lng: f64) -> Vec<String> {
+    let mut cells = Vec::with_capacity(9);
+    for dlat in [-1.0, 0.0, 1.0].iter() {
+        for dlng in [-1.0, 0.0, 1.0].iter() {
+            cells.push(cell(lat + dlat * CELL_SIZE, lng + dlng * CELL_SIZE));
+        }
+    }
+    cells
+}
+
+pub fn create(segment: &Segment, conn: &PlatformConnection) -> bool {
+    conn.execute(&format!("INSERT INTO segments ({}, start_cell)
+                           VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)", COLUMNS),
+                 &[&segment.id,
+                   &segment.user_id,
+                   &segment.name,
+                   &segment.sport,
+                   &segment.distance,
+                   &segment.start_lat,
+                   &segment.start_lng,
+                   &segment.end_lat,
+                   &segment.end_lng,
+                   &segment.polyline,
+                   &segment.created_at,
+                   &cell(segment.start_lat, segment.start_lng)]).is_ok()
+}
+
+pub fn get(id: &Uuid, conn: &PlatformConnection) -> Result<Segment, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM segments WHERE id = $1", COLUMNS), &[id])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(from_row(&rows.get(0)))
+}
+
+// Segments created by the user, newest first
+pub fn get_by_user_id(user_id: &Uuid, conn: &PlatformConnection) -> Result<Vec<Segment>, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM segments
+                                    WHERE user_id = $1
+                                    ORDER BY created_at DESC", COLUMNS),
+                          &[user_id])?;
+    Ok(rows.iter().map(|row| from_row(&row)).collect())
+}
+
+// Segments of the sport starting in any of the cells
+pub fn starting_in(cells: &[String],
+                   sport: &str,
+                   conn: &PlatformConnection) -> Result<Vec<Segment>, Error> {
+    let cells: Vec<String> = cells.to_vec();
+    let rows = conn.query(&format!("SELECT {} FROM segments
+                                    WHERE start_cell = ANY($1) AND sport = $2", COLUMNS),
+                          &[&cells, &sport])?;
+    Ok(rows.iter().map(|row| from_row(&row)).collect())
+}
+
+// Delete a segment created by the user and every effort on it
+pub fn delete(id: &Uuid, user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    let trans = match conn.transaction() {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    match trans.execute("DELETE FROM segments WHERE id = $1 AND user_id = $2", &[id, user_id]) {
+        Ok(1) => {},
+        _ => return false,
+    }
+    if trans.execute("DELETE FROM segment_efforts WHERE segment_id = $1", &[id]).is_err() {
+        return false;
+    }
+    trans.commit().is_ok()
+}
+
+// Replace the efforts of an activity
+pub fn set_activity_efforts(activity_id: &Uuid,
+                            efforts: &[SegmentEffort],
+                            conn: &PlatformConnection) -> bool {
+    let trans = match conn.transaction() {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    if trans.execute("DELETE FROM segment_efforts WHERE activity_id = $1",
+                     &[activity_id]).is_err() {
+        return false;
+    }
+    for effort in efforts {
+        if trans.execute("INSERT INTO segment_efforts
+                          (segment_id, activity_id, user_id, start, start_time, elapsed_time)
+                          VALUES ($1, $2, $3, $4, $5, $6)",
+                         &[&effort.segment_id,
+                           activity_id,
+                           &effort.user_id,
+                           &effort.start,
+                           &effort.start_time,
+                           &effort.elapsed_time]).is_err() {
+            return false;
+        }
+    }
+    trans.commit().is_ok()
+}
+
+pub fn delete_activity_efforts(activity_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("DELETE FROM segment_efforts WHERE activity_id = $1", &[activity_id]).is_ok()
+}
+
+pub fn get_activity_efforts(activity_id: &Uuid,
+                            conn: &PlatformConnection) -> Result<Vec<SegmentEffort>, Error> {
+    let rows = conn.query("SELECT e.segment_id, s.name, e.activity_id, e.user_id, e.start,
+                                  e.start_time, e.elapsed_time
+                           FROM segment_efforts e
+                           JOIN segments s ON s.id = e.segment_id
+                           WHERE e.activity_id = $1
+                           ORDER BY e.start",
+                          &[activity_id])?;
+    Ok(rows.iter().map(|row| effort_from_row(&row)).collect())
+}
+
+// The user's efforts on a segment, fastest first
+pub fn get_user_efforts(segment_id: &Uuid,
+                        user_id: &Uuid,
+                        conn: &PlatformConnection) -> Result<Vec<SegmentEffort>, Error> {
+    let rows = conn.query("SELECT e.segment_id, s.name, e.activity_id, e.user_id, e.start,
+                                  e.start_time, e.elapsed_time
+                           FROM segment_efforts e
+                           JOIN segments s ON s.id = e.segment_id
+                           WHERE e.segment_id = $1 AND e.user_id = $2
+                           ORDER BY e.elapsed_time, e.start_time",
+                          &[segment_id, user_id])?;
+    Ok(rows.iter().map(|row| effort_from_row(&row)).collect())
+}
+
+// The fastest effort of each user, fastest first. Efforts in activities
+// that are not public only count for their owner, the viewer.
+pub fn leaderboard(segment_id: &Uuid,
+                   viewer: &Uuid,
+                   limit: i64,
+                   conn: &PlatformConnection) -> Result<Vec<LeaderboardEntry>, Error> {
+    let rows = conn.query("SELECT user_id, display_name, activity_id, start_time, elapsed_time
+                           FROM (SELECT DISTINCT ON (e.user_id)
+                                        e.user_id, p.display_name, e.activity_id,
+                                        e.start_time, e.elapsed_time
+                                 FROM segment_efforts e
+                                 LEFT JOIN activity_privacy v ON v.activity_id = e.activity_id
+                                 LEFT JOIN athlete_profiles p ON p.user_id = e.user_id
+                                 WHERE e.segment_id = $1
+                                 AND (v.level = $3 OR e.user_id = $2)
+                                 ORDER BY e.user_id, e.elapsed_time, e.start_time) AS best
+                           ORDER BY elapsed_time, start_time
+                           LIMIT $4",
+                          &[segment_id, viewer, &PUBLIC, &limit])?;
+    Ok(rows.iter().enumerate().map(|(i, row)| LeaderboardEntry {
+        rank: i as i64 + 1,
+        user_id: row.get(0),
+        display_name: row.get(1),
+        activity_id: row.get(2),
+        start_time: row.get(3),
+        elapsed_time: row.get(4),
+    }).collect())
+}
+
+fn from_row(row: &Row) -> Segment {
+    Segment {
+        id: row.get(0),
+        user_id: row.get(1),
+        name: row.get(2),
+        sport: row.get(3),
+        distance: row.get(4),
+        start_lat: row.get(5),
+        start_lng: row.get(6),
+        end_lat: row.get(7),
+        end_lng: row.get(8),
+        polyline: row.get(9),
+        created_at: row.get(10),
+    }
+}
+
+fn effort_from_row(row: &Row) -> SegmentEffort {
+    SegmentEffort {
+        segment_id: row.get(0),
+        name: row.get(1),
+        activity_id: row.get(2),
+        user_id: row.get(3),
+        start: row.get(4),
+        start_time: row.get(5),
+        elapsed_time: row.get(6),
+    }
+}
@@ -0,0 +1,68 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+use hdb::platform::models::tokens;
+
+use super::api_keys;
+
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS session_revocations (
+    user_id UUID PRIMARY KEY,
+    revoked_before TIMESTAMPTZ NOT NULL
+);
+CREATE TABLE IF NOT EXISTS used_challenges (
+    id STRING PRIMARY KEY,
+    expires_on TIMESTAMPTZ NOT NULL,
+    INDEX used_challenges_expires_on_idx (expires_on)
+);
+";
+
+// Access tokens are not stored, so they are revoked by rejecting any token
+// for the user issued before the revocation time.
+pub fn revoke_all(user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("UPSERT INTO session_revocations (user_id, revoked_before)
+                  VALUES ($1, $2)",
+                 &[user_id, &Utc::now()]).is_ok()
+}
+
+pub fn revoked_before(user_id: &Uuid, conn: &PlatformConnection) -> Option<DateTime<Utc>> {
+    match conn.query("SELECT revoked_before FROM session_revocations WHERE user_id = $1",
+                     &[user_id]) {
+        Ok(ref rows) if !rows.is_empty() => Some(rows.get(0).get(0)),
+        _ => None,
+    }
+}
+
+// Revoke access tokens and API keys, and remove the stored access token
+// so login issues a new one.
+pub fn revoke_credentials(user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    if let Ok(token) = tokens::get_by_user_id(user_id, conn) {
+        if !tokens::delete(&token.id, conn) {
+            return false;
+        }
+    }
+    revoke_all(user_id, conn) && api_keys::revoke_all(user_id, conn)
+}
+
+// Challenge tokens returned by login are recorded when they are exchanged
+// so each can only be used once. Rows are removed once the token has
+// expired. Returns false if the challenge was already used.
+pub fn use_challenge(id: &str, expires_on: &DateTime<Utc>, conn: &PlatformConnection) -> bool {
+    if conn.execute("DELETE FROM used_challenges WHERE expires_on < $1", &[&Utc::now()]).is_err() {
+        return false;
+    }
+    match conn.execute("INSERT INTO used_challenges (id, expires_on) VALUES ($1, $2)
+                        ON CONFLICT (id) DO NOTHING",
+                       &[&id, expires_on]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+pub fn challenge_used(id: &str, conn: &PlatformConnection) -> bool {
+    match conn.query("SELECT 1 FROM used_challenges WHERE id = $1", &[&id]) {
+        Ok(rows) => !rows.is_empty(),
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,110 @@
+use chrono::{DateTime, Utc};
+use postgres::rows::Row;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// Metrics computed from the activity file when it is imported. Distances
+// are in metres, times in seconds and speeds in metres per second.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS activity_summaries (
+    activity_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    sport STRING NOT NULL,
+    start_time TIMESTAMPTZ NOT NULL,
+    elapsed_time FLOAT NOT NULL,
+    distance FLOAT,
+    avg_speed FLOAT,
+    max_speed FLOAT,
+    avg_heart_rate FLOAT,
+    max_heart_rate FLOAT,
+    avg_power FLOAT,
+    max_power FLOAT,
+    avg_cadence FLOAT,
+    INDEX activity_summaries_user_id_idx (user_id, start_time)
+);
+";
+
+const COLUMNS: &'static str = "activity_id, user_id, sport, start_time, elapsed_time, distance,
+                               avg_speed, max_speed, avg_heart_rate, max_heart_rate,
+                               avg_power, max_power, avg_cadence";
+
+#[derive(Serialize, Clone)]
+pub struct Summary {
+    pub activity_id: Uuid,
+    #[serde(skip_serializing)]
+    pub user_id: Uuid,
+    pub sport: String,
+    pub start_time: DateTime<Utc>,
+    pub elapsed_time: f64,
+    pub distance: Option<f64>,
+    pub avg_speed: Option<f64>,
+    pub max_speed: Option<f64>,
+    pub avg_heart_rate: Option<f64>,
+    pub max_heart_rate: Option<f64>,
+    pub avg_power: Option<f64>,
+    pub max_power: Option<f64>,
+    pub avg_cadence: Option<f64>,
+}
+
+pub fn create(summary: &Summary, conn: &PlatformConnection) -> bool {
+    conn.execute(&format!("UPSERT INTO activity_summaries ({})
+                           VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)", COLUMNS),
+                 &[&summary.activity_id,
+                   &summary.user_id,
+                   &summary.sport,
+                   &summary.start_time,
+                   &summary.elapsed_time,
+                   &summary.distance,
+                   &summary.avg_speed,
+                   &summary.max_speed,
+                   &summary.avg_heart_rate,
+                   &summary.max_heart_rate,
+                   &summary.avg_power,
+                   &summary.max_power,
+                   &summary.avg_cadence]).is_ok()
+}
+
+pub fn get(activity_id: &Uuid, user_id: &Uuid, conn: &PlatformConnection) -> Result<Summary, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM activity_summaries
+                                    WHERE activity_id = $1 AND user_id = $2", COLUMNS),
+                          &[activity_id, user_id])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(from_row(&rows.get(0)))
+}
+
+// Most recent first
+pub fn get_by_user_id(user_id: &Uuid, conn: &PlatformConnection) -> Result<Vec<Summary>, Error> {
+    let rows = conn.query(&format!("SELECT {} FROM activity_summaries
+                                    WHERE user_id = $1
+                                    ORDER BY start_time DESC", COLUMNS),
+                          &[user_id])?;
+    Ok(rows.iter().map(|row| from_row(&row)).collect())
+}
+
+pub fn delete(activity_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("DELETE FROM activity_summaries WHERE activity_id = $1",
+                 &[activity_id]).is_ok()
+}
+
+fn from_row(row: &Row) -> Summary {
+    Summary {
+        activity_id: row.get(0),
+        user_id: row.get(1),
+        sport: row.get(2),
+        start_time: row.get(3),
+        elapsed_time: row.get(4),
+        distance: row.get(5),
+        avg_speed: row.get(6),
+        max_speed: row.get(7),
+        avg_heart_rate: row.get(8),
+        max_heart_rate: row.get(9),
+        avg_power: row.get(10),
+        max_power: row.get(11),
+        avg_cadence: row.get(12),
+    }
+}
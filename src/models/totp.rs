@@ -0,0 +1,139 @@
+use chrono::Utc;
+use postgres;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS user_totp (
+    user_id UUID PRIMARY KEY,
+    secret BYTES NOT NULL,
+    confirmed BOOL NOT NULL DEFAULT false,
+    last_step INT NOT NULL DEFAULT 0,
+    created_on TIMESTAMPTZ NOT NULL
+);
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    user_id UUID NOT NULL,
+    code_hash STRING NOT NULL,
+    used_on TIMESTAMPTZ,
+    PRIMARY KEY (user_id, code_hash)
+);
+";
+
+pub struct UserTotp {
+    pub user_id: Uuid,
+    pub secret: Vec<u8>,
+    pub confirmed: bool,
+    // Last time step a code was accepted for. Codes for this or earlier
+    // time steps are rejected to prevent replay.
+    pub last_step: i64,
+}
+
+// Store a new, unconfirmed secret for the user replacing any previous
+// unconfirmed secret.
+pub fn create(user_id: &Uuid, secret: &[u8], conn: &PlatformConnection) -> bool {
+    conn.execute("UPSERT INTO user_totp
+                  (user_id, secret, confirmed, last_step, created_on)
+                  VALUES ($1, $2, false, 0, $3)",
+                 &[user_id, &secret, &Utc::now()]).is_ok()
+}
+
+pub fn get_by_user_id(user_id: &Uuid,
+                      conn: &PlatformConnection) -> Result<UserTotp, Error> {
+    let rows = conn.query("SELECT user_id, secret, confirmed, last_step
+                           FROM user_totp WHERE user_id = $1",
+                          &[user_id])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let row = rows.get(0);
+    Ok(UserTotp {
+        user_id: row.get(0),
+        secret: row.get(1),
+        confirmed: row.get(2),
+        last_step: row.get(3),
+    })
+}
+
+// Two factor authentication is enabled once the secret has been confirmed
+pub fn is_enabled(user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    match get_by_user_id(user_id, conn) {
+        Ok(t) => t.confirmed,
+        Err(_) => false,
+    }
+}
+
+pub fn confirm(user_id: &Uuid, step: i64, conn: &PlatformConnection) -> bool {
+    match conn.execute("UPDATE user_totp SET confirmed = true, last_step = $2
+                        WHERE user_id = $1",
+                       &[user_id, &step]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+// Record step as used. Returns false if a code for this or a later time
+// step has already been used.
+pub fn use_step(user_id: &Uuid, step: i64, conn: &PlatformConnection) -> bool {
+    match conn.execute("UPDATE user_totp SET last_step = $2
+                        WHERE user_id = $1 AND last_step < $2",
+                       &[user_id, &step]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+// Remove the secret and all recovery codes for the user
+pub fn delete(user_id: &Uuid, conn: &PlatformConnection) -> bool {
+    delete_all(user_id, conn).is_ok()
+}
+
+fn delete_all(user_id: &Uuid, conn: &PlatformConnection) -> Result<(), postgres::Error> {
+    let trans = conn.transaction()?;
+    trans.execute("DELETE FROM recovery_codes WHERE user_id = $1", &[user_id])?;
+    trans.execute("DELETE FROM user_totp WHERE user_id = $1", &[user_id])?;
+    trans.commit()
+}
+
+// Replace all recovery codes for the user with the given code hashes
+pub fn replace_recovery_codes(user_id: &Uuid,
+                              hashes: &[String],
+                              conn: &PlatformConnection) -> bool {
+    replace_codes(user_id, hashes, conn).is_ok()
+}
+
+fn replace_codes(user_id: &Uuid,
+                 hashes: &[String],
+                 conn: &PlatformConnection) -> Result<(), postgres::Error> {
+    let trans = conn.transaction()?;
+    trans.execute("DELETE FROM recovery_codes WHERE user_id = $1", &[user_id])?;
+    for hash in hashes {
+        trans.execute("INSERT INTO recovery_codes (user_id, code_hash)
+                       VALUES ($1, $2)",
+                      &[user_id, hash])?;
+    }
+    trans.commit()
+}
+
+// Mark an unused recovery code as used. Returns false if no unused code
+// matches.
+pub fn use_recovery_code(user_id: &Uuid, hash: &str, conn: &PlatformConnection) -> bool {
+    match conn.execute("UPDATE recovery_codes SET used_on = $3
+                        WHERE user_id = $1 AND code_hash = $2
+                        AND used_on IS NULL",
+                       &[user_id, &hash, &Utc::now()]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+pub fn remaining_recovery_codes(user_id: &Uuid, conn: &PlatformConnection) -> i64 {
+    match conn.query("SELECT count(*) FROM recovery_codes
+                      WHERE user_id = $1 AND used_on IS NULL",
+                     &[user_id]) {
+        Ok(rows) => rows.get(0).get(0),
+        Err(_) => 0,
+    }
+}
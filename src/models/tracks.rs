@@ -0,0 +1,89 @@
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// Distance, elevation gain and moving time of each activity as recorded
+// and after the GPS track is cleaned up. elevation_source is dem when the
+// altitude was corrected from elevation tiles, device when the recorded
+// altitude was smoothed, and NULL when the activity has no altitude.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS activity_tracks (
+    activity_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    raw_distance FLOAT,
+    raw_elevation_gain FLOAT,
+    raw_moving_time FLOAT NOT NULL,
+    distance FLOAT,
+    elevation_gain FLOAT,
+    moving_time FLOAT NOT NULL,
+    elevation_source STRING,
+    INDEX activity_tracks_user_id_idx (user_id)
+);
+";
+
+#[derive(Serialize, Clone)]
+pub struct TrackMetrics {
+    // Metres
+    pub distance: Option<f64>,
+    pub elevation_gain: Option<f64>,
+    // Seconds
+    pub moving_time: f64,
+}
+
+#[derive(Serialize)]
+pub struct Track {
+    pub activity_id: Uuid,
+    #[serde(skip_serializing)]
+    pub user_id: Uuid,
+    pub raw: TrackMetrics,
+    pub corrected: TrackMetrics,
+    pub elevation_source: Option<String>,
+}
+
+pub fn set(track: &Track, conn: &PlatformConnection) -> bool {
+    conn.execute("UPSERT INTO activity_tracks
+                  (activity_id, user_id, raw_distance, raw_elevation_gain, raw_moving_time,
+                   distance, elevation_gain, moving_time, elevation_source)
+                  VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+                 &[&track.activity_id,
+                   &track.user_id,
+                   &track.raw.distance,
+                   &track.raw.elevation_gain,
+                   &track.raw.moving_time,
+                   &track.corrected.distance,
+                   &track.corrected.elevation_gain,
+                   &track.corrected.moving_time,
+                   &track.elevation_source]).is_ok()
+}
+
+pub fn get(activity_id: &Uuid, conn: &PlatformConnection) -> Result<Track, Error> {
+    let rows = conn.query("SELECT user_id, raw_distance, raw_elevation_gain, raw_moving_time,
+                                  distance, elevation_gain, moving_time, elevation_source
+                           FROM activity_tracks WHERE activity_id = $1",
+                          &[activity_id])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let row = rows.get(0);
+    Ok(Track {
+        activity_id: *activity_id,
+        user_id: row.get(0),
+        raw: TrackMetrics {
+            distance: row.get(1),
+            elevation_gain: row.get(2),
+            moving_time: row.get(3),
+        },
+        corrected: TrackMetrics {
+            distance: row.get(4),
+            elevation_gain: row.get(5),
+            moving_time: row.get(6),
+        },
+        elevation_source: row.get(7),
+    })
+}
+
+pub fn delete(activity_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("DELETE FROM activity_tracks WHERE activity_id = $1", &[activity_id]).is_ok()
+}
@@ -0,0 +1,158 @@
+use chrono::NaiveDate;
+use postgres::rows::Row;
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// Training stress of each activity, and the daily series of fitness (CTL)
+// and fatigue (ATL) derived from it. The daily series has a row for every
+// day from the user's first activity to their last, so it can be updated
+// from any day onwards.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS activity_loads (
+    activity_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    day DATE NOT NULL,
+    method STRING NOT NULL,
+    normalized_power FLOAT,
+    intensity_factor FLOAT NOT NULL,
+    tss FLOAT NOT NULL,
+    INDEX activity_loads_user_id_idx (user_id, day)
+);
+CREATE TABLE IF NOT EXISTS training_load (
+    user_id UUID NOT NULL,
+    day DATE NOT NULL,
+    tss FLOAT NOT NULL,
+    ctl FLOAT NOT NULL,
+    atl FLOAT NOT NULL,
+    PRIMARY KEY (user_id, day)
+);
+";
+
+#[derive(Serialize, Clone)]
+pub struct ActivityLoad {
+    #[serde(skip_serializing)]
+    pub activity_id: Uuid,
+    #[serde(skip_serializing)]
+    pub user_id: Uuid,
+    #[serde(skip_serializing)]
+    pub day: NaiveDate,
+    // power, pace or heart_rate
+    pub method: String,
+    pub normalized_power: Option<f64>,
+    pub intensity_factor: f64,
+    pub tss: f64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct DailyLoad {
+    pub day: NaiveDate,
+    pub tss: f64,
+    pub ctl: f64,
+    pub atl: f64,
+}
+
+pub fn set_activity_load(load: &ActivityLoad, conn: &PlatformConnection) -> bool {
+    conn.execute("UPSERT INTO activity_loads
+                  (activity_id, user_id, day, method, normalized_power, intensity_factor, tss)
+                  VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                 &[&load.activity_id,
+                   &load.user_id,
+                   &load.day,
+                   &load.method,
+                   &load.normalized_power,
+                   &load.intensity_factor,
+                   &load.tss]).is_ok()
+}
+
+pub fn get_activity_load(activity_id: &Uuid,
+                         conn: &PlatformConnection) -> Result<ActivityLoad, Error> {
+    let rows = conn.query("SELECT activity_id, user_id, day, method, normalized_power,
+                           intensity_factor, tss
+                           FROM activity_loads WHERE activity_id = $1",
+                          &[activity_id])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let row = rows.get(0);
+    Ok(ActivityLoad {
+        activity_id: row.get(0),
+        user_id: row.get(1),
+        day: row.get(2),
+        method: row.get(3),
+        normalized_power: row.get(4),
+        intensity_factor: row.get(5),
+        tss: row.get(6),
+    })
+}
+
+pub fn delete_activity_load(activity_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("DELETE FROM activity_loads WHERE activity_id = $1", &[activity_id]).is_ok()
+}
+
+// Total training stress of each day on or after from that has activities
+pub fn daily_tss(user_id: &Uuid,
+                 from: &NaiveDate,
+                 conn: &PlatformConnection) -> Result<Vec<(NaiveDate, f64)>, Error> {
+    let rows = conn.query("SELECT day, sum(tss) FROM activity_loads
+                           WHERE user_id = $1 AND day >= $2
+                           GROUP BY day
+                           ORDER BY day",
+                          &[user_id, from])?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+// The last day in the series before day
+pub fn day_before(user_id: &Uuid,
+                  day: &NaiveDate,
+                  conn: &PlatformConnection) -> Result<Option<DailyLoad>, Error> {
+    let rows = conn.query("SELECT day, tss, ctl, atl FROM training_load
+                           WHERE user_id = $1 AND day < $2
+                           ORDER BY day DESC
+                           LIMIT 1",
+                          &[user_id, day])?;
+    Ok(rows.iter().next().map(|row| from_row(&row)))
+}
+
+pub fn last_day(user_id: &Uuid, conn: &PlatformConnection) -> Result<Option<NaiveDate>, Error> {
+    let rows = conn.query("SELECT max(day) FROM training_load WHERE user_id = $1",
+                          &[user_id])?;
+    Ok(rows.iter().next().and_then(|row| row.get(0)))
+}
+
+pub fn set_days(user_id: &Uuid, days: &[DailyLoad], conn: &PlatformConnection) -> bool {
+    let trans = match conn.transaction() {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    for day in days {
+        if trans.execute("UPSERT INTO training_load (user_id, day, tss, ctl, atl)
+                          VALUES ($1, $2, $3, $4, $5)",
+                         &[user_id, &day.day, &day.tss, &day.ctl, &day.atl]).is_err() {
+            return false;
+        }
+    }
+    trans.commit().is_ok()
+}
+
+pub fn get_days(user_id: &Uuid,
+                from: &NaiveDate,
+                to: &NaiveDate,
+                conn: &PlatformConnection) -> Result<Vec<DailyLoad>, Error> {
+    let rows = conn.query("SELECT day, tss, ctl, atl FROM training_load
+                           WHERE user_id = $1 AND day >= $2 AND day <= $3
+                           ORDER BY day",
+                          &[user_id, from, to])?;
+    Ok(rows.iter().map(|row| from_row(&row)).collect())
+}
+
+fn from_row(row: &Row) -> DailyLoad {
+    DailyLoad {
+        day: row.get(0),
+        tss: row.get(1),
+        ctl: row.get(2),
+        atl: row.get(3),
+    }
+}
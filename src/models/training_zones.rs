@@ -0,0 +1,200 @@
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// Zones set manually by the user for a sport, replacing the zones computed
+// from their profile, and the time spent in each zone during an activity.
+// The bounds used are stored with the activity so histograms stay correct
+// when zones change later.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS training_zones (
+    user_id UUID NOT NULL,
+    sport STRING NOT NULL,
+    kind STRING NOT NULL,
+    zone INT NOT NULL,
+    min_value FLOAT NOT NULL,
+    max_value FLOAT,
+    PRIMARY KEY (user_id, sport, kind, zone)
+);
+CREATE TABLE IF NOT EXISTS activity_zone_times (
+    activity_id UUID NOT NULL,
+    user_id UUID NOT NULL,
+    kind STRING NOT NULL,
+    zone INT NOT NULL,
+    min_value FLOAT NOT NULL,
+    max_value FLOAT,
+    seconds FLOAT NOT NULL,
+    PRIMARY KEY (activity_id, kind, zone)
+);
+";
+
+// A zone holds values from min up to, but not including, max. The last
+// zone usually has no upper bound.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Zone {
+    pub min: f64,
+    pub max: Option<f64>,
+}
+
+impl Zone {
+    pub fn contains(&self, value: f64) -> bool {
+        value >= self.min && self.max.map(|max| value < max).unwrap_or(true)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ZoneTime {
+    pub zone: i64,
+    pub min: f64,
+    pub max: Option<f64>,
+    pub seconds: f64,
+}
+
+#[derive(Serialize)]
+pub struct ZoneHistogram {
+    pub kind: String,
+    pub zones: Vec<ZoneTime>,
+}
+
+#[derive(Serialize)]
+pub struct ManualZones {
+    pub sport: String,
+    pub kind: String,
+    pub zones: Vec<Zone>,
+}
+
+// Every manual zone the user has set
+pub fn get_by_user_id(user_id: &Uuid, conn: &PlatformConnection) -> Result<Vec<ManualZones>, Error> {
+    let rows = conn.query("SELECT sport, kind, min_value, max_value FROM training_zones
+                           WHERE user_id = $1
+                           ORDER BY sport, kind, zone",
+                          &[user_id])?;
+    let mut all: Vec<ManualZones> = Vec::new();
+    for row in rows.iter() {
+        let sport: String = row.get(0);
+        let kind: String = row.get(1);
+        let zone = Zone { min: row.get(2), max: row.get(3) };
+        let new_group = all.last().map(|z| z.sport != sport || z.kind != kind).unwrap_or(true);
+        if new_group {
+            all.push(ManualZones { sport: sport, kind: kind, zones: Vec::new() });
+        }
+        if let Some(z) = all.last_mut() {
+            z.zones.push(zone);
+        }
+    }
+    Ok(all)
+}
+
+// Manual zones for the sport and kind, empty if the user has not set any
+pub fn get(user_id: &Uuid,
+           sport: &str,
+           kind: &str,
+           conn: &PlatformConnection) -> Result<Vec<Zone>, Error> {
+    let rows = conn.query("SELECT min_value, max_value FROM training_zones
+                           WHERE user_id = $1 AND sport = $2 AND kind = $3
+                           ORDER BY zone",
+                          &[user_id, &sport, &kind])?;
+    Ok(rows.iter().map(|row| Zone { min: row.get(0), max: row.get(1) }).collect())
+}
+
+// Replace the manual zones for the sport and kind
+pub fn set(user_id: &Uuid,
+           sport: &str,
+           kind: &str,
+           zones: &[Zone],
+           conn: &PlatformConnection) -> bool {
+    let trans = match conn.transaction() {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    if trans.execute("DELETE FROM training_zones
+                      WHERE user_id = $1 AND sport = $2 AND kind = $3",
+                     &[user_id, &sport, &kind]).is_err() {
+        return false;
+    }
+    for (i, zone) in zones.iter().enumerate() {
+        let number = i as i64 + 1;
+        if trans.execute("INSERT INTO training_zones
+                          (user_id, sport, kind, zone, min_value, max_value)
+                          VALUES ($1, $2, $3, $4, $5, $6)",
+                         &[user_id, &sport, &kind, &number, &zone.min, &zone.max]).is_err() {
+            return false;
+        }
+    }
+    trans.commit().is_ok()
+}
+
+// Remove the manual zones so zones are computed from the profile again
+pub fn delete(user_id: &Uuid, sport: &str, kind: &str, conn: &PlatformConnection) -> bool {
+    match conn.execute("DELETE FROM training_zones
+                        WHERE user_id = $1 AND sport = $2 AND kind = $3",
+                       &[user_id, &sport, &kind]) {
+        Ok(n) => n > 0,
+        Err(_) => false,
+    }
+}
+
+pub fn set_activity_times(activity_id: &Uuid,
+                          user_id: &Uuid,
+                          histograms: &[ZoneHistogram],
+                          conn: &PlatformConnection) -> bool {
+    let trans = match conn.transaction() {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    if trans.execute("DELETE FROM activity_zone_times WHERE activity_id = $1",
+                     &[activity_id]).is_err() {
+        return false;
+    }
+    for histogram in histograms {
+        for zone in &histogram.zones {
+            if trans.execute("INSERT INTO activity_zone_times
+                              (activity_id, user_id, kind, zone, min_value, max_value, seconds)
+                              VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                             &[activity_id,
+                               user_id,
+                               &histogram.kind,
+                               &zone.zone,
+                               &zone.min,
+                               &zone.max,
+                               &zone.seconds]).is_err() {
+                return false;
+            }
+        }
+    }
+    trans.commit().is_ok()
+}
+
+pub fn delete_activity_times(activity_id: &Uuid, conn: &PlatformConnection) -> bool {
+    conn.execute("DELETE FROM activity_zone_times WHERE activity_id = $1",
+                 &[activity_id]).is_ok()
+}
+
+pub fn get_activity_times(activity_id: &Uuid,
+                          conn: &PlatformConnection) -> Result<Vec<ZoneHistogram>, Error> {
+    let rows = conn.query("SELECT kind, zone, min_value, max_value, seconds
+                           FROM activity_zone_times
+                           WHERE activity_id = $1
+                           ORDER BY kind, zone",
+                          &[activity_id])?;
+    let mut histograms: Vec<ZoneHistogram> = Vec::new();
+    for row in rows.iter() {
+        let kind: String = row.get(0);
+        let zone = ZoneTime {
+            zone: row.get(1),
+            min: row.get(2),
+            max: row.get(3),
+            seconds: row.get(4),
+        };
+        let new_kind = histograms.last().map(|h| h.kind != kind).unwrap_or(true);
+        if new_kind {
+            histograms.push(ZoneHistogram { kind: kind, zones: Vec::new() });
+        }
+        if let Some(h) = histograms.last_mut() {
+            h.zones.push(zone);
+        }
+    }
+    Ok(histograms)
+}
@@ -0,0 +1,119 @@
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use super::Error;
+
+// Lookups against the platform users table that are not provided by hdb
+
+// The platform users table has no unique index on the normalized
+// username, so registration claims the name here first. Two registrations
+// of the same name cannot both succeed.
+pub const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS usernames (
+    username STRING PRIMARY KEY,
+    created_on TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+";
+
+#[derive(Serialize)]
+pub struct UserSummary {
+    pub id: Uuid,
+    pub username: String,
+    pub active: bool,
+}
+
+pub fn get(id: &Uuid, conn: &PlatformConnection) -> Result<UserSummary, Error> {
+    let rows = conn.query("SELECT id, username, active FROM users WHERE id = $1",
+                          &[id])?;
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let row = rows.get(0);
+    Ok(UserSummary {
+        id: row.get(0),
+        username: row.get(1),
+        active: row.get(2),
+    })
+}
+
+// Usernames registered before usernames were normalized may contain
+// uppercase letters, so compare in lowercase
+pub fn username_taken(username: &str, conn: &PlatformConnection) -> bool {
+    match conn.query("SELECT 1 FROM users WHERE lower(username) = $1 LIMIT 1",
+                     &[&username.to_lowercase()]) {
+        Ok(rows) => !rows.is_empty(),
+        // Err on the side of not creating a duplicate user
+        Err(_) => true,
+    }
+}
+
+// Claim a normalized username for a new user. False if the name is
+// already claimed.
+pub fn reserve_username(username: &str, conn: &PlatformConnection) -> bool {
+    match conn.execute("INSERT INTO usernames (username) VALUES ($1)", &[&username]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+pub fn release_username(username: &str, conn: &PlatformConnection) -> bool {
+    match conn.execute("DELETE FROM usernames WHERE username = $1", &[&username]) {
+        Ok(_) => true,
+        Err(_) => false,
+    }
+}
+
+pub fn set_active(id: &Uuid, active: bool, conn: &PlatformConnection) -> bool {
+    match conn.execute("UPDATE users SET active = $2 WHERE id = $1", &[id, &active]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+// Remove a user whose registration could not be completed. Users with
+// data are removed by an account deletion instead.
+pub fn delete(id: &Uuid, conn: &PlatformConnection) -> bool {
+    match conn.execute("DELETE FROM users WHERE id = $1", &[id]) {
+        Ok(n) => n == 1,
+        Err(_) => false,
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserListing {
+    pub id: Uuid,
+    pub username: String,
+    pub active: bool,
+    pub email: Option<String>,
+}
+
+// Find users with a username or email address containing query
+pub fn search(query: &str,
+              limit: i64,
+              offset: i64,
+              conn: &PlatformConnection) -> Result<Vec<UserListing>, Error> {
+    let pattern = format!("%{}%", escape_like(&query.to_lowercase()));
+    let rows = conn.query("SELECT u.id, u.username, u.active, e.email
+                           FROM users u
+                           LEFT JOIN user_emails e ON e.user_id = u.id
+                           WHERE lower(u.username) LIKE $1 OR e.email LIKE $1
+                           ORDER BY u.username
+                           LIMIT $2 OFFSET $3",
+                          &[&pattern, &limit, &offset])?;
+    Ok(rows.iter().map(|row| UserListing {
+        id: row.get(0),
+        username: row.get(1),
+        active: row.get(2),
+        email: row.get(3),
+    }).collect())
+}
+
+pub fn activity_count(id: &Uuid, conn: &PlatformConnection) -> Result<i64, Error> {
+    let rows = conn.query("SELECT count(*) FROM activities WHERE user_id = $1", &[id])?;
+    Ok(rows.get(0).get(0))
+}
+
+fn escape_like(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("%", "\\%").replace("_", "\\_")
+}
@@ -0,0 +1,186 @@
+// Time-based one-time passwords (RFC 6238) used as a second
+// authentication factor.
+use chrono::Utc;
+
+use ring::{constant_time, digest, hmac};
+
+use auth;
+
+pub const DIGITS: u32 = 6;
+pub const PERIOD: i64 = 30;
+// Number of time steps before and after the current time step in which a
+// code is accepted, allowing for clock drift between server and device.
+const SKEW: i64 = 1;
+
+const BASE32_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// 160 bit secret as recommended by RFC 4226
+pub fn generate_secret() -> Vec<u8> {
+    auth::random_bytes(20)
+}
+
+// Recovery codes are formatted as two groups of five characters
+pub fn generate_recovery_codes(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|_| {
+            let code = auth::random_token(10).to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+// Strip formatting from a recovery code entered by the user before hashing
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+// Key URI understood by authenticator apps, usually shown as a QR code
+pub fn uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    format!("otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            percent_encode(issuer),
+            percent_encode(account),
+            base32_encode(secret),
+            percent_encode(issuer),
+            DIGITS,
+            PERIOD)
+}
+
+pub fn current_step() -> i64 {
+    Utc::now().timestamp() / PERIOD
+}
+
+// Check code against the time steps around step, skipping steps at or
+// before last_step so a code cannot be used twice. Returns the matching
+// time step, which callers store as the new last step.
+pub fn verify(secret: &[u8], code: &str, step: i64, last_step: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    for s in (step - SKEW).max(last_step + 1)..(step + SKEW + 1) {
+        let expected = format!("{:01$}", hotp(secret, s as u64), DIGITS as usize);
+        if constant_time::verify_slices_are_equal(expected.as_bytes(),
+                                                  code.as_bytes()).is_ok() {
+            return Some(s);
+        }
+    }
+    None
+}
+
+// RFC 4226 HOTP value for counter
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = hmac::SigningKey::new(&digest::SHA1, secret);
+    let mut msg = [0u8; 8];
+    for i in 0..8 {
+        msg[i] = (counter >> (56 - 8 * i)) as u8;
+    }
+    let signature = hmac::sign(&key, &msg);
+    let h = signature.as_ref();
+    // Dynamic truncation
+    let offset = (h[h.len() - 1] & 0x0f) as usize;
+    let binary = ((h[offset] as u32 & 0x7f) << 24) |
+                 ((h[offset + 1] as u32) << 16) |
+                 ((h[offset + 2] as u32) << 8) |
+                 (h[offset + 3] as u32);
+    binary % 10u32.pow(DIGITS)
+}
+
+// RFC 4648 base32 without padding
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+pub fn percent_encode(value: &str) -> String {
+    let mut out = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            },
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Secret used by the test vectors of RFC 4226 and RFC 6238
+    const SECRET: &'static [u8] = b"12345678901234567890";
+
+    fn code(step: i64) -> String {
+        format!("{:06}", hotp(SECRET, step as u64))
+    }
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let expected = [755224, 287082, 359152, 969429, 338314,
+                        254676, 287922, 162583, 399871, 520489];
+        for (counter, value) in expected.iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), *value);
+        }
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238() {
+        // The last six digits of the SHA-1 vectors
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for &(time, expected) in vectors.iter() {
+            let step = time / PERIOD;
+            assert_eq!(verify(SECRET, expected, step, 0), Some(step));
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_either_side() {
+        let code = code(100);
+        assert_eq!(verify(SECRET, &code, 99, 0), Some(100));
+        assert_eq!(verify(SECRET, &code, 100, 0), Some(100));
+        assert_eq!(verify(SECRET, &code, 101, 0), Some(100));
+        assert_eq!(verify(SECRET, &code, 98, 0), None);
+        assert_eq!(verify(SECRET, &code, 102, 0), None);
+    }
+
+    #[test]
+    fn rejects_a_used_step() {
+        assert_eq!(verify(SECRET, &code(100), 100, 99), Some(100));
+        assert_eq!(verify(SECRET, &code(100), 100, 100), None);
+        // An earlier code still inside the window
+        assert_eq!(verify(SECRET, &code(99), 100, 99), None);
+        assert_eq!(verify(SECRET, &code(101), 100, 100), Some(101));
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        let code = code(100);
+        assert_eq!(verify(SECRET, &format!(" {} ", code), 100, 0), Some(100));
+        assert_eq!(verify(SECRET, &code[..5], 100, 0), None);
+        assert_eq!(verify(SECRET, "12345a", 100, 0), None);
+    }
+}
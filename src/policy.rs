@@ -0,0 +1,251 @@
+// Username and password policies applied when users choose them. Each
+// check returns every rule that was broken so clients can show errors
+// next to the fields they belong to.
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use ring::digest;
+
+use auth;
+use config::{PasswordConfig, UsernameConfig};
+
+#[derive(Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new<S: Into<String>>(field: &'static str, code: &'static str, message: S) -> FieldError {
+        FieldError {
+            field: field,
+            code: code,
+            message: message.into(),
+        }
+    }
+}
+
+// Usernames are compared case insensitively, so they are stored in
+// lowercase
+pub fn normalize_username(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+// Checks a normalized username
+pub fn check_username(username: &str, config: &UsernameConfig) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    let length = username.chars().count();
+    if length < config.min_length {
+        errors.push(FieldError::new("username", "too_short",
+            format!("username must be at least {} characters", config.min_length)));
+    }
+    if length > config.max_length {
+        errors.push(FieldError::new("username", "too_long",
+            format!("username must be at most {} characters", config.max_length)));
+    }
+    if !username.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c)) {
+        errors.push(FieldError::new("username", "invalid_characters",
+            "username may only contain letters, digits, '.', '_' and '-'"));
+    }
+    if let Some(first) = username.chars().next() {
+        if !first.is_ascii_alphanumeric() {
+            errors.push(FieldError::new("username", "invalid_start",
+                "username must start with a letter or digit"));
+        }
+    }
+    if config.reserved.iter().any(|r| r.to_lowercase() == username) {
+        errors.push(FieldError::new("username", "reserved", "username is reserved"));
+    }
+    errors
+}
+
+pub fn check_password(password: &str,
+                      username: &str,
+                      config: &PasswordConfig) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    let length = password.chars().count();
+    if length < config.min_length {
+        errors.push(FieldError::new("password", "too_short",
+            format!("password must be at least {} characters", config.min_length)));
+    }
+    if length > config.max_length {
+        errors.push(FieldError::new("password", "too_long",
+            format!("password must be at most {} characters", config.max_length)));
+        // Do not spend time scoring or hashing very long passwords
+        return errors;
+    }
+    if username.len() >= 3 && password.to_lowercase().contains(&username.to_lowercase()) {
+        errors.push(FieldError::new("password", "contains_username",
+            "password must not contain the username"));
+    }
+    if entropy_bits(password) < config.min_entropy {
+        errors.push(FieldError::new("password", "too_weak",
+            "password is too easy to guess. Use a longer password or more kinds of characters"));
+    }
+    if !config.breached_passwords.is_empty() {
+        match is_breached(password, &config.breached_passwords) {
+            Ok(true) => {
+                errors.push(FieldError::new("password", "breached",
+                    "password has appeared in a data breach"));
+            },
+            Ok(false) => {},
+            // The check is advisory, so a missing list does not prevent
+            // users from registering
+            Err(e) => eprintln!("Error checking breached passwords: {}", e),
+        }
+    }
+    errors
+}
+
+// Rough estimate of password strength in bits: the number of characters
+// multiplied by the bits per character of the character classes used.
+// Characters repeating or continuing a sequence from the previous
+// character count for a quarter.
+pub fn entropy_bits(password: &str) -> f64 {
+    let mut pool = 0;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if password.chars().any(|c| c.is_ascii_punctuation() || c == ' ') {
+        pool += 33;
+    }
+    if password.chars().any(|c| !c.is_ascii()) {
+        pool += 100;
+    }
+    if pool == 0 {
+        return 0.0;
+    }
+    let mut length = 0.0;
+    let mut previous: Option<char> = None;
+    for c in password.chars() {
+        length += match previous {
+            Some(p) if (p as i64 - c as i64).abs() <= 1 => 0.25,
+            _ => 1.0,
+        };
+        previous = Some(c);
+    }
+    length * (pool as f64).log2()
+}
+
+// Breached passwords are looked up by k-anonymity range, as published by
+// Have I Been Pwned. The directory holds a file for each five character
+// prefix of the uppercase SHA-1 hex digest, named after the prefix, with
+// lines of the form "<remaining 35 characters>:<count>". Only the file for
+// the password's prefix is read.
+fn is_breached(password: &str, dir: &str) -> io::Result<bool> {
+    let hash = auth::to_hex(digest::digest(&digest::SHA1, password.as_bytes()).as_ref())
+        .to_uppercase();
+    let (prefix, suffix) = hash.split_at(5);
+    let dir = Path::new(dir);
+    if !dir.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound,
+                                  format!("{} is not a directory", dir.display())));
+    }
+    let path = dir.join(prefix);
+    let file = match File::open(&path) {
+        Ok(f) => f,
+        // No file means no breached password shares the prefix
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let entry = line.split(':').next().unwrap_or("").trim();
+        if entry.eq_ignore_ascii_case(suffix) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn username_config() -> UsernameConfig {
+        UsernameConfig {
+            min_length: 3,
+            max_length: 32,
+            reserved: vec!["Admin".to_string()],
+        }
+    }
+
+    fn codes(errors: &[FieldError]) -> Vec<&'static str> {
+        errors.iter().map(|e| e.code).collect()
+    }
+
+    // A breached password directory holding the file for the prefix of
+    // "password", with lowercase hashes to check they match regardless of
+    // case
+    fn breached_dir() -> String {
+        let dir = env::temp_dir().join(format!("hapi-pwned-test-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let mut f = File::create(dir.join("5BAA6")).unwrap();
+        f.write_all(b"0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n\
+                      1e4c9b93f3f0682250b6cf8331b7ee68fd8:3730471\r\n").unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn usernames_are_trimmed_and_lowercased() {
+        assert_eq!(normalize_username("  Alice.Smith "), "alice.smith");
+        assert_eq!(normalize_username("BOB"), normalize_username("bob"));
+    }
+
+    #[test]
+    fn check_username_reports_every_rule() {
+        assert!(check_username("alice_1", &username_config()).is_empty());
+        assert_eq!(codes(&check_username("_a", &username_config())),
+                   vec!["too_short", "invalid_start"]);
+        assert_eq!(codes(&check_username("alice smith", &username_config())),
+                   vec!["invalid_characters"]);
+        assert_eq!(codes(&check_username("admin", &username_config())), vec!["reserved"]);
+    }
+
+    #[test]
+    fn entropy_depends_on_character_classes() {
+        assert_eq!(entropy_bits(""), 0.0);
+        assert_eq!(entropy_bits("qwzx"), 4.0 * 26f64.log2());
+        assert_eq!(entropy_bits("Qwz7"), 4.0 * 62f64.log2());
+        assert_eq!(entropy_bits("Qw7!"), 4.0 * 95f64.log2());
+        assert_eq!(entropy_bits("qé"), 2.0 * 126f64.log2());
+    }
+
+    #[test]
+    fn repeats_and_sequences_count_for_a_quarter() {
+        assert_eq!(entropy_bits("aaaa"), 1.75 * 26f64.log2());
+        assert_eq!(entropy_bits("abcd"), 1.75 * 26f64.log2());
+        assert_eq!(entropy_bits("dcba"), 1.75 * 26f64.log2());
+        // The repeated s
+        assert_eq!(entropy_bits("password"), 7.25 * 26f64.log2());
+    }
+
+    #[test]
+    fn breached_passwords_are_found_by_prefix() {
+        let dir = breached_dir();
+        assert!(is_breached("password", &dir).unwrap());
+        // Shares no prefix file with the list
+        assert!(!is_breached("correct horse battery staple", &dir).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_breached_password_directory_is_an_error() {
+        let dir = env::temp_dir().join(format!("hapi-pwned-missing-{}", Uuid::new_v4()));
+        assert!(is_breached("password", dir.to_str().unwrap()).is_err());
+    }
+}
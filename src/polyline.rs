@@ -0,0 +1,229 @@
+// Simplified map geometry of an activity. The track is simplified with
+// Douglas-Peucker at a few tolerances and each result is stored in the
+// Google encoded polyline format, which map libraries draw directly.
+use analysis;
+use fit::Sample;
+use models::polylines::Polyline;
+use models::privacy::PrivacyZone;
+
+pub const SUMMARY: &'static str = "summary";
+pub const MEDIUM: &'static str = "medium";
+pub const DETAILED: &'static str = "detailed";
+
+// Levels and their tolerances in metres. summary is small enough for list
+// views; detailed is used when a single activity is shown.
+pub const LEVELS: [(&'static str, f64); 3] = [
+    (SUMMARY, 50.0),
+    (MEDIUM, 15.0),
+    (DETAILED, 5.0),
+];
+
+// Metres per degree of latitude
+const METRES_PER_DEGREE: f64 = 111319.49;
+
+// A polyline for every level, or none when the activity has no track
+pub fn polylines(samples: &[Sample]) -> Vec<Polyline> {
+    let track = analysis::track(samples);
+    if track.len() < 2 {
+        return Vec::new();
+    }
+    LEVELS.iter().map(|&(level, tolerance)| {
+        let points: Vec<(f64, f64)> = simplify(&track, tolerance).iter().map(|i| track[*i]).collect();
+        Polyline {
+            level: level.to_string(),
+            tolerance: tolerance,
+            points: points.len() as i64,
+            polyline: encode(&points),
+        }
+    }).collect()
+}
+
+// Indices of the points kept by Douglas-Peucker: the point furthest from
+// the line between the ends of a stretch is kept if it is more than
+// tolerance metres away, and both halves are simplified in turn
+pub fn simplify(points: &[(f64, f64)], tolerance: f64) -> Vec<usize> {
+    if points.len() < 3 {
+        return (0..points.len()).collect();
+    }
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+    let mut stretches = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = stretches.pop() {
+        let mut furthest = first;
+        let mut max_distance = 0.0;
+        for i in first + 1..last {
+            let d = distance_to_line(points[i], points[first], points[last]);
+            if d > max_distance {
+                max_distance = d;
+                furthest = i;
+            }
+        }
+        if max_distance > tolerance {
+            keep[furthest] = true;
+            stretches.push((first, furthest));
+            stretches.push((furthest, last));
+        }
+    }
+    (0..points.len()).filter(|i| keep[*i]).collect()
+}
+
+// Distance in metres from p to the segment a-b, on a flat projection
+// around a. Accurate enough over the length of an activity.
+fn distance_to_line(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let scale = a.0.to_radians().cos();
+    let project = |q: (f64, f64)| ((q.1 - a.1) * scale * METRES_PER_DEGREE,
+                                   (q.0 - a.0) * METRES_PER_DEGREE);
+    let (px, py) = project(p);
+    let (bx, by) = project(b);
+    let length = bx * bx + by * by;
+    let t = if length > 0.0 { ((px * bx + py * by) / length).max(0.0).min(1.0) } else { 0.0 };
+    ((px - t * bx).powi(2) + (py - t * by).powi(2)).sqrt()
+}
+
+// Google encoded polyline with five decimal places
+pub fn encode(points: &[(f64, f64)]) -> String {
+    let mut encoded = String::new();
+    let (mut last_lat, mut last_lng) = (0i64, 0i64);
+    for &(lat, lng) in points {
+        let lat = (lat * 1e5).round() as i64;
+        let lng = (lng * 1e5).round() as i64;
+        encode_value(lat - last_lat, &mut encoded);
+        encode_value(lng - last_lng, &mut encoded);
+        last_lat = lat;
+        last_lng = lng;
+    }
+    encoded
+}
+
+pub fn decode(encoded: &str) -> Vec<(f64, f64)> {
+    let bytes = encoded.as_bytes();
+    let mut points = Vec::new();
+    let (mut i, mut lat, mut lng) = (0, 0i64, 0i64);
+    while i < bytes.len() {
+        let (dlat, next) = match decode_value(bytes, i) {
+            Some(v) => v,
+            None => break,
+        };
+        let (dlng, next) = match decode_value(bytes, next) {
+            Some(v) => v,
+            None => break,
+        };
+        lat += dlat;
+        lng += dlng;
+        i = next;
+        points.push((lat as f64 / 1e5, lng as f64 / 1e5));
+    }
+    points
+}
+
+// The polyline without the points inside any of the privacy zones
+pub fn trim(polyline: &Polyline, zones: &[PrivacyZone]) -> Polyline {
+    let points: Vec<(f64, f64)> = decode(&polyline.polyline).into_iter()
+        .filter(|p| !zones.iter().any(|z| z.contains(*p)))
+        .collect();
+    Polyline {
+        level: polyline.level.clone(),
+        tolerance: polyline.tolerance,
+        points: points.len() as i64,
+        polyline: encode(&points),
+    }
+}
+
+fn encode_value(value: i64, encoded: &mut String) {
+    let mut value = if value < 0 { !(value << 1) } else { value << 1 };
+    while value >= 0x20 {
+        encoded.push((((value & 0x1F) | 0x20) as u8 + 63) as char);
+        value >>= 5;
+    }
+    encoded.push((value as u8 + 63) as char);
+}
+
+// The value starting at byte i and the index of the byte after it
+fn decode_value(bytes: &[u8], mut i: usize) -> Option<(i64, usize)> {
+    let mut value = 0i64;
+    let mut shift = 0;
+    loop {
+        let chunk = *bytes.get(i)? as i64 - 63;
+        i += 1;
+        value |= (chunk & 0x1F) << shift;
+        shift += 5;
+        if chunk < 0x20 {
+            break;
+        }
+    }
+    Some((if value & 1 != 0 { !(value >> 1) } else { value >> 1 }, i))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::*;
+
+    // The example from the encoded polyline format documentation
+    const SAMPLE: &'static str = "_p~iF~ps|U_ulLnnqC_mqNvxq`@";
+    const SAMPLE_POINTS: [(f64, f64); 3] = [(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)];
+
+    #[test]
+    fn encodes_the_documented_example() {
+        assert_eq!(encode(&SAMPLE_POINTS), SAMPLE);
+    }
+
+    #[test]
+    fn decodes_the_documented_example() {
+        let points = decode(SAMPLE);
+        assert_eq!(points.len(), 3);
+        for (p, q) in points.iter().zip(SAMPLE_POINTS.iter()) {
+            assert!((p.0 - q.0).abs() < 1e-9 && (p.1 - q.1).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn decoding_stops_at_a_truncated_point() {
+        assert_eq!(decode(&SAMPLE[..SAMPLE.len() - 2]).len(), 2);
+        assert!(decode("").is_empty());
+    }
+
+    #[test]
+    fn simplify_drops_points_on_a_straight_line() {
+        let line: Vec<(f64, f64)> = (0..100).map(|i| (45.0 + i as f64 * 0.0001, 6.0)).collect();
+        assert_eq!(simplify(&line, 5.0), vec![0, 99]);
+    }
+
+    #[test]
+    fn simplify_keeps_corners_further_than_the_tolerance() {
+        // North for about 1.1 km, then east
+        let mut track: Vec<(f64, f64)> = (0..11).map(|i| (45.0 + i as f64 * 0.001, 6.0)).collect();
+        track.extend((1..11).map(|i| (45.01, 6.0 + i as f64 * 0.001)));
+        assert_eq!(simplify(&track, 5.0), vec![0, 10, 20]);
+        // A 10 metre kink is kept at 5 metres and dropped at 15. A degree
+        // of longitude is about 78715 metres at 45 degrees north.
+        let kinked = vec![(45.0, 6.0), (45.001, 6.0 + 10.0 / 78715.0), (45.002, 6.0)];
+        assert_eq!(simplify(&kinked, 5.0), vec![0, 1, 2]);
+        assert_eq!(simplify(&kinked, 15.0), vec![0, 2]);
+    }
+
+    #[test]
+    fn trim_removes_points_inside_privacy_zones() {
+        let zone = PrivacyZone {
+            id: Uuid::nil(),
+            user_id: Uuid::nil(),
+            lat: 38.5,
+            lng: -120.2,
+            radius: 500.0,
+            created_at: Utc::now(),
+        };
+        let polyline = Polyline {
+            level: DETAILED.to_string(),
+            tolerance: 5.0,
+            points: 3,
+            polyline: SAMPLE.to_string(),
+        };
+        let trimmed = trim(&polyline, &[zone]);
+        assert_eq!(trimmed.points, 2);
+        assert_eq!(trimmed.polyline, encode(&SAMPLE_POINTS[1..]));
+        assert_eq!(trimmed.level, DETAILED);
+    }
+}
@@ -0,0 +1,274 @@
+// Analysis run when an activity is imported. The activity file is decoded
+// before it is stored, so files that cannot be read are rejected, and the
+// results are stored alongside the activity. The GPS track is cleaned up
+// before anything else is computed. Activities are analysed again when
+// their sport is changed, and from the day a changed physiological value
+// takes effect.
+use std::fs::File;
+use std::io::Read;
+use std::thread;
+
+use chrono::NaiveDate;
+use uuid::Uuid;
+
+use hdb::platform::{Pool, PlatformConnection};
+
+use analysis::{self, RUNNING};
+use config::TrackConfig;
+use file;
+use fit::{self, Recording};
+use gps;
+use load;
+use matching;
+use mean_max::{self, PersonalRecord};
+use polyline;
+use models::{activities, curves, laps, polylines, profiles, segments, summaries, tracks,
+             training_load, training_zones};
+use models::curves::{BestEffort, Curve, Range};
+use models::laps::Lap;
+use models::polylines::Polyline;
+use models::segments::SegmentEffort;
+use models::summaries::Summary;
+use models::tracks::Track;
+use models::training_load::ActivityLoad;
+use models::training_zones::ZoneHistogram;
+use splits;
+use zones;
+
+#[derive(Serialize)]
+pub struct Analysis {
+    pub summary: Summary,
+    // Missing for activities analysed before tracks were cleaned up
+    pub track: Option<Track>,
+    pub polylines: Vec<Polyline>,
+    pub laps: Vec<Lap>,
+    pub zones: Vec<ZoneHistogram>,
+    // Missing when the profile has none of the values training stress is
+    // based on
+    pub load: Option<ActivityLoad>,
+    pub curves: Vec<Curve>,
+    pub best_efforts: Vec<BestEffort>,
+    pub segment_efforts: Vec<SegmentEffort>,
+    // Only found when the activity is analysed
+    pub records: Vec<PersonalRecord>,
+}
+
+pub fn process(activity_id: &Uuid,
+               user_id: &Uuid,
+               sport: &str,
+               recording: &Recording,
+               config: &TrackConfig,
+               conn: &PlatformConnection) -> Result<Analysis, String> {
+    let cleaned = gps::clean(recording, config);
+    let track = Track {
+        activity_id: *activity_id,
+        user_id: *user_id,
+        raw: gps::metrics(&recording.samples, config.pause_speed),
+        corrected: gps::metrics(&cleaned.recording.samples, config.pause_speed),
+        elevation_source: cleaned.elevation_source.map(|s| s.to_string()),
+    };
+    let recording = &cleaned.recording;
+    let summary = analysis::summarize(activity_id, user_id, sport, recording);
+    let device_laps = splits::device_laps(recording);
+    let activity_polylines = polyline::polylines(&recording.samples);
+    let day = summary.start_time.naive_utc().date();
+    // Zones and training stress are based on the values in effect on the
+    // day of the activity
+    let history = profiles::history(user_id, conn).map_err(|e| e.to_string())?;
+    let physiology = history.physiology_on(&day);
+    let profile = profiles::get(user_id, conn).map_err(|e| e.to_string())?;
+
+    let mut histograms = Vec::new();
+    for kind in zones::kinds(sport) {
+        if !recording.samples.iter().any(|s| zones::value(kind, s).is_some()) {
+            continue;
+        }
+        let (user_zones, _) = zones::effective(user_id, sport, kind, &physiology, conn)
+            .map_err(|e| e.to_string())?;
+        if !user_zones.is_empty() {
+            histograms.push(zones::histogram(kind, &user_zones, &recording.samples));
+        }
+    }
+    let activity_load = load::activity_load(&summary,
+                                            &recording.samples,
+                                            &physiology,
+                                            profile.sex.as_ref().map(|s| s.as_str()));
+
+    let activity_curves = mean_max::mean_max_curves(&recording.samples);
+    let efforts = if sport == RUNNING {
+        mean_max::best_efforts(&recording.samples)
+    } else {
+        Vec::new()
+    };
+    // Earlier curves of this activity are removed first so records are
+    // found against the user's other activities
+    if !curves::delete_activity(activity_id, conn) {
+        return Err("deleting curves".to_string());
+    }
+    let all_time = Range { sport: Some(sport), from: None, to: None };
+    let best_power = curves::best_curve(user_id, mean_max::POWER, &all_time, conn)
+        .map_err(|e| e.to_string())?;
+    let best_times = curves::best_efforts(user_id, &all_time, conn).map_err(|e| e.to_string())?;
+    let records = mean_max::records(&activity_curves, &efforts, &best_power, &best_times);
+
+    if !summaries::create(&summary, conn) {
+        return Err("storing summary".to_string());
+    }
+    if !tracks::set(&track, conn) {
+        return Err("storing track metrics".to_string());
+    }
+    if !polylines::set(activity_id, user_id, &activity_polylines, conn) {
+        return Err("storing polylines".to_string());
+    }
+    if !laps::set(activity_id, user_id, &device_laps, conn) {
+        return Err("storing laps".to_string());
+    }
+    if !training_zones::set_activity_times(activity_id, user_id, &histograms, conn) {
+        return Err("storing time in zones".to_string());
+    }
+    let stored = match activity_load {
+        Some(ref l) => training_load::set_activity_load(l, conn),
+        None => training_load::delete_activity_load(activity_id, conn),
+    };
+    if !stored {
+        return Err("storing training load".to_string());
+    }
+    if !curves::set(activity_id, user_id, sport, &day, &activity_curves, &efforts, conn) {
+        return Err("storing curves".to_string());
+    }
+    let segment_efforts = match_segments(activity_id, user_id, sport, recording, conn)?;
+    load::update_from(user_id, &day, conn)?;
+    Ok(Analysis {
+        summary: summary,
+        track: Some(track),
+        polylines: activity_polylines,
+        laps: device_laps,
+        zones: histograms,
+        load: activity_load,
+        curves: activity_curves,
+        best_efforts: efforts,
+        segment_efforts: segment_efforts,
+        records: records,
+    })
+}
+
+// Match a cleaned recording against the segments of its sport near its
+// track and store the efforts found
+pub fn match_segments(activity_id: &Uuid,
+                      user_id: &Uuid,
+                      sport: &str,
+                      recording: &Recording,
+                      conn: &PlatformConnection) -> Result<Vec<SegmentEffort>, String> {
+    let cells = matching::track_cells(recording);
+    let nearby = if cells.is_empty() {
+        Vec::new()
+    } else {
+        segments::starting_in(&cells, sport, conn).map_err(|e| e.to_string())?
+    };
+    let efforts = matching::efforts(activity_id, user_id, recording, &nearby);
+    if !segments::set_activity_efforts(activity_id, &efforts, conn) {
+        return Err("storing segment efforts".to_string());
+    }
+    Ok(efforts)
+}
+
+// Decode the stored activity file
+pub fn read(user_id: &Uuid, file_dir: &str, filename: &str) -> Result<Recording, String> {
+    let path = file::activity_path(file_dir, user_id, filename);
+    let mut buffer = Vec::new();
+    File::open(&path)
+        .and_then(|mut f| f.read_to_end(&mut buffer))
+        .map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    fit::decode(&buffer).map_err(|e| e.to_string())
+}
+
+// Decode the stored activity file and analyse it again
+pub fn reprocess(activity_id: &Uuid,
+                 user_id: &Uuid,
+                 requested_sport: Option<&str>,
+                 file_dir: &str,
+                 filename: &str,
+                 config: &TrackConfig,
+                 conn: &PlatformConnection) -> Result<Analysis, String> {
+    let recording = read(user_id, file_dir, filename)?;
+    let sport = analysis::sport(requested_sport, &recording);
+    process(activity_id, user_id, &sport, &recording, config, conn)
+}
+
+// Analyse the user's activities on or after from again in a background
+// thread, after a physiological value taking effect on from was changed
+// or removed. Zones, training stress and training load then use the
+// values in effect on the day of each activity.
+pub fn spawn_reprocess(user_id: Uuid,
+                       from: NaiveDate,
+                       pool: Pool,
+                       file_dir: String,
+                       config: TrackConfig) {
+    thread::spawn(move || {
+        let conn = match pool.get() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Error connecting to database to reanalyse activities of {}: {}",
+                          user_id, e);
+                return;
+            }
+        };
+        if let Err(e) = reprocess_from(&user_id, &from, &file_dir, &config, &conn) {
+            eprintln!("Error reanalysing activities of {}: {}", user_id, e);
+        }
+    });
+}
+
+// Activities are analysed oldest first. An activity that fails is logged
+// and the rest are still analysed.
+fn reprocess_from(user_id: &Uuid,
+                  from: &NaiveDate,
+                  file_dir: &str,
+                  config: &TrackConfig,
+                  conn: &PlatformConnection) -> Result<(), String> {
+    let mut affected: Vec<Summary> = summaries::get_by_user_id(user_id, conn)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter(|s| s.start_time.naive_utc().date() >= *from)
+        .collect();
+    affected.sort_by_key(|s| s.start_time);
+    let user_activities = activities::get_by_user_id(user_id, conn).map_err(|e| e.to_string())?;
+    for summary in affected {
+        let activity = match user_activities.iter().find(|a| a.id == summary.activity_id) {
+            Some(a) => a,
+            None => continue,
+        };
+        let requested = activity.activity_type.as_ref().map(|s| s.as_str());
+        if let Err(e) = reprocess(&activity.id,
+                                  user_id,
+                                  requested,
+                                  file_dir,
+                                  &activity.filename,
+                                  config,
+                                  conn) {
+            eprintln!("Error processing activity {}: {}", activity.id, e);
+        }
+    }
+    Ok(())
+}
+
+// Remove the analysis of a deleted activity and update the training load
+// of the days after it
+pub fn remove(activity_id: &Uuid, user_id: &Uuid, conn: &PlatformConnection) -> Result<(), String> {
+    let summary = match summaries::get(activity_id, user_id, conn) {
+        Ok(s) => s,
+        // Activities that were never analysed have nothing to remove
+        Err(_) => return Ok(()),
+    };
+    if !training_zones::delete_activity_times(activity_id, conn)
+        || !training_load::delete_activity_load(activity_id, conn)
+        || !curves::delete_activity(activity_id, conn)
+        || !laps::delete_activity(activity_id, conn)
+        || !tracks::delete(activity_id, conn)
+        || !polylines::delete_activity(activity_id, conn)
+        || !segments::delete_activity_efforts(activity_id, conn)
+        || !summaries::delete(activity_id, conn) {
+        return Err("deleting analysis".to_string());
+    }
+    load::update_from(user_id, &summary.start_time.naive_utc().date(), conn)
+}
@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use rocket::request::State;
+use rocket::response::status;
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use uuid::Uuid;
+
+use audit::{self, AuditLog, Event};
+use auth::Principal;
+use client::ClientInfo;
+use config::{ServerConfig, TrackConfig};
+use db::Conn;
+use file;
+use fit::Recording;
+use gps;
+use models::activities::{self, ActivitySummary};
+use models::curves::{self, BestEffort, Curve};
+use models::laps::{self, Lap};
+use models::polylines::{self, Polyline};
+use models::privacy;
+use models::segments::{self, SegmentEffort};
+use models::summaries::{self, Summary};
+use models::tracks::{self, Track};
+use models::training_load::{self, ActivityLoad};
+use models::training_zones::{self, ZoneHistogram};
+use polyline;
+use processing;
+use scope::{self, ActivitiesRead, ActivitiesWrite, Scoped};
+use splits::{self, Split};
+use streams::{self, Stream};
+use super::{Response, bad_request, internal_server_error};
+
+#[derive(Serialize)]
+struct ActivityListItem {
+    activity: ActivitySummary,
+    // Activities that could not be analysed have no summary
+    summary: Option<Summary>,
+    // Encoded polyline of the track simplified for map previews
+    summary_polyline: Option<String>,
+    privacy: String,
+}
+
+#[derive(Serialize)]
+struct ActivityDetail {
+    activity: ActivitySummary,
+    summary: Option<Summary>,
+    track: Option<Track>,
+    polyline: Option<Polyline>,
+    start_latlng: Option<(f64, f64)>,
+    privacy: String,
+    zones: Vec<ZoneHistogram>,
+    load: Option<ActivityLoad>,
+    curves: Vec<Curve>,
+    best_efforts: Vec<BestEffort>,
+    segment_efforts: Vec<SegmentEffort>,
+}
+
+// What users other than the owner see of a public activity. Track points
+// inside the owner's privacy zones are removed.
+#[derive(Serialize)]
+struct SharedActivity {
+    id: Uuid,
+    name: Option<String>,
+    activity_type: Option<String>,
+    summary: Option<Summary>,
+    track: Option<Track>,
+    laps: Vec<Lap>,
+    polyline: Option<Polyline>,
+    // Hidden when the activity starts inside a privacy zone
+    start_latlng: Option<(f64, f64)>,
+}
+
+// Fields left out are unchanged. Changing activity_type analyses the
+// activity again for the new sport. privacy is private, followers or
+// public.
+#[derive(Deserialize)]
+struct ActivityUpdate {
+    name: Option<String>,
+    activity_type: Option<String>,
+    privacy: Option<String>,
+}
+
+// keys is a comma separated list of streams and defaults to all of them.
+// resolution is low, medium or high and defaults to medium.
+#[derive(FromForm)]
+struct StreamsQuery {
+    keys: Option<String>,
+    resolution: Option<String>,
+}
+
+// Exactly one of unit (km or mile), distance in metres or time in seconds
+#[derive(FromForm)]
+struct SplitsQuery {
+    unit: Option<String>,
+    distance: Option<f64>,
+    time: Option<f64>,
+}
+
+// Shortest splits allowed, so a request cannot produce a split per sample
+const MIN_SPLIT_DISTANCE: f64 = 100.0;
+const MIN_SPLIT_TIME: f64 = 10.0;
+
+#[derive(Serialize)]
+struct Streams {
+    resolution: String,
+    // Keys the activity has no samples for are left out
+    streams: HashMap<String, Stream>,
+}
+
+#[get("/<id>/activities")]
+fn list(_auth: Scoped<ActivitiesRead>,
+        id: UUID,
+        db: Conn) -> status::Custom<Json<Value>> {
+    let user_activities = match activities::get_by_user_id(&id, &db) {
+        Ok(a) => a,
+        Err(_) => return internal_server_error(),
+    };
+    let mut user_summaries: HashMap<_, _> = match summaries::get_by_user_id(&id, &db) {
+        Ok(s) => s.into_iter().map(|s| (s.activity_id, s)).collect(),
+        Err(_) => return internal_server_error(),
+    };
+    let mut user_polylines = match polylines::get_by_user_id(&id, polyline::SUMMARY, &db) {
+        Ok(p) => p,
+        Err(_) => return internal_server_error(),
+    };
+    let mut levels = match privacy::get_levels(&id, &db) {
+        Ok(l) => l,
+        Err(_) => return internal_server_error(),
+    };
+    let items: Vec<ActivityListItem> = user_activities.into_iter().map(|activity| {
+        ActivityListItem {
+            summary: user_summaries.remove(&activity.id),
+            summary_polyline: user_polylines.remove(&activity.id),
+            privacy: levels.remove(&activity.id).unwrap_or_else(|| privacy::PRIVATE.to_string()),
+            activity: activity,
+        }
+    }).collect();
+    status::Custom(
+        Status::Ok,
+        Json(json!(items))
+    )
+}
+
+#[get("/<id>/activities/<activity_id>")]
+fn view(_auth: Scoped<ActivitiesRead>,
+        id: UUID,
+        activity_id: UUID,
+        db: Conn) -> status::Custom<Json<Value>> {
+    let activity = match activities::get(&activity_id, &id, &db) {
+        Ok(a) => a,
+        Err(_) => return not_found(),
+    };
+    detail(&id, activity, &db)
+}
+
+#[put("/<id>/activities/<activity_id>", format = "application/json", data = "<message>")]
+fn update(_auth: Scoped<ActivitiesWrite>,
+          id: UUID,
+          activity_id: UUID,
+          message: Json<ActivityUpdate>,
+          db: Conn,
+          conf: State<ServerConfig>,
+          track_conf: State<TrackConfig>) -> status::Custom<Json<Value>> {
+    let mut activity = match activities::get(&activity_id, &id, &db) {
+        Ok(a) => a,
+        Err(_) => return not_found(),
+    };
+    let message = message.into_inner();
+    if let Some(ref level) = message.privacy {
+        if !privacy::LEVELS.iter().any(|l| *l == *level) {
+            return bad_request("privacy must be private, followers or public");
+        }
+        if !privacy::set_level(&activity_id, &id, level, &db) {
+            return internal_server_error();
+        }
+    }
+    let sport_changed = message.activity_type.is_some()
+        && message.activity_type != activity.activity_type;
+    if message.name.is_some() {
+        activity.name = message.name;
+    }
+    if message.activity_type.is_some() {
+        activity.activity_type = message.activity_type;
+    }
+    if !activities::update(&activity_id, &id, &activity.name, &activity.activity_type, &db) {
+        return internal_server_error();
+    }
+    if sport_changed {
+        let requested = activity.activity_type.as_ref().map(|s| s.as_str());
+        if let Err(e) = processing::reprocess(&activity_id,
+                                              &id,
+                                              requested,
+                                              &conf.file_dir,
+                                              &activity.filename,
+                                              &track_conf,
+                                              &db) {
+            eprintln!("Error processing activity {}: {}", activity_id, e);
+            return internal_server_error();
+        }
+    }
+    detail(&id, activity, &db)
+}
+
+// Delete the activity, its file and its analysis. The training load of
+// the following days is recomputed.
+#[delete("/<id>/activities/<activity_id>")]
+fn delete(_auth: Scoped<ActivitiesWrite>,
+          id: UUID,
+          activity_id: UUID,
+          db: Conn,
+          client: ClientInfo,
+          audit_log: State<AuditLog>,
+          conf: State<ServerConfig>) -> status::Custom<Json<Value>> {
+    let activity = match activities::get(&activity_id, &id, &db) {
+        Ok(a) => a,
+        Err(_) => return not_found(),
+    };
+    if let Err(e) = processing::remove(&activity_id, &id, &db) {
+        eprintln!("Error removing analysis of activity {}: {}", activity_id, e);
+        return internal_server_error();
+    }
+    match fs::remove_file(file::activity_path(&conf.file_dir, &id, &activity.filename)) {
+        Ok(_) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(_) => return internal_server_error(),
+    }
+    if !privacy::delete_level(&activity_id, &db) || !activities::delete(&activity_id, &id, &db) {
+        return internal_server_error();
+    }
+    audit_log.record(Event::success(audit::ACTIVITY_DELETED, &client)
+                         .user(&id)
+                         .details(activity.filename),
+                     &db);
+    status::Custom(
+        Status::Ok,
+        Json(json!(Response::new("success", "activity deleted")))
+    )
+}
+
+// Sample streams of the activity, read from the stored activity file and
+// cleaned up as when it was analysed
+#[get("/<id>/activities/<activity_id>/streams?<query>")]
+fn streams(_auth: Scoped<ActivitiesRead>,
+           id: UUID,
+           activity_id: UUID,
+           query: StreamsQuery,
+           db: Conn,
+           conf: State<ServerConfig>,
+           track_conf: State<TrackConfig>) -> status::Custom<Json<Value>> {
+    let keys: Vec<String> = match query.keys {
+        Some(ref keys) => keys.split(',').map(|k| k.trim().to_string()).collect(),
+        None => streams::KEYS.iter().map(|k| k.to_string()).collect(),
+    };
+    if let Some(k) = keys.iter().find(|k| !streams::KEYS.iter().any(|s| *s == k.as_str())) {
+        return bad_request(&format!("unknown stream {}", k));
+    }
+    let resolution = query.resolution.unwrap_or_else(|| "medium".to_string());
+    let max_points = match streams::max_points(&resolution) {
+        Some(m) => m,
+        None => return bad_request("resolution must be low, medium or high"),
+    };
+    let activity = match activities::get(&activity_id, &id, &db) {
+        Ok(a) => a,
+        Err(_) => return not_found(),
+    };
+    let recording = match read_cleaned(&id, &activity, &conf, &track_conf) {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    let mut result = HashMap::new();
+    for key in keys {
+        if let Some(stream) = streams::stream(&key, &recording.samples, max_points) {
+            result.insert(key, stream);
+        }
+    }
+    status::Custom(
+        Status::Ok,
+        Json(json!(Streams { resolution: resolution, streams: result }))
+    )
+}
+
+#[get("/<id>/activities/<activity_id>/streams", rank = 2)]
+fn default_streams(auth: Scoped<ActivitiesRead>,
+                   id: UUID,
+                   activity_id: UUID,
+                   db: Conn,
+                   conf: State<ServerConfig>,
+                   track_conf: State<TrackConfig>) -> status::Custom<Json<Value>> {
+    let query = StreamsQuery { keys: None, resolution: None };
+    streams(auth, id, activity_id, query, db, conf, track_conf)
+}
+
+// Laps recorded by the device
+#[get("/<id>/activities/<activity_id>/laps")]
+fn laps(_auth: Scoped<ActivitiesRead>,
+        id: UUID,
+        activity_id: UUID,
+        db: Conn) -> status::Custom<Json<Value>> {
+    if activities::get(&activity_id, &id, &db).is_err() {
+        return not_found();
+    }
+    match laps::get_by_activity_id(&activity_id, &db) {
+        Ok(l) => status::Custom(
+            Status::Ok,
+            Json(json!(l))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+// Automatic splits, computed from the stored activity file
+#[get("/<id>/activities/<activity_id>/splits?<query>")]
+fn splits(_auth: Scoped<ActivitiesRead>,
+          id: UUID,
+          activity_id: UUID,
+          query: SplitsQuery,
+          db: Conn,
+          conf: State<ServerConfig>,
+          track_conf: State<TrackConfig>) -> status::Custom<Json<Value>> {
+    let split = match (query.unit, query.distance, query.time) {
+        (Some(unit), None, None) => match unit.as_str() {
+            "km" => Split::Distance(splits::KILOMETRE),
+            "mile" => Split::Distance(splits::MILE),
+            _ => return bad_request("unit must be km or mile"),
+        },
+        (None, Some(distance), None) => {
+            if !(distance >= MIN_SPLIT_DISTANCE) {
+                return bad_request("distance must be at least 100 metres");
+            }
+            Split::Distance(distance)
+        },
+        (None, None, Some(time)) => {
+            if !(time >= MIN_SPLIT_TIME) {
+                return bad_request("time must be at least 10 seconds");
+            }
+            Split::Time(time)
+        },
+        _ => return bad_request("give one of unit, distance or time"),
+    };
+    let activity = match activities::get(&activity_id, &id, &db) {
+        Ok(a) => a,
+        Err(_) => return not_found(),
+    };
+    let recording = match read_cleaned(&id, &activity, &conf, &track_conf) {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    status::Custom(
+        Status::Ok,
+        Json(json!(splits::splits(&recording.samples, &split)))
+    )
+}
+
+#[get("/<id>/activities/<activity_id>/splits", rank = 2)]
+fn km_splits(auth: Scoped<ActivitiesRead>,
+             id: UUID,
+             activity_id: UUID,
+             db: Conn,
+             conf: State<ServerConfig>,
+             track_conf: State<TrackConfig>) -> status::Custom<Json<Value>> {
+    let query = SplitsQuery { unit: Some("km".to_string()), distance: None, time: None };
+    splits(auth, id, activity_id, query, db, conf, track_conf)
+}
+
+// Samples of the activity file after the same cleanup as when the
+// activity was analysed
+fn read_cleaned(id: &UUID,
+                activity: &ActivitySummary,
+                conf: &ServerConfig,
+                track_conf: &TrackConfig) -> Result<Recording, status::Custom<Json<Value>>> {
+    match processing::read(id, &conf.file_dir, &activity.filename) {
+        Ok(r) => Ok(gps::clean(&r, track_conf).recording),
+        Err(e) => {
+            eprintln!("Error reading activity {}: {}", activity.id, e);
+            Err(internal_server_error())
+        }
+    }
+}
+
+// An activity read by id alone. The owner sees the full activity, and
+// other users and requests without credentials see public activities
+// with their privacy zones removed. hapi does not keep followers yet, so
+// activities shared with followers are only shown to their owner.
+#[get("/<activity_id>")]
+fn shared(principal: Option<Principal>,
+          activity_id: UUID,
+          db: Conn) -> status::Custom<Json<Value>> {
+    let owner = match activities::owner(&activity_id, &db) {
+        Ok(o) => o,
+        Err(_) => return not_found(),
+    };
+    let activity = match activities::get(&activity_id, &owner, &db) {
+        Ok(a) => a,
+        Err(_) => return not_found(),
+    };
+    if let Some(ref p) = principal {
+        if p.user_id == owner && p.allows(scope::ACTIVITIES_READ) {
+            return detail(&owner, activity, &db);
+        }
+    }
+    match privacy::get_level(&activity_id, &db) {
+        Ok(ref level) if level == privacy::PUBLIC => {},
+        Ok(_) => return not_found(),
+        Err(_) => return internal_server_error(),
+    }
+    let zones = match privacy::get_zones(&owner, &db) {
+        Ok(z) => z,
+        Err(_) => return internal_server_error(),
+    };
+    let activity_laps = match laps::get_by_activity_id(&activity_id, &db) {
+        Ok(l) => l,
+        Err(_) => return internal_server_error(),
+    };
+    let detailed = polylines::get(&activity_id, polyline::DETAILED, &db).ok();
+    let start = detailed.as_ref()
+        .and_then(|p| start_latlng(p))
+        .and_then(|s| if zones.iter().any(|z| z.contains(s)) { None } else { Some(s) });
+    status::Custom(
+        Status::Ok,
+        Json(json!(SharedActivity {
+            id: activity.id,
+            name: activity.name,
+            activity_type: activity.activity_type,
+            summary: summaries::get(&activity_id, &owner, &db).ok(),
+            track: tracks::get(&activity_id, &db).ok(),
+            laps: activity_laps,
+            polyline: detailed.map(|p| polyline::trim(&p, &zones)),
+            start_latlng: start,
+        }))
+    )
+}
+
+fn start_latlng(polyline: &Polyline) -> Option<(f64, f64)> {
+    polyline::decode(&polyline.polyline).first().cloned()
+}
+
+fn detail(id: &Uuid, activity: ActivitySummary, db: &Conn) -> status::Custom<Json<Value>> {
+    let zones = match training_zones::get_activity_times(&activity.id, db) {
+        Ok(z) => z,
+        Err(_) => return internal_server_error(),
+    };
+    let activity_curves = match curves::get_activity_curves(&activity.id, db) {
+        Ok(c) => c,
+        Err(_) => return internal_server_error(),
+    };
+    let efforts = match curves::get_activity_efforts(&activity.id, db) {
+        Ok(e) => e,
+        Err(_) => return internal_server_error(),
+    };
+    let segment_efforts = match segments::get_activity_efforts(&activity.id, db) {
+        Ok(e) => e,
+        Err(_) => return internal_server_error(),
+    };
+    let level = match privacy::get_level(&activity.id, db) {
+        Ok(l) => l,
+        Err(_) => return internal_server_error(),
+    };
+    let detailed = polylines::get(&activity.id, polyline::DETAILED, db).ok();
+    status::Custom(
+        Status::Ok,
+        Json(json!(ActivityDetail {
+            summary: summaries::get(&activity.id, id, db).ok(),
+            track: tracks::get(&activity.id, db).ok(),
+            start_latlng: detailed.as_ref().and_then(|p| start_latlng(p)),
+            polyline: detailed,
+            privacy: level,
+            load: training_load::get_activity_load(&activity.id, db).ok(),
+            activity: activity,
+            zones: zones,
+            curves: activity_curves,
+            best_efforts: efforts,
+            segment_efforts: segment_efforts,
+        }))
+    )
+}
+
+pub fn not_found() -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::NotFound,
+        Json(json!(Response::new("error", "activity not found")))
+    )
+}
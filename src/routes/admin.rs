@@ -0,0 +1,376 @@
+use std::fs;
+use std::path::Path;
+
+use rocket::request::State;
+use rocket::response::status;
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use uuid::Uuid;
+
+use hdb::platform::models::users as platform_users;
+
+use audit::{self, AuditLog, Event};
+use config::ServerConfig;
+use db::Conn;
+use file::{self, Usage};
+use models::admin_actions::{self, NewAdminAction};
+use models::audit::{self as audit_events, Filter};
+use models::{deletions, emails, passwords, roles, sessions, totp, users};
+use scope::Admin;
+use super::{Response, bad_request, internal_server_error};
+
+// Default and maximum number of results returned by list routes
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 500;
+
+#[derive(FromForm)]
+struct SearchQuery {
+    q: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(FromForm)]
+struct PageQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(FromForm)]
+struct AuditQuery {
+    user_id: Option<String>,
+    event: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct UserDetails {
+    id: Uuid,
+    username: String,
+    active: bool,
+    email: Option<emails::UserEmail>,
+    roles: Vec<String>,
+    two_factor_enabled: bool,
+    password_reset_required: bool,
+    activities: i64,
+    storage: Usage,
+}
+
+#[derive(Serialize)]
+struct UserStorage {
+    user_id: Uuid,
+    username: Option<String>,
+    bytes: u64,
+    files: u64,
+}
+
+#[derive(Serialize)]
+struct StorageOverview {
+    bytes: u64,
+    files: u64,
+    // Users ordered by bytes used, largest first
+    users: Vec<UserStorage>,
+}
+
+// Search users by username or email address
+#[get("/users?<query>")]
+fn search(admin: Admin,
+          query: SearchQuery,
+          db: Conn,
+          audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    let q = query.q.unwrap_or_default();
+    let (limit, offset) = page(query.limit, query.offset);
+    record(&admin, "search_users", None, Some(format!("q={}", q)), &db, &audit_log);
+    match users::search(q.trim(), limit, offset, &db) {
+        Ok(results) => status::Custom(
+            Status::Ok,
+            Json(json!(results))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[get("/users", rank = 2)]
+fn list(admin: Admin,
+        db: Conn,
+        audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    search(admin, SearchQuery { q: None, limit: None, offset: None }, db, audit_log)
+}
+
+#[get("/users/<id>")]
+fn view(admin: Admin,
+        id: UUID,
+        db: Conn,
+        conf: State<ServerConfig>,
+        audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    let user = match users::get(&id, &db) {
+        Ok(u) => u,
+        Err(_) => return not_found(),
+    };
+    record(&admin, "view_user", Some(*id), None, &db, &audit_log);
+    let user_roles = match roles::get_by_user_id(&id, &db) {
+        Ok(r) => r,
+        Err(_) => return internal_server_error(),
+    };
+    let activities = match users::activity_count(&id, &db) {
+        Ok(n) => n,
+        Err(_) => return internal_server_error(),
+    };
+    let storage = match file::usage(&Path::new(&conf.file_dir).join(id.to_string())) {
+        Ok(u) => u,
+        Err(_) => return internal_server_error(),
+    };
+    status::Custom(
+        Status::Ok,
+        Json(json!(UserDetails {
+            id: user.id,
+            username: user.username,
+            active: user.active,
+            email: emails::get_by_user_id(&id, &db).ok(),
+            roles: user_roles,
+            two_factor_enabled: totp::is_enabled(&id, &db),
+            password_reset_required: passwords::reset_required(&id, &db),
+            activities: activities,
+            storage: storage,
+        }))
+    )
+}
+
+// Deactivating a user also revokes their sessions and API keys
+#[post("/users/<id>/deactivate")]
+fn deactivate(admin: Admin,
+              id: UUID,
+              db: Conn,
+              audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    if *id == admin.user_id {
+        return bad_request("administrators cannot deactivate their own account");
+    }
+    if users::get(&id, &db).is_err() {
+        return not_found();
+    }
+    if !platform_users::inactivate(&id, &db) || !sessions::revoke_credentials(&id, &db) {
+        return internal_server_error();
+    }
+    record(&admin, "deactivate_user", Some(*id), None, &db, &audit_log);
+    ok("user deactivated")
+}
+
+// Reactivating an account also cancels a pending deletion so the account
+// is not purged while in use
+#[post("/users/<id>/reactivate")]
+fn reactivate(admin: Admin,
+              id: UUID,
+              db: Conn,
+              audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    if !users::set_active(&id, true, &db) {
+        return not_found();
+    }
+    if deletions::cancel(&id, &db) {
+        audit_log.record(Event::success(audit::ACCOUNT_DELETION_CANCELLED, &admin.client)
+                             .user(&id)
+                             .details(format!("cancelled by administrator {}", admin.user_id)),
+                         &db);
+        record(&admin,
+               "reactivate_user",
+               Some(*id),
+               Some("pending deletion cancelled".to_string()),
+               &db,
+               &audit_log);
+    } else {
+        record(&admin, "reactivate_user", Some(*id), None, &db, &audit_log);
+    }
+    ok("user reactivated")
+}
+
+// Require the user to set a new password on their next login. Existing
+// sessions are revoked so the user must login again.
+#[post("/users/<id>/password-reset")]
+fn force_password_reset(admin: Admin,
+                        id: UUID,
+                        db: Conn,
+                        audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    if users::get(&id, &db).is_err() {
+        return not_found();
+    }
+    if !passwords::require_reset(&id, &db) || !sessions::revoke_credentials(&id, &db) {
+        return internal_server_error();
+    }
+    record(&admin, "force_password_reset", Some(*id), None, &db, &audit_log);
+    ok("password reset required")
+}
+
+// Revoke all access tokens and API keys issued to the user
+#[delete("/users/<id>/sessions")]
+fn revoke_user_sessions(admin: Admin,
+                        id: UUID,
+                        db: Conn,
+                        audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    if users::get(&id, &db).is_err() {
+        return not_found();
+    }
+    if !sessions::revoke_credentials(&id, &db) {
+        return internal_server_error();
+    }
+    record(&admin, "revoke_sessions", Some(*id), None, &db, &audit_log);
+    ok("sessions revoked")
+}
+
+// Disk space used by each user's activity files
+#[get("/storage")]
+fn storage(admin: Admin,
+           db: Conn,
+           conf: State<ServerConfig>,
+           audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    let entries = match fs::read_dir(&conf.file_dir) {
+        Ok(e) => e,
+        Err(_) => return internal_server_error(),
+    };
+    let mut overview = StorageOverview {
+        bytes: 0,
+        files: 0,
+        users: Vec::new(),
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(_) => return internal_server_error(),
+        };
+        // Activity files are stored in a directory named after the user id
+        let user_id = match entry.file_name().to_str().and_then(|n| Uuid::parse_str(n).ok()) {
+            Some(id) => id,
+            None => continue,
+        };
+        let usage = match file::usage(&entry.path()) {
+            Ok(u) => u,
+            Err(_) => return internal_server_error(),
+        };
+        overview.bytes += usage.bytes;
+        overview.files += usage.files;
+        overview.users.push(UserStorage {
+            user_id: user_id,
+            username: users::get(&user_id, &db).ok().map(|u| u.username),
+            bytes: usage.bytes,
+            files: usage.files,
+        });
+    }
+    overview.users.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+    record(&admin, "view_storage", None, None, &db, &audit_log);
+    status::Custom(
+        Status::Ok,
+        Json(json!(overview))
+    )
+}
+
+// Recorded admin actions, most recent first
+#[get("/actions?<query>")]
+fn actions(admin: Admin,
+           query: PageQuery,
+           db: Conn,
+           audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    let (limit, offset) = page(query.limit, query.offset);
+    record(&admin, "view_actions", None, None, &db, &audit_log);
+    match admin_actions::recent(limit, offset, &db) {
+        Ok(results) => status::Custom(
+            Status::Ok,
+            Json(json!(results))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[get("/actions", rank = 2)]
+fn recent_actions(admin: Admin,
+                  db: Conn,
+                  audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    actions(admin, PageQuery { limit: None, offset: None }, db, audit_log)
+}
+
+// Audit events for all users, most recent first. Filter by user with
+// user_id and by event type with event.
+#[get("/audit?<query>")]
+fn events(admin: Admin,
+          query: AuditQuery,
+          db: Conn,
+          audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    let user_id = match query.user_id {
+        Some(ref id) => match Uuid::parse_str(id) {
+            Ok(id) => Some(id),
+            Err(_) => return bad_request("user_id is not a valid id"),
+        },
+        None => None,
+    };
+    let (limit, offset) = page(query.limit, query.offset);
+    record(&admin, "view_audit", user_id, query.event.clone(), &db, &audit_log);
+    let filter = Filter {
+        user_id: user_id,
+        event: query.event,
+        limit: limit,
+        offset: offset,
+    };
+    match audit_events::query(&filter, &db) {
+        Ok(results) => status::Custom(
+            Status::Ok,
+            Json(json!(results))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[get("/audit", rank = 2)]
+fn recent_events(admin: Admin,
+                 db: Conn,
+                 audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    let query = AuditQuery { user_id: None, event: None, limit: None, offset: None };
+    events(admin, query, db, audit_log)
+}
+
+// Admin actions are recorded with the other admin actions and in the
+// audit log
+fn record(admin: &Admin,
+          action: &str,
+          target_user_id: Option<Uuid>,
+          details: Option<String>,
+          db: &Conn,
+          audit_log: &AuditLog) {
+    let mut event = Event::success(audit::ADMIN_ACTION, &admin.client).user(&admin.user_id);
+    event = match target_user_id {
+        Some(target) => event.details(format!("{} {}", action, target)),
+        None => event.details(action),
+    };
+    audit_log.record(event, db);
+    let recorded = admin_actions::record(NewAdminAction {
+        admin_id: Some(admin.user_id),
+        action: action,
+        target_user_id: target_user_id,
+        details: details,
+    }, db);
+    if !recorded {
+        eprintln!("Error recording admin action {} by {}", action, admin.user_id);
+    }
+}
+
+// Limit and offset for list routes, falling back to the defaults when out
+// of range
+pub fn page(limit: Option<i64>, offset: Option<i64>) -> (i64, i64) {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT);
+    let limit = if limit < 1 || limit > MAX_LIMIT { DEFAULT_LIMIT } else { limit };
+    let offset = offset.unwrap_or(0);
+    (limit, if offset < 0 { 0 } else { offset })
+}
+
+fn ok(reason: &str) -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::Ok,
+        Json(json!(Response::new("ok", reason)))
+    )
+}
+
+fn not_found() -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::NotFound,
+        Json(json!(Response::new("error", "user not found")))
+    )
+}
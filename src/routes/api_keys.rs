@@ -0,0 +1,144 @@
+use rocket::request::State;
+use rocket::response::status;
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use chrono::{DateTime, Duration, Utc};
+use uuid::Uuid;
+
+use audit::{self, AuditLog, Event};
+use auth::{self, ApiKey, API_KEY_DISPLAY_LENGTH};
+use client::ClientInfo;
+use db::Conn;
+use models::api_keys::{self, NewApiKey};
+use scope::{self, AccountSecurity, Scoped};
+use super::{Response, bad_request, internal_server_error};
+
+// Keys can be created to expire in up to ten years
+const MAX_EXPIRES_IN_DAYS: i64 = 3650;
+
+#[derive(Deserialize)]
+struct ApiKeyRequest {
+    name: String,
+    // Number of days until the key expires. Keys without an expiry are
+    // valid until revoked.
+    expires_in_days: Option<i64>,
+    // Scopes granted to the key. Defaults to reading and importing
+    // activities.
+    scopes: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct CreatedApiKey {
+    id: Uuid,
+    name: String,
+    prefix: String,
+    scopes: Vec<String>,
+    created_on: DateTime<Utc>,
+    expires_on: Option<DateTime<Utc>>,
+    // Only returned when the key is created
+    key: String,
+}
+
+// API keys are managed with access tokens only. Keys are never granted
+// the account:security scope, so a leaked key cannot be used to create
+// further keys.
+
+#[post("/<id>/api-keys", format="application/json", data="<message>")]
+fn create(_auth: Scoped<AccountSecurity>,
+          id: UUID,
+          message: Json<ApiKeyRequest>,
+          db: Conn,
+          client: ClientInfo,
+          audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    let name = message.0.name.trim().to_string();
+    if name.is_empty() {
+        return bad_request("name is required");
+    }
+    let expires_on = match message.0.expires_in_days {
+        Some(days) if days < 1 || days > MAX_EXPIRES_IN_DAYS => {
+            return bad_request(&format!("expires_in_days must be between 1 and {}",
+                                        MAX_EXPIRES_IN_DAYS));
+        },
+        Some(days) => Some(Utc::now() + Duration::days(days)),
+        None => None,
+    };
+    let scopes = match message.0.scopes {
+        Some(ref scopes) if scopes.is_empty() => return bad_request("scopes must not be empty"),
+        Some(ref scopes) => {
+            if scopes.iter().any(|s| !scope::is_known(s) || s == scope::ACCOUNT_SECURITY) {
+                return bad_request("scopes contains an unknown or disallowed scope");
+            }
+            scopes.clone()
+        },
+        None => scope::API_KEY_DEFAULT.iter().map(|s| s.to_string()).collect(),
+    };
+    let key = ApiKey::new();
+    let new_key = NewApiKey {
+        user_id: id.into_inner(),
+        name: name,
+        prefix: key[..API_KEY_DISPLAY_LENGTH].to_string(),
+        key_hash: auth::hash_token(&key),
+        scopes: scopes,
+        expires_on: expires_on,
+    };
+    match api_keys::create(new_key, &db) {
+        Ok(api_key) => {
+            audit_log.record(Event::success(audit::API_KEY_CREATED, &client)
+                                 .user(&id)
+                                 .details(format!("{} {}", api_key.id, api_key.prefix)),
+                             &db);
+            status::Custom(
+                Status::Created,
+                Json(json!(CreatedApiKey {
+                    id: api_key.id,
+                    name: api_key.name,
+                    prefix: api_key.prefix,
+                    scopes: api_key.scopes,
+                    created_on: api_key.created_on,
+                    expires_on: api_key.expires_on,
+                    key: key,
+                }))
+            )
+        },
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[get("/<id>/api-keys")]
+fn list(_auth: Scoped<AccountSecurity>,
+        id: UUID,
+        db: Conn) -> status::Custom<Json<Value>> {
+    match api_keys::get_by_user_id(&id, &db) {
+        Ok(user_keys) => status::Custom(
+            Status::Ok,
+            Json(json!(user_keys))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[delete("/<id>/api-keys/<key_id>")]
+fn revoke(_auth: Scoped<AccountSecurity>,
+          id: UUID,
+          key_id: UUID,
+          db: Conn,
+          client: ClientInfo,
+          audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    if api_keys::revoke(&key_id, &id, &db) {
+        audit_log.record(Event::success(audit::API_KEY_REVOKED, &client)
+                             .user(&id)
+                             .details(key_id.to_string()),
+                         &db);
+        status::Custom(
+            Status::Ok,
+            Json(json!(Response::new("ok", "api key revoked")))
+        )
+    } else {
+        status::Custom(
+            Status::NotFound,
+            Json(json!(Response::new("error", "api key not found")))
+        )
+    }
+}
@@ -0,0 +1,47 @@
+use rocket::response::status;
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use db::Conn;
+use models::audit::{self, Filter};
+use scope::{AccountSecurity, Scoped};
+use super::admin::page;
+use super::internal_server_error;
+
+#[derive(FromForm)]
+struct EventQuery {
+    event: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+// The user's own audit events, most recent first. Filter by event type
+// with event.
+#[get("/<id>/audit?<query>")]
+fn events(_auth: Scoped<AccountSecurity>,
+          id: UUID,
+          query: EventQuery,
+          db: Conn) -> status::Custom<Json<Value>> {
+    let (limit, offset) = page(query.limit, query.offset);
+    let filter = Filter {
+        user_id: Some(id.into_inner()),
+        event: query.event,
+        limit: limit,
+        offset: offset,
+    };
+    match audit::query(&filter, &db) {
+        Ok(results) => status::Custom(
+            Status::Ok,
+            Json(json!(results))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[get("/<id>/audit", rank = 2)]
+fn recent_events(auth: Scoped<AccountSecurity>,
+                 id: UUID,
+                 db: Conn) -> status::Custom<Json<Value>> {
+    events(auth, id, EventQuery { event: None, limit: None, offset: None }, db)
+}
@@ -0,0 +1,92 @@
+use rocket::response::status;
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use db::Conn;
+use mean_max;
+use models::curves::{self, Range};
+use scope::{ActivitiesRead, Scoped};
+use super::{bad_request, internal_server_error, parse_date};
+
+// Every field is optional. Dates are YYYY-MM-DD and inclusive.
+#[derive(FromForm)]
+struct RangeQuery {
+    from: Option<String>,
+    to: Option<String>,
+    sport: Option<String>,
+}
+
+// The user's best mean-maximal curve for power, heart_rate or speed over
+// the activities in the range
+#[get("/<id>/curves/<kind>?<query>")]
+fn best_curve(_auth: Scoped<ActivitiesRead>,
+              id: UUID,
+              kind: String,
+              query: RangeQuery,
+              db: Conn) -> status::Custom<Json<Value>> {
+    if !mean_max::KINDS.iter().any(|k| *k == kind) {
+        return bad_request("kind must be power, heart_rate or speed");
+    }
+    let range = match range(&query) {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    match curves::best_curve(&id, &kind, &range, &db) {
+        Ok(curve) => status::Custom(
+            Status::Ok,
+            Json(json!(curve))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[get("/<id>/curves/<kind>", rank = 2)]
+fn all_time_curve(auth: Scoped<ActivitiesRead>,
+                  id: UUID,
+                  kind: String,
+                  db: Conn) -> status::Custom<Json<Value>> {
+    best_curve(auth, id, kind, RangeQuery { from: None, to: None, sport: None }, db)
+}
+
+// The user's fastest times over 1k, 5k, 10k, half marathon and marathon
+#[get("/<id>/best-efforts?<query>")]
+fn best_efforts(_auth: Scoped<ActivitiesRead>,
+                id: UUID,
+                query: RangeQuery,
+                db: Conn) -> status::Custom<Json<Value>> {
+    let range = match range(&query) {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    match curves::best_efforts(&id, &range, &db) {
+        Ok(efforts) => status::Custom(
+            Status::Ok,
+            Json(json!(efforts))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[get("/<id>/best-efforts", rank = 2)]
+fn all_time_best_efforts(auth: Scoped<ActivitiesRead>,
+                         id: UUID,
+                         db: Conn) -> status::Custom<Json<Value>> {
+    best_efforts(auth, id, RangeQuery { from: None, to: None, sport: None }, db)
+}
+
+fn range(query: &RangeQuery) -> Result<Range, status::Custom<Json<Value>>> {
+    let from = match query.from {
+        Some(ref from) => Some(parse_date("from", from)?),
+        None => None,
+    };
+    let to = match query.to {
+        Some(ref to) => Some(parse_date("to", to)?),
+        None => None,
+    };
+    Ok(Range {
+        sport: query.sport.as_ref().map(|s| s.as_str()),
+        from: from,
+        to: to,
+    })
+}
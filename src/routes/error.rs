@@ -1,10 +1,117 @@
+use rocket::Request;
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+
 use rocket_contrib::{Json, Value};
 
+use auth::{self, AuthFailure};
 use super::Response;
 
+// Realm sent in WWW-Authenticate challenges
+const REALM: &'static str = "hapi";
+
+// Error response for failed authentication. Carries a bearer
+// WWW-Authenticate challenge with an RFC 6750 error code when the failure
+// was caused by the credentials sent with the request.
+struct AuthError {
+    status: Status,
+    reason: String,
+    challenge: Option<String>,
+}
+
+impl AuthError {
+    fn new<S: Into<String>>(status: Status, reason: S) -> AuthError {
+        AuthError {
+            status: status,
+            reason: reason.into(),
+            challenge: None,
+        }
+    }
+
+    fn challenge(mut self, error: Option<&str>) -> AuthError {
+        let mut challenge = format!("Bearer realm=\"{}\"", REALM);
+        if let Some(error) = error {
+            challenge.push_str(&format!(", error=\"{}\", error_description=\"{}\"",
+                                        error, self.reason));
+        }
+        self.challenge = Some(challenge);
+        self
+    }
+
+    // Scope the request requires, sent with insufficient_scope errors
+    fn scope(mut self, scope: &str) -> AuthError {
+        if let Some(ref mut challenge) = self.challenge {
+            challenge.push_str(&format!(", scope=\"{}\"", scope));
+        }
+        self
+    }
+}
+
+impl<'r> Responder<'r> for AuthError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let body = Json(json!(Response::new("error", self.reason.as_str())));
+        let mut response = response::Response::build_from(body.respond_to(request)?);
+        response.status(self.status);
+        if let Some(challenge) = self.challenge {
+            response.raw_header("WWW-Authenticate", challenge);
+        }
+        response.ok()
+    }
+}
+
+// The catchers describe the failure recorded by the authentication guards
+// that rejected the request. Errors not caused by a guard get a generic
+// response.
+
 #[error(400)]
-fn bad_request() -> Json<Value> {
-    Json(json!(Response::new("error", "The request could not be understood by the server")))
+fn bad_request() -> AuthError {
+    match auth::take_failure() {
+        Some(AuthFailure::Malformed) => {
+            AuthError::new(Status::BadRequest, "malformed or multiple credentials")
+                .challenge(Some("invalid_request"))
+        },
+        _ => AuthError::new(Status::BadRequest,
+                            "The request could not be understood by the server"),
+    }
+}
+
+#[error(401)]
+fn unauthorized() -> AuthError {
+    match auth::take_failure() {
+        Some(AuthFailure::InvalidToken) => {
+            AuthError::new(Status::Unauthorized, "invalid, expired or revoked token")
+                .challenge(Some("invalid_token"))
+        },
+        Some(AuthFailure::WrongUser) => {
+            AuthError::new(Status::Unauthorized, "token was not issued for this user")
+                .challenge(Some("invalid_token"))
+        },
+        _ => AuthError::new(Status::Unauthorized, "unauthorized").challenge(None),
+    }
+}
+
+// Returned when credentials are valid but do not grant the scope a route
+// requires, a request authenticated by the session cookie is missing its
+// CSRF token, or a route requires a login access token or the admin role
+#[error(403)]
+fn forbidden() -> AuthError {
+    match auth::take_failure() {
+        Some(AuthFailure::InsufficientScope(scope)) => {
+            AuthError::new(Status::Forbidden, format!("token does not grant the {} scope", scope))
+                .challenge(Some("insufficient_scope"))
+                .scope(scope)
+        },
+        Some(AuthFailure::Csrf) => {
+            AuthError::new(Status::Forbidden, "missing or invalid CSRF token")
+        },
+        Some(AuthFailure::LoginRequired) => {
+            AuthError::new(Status::Forbidden, "an access token issued by login is required")
+        },
+        Some(AuthFailure::AdminRequired) => {
+            AuthError::new(Status::Forbidden, "admin role required")
+        },
+        _ => AuthError::new(Status::Forbidden, "forbidden"),
+    }
 }
 
 #[error(411)]
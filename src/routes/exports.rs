@@ -0,0 +1,161 @@
+use std::fs::File;
+
+use rocket::Request;
+use rocket::request::State;
+use rocket::response::{self, status, Responder};
+use rocket::http::{ContentType, Status};
+
+use rocket_contrib::{Json, Value, UUID};
+
+use hdb::platform::Pool;
+
+use audit::{self, AuditLog, Event};
+use auth;
+use client::ClientInfo;
+use config::{ExportConfig, ServerConfig};
+use db::Conn;
+use export;
+use mail::Mailer;
+use models::exports::{self, DataExport};
+use scope::{AccountSecurity, Scoped};
+use super::{Response, internal_server_error};
+
+// Length of the token in download links
+const DOWNLOAD_TOKEN_LENGTH: usize = 48;
+
+#[derive(Serialize)]
+struct RequestedExport {
+    export: DataExport,
+    // Only returned when the export is requested. Works once the export is
+    // ready until it expires.
+    download_url: String,
+}
+
+// Zip archive sent as an attachment
+struct Download {
+    file: File,
+    filename: String,
+}
+
+impl<'r> Responder<'r> for Download {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        response::Response::build()
+            .header(ContentType::new("application", "zip"))
+            .raw_header("Content-Disposition",
+                        format!("attachment; filename=\"{}\"", self.filename))
+            .raw_header("Cache-Control", "no-store")
+            .streamed_body(self.file)
+            .ok()
+    }
+}
+
+// Start building an export of everything stored for the user. Only one
+// export can be in progress at a time.
+#[post("/<id>/exports")]
+fn create(_auth: Scoped<AccountSecurity>,
+          id: UUID,
+          db: Conn,
+          client: ClientInfo,
+          audit_log: State<AuditLog>,
+          pool: State<Pool>,
+          conf: State<ServerConfig>,
+          config: State<ExportConfig>,
+          mailer: State<Mailer>) -> status::Custom<Json<Value>> {
+    if exports::has_pending(&id, &export::pending_cutoff(&config), &db) {
+        return status::Custom(
+            Status::Conflict,
+            Json(json!(Response::new("error", "an export is already in progress")))
+        );
+    }
+    let token = auth::random_token(DOWNLOAD_TOKEN_LENGTH);
+    let data_export = match exports::create(&id, &auth::hash_token(&token), &db) {
+        Ok(e) => e,
+        Err(_) => return internal_server_error(),
+    };
+    audit_log.record(Event::success(audit::DATA_EXPORT_REQUESTED, &client)
+                         .user(&id)
+                         .details(data_export.id.to_string()),
+                     &db);
+    let download_url = format!("{}/{}", config.download_url, token);
+    export::start(data_export.id,
+                  id.into_inner(),
+                  token,
+                  pool.inner().clone(),
+                  conf.file_dir.clone(),
+                  config.inner().clone(),
+                  mailer.inner().clone(),
+                  audit_log.inner().clone());
+    status::Custom(
+        Status::Accepted,
+        Json(json!(RequestedExport {
+            export: data_export,
+            download_url: download_url,
+        }))
+    )
+}
+
+#[get("/<id>/exports")]
+fn list(_auth: Scoped<AccountSecurity>,
+        id: UUID,
+        db: Conn) -> status::Custom<Json<Value>> {
+    match exports::get_by_user_id(&id, &db) {
+        Ok(user_exports) => status::Custom(
+            Status::Ok,
+            Json(json!(user_exports))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[get("/<id>/exports/<export_id>")]
+fn view(_auth: Scoped<AccountSecurity>,
+        id: UUID,
+        export_id: UUID,
+        db: Conn) -> status::Custom<Json<Value>> {
+    match exports::get(&export_id, &id, &db) {
+        Ok(data_export) => status::Custom(
+            Status::Ok,
+            Json(json!(data_export))
+        ),
+        Err(_) => not_found(),
+    }
+}
+
+// Download an export with the token from the download link. The token is
+// the only credential required, so links can be opened from email.
+#[get("/<token>")]
+fn download(token: String,
+            db: Conn,
+            client: ClientInfo,
+            audit_log: State<AuditLog>,
+            config: State<ExportConfig>) -> Result<Download, status::Custom<Json<Value>>> {
+    let data_export = match exports::get_by_token_hash(&auth::hash_token(&token), &db) {
+        Ok(e) => e,
+        Err(_) => return Err(not_found()),
+    };
+    if !data_export.is_downloadable() {
+        return Err(status::Custom(
+            Status::Gone,
+            Json(json!(Response::new("error", "export is not ready or has expired")))
+        ));
+    }
+    let file = match File::open(export::archive_path(&config.dir, &data_export.id)) {
+        Ok(f) => f,
+        Err(_) => return Err(not_found()),
+    };
+    audit_log.record(Event::success(audit::DATA_EXPORT_DOWNLOADED, &client)
+                         .user(&data_export.user_id)
+                         .details(data_export.id.to_string()),
+                     &db);
+    Ok(Download {
+        file: file,
+        filename: format!("hapi-export-{}.zip", data_export.id),
+    })
+}
+
+fn not_found() -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::NotFound,
+        Json(json!(Response::new("error", "export not found")))
+    )
+}
@@ -1,5 +1,29 @@
+use rocket::request::{Request, State};
+use rocket::response::{self, status, Responder};
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value};
+
+use chrono::NaiveDate;
+
+use keys::KeySet;
+use policy::FieldError;
+
+pub mod activities;
+pub mod admin;
+pub mod api_keys;
+pub mod audit;
+pub mod curves;
 pub mod error;
+pub mod exports;
+pub mod oauth;
+pub mod privacy;
+pub mod profile;
+pub mod segments;
+pub mod totp;
+pub mod training_load;
 pub mod user;
+pub mod zones;
 
 #[derive(Serialize)]
 struct Response {
@@ -19,7 +43,84 @@ impl Response {
     }
 }
 
+// Returned when submitted fields fail validation, with an error for each
+// rule that was broken
+#[derive(Serialize)]
+struct ValidationErrors {
+    status: String,
+    reason: String,
+    errors: Vec<FieldError>,
+}
+
+// Returned when a client has made too many failed attempts. Tells the
+// client how many seconds to wait with a Retry-After header.
+#[derive(Debug)]
+pub struct TooManyRequests(pub i64);
+
+impl<'r> Responder<'r> for TooManyRequests {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let body = Json(json!(Response::new("error", "too many failed attempts")));
+        response::Response::build_from(body.respond_to(request)?)
+            .status(Status::TooManyRequests)
+            .raw_header("Retry-After", self.0.to_string())
+            .ok()
+    }
+}
+
 #[get("/")]
 pub fn index() -> &'static str {
     "Welcome to hapi"
 }
+
+// Public keys for verifying access tokens issued by hapi
+#[get("/.well-known/jwks.json")]
+pub fn jwks(keys: State<KeySet>) -> Json<Value> {
+    Json(keys.jwks())
+}
+
+// Responses shared by routes
+
+pub fn bad_request(reason: &str) -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::BadRequest,
+        Json(json!(Response::new("error", reason)))
+    )
+}
+
+// Dates in query strings and paths are formatted YYYY-MM-DD
+pub fn parse_date(name: &str, value: &str) -> Result<NaiveDate, status::Custom<Json<Value>>> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| bad_request(&format!("{} must be formatted YYYY-MM-DD", name)))
+}
+
+pub fn validation_failed(errors: Vec<FieldError>) -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::BadRequest,
+        Json(json!(ValidationErrors {
+            status: "error".to_string(),
+            reason: "validation failed".to_string(),
+            errors: errors,
+        }))
+    )
+}
+
+pub fn forbidden(reason: &str) -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::Forbidden,
+        Json(json!(Response::new("error", reason)))
+    )
+}
+
+pub fn unauthorized_token() -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::Unauthorized,
+        Json(json!(Response::new("error", "unauthorized")))
+    )
+}
+
+pub fn internal_server_error() -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::InternalServerError,
+        Json(json!(Response::new("error", "internal server error")))
+    )
+}
@@ -0,0 +1,514 @@
+// OAuth 2.0 authorization code flow with PKCE (RFC 6749, RFC 7636) for
+// third party applications.
+//
+// 1. The application sends the user to its consent screen with the
+//    authorization request parameters. The consent screen fetches the
+//    details to show with GET /oauth/authorize.
+// 2. The user approves or denies the request with POST /oauth/authorize.
+//    The response contains the redirect URI to send the user back to,
+//    with an authorization code when approved.
+// 3. The application exchanges the code and its PKCE code verifier for an
+//    access token with POST /oauth/token.
+//
+// Access tokens issued to applications only carry the scopes the user
+// approved and are checked by the same Scoped guard as other tokens.
+use rocket::request::{Form, Request, State};
+use rocket::response::{self, status, Responder};
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use chrono::{Duration, Utc};
+use ring::{constant_time, digest};
+use base64;
+
+use audit::{self, AuditLog, Event};
+use auth::{self, UserToken, ACCESS_TOKEN_LIFETIME};
+use client::ClientInfo;
+use db::Conn;
+use keys::KeySet;
+use models::oauth::{self, AuthorizationCode, Client, NewClient};
+use models::users;
+use otp;
+use scope::{self, AccountSecurity, Scoped, Session};
+use super::{Response, bad_request, internal_server_error};
+
+// Lifetime of authorization codes in seconds
+const CODE_LIFETIME: i64 = 300;
+// Maximum number of redirect URIs registered for a client
+const MAX_REDIRECT_URIS: usize = 10;
+
+// Parameters of an authorization request. Read from the query string by
+// the consent screen and sent back as JSON, along with the user's
+// decision, to approve or deny the request.
+#[derive(FromForm, Deserialize)]
+struct AuthorizationRequest {
+    response_type: Option<String>,
+    client_id: Option<String>,
+    redirect_uri: Option<String>,
+    scope: Option<String>,
+    state: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    approve: Option<bool>,
+}
+
+#[derive(FromForm)]
+struct TokenRequest {
+    grant_type: Option<String>,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    code_verifier: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClientRequest {
+    name: String,
+    redirect_uris: Vec<String>,
+    // Confidential clients are issued a secret. Public clients, such as
+    // mobile apps, rely on PKCE alone. Defaults to true.
+    confidential: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct CreatedClient {
+    client_id: String,
+    // Only returned when the client is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+    name: String,
+    redirect_uris: Vec<String>,
+}
+
+// Details shown on the consent screen
+#[derive(Serialize)]
+struct Consent {
+    client_id: String,
+    client_name: String,
+    scopes: Vec<String>,
+    redirect_uri: String,
+    state: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Redirect {
+    redirect_uri: String,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: String,
+    expires_in: i64,
+    scope: String,
+}
+
+#[derive(Serialize)]
+struct OAuthError {
+    error: String,
+    error_description: String,
+}
+
+// A validated authorization request
+struct Authorization {
+    client: Client,
+    redirect_uri: String,
+    scopes: Vec<String>,
+    state: Option<String>,
+    code_challenge: String,
+}
+
+// Token responses must not be cached (RFC 6749 section 5.1)
+struct NoStore(status::Custom<Json<Value>>);
+
+impl<'r> Responder<'r> for NoStore {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        response::Response::build_from(self.0.respond_to(request)?)
+            .raw_header("Cache-Control", "no-store")
+            .raw_header("Pragma", "no-cache")
+            .ok()
+    }
+}
+
+#[get("/authorize?<request>")]
+fn consent(_session: Session,
+           request: AuthorizationRequest,
+           db: Conn) -> status::Custom<Json<Value>> {
+    let authorization = match validate_authorization(&request, &db) {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    status::Custom(
+        Status::Ok,
+        Json(json!(Consent {
+            client_id: authorization.client.id,
+            client_name: authorization.client.name,
+            scopes: authorization.scopes,
+            redirect_uri: authorization.redirect_uri,
+            state: authorization.state,
+        }))
+    )
+}
+
+#[post("/authorize", format="application/json", data="<message>")]
+fn authorize(session: Session,
+             message: Json<AuthorizationRequest>,
+             db: Conn,
+             client: ClientInfo,
+             audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    let authorization = match validate_authorization(&message.0, &db) {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let mut params = Vec::new();
+    let details = format!("client {}: {}",
+                          authorization.client.id,
+                          scope::join(&authorization.scopes));
+    if message.0.approve == Some(true) {
+        let code = auth::random_token(32);
+        let new_code = AuthorizationCode {
+            client_id: authorization.client.id.clone(),
+            user_id: session.user_id,
+            redirect_uri: authorization.redirect_uri.clone(),
+            scopes: authorization.scopes,
+            code_challenge: authorization.code_challenge,
+            expires_on: Utc::now() + Duration::seconds(CODE_LIFETIME),
+        };
+        if !oauth::create_code(&auth::hash_token(&code), new_code, &db) {
+            return internal_server_error();
+        }
+        params.push(("code", code));
+        audit_log.record(Event::success(audit::OAUTH_AUTHORIZATION, &client)
+                             .user(&session.user_id)
+                             .details(details),
+                         &db);
+    } else {
+        params.push(("error", "access_denied".to_string()));
+        audit_log.record(Event::failure(audit::OAUTH_AUTHORIZATION, &client)
+                             .user(&session.user_id)
+                             .details(format!("{} (denied)", details)),
+                         &db);
+    }
+    if let Some(state) = authorization.state {
+        params.push(("state", state));
+    }
+    status::Custom(
+        Status::Ok,
+        Json(json!(Redirect {
+            redirect_uri: append_query(&authorization.redirect_uri, &params),
+        }))
+    )
+}
+
+#[post("/token", data="<request>")]
+fn token(request: Form<TokenRequest>,
+         db: Conn,
+         client: ClientInfo,
+         audit_log: State<AuditLog>,
+         keys: State<KeySet>) -> NoStore {
+    NoStore(exchange_code(request.into_inner(), &db, &client, &audit_log, &keys))
+}
+
+fn exchange_code(request: TokenRequest,
+                 db: &Conn,
+                 client_info: &ClientInfo,
+                 audit_log: &AuditLog,
+                 keys: &KeySet) -> status::Custom<Json<Value>> {
+    let failure = |details: &str| {
+        let client_id = request.client_id.as_ref().map_or("", |id| id.as_str());
+        audit_log.record(Event::failure(audit::OAUTH_TOKEN_ISSUED, client_info)
+                             .details(format!("client {}: {}", client_id, details)),
+                         db);
+    };
+    if request.grant_type.as_ref().map(|g| g.as_str()) != Some("authorization_code") {
+        return oauth_error(Status::BadRequest,
+                           "unsupported_grant_type",
+                           "only authorization_code is supported");
+    }
+    let client = match request.client_id.as_ref().and_then(|id| oauth::get_client(id, db).ok()) {
+        Some(c) => c,
+        None => {
+            failure("unknown client");
+            return invalid_client();
+        },
+    };
+    if let Some(ref secret_hash) = client.secret_hash {
+        let secret = match request.client_secret {
+            Some(ref s) => auth::hash_token(s),
+            None => {
+                failure("missing client secret");
+                return invalid_client();
+            },
+        };
+        if constant_time::verify_slices_are_equal(secret.as_bytes(),
+                                                  secret_hash.as_bytes()).is_err() {
+            failure("incorrect client secret");
+            return invalid_client();
+        }
+    }
+    let (code, verifier) = match (request.code.as_ref(), request.code_verifier.as_ref()) {
+        (Some(c), Some(v)) => (c, v),
+        _ => return oauth_error(Status::BadRequest,
+                                "invalid_request",
+                                "code and code_verifier are required"),
+    };
+    let authorization = match oauth::use_code(&auth::hash_token(code), db) {
+        Ok(a) => a,
+        Err(_) => {
+            failure("invalid or expired code");
+            return invalid_grant();
+        },
+    };
+    if authorization.client_id != client.id ||
+        request.redirect_uri.as_ref() != Some(&authorization.redirect_uri) ||
+        !verify_code_challenge(verifier, &authorization.code_challenge) {
+        failure("code does not match request");
+        return invalid_grant();
+    }
+    match users::get(&authorization.user_id, db) {
+        Ok(ref user) if user.active => {},
+        _ => {
+            failure("account is inactive");
+            return invalid_grant();
+        },
+    }
+    let access_token = match UserToken::for_client(&authorization.user_id.to_string(),
+                                                   &authorization.scopes,
+                                                   &client.id,
+                                                   keys) {
+        Ok(t) => t,
+        Err(_) => return internal_server_error(),
+    };
+    audit_log.record(Event::success(audit::OAUTH_TOKEN_ISSUED, client_info)
+                         .user(&authorization.user_id)
+                         .details(format!("client {}: {}",
+                                          client.id,
+                                          scope::join(&authorization.scopes))),
+                     db);
+    status::Custom(
+        Status::Ok,
+        Json(json!(TokenResponse {
+            access_token: access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: ACCESS_TOKEN_LIFETIME,
+            scope: scope::join(&authorization.scopes),
+        }))
+    )
+}
+
+// Register an application that can request access to user accounts
+#[post("/<id>/oauth-clients", format="application/json", data="<message>")]
+fn create_client(_auth: Scoped<AccountSecurity>,
+                 id: UUID,
+                 message: Json<ClientRequest>,
+                 db: Conn,
+                 client: ClientInfo,
+                 audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    let name = message.0.name.trim().to_string();
+    if name.is_empty() {
+        return bad_request("name is required");
+    }
+    let redirect_uris = message.0.redirect_uris;
+    if redirect_uris.is_empty() || redirect_uris.len() > MAX_REDIRECT_URIS {
+        return bad_request("between 1 and 10 redirect_uris are required");
+    }
+    if !redirect_uris.iter().all(|uri| valid_redirect_uri(uri)) {
+        return bad_request("redirect_uris contains an invalid URI");
+    }
+    let secret = if message.0.confidential.unwrap_or(true) {
+        Some(auth::random_token(40))
+    } else {
+        None
+    };
+    let new_client = NewClient {
+        id: auth::random_token(24),
+        owner_id: id.into_inner(),
+        name: name,
+        secret_hash: secret.as_ref().map(|s| auth::hash_token(s)),
+        redirect_uris: redirect_uris,
+    };
+    match oauth::create_client(new_client, &db) {
+        Ok(oauth_client) => {
+            audit_log.record(Event::success(audit::OAUTH_CLIENT_CREATED, &client)
+                                 .user(&id)
+                                 .details(oauth_client.id.clone()),
+                             &db);
+            status::Custom(
+                Status::Created,
+                Json(json!(CreatedClient {
+                    client_id: oauth_client.id,
+                    client_secret: secret,
+                    name: oauth_client.name,
+                    redirect_uris: oauth_client.redirect_uris,
+                }))
+            )
+        },
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[get("/<id>/oauth-clients")]
+fn list_clients(_auth: Scoped<AccountSecurity>,
+                id: UUID,
+                db: Conn) -> status::Custom<Json<Value>> {
+    match oauth::get_clients_by_owner(&id, &db) {
+        Ok(clients) => status::Custom(
+            Status::Ok,
+            Json(json!(clients))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+// Deleting a client also invalidates access tokens issued to it
+#[delete("/<id>/oauth-clients/<client_id>")]
+fn delete_client(_auth: Scoped<AccountSecurity>,
+                 id: UUID,
+                 client_id: String,
+                 db: Conn,
+                 client: ClientInfo,
+                 audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    if oauth::delete_client(&client_id, &id, &db) {
+        audit_log.record(Event::success(audit::OAUTH_CLIENT_DELETED, &client)
+                             .user(&id)
+                             .details(client_id.clone()),
+                         &db);
+        status::Custom(
+            Status::Ok,
+            Json(json!(Response::new("ok", "client deleted")))
+        )
+    } else {
+        status::Custom(
+            Status::NotFound,
+            Json(json!(Response::new("error", "client not found")))
+        )
+    }
+}
+
+fn validate_authorization(request: &AuthorizationRequest,
+                          db: &Conn) -> Result<Authorization, status::Custom<Json<Value>>> {
+    let client = match request.client_id.as_ref().and_then(|id| oauth::get_client(id, db).ok()) {
+        Some(c) => c,
+        None => return Err(oauth_error(Status::BadRequest,
+                                       "invalid_client",
+                                       "client_id is not a registered client")),
+    };
+    // Redirect URIs must exactly match a registered URI
+    let redirect_uri = match request.redirect_uri {
+        Some(ref uri) if client.redirect_uris.contains(uri) => uri.clone(),
+        _ => return Err(oauth_error(Status::BadRequest,
+                                    "invalid_request",
+                                    "redirect_uri is not registered for the client")),
+    };
+    if request.response_type.as_ref().map(|r| r.as_str()) != Some("code") {
+        return Err(oauth_error(Status::BadRequest,
+                               "unsupported_response_type",
+                               "response_type must be code"));
+    }
+    let scopes = match request.scope {
+        Some(ref s) => scope::parse(s),
+        None => vec![scope::ACTIVITIES_READ.to_string()],
+    };
+    let allowed = scopes.iter().all(|s| scope::OAUTH.iter().any(|o| *o == s.as_str()));
+    if scopes.is_empty() || !allowed {
+        return Err(oauth_error(Status::BadRequest,
+                               "invalid_scope",
+                               "scope contains an unknown or disallowed scope"));
+    }
+    if request.code_challenge_method.as_ref().map(|m| m.as_str()) != Some("S256") {
+        return Err(oauth_error(Status::BadRequest,
+                               "invalid_request",
+                               "code_challenge_method must be S256"));
+    }
+    // A base64url encoded SHA-256 digest without padding
+    let code_challenge = match request.code_challenge {
+        Some(ref c) if c.len() == 43 && c.chars().all(is_base64url) => c.clone(),
+        _ => return Err(oauth_error(Status::BadRequest,
+                                    "invalid_request",
+                                    "code_challenge is invalid")),
+    };
+    Ok(Authorization {
+        client: client,
+        redirect_uri: redirect_uri,
+        scopes: scopes,
+        state: request.state.clone(),
+        code_challenge: code_challenge,
+    })
+}
+
+fn verify_code_challenge(verifier: &str, challenge: &str) -> bool {
+    // Verifiers are 43 to 128 unreserved characters (RFC 7636 section 4.1)
+    let valid = verifier.len() >= 43 && verifier.len() <= 128 &&
+        verifier.chars().all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c));
+    if !valid {
+        return false;
+    }
+    let d = digest::digest(&digest::SHA256, verifier.as_bytes());
+    let expected = base64::encode_config(d.as_ref(), base64::URL_SAFE_NO_PAD);
+    constant_time::verify_slices_are_equal(expected.as_bytes(), challenge.as_bytes()).is_ok()
+}
+
+fn is_base64url(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+// Redirect URIs must use https, http on a loopback address for native apps
+// (RFC 8252 section 7.3), or a private-use scheme for native apps.
+fn valid_redirect_uri(uri: &str) -> bool {
+    if uri.contains('#') || uri.chars().any(|c| c.is_whitespace()) {
+        return false;
+    }
+    let scheme = match uri.find(':') {
+        Some(idx) => uri[..idx].to_lowercase(),
+        None => return false,
+    };
+    if scheme.is_empty() ||
+        !scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) {
+        return false;
+    }
+    match scheme.as_str() {
+        "https" => uri.len() > "https://".len() && uri[scheme.len()..].starts_with("://"),
+        "http" => {
+            ["localhost", "127.0.0.1", "[::1]"].iter().any(|host| {
+                let prefix = format!("http://{}", host);
+                uri.starts_with(&prefix) &&
+                    uri[prefix.len()..].chars().next().map_or(true, |c| c == ':' || c == '/')
+            })
+        },
+        "javascript" | "data" | "file" | "vbscript" => false,
+        _ => true,
+    }
+}
+
+fn append_query(uri: &str, params: &[(&str, String)]) -> String {
+    let query = params.iter()
+        .map(|&(k, ref v)| format!("{}={}", k, otp::percent_encode(v)))
+        .collect::<Vec<String>>()
+        .join("&");
+    let separator = if uri.contains('?') { "&" } else { "?" };
+    format!("{}{}{}", uri, separator, query)
+}
+
+fn oauth_error(status: Status,
+               error: &str,
+               description: &str) -> status::Custom<Json<Value>> {
+    status::Custom(
+        status,
+        Json(json!(OAuthError {
+            error: error.to_string(),
+            error_description: description.to_string(),
+        }))
+    )
+}
+
+fn invalid_client() -> status::Custom<Json<Value>> {
+    oauth_error(Status::Unauthorized, "invalid_client", "client authentication failed")
+}
+
+fn invalid_grant() -> status::Custom<Json<Value>> {
+    oauth_error(Status::BadRequest,
+                "invalid_grant",
+                "authorization code is invalid, expired or was already used")
+}
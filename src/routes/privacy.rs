@@ -0,0 +1,100 @@
+use rocket::response::status;
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use db::Conn;
+use models::privacy::{self, PrivacyZone};
+use policy::FieldError;
+use scope::{ActivitiesRead, ProfileWrite, Scoped};
+use super::{Response, internal_server_error, validation_failed};
+
+// Radius limits in metres. Small zones hide too little to be useful.
+const MIN_RADIUS: f64 = 100.0;
+const MAX_RADIUS: f64 = 5000.0;
+
+#[derive(Deserialize)]
+struct ZoneRequest {
+    lat: f64,
+    lng: f64,
+    radius: f64,
+}
+
+#[get("/<id>/privacy-zones")]
+fn list(_auth: Scoped<ActivitiesRead>,
+        id: UUID,
+        db: Conn) -> status::Custom<Json<Value>> {
+    match privacy::get_zones(&id, &db) {
+        Ok(zones) => status::Custom(
+            Status::Ok,
+            Json(json!(zones))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[post("/<id>/privacy-zones", format = "application/json", data = "<message>")]
+fn create(_auth: Scoped<ProfileWrite>,
+          id: UUID,
+          message: Json<ZoneRequest>,
+          db: Conn) -> status::Custom<Json<Value>> {
+    let errors = check(&message);
+    if !errors.is_empty() {
+        return validation_failed(errors);
+    }
+    let zone = PrivacyZone {
+        id: Uuid::new_v4(),
+        user_id: id.into_inner(),
+        lat: message.lat,
+        lng: message.lng,
+        radius: message.radius,
+        created_at: Utc::now(),
+    };
+    if privacy::create_zone(&zone, &db) {
+        status::Custom(
+            Status::Created,
+            Json(json!(zone))
+        )
+    } else {
+        internal_server_error()
+    }
+}
+
+#[delete("/<id>/privacy-zones/<zone_id>")]
+fn delete(_auth: Scoped<ProfileWrite>,
+          id: UUID,
+          zone_id: UUID,
+          db: Conn) -> status::Custom<Json<Value>> {
+    if privacy::delete_zone(&zone_id, &id, &db) {
+        status::Custom(
+            Status::Ok,
+            Json(json!(Response::new("success", "privacy zone deleted")))
+        )
+    } else {
+        status::Custom(
+            Status::NotFound,
+            Json(json!(Response::new("error", "privacy zone not found")))
+        )
+    }
+}
+
+fn check(message: &ZoneRequest) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    if !(message.lat >= -90.0 && message.lat <= 90.0) {
+        errors.push(FieldError::new("lat", "out_of_range", "lat must be between -90 and 90"));
+    }
+    if !(message.lng >= -180.0 && message.lng <= 180.0) {
+        errors.push(FieldError::new("lng", "out_of_range", "lng must be between -180 and 180"));
+    }
+    if !(message.radius >= MIN_RADIUS && message.radius <= MAX_RADIUS) {
+        errors.push(FieldError::new(
+            "radius",
+            "out_of_range",
+            format!("radius must be between {} and {} metres", MIN_RADIUS, MAX_RADIUS)
+        ));
+    }
+    errors
+}
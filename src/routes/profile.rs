@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+
+use rocket::request::State;
+use rocket::response::status;
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use chrono::{Datelike, NaiveDate, Utc};
+
+use hdb::platform::Pool;
+
+use config::{ServerConfig, TrackConfig};
+use db::Conn;
+use models::profiles::{self, Physiology, Profile, SettingValue};
+use policy::FieldError;
+use processing;
+use scope::{ActivitiesRead, ProfileWrite, Scoped};
+use super::{Response, bad_request, internal_server_error, validation_failed};
+
+// Upper bounds of physiological values, to catch values entered in the
+// wrong unit
+const MAX_WEIGHT_KG: f64 = 500.0;
+const MAX_FTP_WATTS: f64 = 2500.0;
+const MAX_HEART_RATE: f64 = 250.0;
+const MAX_THRESHOLD_PACE: f64 = 3600.0;
+const MAX_HEIGHT_CM: f64 = 300.0;
+const MAX_DISPLAY_NAME_LENGTH: usize = 64;
+const SEXES: [&'static str; 3] = ["female", "male", "other"];
+
+#[derive(FromForm)]
+struct ProfileQuery {
+    // YYYY-MM-DD
+    date: String,
+}
+
+// Fields left out are unchanged. Physiological values take effect on
+// effective_from, or today if it is not given, and are added to the
+// history instead of replacing earlier values.
+#[derive(Deserialize)]
+struct ProfileRequest {
+    display_name: Option<String>,
+    birth_year: Option<i32>,
+    sex: Option<String>,
+    height_cm: Option<f64>,
+    units: Option<String>,
+    effective_from: Option<NaiveDate>,
+    weight_kg: Option<f64>,
+    ftp_watts: Option<f64>,
+    max_hr: Option<f64>,
+    resting_hr: Option<f64>,
+    threshold_hr: Option<f64>,
+    threshold_pace: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct AthleteProfile {
+    profile: Profile,
+    // Values in effect on date
+    date: NaiveDate,
+    physiology: Physiology,
+    history: BTreeMap<&'static str, Vec<SettingValue>>,
+}
+
+// The profile with the physiological values in effect on date
+#[get("/<id>/profile?<query>")]
+fn view_on(_auth: Scoped<ActivitiesRead>,
+           id: UUID,
+           query: ProfileQuery,
+           db: Conn) -> status::Custom<Json<Value>> {
+    match NaiveDate::parse_from_str(&query.date, "%Y-%m-%d") {
+        Ok(date) => athlete_profile(&id, date, &db),
+        Err(_) => bad_request("date must be formatted YYYY-MM-DD"),
+    }
+}
+
+#[get("/<id>/profile", rank = 2)]
+fn view(_auth: Scoped<ActivitiesRead>,
+        id: UUID,
+        db: Conn) -> status::Custom<Json<Value>> {
+    athlete_profile(&id, Utc::today().naive_utc(), &db)
+}
+
+// Activities on or after effective_from are analysed again in the
+// background when a physiological value is given
+#[put("/<id>/profile", format = "application/json", data = "<message>")]
+fn update(_auth: Scoped<ProfileWrite>,
+          id: UUID,
+          message: Json<ProfileRequest>,
+          db: Conn,
+          pool: State<Pool>,
+          conf: State<ServerConfig>,
+          tracks: State<TrackConfig>) -> status::Custom<Json<Value>> {
+    let message = message.into_inner();
+    let errors = check(&message);
+    if !errors.is_empty() {
+        return validation_failed(errors);
+    }
+    let mut profile = match profiles::get(&id, &db) {
+        Ok(p) => p,
+        Err(_) => return internal_server_error(),
+    };
+    if let Some(display_name) = message.display_name {
+        let display_name = display_name.trim().to_string();
+        profile.display_name = if display_name.is_empty() { None } else { Some(display_name) };
+    }
+    if message.birth_year.is_some() {
+        profile.birth_year = message.birth_year;
+    }
+    if message.sex.is_some() {
+        profile.sex = message.sex;
+    }
+    if message.height_cm.is_some() {
+        profile.height_cm = message.height_cm;
+    }
+    if let Some(units) = message.units {
+        profile.units = units;
+    }
+    if !profiles::upsert(&id, &profile, &db) {
+        return internal_server_error();
+    }
+    let effective_from = message.effective_from.unwrap_or(Utc::today().naive_utc());
+    let values = [
+        (profiles::WEIGHT, message.weight_kg),
+        (profiles::FTP, message.ftp_watts),
+        (profiles::MAX_HR, message.max_hr),
+        (profiles::RESTING_HR, message.resting_hr),
+        (profiles::THRESHOLD_HR, message.threshold_hr),
+        (profiles::THRESHOLD_PACE, message.threshold_pace),
+    ];
+    for &(setting, value) in values.iter() {
+        if let Some(value) = value {
+            let value = SettingValue { effective_from: effective_from, value: value };
+            if !profiles::set_value(&id, setting, &value, &db) {
+                return internal_server_error();
+            }
+        }
+    }
+    if values.iter().any(|&(_, value)| value.is_some()) {
+        processing::spawn_reprocess(*id,
+                                    effective_from,
+                                    pool.inner().clone(),
+                                    conf.file_dir.clone(),
+                                    tracks.inner().clone());
+    }
+    athlete_profile(&id, Utc::today().naive_utc(), &db)
+}
+
+// Remove a value from the history, for values entered by mistake.
+// Activities on or after effective_from are analysed again in the
+// background.
+#[delete("/<id>/profile/<setting>/<effective_from>")]
+fn delete_value(_auth: Scoped<ProfileWrite>,
+                id: UUID,
+                setting: String,
+                effective_from: String,
+                db: Conn,
+                pool: State<Pool>,
+                conf: State<ServerConfig>,
+                tracks: State<TrackConfig>) -> status::Custom<Json<Value>> {
+    if !profiles::SETTINGS.iter().any(|s| *s == setting) {
+        return bad_request("unknown setting");
+    }
+    let effective_from = match NaiveDate::parse_from_str(&effective_from, "%Y-%m-%d") {
+        Ok(d) => d,
+        Err(_) => return bad_request("date must be formatted YYYY-MM-DD"),
+    };
+    if profiles::delete_value(&id, &setting, &effective_from, &db) {
+        processing::spawn_reprocess(*id,
+                                    effective_from,
+                                    pool.inner().clone(),
+                                    conf.file_dir.clone(),
+                                    tracks.inner().clone());
+        status::Custom(
+            Status::Ok,
+            Json(json!(Response::new("success", "value deleted")))
+        )
+    } else {
+        status::Custom(
+            Status::NotFound,
+            Json(json!(Response::new("error", "value not found")))
+        )
+    }
+}
+
+fn athlete_profile(id: &UUID, date: NaiveDate, db: &Conn) -> status::Custom<Json<Value>> {
+    let profile = match profiles::get(id, db) {
+        Ok(p) => p,
+        Err(_) => return internal_server_error(),
+    };
+    let history = match profiles::history(id, db) {
+        Ok(h) => h,
+        Err(_) => return internal_server_error(),
+    };
+    status::Custom(
+        Status::Ok,
+        Json(json!(AthleteProfile {
+            profile: profile,
+            date: date,
+            physiology: history.physiology_on(&date),
+            history: profiles::SETTINGS.iter().map(|s| (*s, history.values(s))).collect(),
+        }))
+    )
+}
+
+fn check(message: &ProfileRequest) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    if let Some(ref display_name) = message.display_name {
+        if display_name.trim().chars().count() > MAX_DISPLAY_NAME_LENGTH {
+            errors.push(FieldError::new(
+                "display_name",
+                "too_long",
+                format!("display name must be at most {} characters", MAX_DISPLAY_NAME_LENGTH)
+            ));
+        }
+    }
+    if let Some(birth_year) = message.birth_year {
+        if birth_year < 1900 || birth_year > Utc::today().year() {
+            errors.push(FieldError::new("birth_year", "out_of_range", "birth year is not valid"));
+        }
+    }
+    if let Some(ref sex) = message.sex {
+        if !SEXES.iter().any(|s| *s == *sex) {
+            errors.push(FieldError::new("sex",
+                                        "invalid",
+                                        "sex must be female, male or other"));
+        }
+    }
+    if let Some(ref units) = message.units {
+        if units != profiles::METRIC && units != profiles::IMPERIAL {
+            errors.push(FieldError::new("units",
+                                        "invalid",
+                                        "units must be metric or imperial"));
+        }
+    }
+    if let Some(effective_from) = message.effective_from {
+        if effective_from > Utc::today().naive_utc() {
+            errors.push(FieldError::new("effective_from",
+                                        "in_future",
+                                        "values cannot take effect in the future"));
+        }
+    }
+    check_range(&mut errors, "height_cm", message.height_cm, MAX_HEIGHT_CM);
+    check_range(&mut errors, "weight_kg", message.weight_kg, MAX_WEIGHT_KG);
+    check_range(&mut errors, "ftp_watts", message.ftp_watts, MAX_FTP_WATTS);
+    check_range(&mut errors, "max_hr", message.max_hr, MAX_HEART_RATE);
+    check_range(&mut errors, "resting_hr", message.resting_hr, MAX_HEART_RATE);
+    check_range(&mut errors, "threshold_hr", message.threshold_hr, MAX_HEART_RATE);
+    check_range(&mut errors, "threshold_pace", message.threshold_pace, MAX_THRESHOLD_PACE);
+    errors
+}
+
+fn check_range(errors: &mut Vec<FieldError>, field: &'static str, value: Option<f64>, max: f64) {
+    if let Some(value) = value {
+        if !(value > 0.0 && value <= max) {
+            errors.push(FieldError::new(field,
+                                        "out_of_range",
+                                        format!("{} must be greater than 0 and at most {}", field, max)));
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use rocket::request::State;
+use rocket::response::status;
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use analysis::{self, SPORTS};
+use config::{ServerConfig, TrackConfig};
+use db::Conn;
+use gps;
+use models::{activities, privacy, summaries};
+use models::segments::{self, Segment, SegmentEffort};
+use policy::FieldError;
+use polyline;
+use processing;
+use scope::{ActivitiesRead, ActivitiesWrite, Scoped};
+use super::{Response, internal_server_error, validation_failed};
+use super::activities::not_found;
+
+const MAX_NAME_LENGTH: usize = 100;
+// Metres. Shorter segments are mostly GPS noise.
+const MIN_DISTANCE: f64 = 100.0;
+const MAX_POINTS: usize = 10000;
+// Segments are simplified as finely as detailed activity polylines
+const TOLERANCE: f64 = 5.0;
+const LEADERBOARD_SIZE: i64 = 100;
+
+#[derive(Serialize)]
+struct SegmentDetail {
+    segment: Segment,
+    // The user's fastest effort on the segment
+    best_effort: Option<SegmentEffort>,
+}
+
+// A segment is either the part of one of the user's activities between
+// start and end, in seconds from the start of the activity, or a list of
+// [lat, lng] points in the direction of travel with a sport
+#[derive(Deserialize)]
+struct SegmentRequest {
+    name: String,
+    activity_id: Option<Uuid>,
+    start: Option<f64>,
+    end: Option<f64>,
+    points: Option<Vec<(f64, f64)>>,
+    sport: Option<String>,
+}
+
+// Create a segment. A segment created from an activity is matched against
+// that activity straight away; other activities are matched when they are
+// imported or analysed again. Segments are visible to every user, so they
+// can only be created from public activities and may not pass through the
+// user's privacy zones.
+#[post("/<id>/segments", format = "application/json", data = "<message>")]
+fn create(_auth: Scoped<ActivitiesWrite>,
+          id: UUID,
+          message: Json<SegmentRequest>,
+          db: Conn,
+          conf: State<ServerConfig>,
+          track_conf: State<TrackConfig>) -> status::Custom<Json<Value>> {
+    let message = message.into_inner();
+    let name = message.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return validation_failed(vec![FieldError::new(
+            "name",
+            "invalid",
+            format!("name must be between 1 and {} characters", MAX_NAME_LENGTH)
+        )]);
+    }
+    let (points, sport, source) = match (message.activity_id, message.points) {
+        (Some(activity_id), None) => {
+            let (start, end) = match (message.start, message.end) {
+                (Some(start), Some(end)) if start >= 0.0 && end > start => (start, end),
+                _ => return validation_failed(vec![FieldError::new(
+                    "end",
+                    "invalid",
+                    "start and end must be given with end after start"
+                )]),
+            };
+            let activity = match activities::get(&activity_id, &id, &db) {
+                Ok(a) => a,
+                Err(_) => return not_found(),
+            };
+            match privacy::get_level(&activity_id, &db) {
+                Ok(ref level) if level == privacy::PUBLIC => {},
+                Ok(_) => return validation_failed(vec![FieldError::new(
+                    "activity_id",
+                    "not_public",
+                    "segments can only be created from public activities"
+                )]),
+                Err(_) => return internal_server_error(),
+            }
+            let sport = match summaries::get(&activity_id, &id, &db) {
+                Ok(s) => s.sport,
+                Err(_) => return validation_failed(vec![FieldError::new(
+                    "activity_id",
+                    "not_analysed",
+                    "the activity has not been analysed"
+                )]),
+            };
+            let recording = match processing::read(&id, &conf.file_dir, &activity.filename) {
+                Ok(r) => gps::clean(&r, &track_conf).recording,
+                Err(e) => {
+                    eprintln!("Error reading activity {}: {}", activity_id, e);
+                    return internal_server_error();
+                }
+            };
+            let portion: Vec<_> = recording.samples.iter()
+                .filter(|s| s.time >= start && s.time <= end)
+                .cloned()
+                .collect();
+            (analysis::track(&portion), sport, Some((activity_id, recording)))
+        },
+        (None, Some(points)) => {
+            let sport = match message.sport {
+                Some(ref s) if SPORTS.iter().any(|k| *k == s.as_str()) => s.clone(),
+                _ => return validation_failed(vec![FieldError::new(
+                    "sport",
+                    "invalid",
+                    "sport must be one of the supported sports"
+                )]),
+            };
+            let valid = points.iter().all(|p| p.0.abs() <= 90.0 && p.1.abs() <= 180.0);
+            if !valid || points.len() > MAX_POINTS {
+                return validation_failed(vec![FieldError::new(
+                    "points",
+                    "invalid",
+                    format!("points must be at most {} valid [lat, lng] pairs", MAX_POINTS)
+                )]);
+            }
+            (points, sport, None)
+        },
+        _ => return validation_failed(vec![FieldError::new(
+            "points",
+            "invalid",
+            "give either activity_id with start and end, or points"
+        )]),
+    };
+    let distance: f64 = points.windows(2).map(|w| analysis::haversine(w[0], w[1])).sum();
+    if points.len() < 2 || distance < MIN_DISTANCE {
+        return validation_failed(vec![FieldError::new(
+            "points",
+            "too_short",
+            format!("segments must be at least {} metres long", MIN_DISTANCE)
+        )]);
+    }
+    let zones = match privacy::get_zones(&id, &db) {
+        Ok(z) => z,
+        Err(_) => return internal_server_error(),
+    };
+    if points.iter().any(|p| zones.iter().any(|z| z.contains(*p))) {
+        return validation_failed(vec![FieldError::new(
+            "points",
+            "privacy_zone",
+            "segments may not pass through a privacy zone"
+        )]);
+    }
+    let route: Vec<(f64, f64)> = polyline::simplify(&points, TOLERANCE).iter()
+        .map(|i| points[*i])
+        .collect();
+    let (first, last) = (route[0], route[route.len() - 1]);
+    let segment = Segment {
+        id: Uuid::new_v4(),
+        user_id: id.into_inner(),
+        name: name,
+        sport: sport,
+        distance: distance,
+        start_lat: first.0,
+        start_lng: first.1,
+        end_lat: last.0,
+        end_lng: last.1,
+        polyline: polyline::encode(&route),
+        created_at: Utc::now(),
+    };
+    if !segments::create(&segment, &db) {
+        return internal_server_error();
+    }
+    if let Some((activity_id, recording)) = source {
+        if let Err(e) = processing::match_segments(&activity_id,
+                                                   &segment.user_id,
+                                                   &segment.sport,
+                                                   &recording,
+                                                   &db) {
+            eprintln!("Error matching activity {} to segments: {}", activity_id, e);
+        }
+    }
+    status::Custom(
+        Status::Created,
+        Json(json!(segment))
+    )
+}
+
+// Segments created by the user
+#[get("/<id>/segments")]
+fn list(_auth: Scoped<ActivitiesRead>,
+        id: UUID,
+        db: Conn) -> status::Custom<Json<Value>> {
+    match segments::get_by_user_id(&id, &db) {
+        Ok(s) => status::Custom(
+            Status::Ok,
+            Json(json!(s))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+// A segment created by any user
+#[get("/<id>/segments/<segment_id>")]
+fn view(_auth: Scoped<ActivitiesRead>,
+        id: UUID,
+        segment_id: UUID,
+        db: Conn) -> status::Custom<Json<Value>> {
+    let segment = match segments::get(&segment_id, &db) {
+        Ok(s) => s,
+        Err(_) => return segment_not_found(),
+    };
+    match segments::get_user_efforts(&segment_id, &id, &db) {
+        Ok(efforts) => status::Custom(
+            Status::Ok,
+            Json(json!(SegmentDetail {
+                segment: segment,
+                best_effort: efforts.into_iter().next(),
+            }))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+// Delete a segment the user created, with everyone's efforts on it
+#[delete("/<id>/segments/<segment_id>")]
+fn delete(_auth: Scoped<ActivitiesWrite>,
+          id: UUID,
+          segment_id: UUID,
+          db: Conn) -> status::Custom<Json<Value>> {
+    if segments::delete(&segment_id, &id, &db) {
+        status::Custom(
+            Status::Ok,
+            Json(json!(Response::new("success", "segment deleted")))
+        )
+    } else {
+        segment_not_found()
+    }
+}
+
+// The user's efforts on the segment, fastest first
+#[get("/<id>/segments/<segment_id>/efforts")]
+fn efforts(_auth: Scoped<ActivitiesRead>,
+           id: UUID,
+           segment_id: UUID,
+           db: Conn) -> status::Custom<Json<Value>> {
+    if segments::get(&segment_id, &db).is_err() {
+        return segment_not_found();
+    }
+    match segments::get_user_efforts(&segment_id, &id, &db) {
+        Ok(e) => status::Custom(
+            Status::Ok,
+            Json(json!(e))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+// The fastest effort of each user in public activities, and the user's
+// own fastest effort in any of their activities
+#[get("/<id>/segments/<segment_id>/leaderboard")]
+fn leaderboard(_auth: Scoped<ActivitiesRead>,
+               id: UUID,
+               segment_id: UUID,
+               db: Conn) -> status::Custom<Json<Value>> {
+    if segments::get(&segment_id, &db).is_err() {
+        return segment_not_found();
+    }
+    match segments::leaderboard(&segment_id, &id, LEADERBOARD_SIZE, &db) {
+        Ok(l) => status::Custom(
+            Status::Ok,
+            Json(json!(l))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+fn segment_not_found() -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::NotFound,
+        Json(json!(Response::new("error", "segment not found")))
+    )
+}
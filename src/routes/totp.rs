@@ -0,0 +1,179 @@
+use rocket::request::State;
+use rocket::response::status;
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use uuid::Uuid;
+
+use audit::{self, AuditLog, Event};
+use auth;
+use client::ClientInfo;
+use config::AccountsConfig;
+use db::Conn;
+use models::totp;
+use models::users;
+use otp;
+use scope::{AccountSecurity, Scoped};
+use super::{Response, bad_request, internal_server_error};
+
+// Number of recovery codes issued when two factor authentication is
+// enabled or recovery codes are regenerated
+const RECOVERY_CODES: usize = 10;
+
+#[derive(Deserialize)]
+struct CodeRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+struct Enrollment {
+    secret: String,
+    uri: String,
+}
+
+#[derive(Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+// Start enrolling in two factor authentication. Returns a new secret and
+// otpauth:// URI to add to an authenticator app. Two factor authentication
+// is not enabled until a code generated from the secret is confirmed.
+#[post("/<id>/totp")]
+fn enroll(_auth: Scoped<AccountSecurity>,
+          id: UUID,
+          db: Conn,
+          accounts: State<AccountsConfig>) -> status::Custom<Json<Value>> {
+    if totp::is_enabled(&id, &db) {
+        return already_enabled();
+    }
+    let user = match users::get(&id, &db) {
+        Ok(u) => u,
+        Err(_) => return internal_server_error(),
+    };
+    let secret = otp::generate_secret();
+    if !totp::create(&id, &secret, &db) {
+        return internal_server_error();
+    }
+    status::Custom(
+        Status::Ok,
+        Json(json!(Enrollment {
+            secret: otp::base32_encode(&secret),
+            uri: otp::uri(&secret, &accounts.totp_issuer, &user.username),
+        }))
+    )
+}
+
+// Confirm enrollment with a code from the authenticator app. Recovery
+// codes are returned once and cannot be retrieved again.
+#[post("/<id>/totp/confirm", format="application/json", data="<message>")]
+fn confirm(_auth: Scoped<AccountSecurity>,
+           id: UUID,
+           message: Json<CodeRequest>,
+           db: Conn,
+           client: ClientInfo,
+           audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    let second_factor = match totp::get_by_user_id(&id, &db) {
+        Ok(t) => t,
+        Err(_) => return bad_request("two factor enrollment has not been started"),
+    };
+    if second_factor.confirmed {
+        return already_enabled();
+    }
+    let step = match otp::verify(&second_factor.secret,
+                                 &message.0.code,
+                                 otp::current_step(),
+                                 second_factor.last_step) {
+        Some(s) => s,
+        None => return incorrect_code(),
+    };
+    if !totp::confirm(&id, step, &db) {
+        return internal_server_error();
+    }
+    audit_log.record(Event::success(audit::TOTP_ENABLED, &client).user(&id), &db);
+    issue_recovery_codes(&id, &db)
+}
+
+// Replace all recovery codes. Requires a current code.
+#[post("/<id>/totp/recovery-codes", format="application/json", data="<message>")]
+fn regenerate_recovery_codes(_auth: Scoped<AccountSecurity>,
+                             id: UUID,
+                             message: Json<CodeRequest>,
+                             db: Conn,
+                             client: ClientInfo,
+                             audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    if let Err(response) = verify_code(&id, &message.0.code, &db) {
+        audit_log.record(Event::failure(audit::RECOVERY_CODES_REGENERATED, &client).user(&id),
+                         &db);
+        return response;
+    }
+    audit_log.record(Event::success(audit::RECOVERY_CODES_REGENERATED, &client).user(&id),
+                     &db);
+    issue_recovery_codes(&id, &db)
+}
+
+// Disable two factor authentication. Requires a current code so a stolen
+// access token cannot be used to remove the second factor.
+#[post("/<id>/totp/disable", format="application/json", data="<message>")]
+fn disable(_auth: Scoped<AccountSecurity>,
+           id: UUID,
+           message: Json<CodeRequest>,
+           db: Conn,
+           client: ClientInfo,
+           audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
+    if let Err(response) = verify_code(&id, &message.0.code, &db) {
+        audit_log.record(Event::failure(audit::TOTP_DISABLED, &client).user(&id), &db);
+        return response;
+    }
+    if totp::delete(&id, &db) {
+        audit_log.record(Event::success(audit::TOTP_DISABLED, &client).user(&id), &db);
+        status::Custom(
+            Status::Ok,
+            Json(json!(Response::new("ok", "two factor authentication disabled")))
+        )
+    } else {
+        internal_server_error()
+    }
+}
+
+fn verify_code(user_id: &Uuid,
+               code: &str,
+               db: &Conn) -> Result<(), status::Custom<Json<Value>>> {
+    let (secret, last_step) = match totp::get_by_user_id(user_id, db) {
+        Ok(ref t) if t.confirmed => (t.secret.clone(), t.last_step),
+        _ => return Err(bad_request("two factor authentication is not enabled")),
+    };
+    match otp::verify(&secret, code, otp::current_step(), last_step) {
+        Some(step) if totp::use_step(user_id, step, db) => Ok(()),
+        _ => Err(incorrect_code()),
+    }
+}
+
+fn issue_recovery_codes(user_id: &Uuid, db: &Conn) -> status::Custom<Json<Value>> {
+    let codes = otp::generate_recovery_codes(RECOVERY_CODES);
+    let hashes = codes.iter()
+        .map(|c| auth::hash_token(&otp::normalize_recovery_code(c)))
+        .collect::<Vec<String>>();
+    if !totp::replace_recovery_codes(user_id, &hashes, db) {
+        return internal_server_error();
+    }
+    status::Custom(
+        Status::Ok,
+        Json(json!(RecoveryCodes { recovery_codes: codes }))
+    )
+}
+
+fn already_enabled() -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::Conflict,
+        Json(json!(Response::new("error", "two factor authentication is already enabled")))
+    )
+}
+
+fn incorrect_code() -> status::Custom<Json<Value>> {
+    status::Custom(
+        Status::Unauthorized,
+        Json(json!(Response::new("error", "code is incorrect")))
+    )
+}
@@ -0,0 +1,69 @@
+use rocket::response::status;
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use chrono::{Duration, Utc};
+
+use db::Conn;
+use load;
+use scope::{ActivitiesRead, Scoped};
+use super::{bad_request, internal_server_error, parse_date};
+
+// Days returned when no range is given
+const DEFAULT_DAYS: i64 = 90;
+// Longest range that can be requested
+const MAX_DAYS: i64 = 3 * 366;
+
+#[derive(FromForm)]
+struct RangeQuery {
+    // YYYY-MM-DD, both inclusive
+    from: String,
+    to: Option<String>,
+}
+
+// Daily training stress, fitness (ctl), fatigue (atl) and form (tsb)
+#[get("/<id>/training-load?<query>")]
+fn series(_auth: Scoped<ActivitiesRead>,
+          id: UUID,
+          query: RangeQuery,
+          db: Conn) -> status::Custom<Json<Value>> {
+    let from = match parse_date("from", &query.from) {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+    let to = match query.to {
+        Some(ref to) => match parse_date("to", to) {
+            Ok(d) => d,
+            Err(e) => return e,
+        },
+        None => Utc::today().naive_utc(),
+    };
+    if to < from {
+        return bad_request("to must not be before from");
+    }
+    if (to - from).num_days() >= MAX_DAYS {
+        return bad_request("range is too long");
+    }
+    match load::series(&id, &from, &to, &db) {
+        Ok(days) => status::Custom(
+            Status::Ok,
+            Json(json!(days))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+#[get("/<id>/training-load", rank = 2)]
+fn recent(_auth: Scoped<ActivitiesRead>,
+          id: UUID,
+          db: Conn) -> status::Custom<Json<Value>> {
+    let to = Utc::today().naive_utc();
+    match load::series(&id, &(to - Duration::days(DEFAULT_DAYS - 1)), &to, &db) {
+        Ok(days) => status::Custom(
+            Status::Ok,
+            Json(json!(days))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
@@ -1,30 +1,93 @@
 use rocket::request::State;
 use rocket::response::status;
-use rocket::http::Status;
+use rocket::http::{Cookies, Status};
 
 use rocket_contrib::{Json, Value, UUID};
 
-use chrono::Utc;
+use chrono::{Duration, Utc};
 use uuid::Uuid;
 
 use hdb::platform::models::users::{self, NewUser};
 use hdb::platform::models::tokens::{self, NewUserToken};
-use hdb::platform::models::activities::{self, NewActivity};
+use hdb::platform::models::activities::{self, Activity, NewActivity};
 
+use analysis;
+use audit::{self, AuditLog, Event};
+use client::ClientInfo;
 use db::Conn;
+use keys::KeySet;
 use file::{self, ActivityRequest};
-use super::Response;
-use auth::{self, AccessToken, UserToken};
-use config::ServerConfig;
+use fit;
+use mail::{Mailer, Message};
+use models::Error;
+use models::emails::{self, NewUserEmail};
+use models::deletions;
+use models::passwords;
+use models::sessions;
+use models::totp::{self, UserTotp};
+use models::users as account_users;
+use super::{Response, TooManyRequests, bad_request, forbidden, internal_server_error,
+            unauthorized_token, validation_failed};
+use auth::{self, ChallengeToken, PasswordMatch, UserToken};
+use otp;
+use policy::{self, FieldError};
+use processing::{self, Analysis};
+use scope::{self, AccountDelete, AccountSecurity, ActivitiesWrite, Scoped};
+use session;
+use config::{AccountsConfig, PasswordConfig, ServerConfig, ThrottleConfig, TrackConfig,
+             UsernameConfig};
+use throttle::{self, Subject};
 
 use std::fs::File;
-use std::path::Path;
 use std::io::{Read, Write};
 
 #[derive(Deserialize)]
 struct UserRequest {
     username: String,
     password: String,
+    email: Option<String>,
+    // Login sets a session cookie instead of returning the access token
+    session: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct EmailRequest {
+    email: String,
+}
+
+#[derive(FromForm)]
+struct VerifyRequest {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct SecondFactorRequest {
+    challenge_token: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+    session: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct PasswordChangeRequest {
+    challenge_token: String,
+    password: String,
+    session: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct ImportedActivity {
+    activity: Activity,
+    analysis: Option<Analysis>,
+}
+
+// Returned by login when another step is required before an access token
+// is issued
+#[derive(Serialize)]
+struct LoginChallenge {
+    status: String,
+    reason: String,
+    challenge_token: String,
 }
 
 #[derive(Serialize)]
@@ -34,35 +97,114 @@ struct AuthenticatedUser {
     access_token: String,
 }
 
+// Returned by login in session mode. The access token is only sent in the
+// session cookie.
+#[derive(Serialize)]
+struct SessionUser {
+    user_id: Uuid,
+    username: String,
+    csrf_token: String,
+}
+
 #[post("/register", format="application/json", data="<message>")]
-fn register(message: Json<UserRequest>, db: Conn) -> status::Custom<Json<Value>> {
-    // Check if user already exists. Return error
-    let exists = users::exists(&message.0.username, &db);
-    // Create new user, return user or error
-    if exists {
+fn register(message: Json<UserRequest>,
+            db: Conn,
+            client: ClientInfo,
+            audit_log: State<AuditLog>,
+            accounts: State<AccountsConfig>,
+            policy: State<PasswordConfig>,
+            usernames: State<UsernameConfig>,
+            mailer: State<Mailer>) -> status::Custom<Json<Value>> {
+    let username = policy::normalize_username(&message.0.username);
+    let mut errors = policy::check_username(&username, &usernames);
+    errors.extend(policy::check_password(&message.0.password, &username, &policy));
+    // Validate email address if one was given, or reject the request if
+    // an email address is required and none was given.
+    let email = match message.0.email {
+        Some(ref e) => {
+            let e = normalize_email(e);
+            if !valid_email(&e) {
+                errors.push(FieldError::new("email", "invalid", "email address is invalid"));
+            }
+            Some(e)
+        },
+        None if accounts.require_email => {
+            errors.push(FieldError::new("email", "required", "email address is required"));
+            None
+        },
+        None => None,
+    };
+    if !errors.is_empty() {
+        return validation_failed(errors);
+    }
+    if let Some(ref e) = email {
+        if emails::exists(e, &db) {
+            return status::Custom(
+                Status::Conflict,
+                Json(json!(Response::new("error", "Email address already exists")))
+            )
+        }
+    }
+    // Check if user already exists and claim the username so a concurrent
+    // registration cannot take it. Return error
+    if account_users::username_taken(&username, &db) ||
+        !account_users::reserve_username(&username, &db) {
         return status::Custom(
             Status::Conflict,
             Json(json!(Response::new("error", "Username already exists")))
         )
     }
-    // Generate Salt
-    let salt = auth::generate_salt();
-    // Generate password hash
-    let hash = auth::generate_hash(message.0.password, &salt);
+    // Generate password hash. The salt and hash parameters are encoded
+    // in the hash itself.
+    let hash = match auth::hash_password(&message.0.password, &policy) {
+        Ok(h) => h,
+        Err(_) => {
+            account_users::release_username(&username, &db);
+            return internal_server_error();
+        },
+    };
     let new_user = NewUser {
-        username: message.0.username, //Sanity check username??
-        salt: salt,
-        password: hash,
+        username: username,
+        salt: Vec::new(),
+        password: hash.into_bytes(),
         active: true,
         created_on: Utc::now(),
     };
+    let username = new_user.username.clone();
     let success = users::create(new_user, &db);
     if success {
+        // Look up the new user to get the assigned id
+        let user = match users::get_by_username(&username, &db) {
+            Ok(u) => u,
+            Err(_) => return internal_server_error(),
+        };
+        // The user is removed if the email address cannot be stored so
+        // registration can be retried with the same username
+        if let Some(email) = email {
+            if !set_email(&user.id, email, &db, &accounts, &mailer) {
+                if account_users::delete(&user.id, &db) {
+                    account_users::release_username(&username, &db);
+                } else {
+                    eprintln!("Error removing user {} after failed registration", user.id);
+                }
+                audit_log.record(Event::failure(audit::REGISTER, &client)
+                                     .username(&username)
+                                     .details("email address could not be stored"),
+                                 &db);
+                return internal_server_error();
+            }
+        }
+        audit_log.record(Event::success(audit::REGISTER, &client)
+                             .user(&user.id)
+                             .username(&username),
+                         &db);
         status::Custom(
             Status::Created,
             Json(json!(Response::new("ok", "User created")))
         )
     } else {
+        account_users::release_username(&username, &db);
+        audit_log.record(Event::failure(audit::REGISTER, &client).username(&username), &db);
         status::Custom(
             Status::InternalServerError,
             Json(json!(Response::new("error", "error creating user")))
@@ -73,123 +215,561 @@ fn register(message: Json<UserRequest>, db: Conn) -> status::Custom<Json<Value>>
 #[post("/login", format="application/json", data="<message>")]
 fn login(message: Json<UserRequest>,
          db: Conn,
-         conf: State<ServerConfig>) -> status::Custom<Json<Value>> {
+         client: ClientInfo,
+         audit_log: State<AuditLog>,
+         keys: State<KeySet>,
+         accounts: State<AccountsConfig>,
+         policy: State<PasswordConfig>,
+         limits: State<ThrottleConfig>,
+         conf: State<ServerConfig>,
+         mut cookies: Cookies)
+         -> Result<status::Custom<Json<Value>>, TooManyRequests> {
+    // Check for too many failed attempts before hashing the password so
+    // repeated attempts cannot be used to exhaust the CPU.
+    let subjects = throttle_subjects(&message.0.username, &client);
+    if let Some(wait) = throttle::retry_after(&subjects, &limits, &db) {
+        audit_log.record(Event::failure(audit::LOGIN, &client)
+                             .username(&message.0.username)
+                             .details("too many failed attempts"),
+                         &db);
+        return Err(TooManyRequests(wait));
+    }
+    let response = check_credentials(message, &db, &client, &audit_log, &keys, &accounts, &policy,
+                                     &conf, &mut cookies);
+    if response.0 == Status::Unauthorized {
+        throttle::record_failure(&subjects, &limits, &db);
+    }
+    Ok(response)
+}
+
+fn check_credentials(message: Json<UserRequest>,
+                     db: &Conn,
+                     client: &ClientInfo,
+                     audit_log: &AuditLog,
+                     keys: &KeySet,
+                     accounts: &AccountsConfig,
+                     policy: &PasswordConfig,
+                     conf: &ServerConfig,
+                     cookies: &mut Cookies) -> status::Custom<Json<Value>> {
+    let failure = |user_id: Option<&Uuid>, details: &str| {
+        let mut event = Event::failure(audit::LOGIN, client)
+            .username(&message.0.username)
+            .details(details);
+        if let Some(id) = user_id {
+            event = event.user(id);
+        }
+        audit_log.record(event, db);
+    };
     // Attempt to find user in the database. Return unauthorized if no user
-    // is found.
-    let user = match users::get_by_username(&message.0.username, &db) {
+    // is found. Usernames registered before usernames were normalized are
+    // matched exactly.
+    let user = match users::get_by_username(&message.0.username, db)
+        .or_else(|_| users::get_by_username(&policy::normalize_username(&message.0.username), db)) {
         Ok(u) => u,
-        Err(_) => return unauthorized(),
+        Err(_) => {
+            failure(None, "unknown username");
+            return unauthorized();
+        },
     };
 
     if !user.active {
+        failure(Some(&user.id), "account is inactive");
         return unauthorized()
     }
 
-    let tph = auth::generate_hash(message.0.password, &user.salt);
-    if tph == user.password {
-        // TODO: Check if user already has an access token and return it
-        // if not expired
-        let user_token = match tokens::get_by_user_id(&user.id, &db) {
-            // If user already has access_token retrieve it from the db
-            // and return it
-            Ok(ut) => {
-                let token = match String::from_utf8(ut.token) {
-                    Ok(t) => t,
-                    Err(_) => return internal_server_error(),
-                };
-                // Check to see if token is valid
-                if UserToken::validate(&token, &conf.secret, &user.id.to_string()) {
-                    token
-                // If the current user token is invalid, generate a new
-                // user token and return it
-                } else {
-                    let user_token = match UserToken::new(&user.id.to_string(), &conf.secret) {
-                        Ok(ut) => ut,
-                        Err(_) => return internal_server_error(),
-                    };
-                    let success = tokens::update(&ut.id,
-                                                 &user_token.as_bytes().to_vec(),
-                                                 &db);
-                    if success {
-                        user_token
-                    } else {
-                        return internal_server_error();
-                    }
-                }
-            },
-
-            // If user does not have an access token, then create a new
-            // access_token for the user
-            Err(_) => {
-                let user_token = match UserToken::new(&user.id.to_string(), &conf.secret) {
-                    Ok(ut) => ut,
-                    Err(_) => return internal_server_error(),
-                };
-                let new_user_token = NewUserToken {
-                    user_id: user.id,
-                    token: user_token.as_bytes().to_vec(),
-                };
-                let success = tokens::create(new_user_token, &db);
-                if success {
-                    user_token
-                } else {
-                    return internal_server_error();
-                }
-            }
+    let verified = match auth::verify_password(&message.0.password,
+                                               &user.password,
+                                               &user.salt,
+                                               policy) {
+        PasswordMatch::Valid => true,
+        // Password is correct but the stored hash was created with an
+        // older algorithm or weaker parameters than the current policy.
+        PasswordMatch::Rehash => {
+            rehash_password(&user.id, &message.0.password, policy, db);
+            true
+        },
+        PasswordMatch::Invalid => false,
+    };
+    if verified {
+        if !accounts.allow_unverified_login && !email_verified(&user.id, accounts, db) {
+            failure(Some(&user.id), "email address has not been verified");
+            return forbidden("email address has not been verified");
+        }
+        let success = |details: &str| {
+            audit_log.record(Event::success(audit::LOGIN, client)
+                                 .user(&user.id)
+                                 .username(&user.username)
+                                 .details(details),
+                             db);
         };
+        // Users with two factor authentication enabled must exchange a
+        // challenge token and a valid code for an access token. This comes
+        // before a required password change so the old password alone
+        // cannot be used to set a new one.
+        if totp::is_enabled(&user.id, db) {
+            success("second factor required");
+            return second_factor_required(&user.id, keys);
+        }
+        // An administrator has required the user to choose a new password
+        if passwords::reset_required(&user.id, db) {
+            success("password change required");
+            return password_change_required(&user.id, keys);
+        }
+        success("password");
+        let user_token = match issue_access_token(&user.id, db, keys, client, audit_log) {
+            Ok(t) => t,
+            Err(_) => return internal_server_error(),
+        };
+        // Failures are only cleared once login is complete, so a correct
+        // password cannot be used to reset the limit on second factor
+        // codes
+        throttle::record_success(&message.0.username, db);
 
         // Return user_id, username, and access_token with successful login
-        status::Custom(
+        logged_in(user.id,
+                  user.username.clone(),
+                  user_token,
+                  message.0.session.unwrap_or(false),
+                  conf,
+                  cookies)
+    } else {
+        failure(Some(&user.id), "incorrect password");
+        unauthorized()
+    }
+}
+
+// Exchange a challenge token returned by login and a TOTP or recovery
+// code for an access token.
+#[post("/login/second-factor", format="application/json", data="<message>")]
+fn login_second_factor(message: Json<SecondFactorRequest>,
+                       db: Conn,
+                       client: ClientInfo,
+                       audit_log: State<AuditLog>,
+                       keys: State<KeySet>,
+                       limits: State<ThrottleConfig>,
+                       conf: State<ServerConfig>,
+                       mut cookies: Cookies)
+                       -> Result<status::Custom<Json<Value>>, TooManyRequests> {
+    let challenge = match ChallengeToken::validate(&message.0.challenge_token,
+                                                   auth::SECOND_FACTOR,
+                                                   &keys,
+                                                   &db) {
+        Some(c) => c,
+        None => return Ok(unauthorized_token()),
+    };
+    let user_id = match Uuid::parse_str(&challenge.sub) {
+        Ok(id) => id,
+        Err(_) => return Ok(unauthorized_token()),
+    };
+    let user = match account_users::get(&user_id, &db) {
+        Ok(u) => u,
+        Err(_) => return Ok(unauthorized_token()),
+    };
+    if !user.active {
+        return Ok(unauthorized_token());
+    }
+    // Codes are short, so failed attempts count towards the same limits
+    // as failed passwords.
+    let subjects = throttle_subjects(&user.username, &client);
+    if let Some(wait) = throttle::retry_after(&subjects, &limits, &db) {
+        audit_log.record(Event::failure(audit::LOGIN_SECOND_FACTOR, &client)
+                             .user(&user.id)
+                             .username(&user.username)
+                             .details("too many failed attempts"),
+                         &db);
+        return Err(TooManyRequests(wait));
+    }
+    let second_factor = match totp::get_by_user_id(&user.id, &db) {
+        Ok(t) => t,
+        Err(_) => return Ok(unauthorized_token()),
+    };
+    if !second_factor.confirmed {
+        return Ok(unauthorized_token());
+    }
+
+    let verified = match verify_second_factor(&user.id, &second_factor, &message.0, &db) {
+        Some(v) => v,
+        None => return Ok(bad_request("code or recovery_code is required")),
+    };
+    let method = if message.0.code.is_some() { "totp" } else { "recovery code" };
+    if !verified {
+        throttle::record_failure(&subjects, &limits, &db);
+        audit_log.record(Event::failure(audit::LOGIN_SECOND_FACTOR, &client)
+                             .user(&user.id)
+                             .username(&user.username)
+                             .details(method),
+                         &db);
+        return Ok(status::Custom(
+            Status::Unauthorized,
+            Json(json!(Response::new("error", "code is incorrect")))
+        ));
+    }
+    // Each challenge can only be exchanged once
+    if !challenge.redeem(&db) {
+        return Ok(unauthorized_token());
+    }
+    audit_log.record(Event::success(audit::LOGIN_SECOND_FACTOR, &client)
+                         .user(&user.id)
+                         .username(&user.username)
+                         .details(method),
+                     &db);
+    if passwords::reset_required(&user.id, &db) {
+        return Ok(password_change_required(&user.id, &keys));
+    }
+
+    let user_token = match issue_access_token(&user.id, &db, &keys, &client, &audit_log) {
+        Ok(t) => t,
+        Err(_) => return Ok(internal_server_error()),
+    };
+    throttle::record_success(&user.username, &db);
+    Ok(logged_in(user.id,
+                 user.username,
+                 user_token,
+                 message.0.session.unwrap_or(false),
+                 &conf,
+                 &mut cookies))
+}
+
+// Exchange a challenge token returned by login, or by the second factor
+// step for users with two factor authentication enabled, and a new
+// password for an access token when a password reset has been required.
+#[post("/login/password-change", format="application/json", data="<message>")]
+fn login_password_change(message: Json<PasswordChangeRequest>,
+                         db: Conn,
+                         client: ClientInfo,
+                         audit_log: State<AuditLog>,
+                         keys: State<KeySet>,
+                         policy: State<PasswordConfig>,
+                         conf: State<ServerConfig>,
+                         mut cookies: Cookies) -> status::Custom<Json<Value>> {
+    let challenge = match ChallengeToken::validate(&message.0.challenge_token,
+                                                   auth::PASSWORD_CHANGE,
+                                                   &keys,
+                                                   &db) {
+        Some(c) => c,
+        None => return unauthorized_token(),
+    };
+    let user_id = match Uuid::parse_str(&challenge.sub) {
+        Ok(id) => id,
+        Err(_) => return unauthorized_token(),
+    };
+    let user = match account_users::get(&user_id, &db) {
+        Ok(u) => u,
+        Err(_) => return unauthorized_token(),
+    };
+    if !user.active || !passwords::reset_required(&user.id, &db) {
+        return unauthorized_token();
+    }
+    let errors = policy::check_password(&message.0.password, &user.username, &policy);
+    if !errors.is_empty() {
+        return validation_failed(errors);
+    }
+    let current = match users::get_by_username(&user.username, &db) {
+        Ok(u) => u,
+        Err(_) => return internal_server_error(),
+    };
+    match auth::verify_password(&message.0.password, &current.password, &current.salt, &policy) {
+        PasswordMatch::Invalid => {},
+        _ => return bad_request("new password must be different from the current password"),
+    }
+    if !challenge.redeem(&db) {
+        return unauthorized_token();
+    }
+    let hash = match auth::hash_password(&message.0.password, &policy) {
+        Ok(h) => h,
+        Err(_) => return internal_server_error(),
+    };
+    if !passwords::update(&user.id, hash.as_bytes(), &db) ||
+        !passwords::clear_reset(&user.id, &db) {
+        return internal_server_error();
+    }
+    audit_log.record(Event::success(audit::PASSWORD_CHANGE, &client)
+                         .user(&user.id)
+                         .username(&user.username)
+                         .details("required by administrator"),
+                     &db);
+    // Users with two factor authentication enabled entered a code before
+    // the challenge token for the password change was issued
+    let user_token = match issue_access_token(&user.id, &db, &keys, &client, &audit_log) {
+        Ok(t) => t,
+        Err(_) => return internal_server_error(),
+    };
+    throttle::record_success(&user.username, &db);
+    logged_in(user.id,
+              user.username,
+              user_token,
+              message.0.session.unwrap_or(false),
+              &conf,
+              &mut cookies)
+}
+
+// End a cookie session. Access tokens are not revoked.
+#[post("/logout")]
+fn logout(mut cookies: Cookies) -> status::Custom<Json<Value>> {
+    session::end(&mut cookies);
+    status::Custom(
+        Status::Ok,
+        Json(json!(Response::new("ok", "logged out")))
+    )
+}
+
+#[get("/verify?<request>")]
+fn verify(request: VerifyRequest, db: Conn) -> status::Custom<Json<Value>> {
+    match emails::verify(&auth::hash_token(&request.token), &db) {
+        Ok(_) => status::Custom(
             Status::Ok,
-            Json(json!(AuthenticatedUser{
-                user_id: user.id,
-                username: user.username,
-                access_token: user_token,
-            }))
+            Json(json!(Response::new("ok", "email address verified")))
+        ),
+        Err(_) => bad_request("verification token is invalid or expired"),
+    }
+}
+
+#[put("/<id>/email", format="application/json", data="<message>")]
+fn update_email(_auth: Scoped<AccountSecurity>,
+                id: UUID,
+                message: Json<EmailRequest>,
+                db: Conn,
+                client: ClientInfo,
+                audit_log: State<AuditLog>,
+                accounts: State<AccountsConfig>,
+                mailer: State<Mailer>) -> status::Custom<Json<Value>> {
+    let email = normalize_email(&message.0.email);
+    if !valid_email(&email) {
+        return bad_request("email address is invalid");
+    }
+    match emails::get_by_user_id(&id, &db) {
+        // Nothing to do if the address did not change
+        Ok(ref current) if current.email == email => {
+            return status::Custom(
+                Status::Ok,
+                Json(json!(current))
+            );
+        },
+        _ => {},
+    }
+    if emails::exists(&email, &db) {
+        return status::Custom(
+            Status::Conflict,
+            Json(json!(Response::new("error", "Email address already exists")))
+        );
+    }
+    if set_email(&id, email, &db, &accounts, &mailer) {
+        audit_log.record(Event::success(audit::EMAIL_CHANGED, &client).user(&id), &db);
+        status::Custom(
+            Status::Accepted,
+            Json(json!(Response::new("accepted", "verification email sent")))
         )
     } else {
-        unauthorized()
+        internal_server_error()
     }
 }
 
+#[post("/<id>/email/verification")]
+fn resend_verification(_auth: Scoped<AccountSecurity>,
+                       id: UUID,
+                       db: Conn,
+                       accounts: State<AccountsConfig>,
+                       mailer: State<Mailer>) -> status::Custom<Json<Value>> {
+    let email = match emails::get_by_user_id(&id, &db) {
+        Ok(e) => e,
+        Err(_) => return bad_request("user does not have an email address"),
+    };
+    if email.verified {
+        return bad_request("email address is already verified");
+    }
+    let token = auth::random_token(32);
+    let expires = Utc::now() + Duration::hours(accounts.verification_ttl);
+    if !emails::set_verification(&id, &auth::hash_token(&token), &expires, &db) {
+        return internal_server_error();
+    }
+    send_verification(&email.email, &token, &accounts, &mailer);
+    status::Custom(
+        Status::Accepted,
+        Json(json!(Response::new("accepted", "verification email sent")))
+    )
+}
+
+// Deactivate the account and schedule its data to be purged once the
+// grace period has ended
 #[delete("/<id>")]
-fn delete(access_token: AccessToken,
+fn delete(_auth: Scoped<AccountDelete>,
           id: UUID,
           db: Conn,
-          conf: State<ServerConfig>) -> status::Custom<Json<Value>> {
-    // Validate received token
-    if UserToken::validate(&access_token.0, &conf.secret, &id.to_string()) {
-        if users::inactivate(&id, &db) {
-            let token = tokens::get_by_user_id(&id, &db).unwrap();
-            if tokens::delete(&token.id, &db) {
-                status::Custom(
-                    Status::Accepted,
-                    Json(json!(Response::new("accepted", "user inactive")))
-                )
-            } else {
-                internal_server_error()
-            }
-        } else {
-            internal_server_error()
-        }
-    } else {
-    // Invalid token passed to us.
-        unauthorized_token()
+          client: ClientInfo,
+          audit_log: State<AuditLog>,
+          accounts: State<AccountsConfig>) -> status::Custom<Json<Value>> {
+    let purge_after = Utc::now() + Duration::days(accounts.deletion_grace_days);
+    let deletion = match deletions::schedule(&id, &purge_after, &db) {
+        Ok(d) => d,
+        Err(_) => return internal_server_error(),
+    };
+    if !users::inactivate(&id, &db) || !sessions::revoke_credentials(&id, &db) {
+        return internal_server_error();
+    }
+    audit_log.record(Event::success(audit::ACCOUNT_DELETION_REQUESTED, &client)
+                         .user(&id)
+                         .details(format!("purge after {}", deletion.purge_after)),
+                     &db);
+    status::Custom(
+        Status::Accepted,
+        Json(json!(deletion))
+    )
+}
+
+// Cancel a pending account deletion with the account's username and
+// password. Users with two factor authentication enabled are returned a
+// challenge token to exchange with a code at /restore/second-factor. The
+// user must login again afterwards.
+#[post("/restore", format="application/json", data="<message>")]
+fn restore(message: Json<UserRequest>,
+           db: Conn,
+           client: ClientInfo,
+           audit_log: State<AuditLog>,
+           keys: State<KeySet>,
+           policy: State<PasswordConfig>,
+           limits: State<ThrottleConfig>)
+           -> Result<status::Custom<Json<Value>>, TooManyRequests> {
+    let subjects = throttle_subjects(&message.0.username, &client);
+    if let Some(wait) = throttle::retry_after(&subjects, &limits, &db) {
+        return Err(TooManyRequests(wait));
+    }
+    let user = match users::get_by_username(&message.0.username, &db)
+        .or_else(|_| users::get_by_username(&policy::normalize_username(&message.0.username), &db)) {
+        Ok(u) => u,
+        Err(_) => {
+            throttle::record_failure(&subjects, &limits, &db);
+            return Ok(unauthorized());
+        },
+    };
+    let verified = match auth::verify_password(&message.0.password,
+                                               &user.password,
+                                               &user.salt,
+                                               &policy) {
+        PasswordMatch::Invalid => false,
+        _ => true,
+    };
+    if !verified {
+        throttle::record_failure(&subjects, &limits, &db);
+        audit_log.record(Event::failure(audit::ACCOUNT_DELETION_CANCELLED, &client)
+                             .user(&user.id)
+                             .username(&user.username)
+                             .details("incorrect password"),
+                         &db);
+        return Ok(unauthorized());
+    }
+    if !deletions::is_pending(&user.id, &db) {
+        return Ok(bad_request("account is not pending deletion"));
+    }
+    if totp::is_enabled(&user.id, &db) {
+        return Ok(challenge(&user.id,
+                            auth::RESTORE,
+                            "second_factor_required",
+                            "a second factor is required to restore the account",
+                            &keys));
+    }
+    Ok(cancel_deletion(&user.id, &user.username, &db, &client, &audit_log, "password"))
+}
+
+// Exchange a challenge token returned by restore and a TOTP or recovery
+// code to cancel a pending account deletion
+#[post("/restore/second-factor", format="application/json", data="<message>")]
+fn restore_second_factor(message: Json<SecondFactorRequest>,
+                         db: Conn,
+                         client: ClientInfo,
+                         audit_log: State<AuditLog>,
+                         keys: State<KeySet>,
+                         limits: State<ThrottleConfig>)
+                         -> Result<status::Custom<Json<Value>>, TooManyRequests> {
+    let challenge = match ChallengeToken::validate(&message.0.challenge_token,
+                                                   auth::RESTORE,
+                                                   &keys,
+                                                   &db) {
+        Some(c) => c,
+        None => return Ok(unauthorized_token()),
+    };
+    let user_id = match Uuid::parse_str(&challenge.sub) {
+        Ok(id) => id,
+        Err(_) => return Ok(unauthorized_token()),
+    };
+    // The account is inactive while its deletion is pending
+    let user = match account_users::get(&user_id, &db) {
+        Ok(u) => u,
+        Err(_) => return Ok(unauthorized_token()),
+    };
+    let subjects = throttle_subjects(&user.username, &client);
+    if let Some(wait) = throttle::retry_after(&subjects, &limits, &db) {
+        return Err(TooManyRequests(wait));
+    }
+    let second_factor = match totp::get_by_user_id(&user.id, &db) {
+        Ok(t) => t,
+        Err(_) => return Ok(unauthorized_token()),
+    };
+    if !second_factor.confirmed {
+        return Ok(unauthorized_token());
+    }
+    let verified = match verify_second_factor(&user.id, &second_factor, &message.0, &db) {
+        Some(v) => v,
+        None => return Ok(bad_request("code or recovery_code is required")),
+    };
+    if !verified {
+        throttle::record_failure(&subjects, &limits, &db);
+        audit_log.record(Event::failure(audit::ACCOUNT_DELETION_CANCELLED, &client)
+                             .user(&user.id)
+                             .username(&user.username)
+                             .details("incorrect code"),
+                         &db);
+        return Ok(status::Custom(
+            Status::Unauthorized,
+            Json(json!(Response::new("error", "code is incorrect")))
+        ));
+    }
+    if !challenge.redeem(&db) {
+        return Ok(unauthorized_token());
+    }
+    let method = if message.0.code.is_some() { "totp" } else { "recovery code" };
+    Ok(cancel_deletion(&user.id, &user.username, &db, &client, &audit_log, method))
+}
+
+// Failures for the username are only cleared once the deletion has been
+// cancelled, so restore cannot be used to reset the login limits
+fn cancel_deletion(user_id: &Uuid,
+                   username: &str,
+                   db: &Conn,
+                   client: &ClientInfo,
+                   audit_log: &AuditLog,
+                   method: &str) -> status::Custom<Json<Value>> {
+    if !deletions::cancel(user_id, db) {
+        return bad_request("account is not pending deletion");
     }
+    if !account_users::set_active(user_id, true, db) {
+        return internal_server_error();
+    }
+    throttle::record_success(username, db);
+    audit_log.record(Event::success(audit::ACCOUNT_DELETION_CANCELLED, client)
+                         .user(user_id)
+                         .username(username)
+                         .details(method),
+                     db);
+    status::Custom(
+        Status::Ok,
+        Json(json!(Response::new("ok", "account restored")))
+    )
 }
 
 #[post("/<id>/activities", data = "<request>")]
-fn import(access_token: AccessToken,
+fn import(_auth: Scoped<ActivitiesWrite>,
           id: UUID,
           request: ActivityRequest,
           conf: State<ServerConfig>,
-          db: Conn) -> status::Custom<Json<Value>> {
-    // TODO: Notify service to process activity file
+          accounts: State<AccountsConfig>,
+          tracks: State<TrackConfig>,
+          db: Conn,
+          client: ClientInfo,
+          audit_log: State<AuditLog>) -> status::Custom<Json<Value>> {
     // TODO: Detect duplicate files
-    // Validate received token
-    if !UserToken::validate(&access_token.0, &conf.secret, &id.to_string()) {
-        // Invalid token passed
-        return unauthorized_token();
+    if !accounts.allow_unverified_upload && !email_verified(&id, &accounts, &db) {
+        file::remove_file(request.file);
+        return forbidden("email address has not been verified");
     }
     // Validate data_type. For now, support only .fit files. If user
     // passes an incorrect data type, return an error and delete temporary
@@ -203,22 +783,30 @@ fn import(access_token: AccessToken,
         );
     }
 
+    let mut buffer = Vec::new();
+    let mut tfile = File::open(&request.file.path).unwrap();
+    tfile.read_to_end(&mut buffer).unwrap();
+    let recording = match fit::decode(&buffer) {
+        Ok(r) => r,
+        Err(e) => {
+            file::remove_file(request.file);
+            return bad_request(&format!("activity file could not be read: {}", e));
+        }
+    };
+    let sport = analysis::sport(request.activity_type.as_ref().map(|s| s.as_str()), &recording);
+
     //Save file to filesystem
     let filename = format!("{}{}.{}", "act", &Utc::now().timestamp(), &request.data_type);
-    let ps = format!("{}/{}/{}", &conf.file_dir, &id.to_string(), &filename);
-    let path = Path::new(&ps);
+    let path = file::activity_path(&conf.file_dir, &id, &filename);
     file::create_dir(&path.parent().unwrap());
     let mut f = File::create(&path).unwrap();
-    let mut buffer = Vec::new();
-    let mut tfile = File::open(&request.file.path).unwrap();
-    tfile.read_to_end(&mut buffer).unwrap();
     f.write_all(&buffer).unwrap();
 
     // Remove temporary file
     file::remove_file(request.file);
 
     // Save activity to database.
-    match activities::create(
+    let activity = match activities::create(
         NewActivity {
             user_id: id.into_inner(),
             filename: path.file_name().unwrap().to_str().unwrap().to_string(),
@@ -226,33 +814,288 @@ fn import(access_token: AccessToken,
             name: request.name,
         },
         &db) {
-        Ok(activity) => {
-            status::Custom(
-                Status::Ok,
-                Json(json!(activity)),
-            )
+        Ok(activity) => activity,
+        Err(_) => return internal_server_error(),
+    };
+    audit_log.record(Event::success(audit::ACTIVITY_IMPORTED, &client)
+                         .user(&id)
+                         .details(filename),
+                     &db);
+    // The activity is kept if analysis fails; it is returned without
+    // an analysis
+    let analysis = match processing::process(&activity.id,
+                                             &id,
+                                             &sport,
+                                             &recording,
+                                             &tracks,
+                                             &db) {
+        Ok(a) => Some(a),
+        Err(e) => {
+            eprintln!("Error processing activity {}: {}", activity.id, e);
+            None
+        }
+    };
+    status::Custom(
+        Status::Ok,
+        Json(json!(ImportedActivity {
+            activity: activity,
+            analysis: analysis,
+        })),
+    )
+}
+
+fn throttle_subjects(username: &str, client: &ClientInfo) -> Vec<Subject> {
+    let mut subjects = vec![Subject::Username(username.to_string())];
+    if let Some(ip) = client.ip {
+        subjects.push(Subject::Address(ip));
+    }
+    subjects
+}
+
+// Return the user's current access token if it is still valid, otherwise
+// issue a new access token and store it.
+fn issue_access_token(user_id: &Uuid,
+                      db: &Conn,
+                      keys: &KeySet,
+                      client: &ClientInfo,
+                      audit_log: &AuditLog) -> Result<String, ()> {
+    let token = current_access_token(user_id, db, keys);
+    let event = match token {
+        Ok(_) => Event::success(audit::TOKEN_ISSUED, client),
+        Err(_) => Event::failure(audit::TOKEN_ISSUED, client),
+    };
+    audit_log.record(event.user(user_id), db);
+    token
+}
+
+fn current_access_token(user_id: &Uuid,
+                        db: &Conn,
+                        keys: &KeySet) -> Result<String, ()> {
+    // TODO: Check if user already has an access token and return it
+    // if not expired
+    match tokens::get_by_user_id(user_id, db) {
+        // If user already has access_token retrieve it from the db
+        // and return it
+        Ok(ut) => {
+            let token = String::from_utf8(ut.token).map_err(|_| ())?;
+            // Check to see if token is valid. Tokens issued before scopes
+            // were added carry none and are replaced.
+            if has_all_scopes(&token, keys, user_id, db) {
+                Ok(token)
+            // If the current user token is invalid, generate a new
+            // user token and return it
+            } else {
+                let user_token = UserToken::new(&user_id.to_string(), &scope::ALL, keys)
+                    .map_err(|_| ())?;
+                let success = tokens::update(&ut.id,
+                                             &user_token.as_bytes().to_vec(),
+                                             db);
+                if success {
+                    Ok(user_token)
+                } else {
+                    Err(())
+                }
+            }
         },
-        Err(_) => internal_server_error(),
+
+        // If user does not have an access token, then create a new
+        // access_token for the user
+        Err(_) => {
+            let user_token = UserToken::new(&user_id.to_string(), &scope::ALL, keys)
+                .map_err(|_| ())?;
+            let new_user_token = NewUserToken {
+                user_id: *user_id,
+                token: user_token.as_bytes().to_vec(),
+            };
+            let success = tokens::create(new_user_token, db);
+            if success {
+                Ok(user_token)
+            } else {
+                Err(())
+            }
+        }
     }
 }
 
-fn unauthorized() -> status::Custom<Json<Value>> {
-    status::Custom(
-        Status::Unauthorized,
-        Json(json!(Response::new("error", "username or password is incorrect")))
-    )
+fn has_all_scopes(token: &str, keys: &KeySet, user_id: &Uuid, db: &Conn) -> bool {
+    match UserToken::active_grant(token, keys, db) {
+        Some(grant) => {
+            grant.sub == user_id.to_string() && scope::ALL.iter().all(|s| grant.allows(s))
+        },
+        None => false,
+    }
 }
 
-fn unauthorized_token() -> status::Custom<Json<Value>> {
+// Response to a completed login. In session mode the access token is set
+// in the session cookie and the CSRF token is returned in its place.
+fn logged_in(user_id: Uuid,
+             username: String,
+             access_token: String,
+             session_mode: bool,
+             conf: &ServerConfig,
+             cookies: &mut Cookies) -> status::Custom<Json<Value>> {
+    if session_mode {
+        let csrf_token = session::start(cookies, access_token, conf.secure_cookies);
+        return status::Custom(
+            Status::Ok,
+            Json(json!(SessionUser {
+                user_id: user_id,
+                username: username,
+                csrf_token: csrf_token,
+            }))
+        );
+    }
     status::Custom(
-        Status::Unauthorized,
-        Json(json!(Response::new("error", "unauthorized")))
+        Status::Ok,
+        Json(json!(AuthenticatedUser {
+            user_id: user_id,
+            username: username,
+            access_token: access_token,
+        }))
     )
 }
 
-fn internal_server_error() -> status::Custom<Json<Value>> {
+fn second_factor_required(user_id: &Uuid, keys: &KeySet) -> status::Custom<Json<Value>> {
+    challenge(user_id,
+              auth::SECOND_FACTOR,
+              "second_factor_required",
+              "a second factor is required to complete login",
+              keys)
+}
+
+fn password_change_required(user_id: &Uuid, keys: &KeySet) -> status::Custom<Json<Value>> {
+    challenge(user_id,
+              auth::PASSWORD_CHANGE,
+              "password_change_required",
+              "a new password must be set to complete login",
+              keys)
+}
+
+fn challenge(user_id: &Uuid,
+             purpose: &str,
+             status: &str,
+             reason: &str,
+             keys: &KeySet) -> status::Custom<Json<Value>> {
+    match ChallengeToken::new(&user_id.to_string(), purpose, keys) {
+        Ok(challenge) => status::Custom(
+            Status::Ok,
+            Json(json!(LoginChallenge {
+                status: status.to_string(),
+                reason: reason.to_string(),
+                challenge_token: challenge,
+            }))
+        ),
+        Err(_) => internal_server_error(),
+    }
+}
+
+// Check the TOTP or recovery code sent with a second factor request. Each
+// code can only be used once. None when neither code was sent.
+fn verify_second_factor(user_id: &Uuid,
+                        second_factor: &UserTotp,
+                        message: &SecondFactorRequest,
+                        db: &Conn) -> Option<bool> {
+    match (&message.code, &message.recovery_code) {
+        (&Some(ref code), _) => {
+            Some(match otp::verify(&second_factor.secret,
+                                   code,
+                                   otp::current_step(),
+                                   second_factor.last_step) {
+                Some(step) => totp::use_step(user_id, step, db),
+                None => false,
+            })
+        },
+        (&None, &Some(ref code)) => {
+            let hash = auth::hash_token(&otp::normalize_recovery_code(code));
+            Some(totp::use_recovery_code(user_id, &hash, db))
+        },
+        (&None, &None) => None,
+    }
+}
+
+// Store a new email address for the user and send a verification email
+// to it.
+fn set_email(user_id: &Uuid,
+             email: String,
+             db: &Conn,
+             accounts: &AccountsConfig,
+             mailer: &Mailer) -> bool {
+    let token = auth::random_token(32);
+    let new_email = NewUserEmail {
+        user_id: *user_id,
+        email: email,
+        verification_hash: auth::hash_token(&token),
+        verification_expires: Utc::now() + Duration::hours(accounts.verification_ttl),
+    };
+    let address = new_email.email.clone();
+    if !emails::upsert(new_email, db) {
+        return false;
+    }
+    send_verification(&address, &token, accounts, mailer);
+    true
+}
+
+fn send_verification(address: &str,
+                     token: &str,
+                     accounts: &AccountsConfig,
+                     mailer: &Mailer) {
+    let body = format!("Please verify your email address by visiting the \
+                        following link:\n\n{}?token={}\n\n\
+                        The link expires in {} hours.",
+                       accounts.verification_url,
+                       token,
+                       accounts.verification_ttl);
+    let message = Message::new(address, "Verify your email address", &body[..]);
+    // A failed delivery is not fatal. The user can request another
+    // verification email.
+    if let Err(e) = mailer.send(&message) {
+        eprintln!("Error sending verification email: {}", e);
+    }
+}
+
+fn rehash_password(user_id: &Uuid, password: &str, policy: &PasswordConfig, db: &Conn) {
+    // Failing to rehash does not fail the login. The password will be
+    // rehashed on the next successful login instead.
+    match auth::hash_password(password, policy) {
+        Ok(hash) => {
+            if !passwords::update(user_id, hash.as_bytes(), db) {
+                eprintln!("Error updating password hash for user {}", user_id);
+            }
+        },
+        Err(e) => eprintln!("Error rehashing password for user {}: {}", user_id, e),
+    }
+}
+
+// Users without an email address are considered verified unless an email
+// address is required. Database errors are treated as unverified.
+fn email_verified(user_id: &Uuid, accounts: &AccountsConfig, db: &Conn) -> bool {
+    match emails::get_by_user_id(user_id, db) {
+        Ok(e) => e.verified,
+        Err(Error::NotFound) => !accounts.require_email,
+        Err(_) => false,
+    }
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn valid_email(email: &str) -> bool {
+    if email.len() > 254 || email.chars().any(|c| c.is_whitespace()) {
+        return false;
+    }
+    match email.rfind('@') {
+        Some(idx) => {
+            let (local, domain) = email.split_at(idx);
+            !local.is_empty() && domain.len() > 1 && domain[1..].contains('.')
+        },
+        None => false,
+    }
+}
+
+fn unauthorized() -> status::Custom<Json<Value>> {
     status::Custom(
-        Status::InternalServerError,
-        Json(json!(Response::new("error", "internal server error")))
+        Status::Unauthorized,
+        Json(json!(Response::new("error", "username or password is incorrect")))
     )
 }
@@ -0,0 +1,102 @@
+use rocket::response::status;
+use rocket::http::Status;
+
+use rocket_contrib::{Json, Value, UUID};
+
+use chrono::Utc;
+
+use analysis::SPORTS;
+use db::Conn;
+use models::profiles;
+use models::training_zones::{self, Zone};
+use policy::FieldError;
+use scope::{ActivitiesRead, ProfileWrite, Scoped};
+use zones;
+use super::{Response, bad_request, internal_server_error, validation_failed};
+
+#[derive(Deserialize)]
+struct ZonesRequest {
+    zones: Vec<Zone>,
+}
+
+#[derive(Serialize)]
+struct SportZones {
+    sport: &'static str,
+    kind: &'static str,
+    // False when the zones are computed from the profile
+    manual: bool,
+    zones: Vec<Zone>,
+}
+
+// Zones in effect today for every sport
+#[get("/<id>/zones")]
+fn list(_auth: Scoped<ActivitiesRead>,
+        id: UUID,
+        db: Conn) -> status::Custom<Json<Value>> {
+    let physiology = match profiles::history(&id, &db) {
+        Ok(h) => h.physiology_on(&Utc::today().naive_utc()),
+        Err(_) => return internal_server_error(),
+    };
+    let mut all = Vec::new();
+    for sport in SPORTS.iter() {
+        for kind in zones::kinds(sport) {
+            match zones::effective(&id, sport, kind, &physiology, &db) {
+                Ok((z, manual)) => all.push(SportZones {
+                    sport: *sport,
+                    kind: kind,
+                    manual: manual,
+                    zones: z,
+                }),
+                Err(_) => return internal_server_error(),
+            }
+        }
+    }
+    status::Custom(
+        Status::Ok,
+        Json(json!(all))
+    )
+}
+
+// Set zones manually. Zones are numbered in the order given.
+#[put("/<id>/zones/<sport>/<kind>", format = "application/json", data = "<message>")]
+fn update(_auth: Scoped<ProfileWrite>,
+          id: UUID,
+          sport: String,
+          kind: String,
+          message: Json<ZonesRequest>,
+          db: Conn) -> status::Custom<Json<Value>> {
+    if !SPORTS.iter().any(|s| *s == sport) || !zones::applies(&sport, &kind) {
+        return bad_request("zones of this kind are not used for this sport");
+    }
+    if let Err(e) = zones::check(&message.zones) {
+        return validation_failed(vec![FieldError::new("zones", "invalid", e)]);
+    }
+    if training_zones::set(&id, &sport, &kind, &message.zones, &db) {
+        status::Custom(
+            Status::Ok,
+            Json(json!(message.0.zones))
+        )
+    } else {
+        internal_server_error()
+    }
+}
+
+// Go back to zones computed from the profile
+#[delete("/<id>/zones/<sport>/<kind>")]
+fn delete(_auth: Scoped<ProfileWrite>,
+          id: UUID,
+          sport: String,
+          kind: String,
+          db: Conn) -> status::Custom<Json<Value>> {
+    if training_zones::delete(&id, &sport, &kind, &db) {
+        status::Custom(
+            Status::Ok,
+            Json(json!(Response::new("success", "zones reset")))
+        )
+    } else {
+        status::Custom(
+            Status::NotFound,
+            Json(json!(Response::new("error", "no manual zones set")))
+        )
+    }
+}
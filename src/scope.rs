@@ -0,0 +1,165 @@
+// Scopes limit what an access token or API key can be used for. Access
+// tokens issued by login carry every scope; API keys carry the scopes
+// chosen when they are created.
+//
+// Routes require a scope by taking a Scoped<S> guard in place of
+// Principal. The guard checks the token was issued for the user id in
+// the first dynamic segment of the route, failing with 401 Unauthorized,
+// and that it grants the scope, failing with 403 Forbidden.
+use std::marker::PhantomData;
+
+use rocket::request::{self, FromRequest};
+use rocket::{Request, Outcome};
+
+use rocket_contrib::UUID;
+
+use uuid::Uuid;
+
+use auth::{self, AuthFailure, Principal};
+use client::ClientInfo;
+use db::Conn;
+use models::roles;
+
+pub const ACTIVITIES_READ: &'static str = "activities:read";
+pub const ACTIVITIES_WRITE: &'static str = "activities:write";
+pub const PROFILE_WRITE: &'static str = "profile:write";
+pub const ACCOUNT_DELETE: &'static str = "account:delete";
+// Changing the email address and managing two factor authentication and
+// API keys. Never granted to API keys or OAuth clients so a leaked key or
+// a third party app cannot create further keys or take over the account
+// recovery address.
+pub const ACCOUNT_SECURITY: &'static str = "account:security";
+
+pub const ALL: [&'static str; 5] = [
+    ACTIVITIES_READ,
+    ACTIVITIES_WRITE,
+    PROFILE_WRITE,
+    ACCOUNT_DELETE,
+    ACCOUNT_SECURITY,
+];
+
+// Scopes given to API keys created without any scopes
+pub const API_KEY_DEFAULT: [&'static str; 2] = [ACTIVITIES_READ, ACTIVITIES_WRITE];
+
+// Scopes third party applications can request through OAuth
+pub const OAUTH: [&'static str; 3] = [ACTIVITIES_READ, ACTIVITIES_WRITE, PROFILE_WRITE];
+
+pub fn is_known(scope: &str) -> bool {
+    ALL.iter().any(|s| *s == scope)
+}
+
+// Scopes are stored and sent space separated, as in OAuth 2.0
+pub fn parse(scopes: &str) -> Vec<String> {
+    scopes.split_whitespace().map(|s| s.to_string()).collect()
+}
+
+pub fn join<S: AsRef<str>>(scopes: &[S]) -> String {
+    scopes.iter().map(|s| s.as_ref()).collect::<Vec<&str>>().join(" ")
+}
+
+pub trait Scope {
+    const NAME: &'static str;
+}
+
+pub struct ActivitiesRead;
+pub struct ActivitiesWrite;
+pub struct ProfileWrite;
+pub struct AccountDelete;
+pub struct AccountSecurity;
+
+impl Scope for ActivitiesRead {
+    const NAME: &'static str = ACTIVITIES_READ;
+}
+
+impl Scope for ActivitiesWrite {
+    const NAME: &'static str = ACTIVITIES_WRITE;
+}
+
+impl Scope for ProfileWrite {
+    const NAME: &'static str = PROFILE_WRITE;
+}
+
+impl Scope for AccountDelete {
+    const NAME: &'static str = ACCOUNT_DELETE;
+}
+
+impl Scope for AccountSecurity {
+    const NAME: &'static str = ACCOUNT_SECURITY;
+}
+
+pub struct Scoped<S: Scope> {
+    pub principal: Principal,
+    scope: PhantomData<S>,
+}
+
+impl<'a, 'r, S: Scope> FromRequest<'a, 'r> for Scoped<S> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Scoped<S>, ()> {
+        let principal = request.guard::<Principal>()?;
+        let id = match request.get_param::<UUID>(0) {
+            Ok(id) => id,
+            Err(_) => return auth::fail(AuthFailure::WrongUser),
+        };
+        if principal.user_id != *id {
+            return auth::fail(AuthFailure::WrongUser);
+        }
+        if !principal.allows(S::NAME) {
+            return auth::fail(AuthFailure::InsufficientScope(S::NAME));
+        }
+        Outcome::Success(Scoped {
+            principal: principal,
+            scope: PhantomData,
+        })
+    }
+}
+
+// Guard for routes acting for the logged in user that do not have a user
+// id in the path. Requires an access token issued by login; API keys and
+// tokens issued to OAuth clients are not accepted.
+pub struct Session {
+    pub user_id: Uuid,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Session {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Session, ()> {
+        let principal = request.guard::<Principal>()?;
+        // Only access tokens issued by login carry account:security
+        if principal.api_key || !principal.allows(ACCOUNT_SECURITY) {
+            return auth::fail(AuthFailure::LoginRequired);
+        }
+        Outcome::Success(Session { user_id: principal.user_id })
+    }
+}
+
+// Guard for routes under /admin. Requires an access token issued by
+// login, not an API key or a token issued to an OAuth client, for a user
+// with the admin role. The role is checked on every
+// request so removing it takes effect immediately.
+pub struct Admin {
+    pub user_id: Uuid,
+    // Recorded with each admin action in the audit log
+    pub client: ClientInfo,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+        let principal = request.guard::<Principal>()?;
+        let db = request.guard::<Conn>()?;
+        if principal.api_key || principal.client_id.is_some() || !principal.allows(ACCOUNT_SECURITY) {
+            return auth::fail(AuthFailure::LoginRequired);
+        }
+        if !roles::has_role(&principal.user_id, roles::ADMIN, &db) {
+            return auth::fail(AuthFailure::AdminRequired);
+        }
+        let client = request.guard::<ClientInfo>()?;
+        Outcome::Success(Admin {
+            user_id: principal.user_id,
+            client: client,
+        })
+    }
+}
@@ -0,0 +1,87 @@
+// Cookie sessions for browser clients. Instead of returning the access
+// token, login can store it in a private session cookie, encrypted and
+// signed with a key derived from the server secret, so the front end
+// never handles the token itself.
+//
+// Cookies are sent by the browser with every request, so state changing
+// requests authenticated by the session cookie must also carry a CSRF
+// token. Login sets the token in a cookie readable by the front end,
+// which echoes it in the X-CSRF-Token header (double submit).
+use chrono::Duration;
+
+use rocket::Request;
+use rocket::http::{Cookie, Cookies, Method, SameSite};
+
+use base64;
+use ring::{constant_time, digest};
+
+use auth::{self, ACCESS_TOKEN_LIFETIME};
+
+pub const SESSION_COOKIE: &'static str = "hapi_session";
+pub const CSRF_COOKIE: &'static str = "hapi_csrf";
+pub const CSRF_HEADER: &'static str = "X-CSRF-Token";
+
+const CSRF_TOKEN_LENGTH: usize = 32;
+
+// Store the access token in the session cookie and set a new CSRF token.
+// Returns the CSRF token.
+pub fn start(cookies: &mut Cookies, access_token: String, secure: bool) -> String {
+    let csrf_token = auth::random_token(CSRF_TOKEN_LENGTH);
+    cookies.add_private(Cookie::build(SESSION_COOKIE, access_token)
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Strict)
+        .max_age(Duration::seconds(ACCESS_TOKEN_LIFETIME))
+        .finish());
+    // Read by the front end, so not HttpOnly
+    cookies.add(Cookie::build(CSRF_COOKIE, csrf_token.clone())
+        .path("/")
+        .secure(secure)
+        .same_site(SameSite::Strict)
+        .max_age(Duration::seconds(ACCESS_TOKEN_LIFETIME))
+        .finish());
+    csrf_token
+}
+
+pub fn end(cookies: &mut Cookies) {
+    cookies.remove_private(Cookie::build(SESSION_COOKIE, "").path("/").finish());
+    cookies.remove(Cookie::build(CSRF_COOKIE, "").path("/").finish());
+}
+
+// Safe methods do not change state and never need a CSRF token
+pub fn requires_csrf(request: &Request) -> bool {
+    match request.method() {
+        Method::Get | Method::Head | Method::Options => false,
+        _ => true,
+    }
+}
+
+// The CSRF header must match the CSRF cookie
+pub fn csrf_valid(request: &Request) -> bool {
+    let header = match request.headers().get_one(CSRF_HEADER) {
+        Some(h) if !h.is_empty() => h.to_string(),
+        _ => return false,
+    };
+    match request.cookies().get(CSRF_COOKIE) {
+        Some(cookie) => {
+            constant_time::verify_slices_are_equal(cookie.value().as_bytes(),
+                                                   header.as_bytes()).is_ok()
+        },
+        None => false,
+    }
+}
+
+// Key used by Rocket to encrypt and sign private cookies, derived from the
+// server secret so sessions survive restarts and are shared between
+// servers. Returns None when no secret is configured, in which case Rocket
+// generates a random key at launch.
+pub fn cookie_key(secret: &str) -> Option<String> {
+    if secret.is_empty() {
+        return None;
+    }
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(b"hapi session cookie key");
+    context.update(secret.as_bytes());
+    Some(base64::encode(context.finish().as_ref()))
+}
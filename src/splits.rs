@@ -0,0 +1,161 @@
+// Laps recorded by the device and automatic splits by distance or time,
+// each with metrics computed from the samples it covers. Splits are
+// computed when they are requested; device laps are stored at import.
+use analysis;
+use fit::{Recording, Sample};
+use models::laps::Lap;
+
+pub const KILOMETRE: f64 = 1000.0;
+pub const MILE: f64 = 1609.344;
+
+pub enum Split {
+    // Metres
+    Distance(f64),
+    // Seconds
+    Time(f64),
+}
+
+pub fn device_laps(recording: &Recording) -> Vec<Lap> {
+    let samples = &recording.samples;
+    let distances = analysis::distances(samples);
+    let mut laps = Vec::new();
+    for lap in &recording.laps {
+        let from = samples.iter().position(|s| s.time >= lap.start);
+        let to = samples.iter().rposition(|s| s.time <= lap.end);
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) if from <= to => (from, to),
+            _ => continue,
+        };
+        let index = laps.len() as i64;
+        laps.push(metrics(index, lap.start, lap.end, &samples[from..to + 1], &distances[from..to + 1]));
+    }
+    laps
+}
+
+// Splits each end at the first sample reaching the next multiple of the
+// split. The last split is shorter unless the activity ends exactly on a
+// boundary.
+pub fn splits(samples: &[Sample], split: &Split) -> Vec<Lap> {
+    let distances = analysis::distances(samples);
+    let (size, positions) = match *split {
+        Split::Distance(d) => (d, distances.clone()),
+        Split::Time(t) => (t, samples.iter().map(|s| s.time).collect::<Vec<f64>>()),
+    };
+    let mut laps = Vec::new();
+    if samples.is_empty() || size <= 0.0 {
+        return laps;
+    }
+    let mut from = 0;
+    let mut boundary = size;
+    for i in 1..samples.len() {
+        if positions[i] < boundary && i < samples.len() - 1 {
+            continue;
+        }
+        if positions[i] > positions[from] {
+            let index = laps.len() as i64;
+            laps.push(metrics(index,
+                              samples[from].time,
+                              samples[i].time,
+                              &samples[from..i + 1],
+                              &distances[from..i + 1]));
+        }
+        from = i;
+        while positions[i] >= boundary {
+            boundary += size;
+        }
+    }
+    laps
+}
+
+fn metrics(index: i64, start: f64, end: f64, samples: &[Sample], distances: &[f64]) -> Lap {
+    let (avg_heart_rate, max_heart_rate) = analysis::avg_max(samples, |s| s.heart_rate);
+    let (avg_power, max_power) = analysis::avg_max(samples, |s| s.power);
+    let (avg_cadence, _) = analysis::avg_max(samples, |s| s.cadence);
+    let (_, max_speed) = analysis::avg_max(samples, |s| s.speed);
+    let elapsed_time = end - start;
+    let measured = samples.iter().any(|s| s.distance.is_some() || s.lat.is_some());
+    let distance = match (distances.first(), distances.last()) {
+        (Some(first), Some(last)) if measured => Some(last - first),
+        _ => None,
+    };
+    Lap {
+        index: index,
+        start: start,
+        elapsed_time: elapsed_time,
+        distance: distance,
+        avg_speed: distance.and_then(|d| {
+            if elapsed_time > 0.0 { Some(d / elapsed_time) } else { None }
+        }),
+        max_speed: max_speed,
+        avg_heart_rate: avg_heart_rate,
+        max_heart_rate: max_heart_rate,
+        avg_power: avg_power,
+        max_power: max_power,
+        avg_cadence: avg_cadence,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use fit;
+    use super::*;
+
+    // 2.5 km at 10 metres a second
+    fn samples() -> Vec<Sample> {
+        (0..251).map(|t| Sample {
+            time: t as f64,
+            distance: Some(t as f64 * 10.0),
+            heart_rate: Some(if t < 100 { 130.0 } else { 150.0 }),
+            ..Default::default()
+        }).collect()
+    }
+
+    #[test]
+    fn distance_splits_end_at_each_kilometre() {
+        let laps = splits(&samples(), &Split::Distance(KILOMETRE));
+        let bounds: Vec<(i64, f64, f64, Option<f64>)> = laps.iter()
+            .map(|l| (l.index, l.start, l.elapsed_time, l.distance))
+            .collect();
+        assert_eq!(bounds, vec![
+            (0, 0.0, 100.0, Some(1000.0)),
+            (1, 100.0, 100.0, Some(1000.0)),
+            (2, 200.0, 50.0, Some(500.0)),
+        ]);
+        assert_eq!(laps[0].avg_speed, Some(10.0));
+        assert_eq!(laps[1].max_heart_rate, Some(150.0));
+    }
+
+    #[test]
+    fn activity_ending_on_a_boundary_has_no_short_split() {
+        let samples: Vec<Sample> = samples().into_iter().take(201).collect();
+        let laps = splits(&samples, &Split::Distance(KILOMETRE));
+        assert_eq!(laps.len(), 2);
+        assert_eq!(laps[1].distance, Some(1000.0));
+    }
+
+    #[test]
+    fn time_splits() {
+        let laps = splits(&samples(), &Split::Time(60.0));
+        let starts: Vec<f64> = laps.iter().map(|l| l.start).collect();
+        assert_eq!(starts, vec![0.0, 60.0, 120.0, 180.0, 240.0]);
+        assert_eq!(laps[4].elapsed_time, 10.0);
+        assert_eq!(laps[4].distance, Some(100.0));
+    }
+
+    #[test]
+    fn device_laps_cover_their_samples() {
+        let recording = Recording {
+            sport: None,
+            start_time: Utc.ymd(2018, 3, 1).and_hms(8, 0, 0),
+            samples: samples(),
+            laps: vec![fit::Lap { start: 0.0, end: 120.0 }, fit::Lap { start: 120.0, end: 250.0 }],
+        };
+        let laps = device_laps(&recording);
+        assert_eq!(laps.len(), 2);
+        assert_eq!(laps[0].distance, Some(1200.0));
+        assert_eq!(laps[1].distance, Some(1300.0));
+        assert_eq!(laps[1].elapsed_time, 130.0);
+    }
+}
@@ -0,0 +1,220 @@
+// Sample streams of an activity for charts and maps. Each stream is
+// returned as columns: the time of each point in seconds from the start
+// and its value. Streams longer than the requested resolution are
+// downsampled, series with Largest-Triangle-Three-Buckets so peaks are
+// kept, and the track by distance so points are spread evenly along the
+// route.
+use analysis;
+use fit::Sample;
+
+pub const TIME: &'static str = "time";
+pub const LATLNG: &'static str = "latlng";
+pub const ALTITUDE: &'static str = "altitude";
+pub const HEART_RATE: &'static str = "heartrate";
+pub const POWER: &'static str = "watts";
+pub const CADENCE: &'static str = "cadence";
+pub const SPEED: &'static str = "speed";
+pub const DISTANCE: &'static str = "distance";
+
+pub const KEYS: [&'static str; 8] = [
+    TIME,
+    LATLNG,
+    ALTITUDE,
+    HEART_RATE,
+    POWER,
+    CADENCE,
+    SPEED,
+    DISTANCE,
+];
+
+// Largest number of points in each stream at each resolution
+pub fn max_points(resolution: &str) -> Option<usize> {
+    match resolution {
+        "low" => Some(100),
+        "medium" => Some(1000),
+        "high" => Some(10000),
+        _ => None,
+    }
+}
+
+#[derive(Serialize)]
+pub struct Stream {
+    pub time: Vec<f64>,
+    // Numbers, or [lat, lng] pairs for latlng
+    pub data: Vec<Vec<f64>>,
+    // Number of points before downsampling
+    pub original_size: usize,
+}
+
+pub fn value(key: &str, sample: &Sample) -> Option<f64> {
+    match key {
+        TIME => Some(sample.time),
+        ALTITUDE => sample.altitude,
+        HEART_RATE => sample.heart_rate,
+        POWER => sample.power,
+        CADENCE => sample.cadence,
+        SPEED => sample.speed,
+        DISTANCE => sample.distance,
+        _ => None,
+    }
+}
+
+// The stream for key, or None if no sample has a value for it
+pub fn stream(key: &str, samples: &[Sample], max_points: usize) -> Option<Stream> {
+    if key == LATLNG {
+        let points: Vec<(f64, (f64, f64))> = samples.iter()
+            .filter_map(|s| match (s.lat, s.lng) {
+                (Some(lat), Some(lng)) => Some((s.time, (lat, lng))),
+                _ => None,
+            })
+            .collect();
+        if points.is_empty() {
+            return None;
+        }
+        let track: Vec<(f64, f64)> = points.iter().map(|p| p.1).collect();
+        let indices = by_distance(&track, max_points);
+        return Some(Stream {
+            time: indices.iter().map(|i| points[*i].0).collect(),
+            data: indices.iter().map(|i| vec![points[*i].1 .0, points[*i].1 .1]).collect(),
+            original_size: points.len(),
+        });
+    }
+    let points: Vec<(f64, f64)> = samples.iter()
+        .filter_map(|s| value(key, s).map(|v| (s.time, v)))
+        .collect();
+    if points.is_empty() {
+        return None;
+    }
+    let indices = lttb(&points, max_points);
+    Some(Stream {
+        time: indices.iter().map(|i| points[*i].0).collect(),
+        data: indices.iter().map(|i| vec![points[*i].1]).collect(),
+        original_size: points.len(),
+    })
+}
+
+// Indices of the points kept by Largest-Triangle-Three-Buckets. The first
+// and last points are always kept; the points between are split into
+// buckets and the point of each bucket forming the largest triangle with
+// the previous kept point and the average of the next bucket is kept.
+pub fn lttb(points: &[(f64, f64)], threshold: usize) -> Vec<usize> {
+    let n = points.len();
+    if threshold >= n || threshold < 3 {
+        return (0..n).collect();
+    }
+    let mut kept = Vec::with_capacity(threshold);
+    kept.push(0);
+    let every = (n - 2) as f64 / (threshold - 2) as f64;
+    let mut a = 0;
+    for bucket in 0..threshold - 2 {
+        let start = (bucket as f64 * every) as usize + 1;
+        let end = (((bucket + 1) as f64 * every) as usize + 1).min(n - 1);
+        // Average of the next bucket, or the last point for the last bucket
+        let next_start = end;
+        let next_end = (((bucket + 2) as f64 * every) as usize + 1).min(n);
+        let next = &points[next_start..next_end.max(next_start + 1)];
+        let avg_x = next.iter().map(|p| p.0).sum::<f64>() / next.len() as f64;
+        let avg_y = next.iter().map(|p| p.1).sum::<f64>() / next.len() as f64;
+
+        let (ax, ay) = points[a];
+        let mut best = start;
+        let mut best_area = -1.0;
+        for i in start..end.max(start + 1) {
+            let (bx, by) = points[i];
+            let area = ((ax - avg_x) * (by - ay) - (ax - bx) * (avg_y - ay)).abs();
+            if area > best_area {
+                best_area = area;
+                best = i;
+            }
+        }
+        kept.push(best);
+        a = best;
+    }
+    kept.push(n - 1);
+    kept
+}
+
+// Indices of track points at least total distance / threshold apart. The
+// first and last points are always kept.
+pub fn by_distance(track: &[(f64, f64)], threshold: usize) -> Vec<usize> {
+    let n = track.len();
+    if threshold >= n || threshold < 2 {
+        return (0..n).collect();
+    }
+    let total: f64 = track.windows(2).map(|w| analysis::haversine(w[0], w[1])).sum();
+    let spacing = total / (threshold - 1) as f64;
+    let mut kept = vec![0];
+    let mut travelled = 0.0;
+    for i in 1..n - 1 {
+        travelled += analysis::haversine(track[i - 1], track[i]);
+        if travelled >= spacing {
+            kept.push(i);
+            travelled = 0.0;
+        }
+    }
+    kept.push(n - 1);
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(n: usize) -> Vec<(f64, f64)> {
+        (0..n).map(|i| (i as f64, (i as f64 / 10.0).sin())).collect()
+    }
+
+    #[test]
+    fn lttb_keeps_threshold_points_including_the_ends() {
+        for &(n, threshold) in &[(1000, 100), (1000, 3), (101, 100), (250, 7)] {
+            let kept = lttb(&series(n), threshold);
+            assert_eq!(kept.len(), threshold);
+            assert_eq!(kept[0], 0);
+            assert_eq!(kept[threshold - 1], n - 1);
+            assert!(kept.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn lttb_keeps_peaks() {
+        let mut points: Vec<(f64, f64)> = (0..1000).map(|i| (i as f64, 0.0)).collect();
+        points[437].1 = 100.0;
+        points[813].1 = -50.0;
+        let kept = lttb(&points, 20);
+        assert!(kept.contains(&437));
+        assert!(kept.contains(&813));
+    }
+
+    #[test]
+    fn lttb_keeps_short_series() {
+        assert_eq!(lttb(&series(10), 10), (0..10).collect::<Vec<usize>>());
+        assert_eq!(lttb(&series(10), 100), (0..10).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn track_is_downsampled_by_distance() {
+        // Evenly spaced points along a meridian
+        let track: Vec<(f64, f64)> = (0..1000).map(|i| (i as f64 * 0.0001, 0.0)).collect();
+        let kept = by_distance(&track, 100);
+        assert_eq!(kept[0], 0);
+        assert_eq!(kept[kept.len() - 1], 999);
+        assert!(kept.len() <= 101);
+        assert!(kept.len() >= 90);
+    }
+
+    #[test]
+    fn stream_reports_original_size() {
+        let samples: Vec<Sample> = (0..500).map(|t| Sample {
+            time: t as f64,
+            heart_rate: if t % 2 == 0 { Some(140.0) } else { None },
+            ..Default::default()
+        }).collect();
+        let heart_rate = stream(HEART_RATE, &samples, 100).unwrap();
+        assert_eq!(heart_rate.original_size, 250);
+        assert_eq!(heart_rate.time.len(), 100);
+        assert_eq!(heart_rate.data.len(), 100);
+        assert_eq!(heart_rate.time[0], 0.0);
+        assert_eq!(heart_rate.time[99], 498.0);
+        assert!(stream(POWER, &samples, 100).is_none());
+    }
+}
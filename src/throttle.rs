@@ -0,0 +1,109 @@
+// Tracking of failed login attempts. Each failure increases the time a
+// client must wait before attempting to login again, and too many
+// failures lock the username or address out for a period of time. Failed
+// attempts are stored in the database so limits apply across all hapi
+// instances.
+use std::cmp;
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+
+use hdb::platform::PlatformConnection;
+
+use config::ThrottleConfig;
+use models::login_failures;
+
+pub enum Subject {
+    Username(String),
+    Address(IpAddr),
+}
+
+impl Subject {
+    pub fn key(&self) -> String {
+        match *self {
+            Subject::Username(ref u) => format!("user:{}", u.to_lowercase()),
+            Subject::Address(ref a) => format!("ip:{}", a),
+        }
+    }
+
+    // Many users may share an address, so addresses get a higher limit
+    fn lockout_threshold(&self, config: &ThrottleConfig) -> i64 {
+        match *self {
+            Subject::Username(_) => config.lockout_threshold,
+            Subject::Address(_) => config.ip_lockout_threshold,
+        }
+    }
+}
+
+// Returns the number of seconds until any of the subjects may attempt to
+// login again, or None if a login attempt is allowed.
+pub fn retry_after(subjects: &[Subject],
+                   config: &ThrottleConfig,
+                   conn: &PlatformConnection) -> Option<i64> {
+    let now = Utc::now();
+    let mut wait = 0;
+    for subject in subjects {
+        if let Ok(f) = login_failures::get(&subject.key(), conn) {
+            let until = blocked_until(f.failures,
+                                      &f.last_failure,
+                                      subject.lockout_threshold(config),
+                                      config);
+            wait = cmp::max(wait, until.signed_duration_since(now).num_seconds());
+        }
+    }
+    if wait > 0 {
+        Some(wait)
+    } else {
+        None
+    }
+}
+
+// Failures are recorded for usernames that do not exist as well, so the
+// response does not reveal which usernames exist. Failures that can no
+// longer delay or lock out a login are removed at the same time so the
+// table does not grow without bound.
+pub fn record_failure(subjects: &[Subject],
+                      config: &ThrottleConfig,
+                      conn: &PlatformConnection) {
+    let now = Utc::now();
+    let reset_before = now - Duration::seconds(config.reset_after);
+    let expired = now - Duration::seconds(cmp::max(config.reset_after,
+                                                   cmp::max(config.lockout_duration,
+                                                            config.max_delay)));
+    if !login_failures::delete_before(&expired, conn) {
+        eprintln!("Error removing expired login failures");
+    }
+    for subject in subjects {
+        if !login_failures::increment(&subject.key(), &reset_before, conn) {
+            eprintln!("Error recording failed login for {}", subject.key());
+        }
+    }
+}
+
+// A successful login clears failures for the username. Failures for the
+// address are kept so one valid account cannot be used to reset the
+// limit while guessing passwords for others.
+pub fn record_success(username: &str, conn: &PlatformConnection) {
+    let _ = login_failures::delete(&Subject::Username(username.to_string()).key(), conn);
+}
+
+pub fn unlock(subject: &Subject, conn: &PlatformConnection) -> bool {
+    login_failures::delete(&subject.key(), conn)
+}
+
+fn blocked_until(failures: i64,
+                 last_failure: &DateTime<Utc>,
+                 lockout_threshold: i64,
+                 config: &ThrottleConfig) -> DateTime<Utc> {
+    if failures >= lockout_threshold {
+        return *last_failure + Duration::seconds(config.lockout_duration);
+    }
+    if failures <= config.free_attempts {
+        return *last_failure;
+    }
+    // Delay doubles with each failure after the free attempts
+    let exponent = cmp::min(failures - config.free_attempts - 1, 30) as u32;
+    let delay = cmp::min(config.base_delay.saturating_mul(2i64.pow(exponent)),
+                         config.max_delay);
+    *last_failure + Duration::seconds(delay)
+}
@@ -0,0 +1,277 @@
+// Training zones. Zones are computed from the athlete's profile unless the
+// user has set them manually for the sport:
+//
+//   heart_rate  five zones from threshold heart rate, or from maximum heart
+//               rate when threshold heart rate is not set
+//   power       Coggan's seven zones from FTP
+//   pace        five zones from threshold pace, in seconds per kilometre
+use uuid::Uuid;
+
+use hdb::platform::PlatformConnection;
+
+use analysis::{self, CYCLING, MAX_SAMPLE_GAP, RUNNING};
+use fit::Sample;
+use models::Error;
+use models::profiles::Physiology;
+use models::training_zones::{self, Zone, ZoneHistogram, ZoneTime};
+
+pub const HEART_RATE: &'static str = "heart_rate";
+pub const POWER: &'static str = "power";
+pub const PACE: &'static str = "pace";
+
+pub const KINDS: [&'static str; 3] = [HEART_RATE, POWER, PACE];
+
+// Zone bounds as fractions of the threshold value
+const LTHR_ZONES: [f64; 4] = [0.68, 0.83, 0.94, 1.05];
+const MAX_HR_ZONES: [f64; 4] = [0.6, 0.7, 0.8, 0.9];
+const POWER_ZONES: [f64; 6] = [0.55, 0.75, 0.90, 1.05, 1.20, 1.50];
+// Slower paces are larger, so these run from the easiest zone down
+const PACE_ZONES: [f64; 4] = [1.29, 1.14, 1.06, 0.99];
+
+// Kinds of zones that apply to a sport
+pub fn kinds(sport: &str) -> Vec<&'static str> {
+    match sport {
+        CYCLING => vec![HEART_RATE, POWER],
+        RUNNING => vec![HEART_RATE, PACE],
+        _ => vec![HEART_RATE],
+    }
+}
+
+pub fn applies(sport: &str, kind: &str) -> bool {
+    kinds(sport).iter().any(|k| *k == kind)
+}
+
+// The user's manual zones for the sport, or the zones computed from the
+// physiological values if there are none. Returns whether the zones were
+// set manually.
+pub fn effective(user_id: &Uuid,
+                 sport: &str,
+                 kind: &str,
+                 physiology: &Physiology,
+                 conn: &PlatformConnection) -> Result<(Vec<Zone>, bool), Error> {
+    let manual = training_zones::get(user_id, sport, kind, conn)?;
+    if manual.is_empty() {
+        Ok((automatic(kind, physiology), false))
+    } else {
+        Ok((manual, true))
+    }
+}
+
+// Zones computed from the physiological values, empty when the values
+// they are based on are not set
+pub fn automatic(kind: &str, physiology: &Physiology) -> Vec<Zone> {
+    match kind {
+        HEART_RATE => match (physiology.threshold_hr, physiology.max_hr) {
+            (Some(lthr), _) => ascending(lthr, &LTHR_ZONES),
+            (None, Some(max)) => ascending(max, &MAX_HR_ZONES),
+            _ => Vec::new(),
+        },
+        POWER => physiology.ftp_watts
+            .map(|ftp| ascending(ftp, &POWER_ZONES))
+            .unwrap_or(Vec::new()),
+        PACE => physiology.threshold_pace.map(descending).unwrap_or(Vec::new()),
+        _ => Vec::new(),
+    }
+}
+
+fn ascending(threshold: f64, bounds: &[f64]) -> Vec<Zone> {
+    let mut zones = Vec::with_capacity(bounds.len() + 1);
+    let mut min = 0.0;
+    for bound in bounds {
+        let max = (threshold * bound).round();
+        zones.push(Zone { min: min, max: Some(max) });
+        min = max;
+    }
+    zones.push(Zone { min: min, max: None });
+    zones
+}
+
+fn descending(threshold: f64) -> Vec<Zone> {
+    let mut zones = Vec::with_capacity(PACE_ZONES.len() + 1);
+    let mut max = None;
+    for bound in PACE_ZONES.iter() {
+        let min = (threshold * bound).round();
+        zones.push(Zone { min: min, max: max });
+        max = Some(min);
+    }
+    zones.push(Zone { min: 0.0, max: max });
+    zones
+}
+
+// Value of a sample used for a kind of zone
+pub fn value(kind: &str, sample: &Sample) -> Option<f64> {
+    match kind {
+        HEART_RATE => sample.heart_rate,
+        POWER => sample.power,
+        PACE => analysis::pace(sample),
+        _ => None,
+    }
+}
+
+// Seconds spent in each zone. Each sample counts for the time until the
+// next sample.
+pub fn histogram(kind: &str, zones: &[Zone], samples: &[Sample]) -> ZoneHistogram {
+    let mut seconds = vec![0.0; zones.len()];
+    for pair in samples.windows(2) {
+        let v = match value(kind, &pair[0]) {
+            Some(v) => v,
+            None => continue,
+        };
+        let dt = (pair[1].time - pair[0].time).min(MAX_SAMPLE_GAP);
+        if let Some(i) = zones.iter().position(|z| z.contains(v)) {
+            seconds[i] += dt;
+        }
+    }
+    ZoneHistogram {
+        kind: kind.to_string(),
+        zones: zones.iter().zip(seconds).enumerate().map(|(i, (zone, s))| ZoneTime {
+            zone: i as i64 + 1,
+            min: zone.min,
+            max: zone.max,
+            seconds: s,
+        }).collect(),
+    }
+}
+
+// Manual zones must not overlap and each zone must have a lower bound
+// below its upper bound
+pub fn check(zones: &[Zone]) -> Result<(), String> {
+    if zones.is_empty() || zones.len() > 10 {
+        return Err("between 1 and 10 zones are required".to_string());
+    }
+    for (i, zone) in zones.iter().enumerate() {
+        if !(zone.min >= 0.0) || zone.max.map(|max| !(max > zone.min)).unwrap_or(false) {
+            return Err(format!("zone {} must have min at least 0 and below max", i + 1));
+        }
+        for other in &zones[i + 1..] {
+            let overlaps = zone.max.map(|max| other.min < max).unwrap_or(true)
+                && other.max.map(|max| zone.min < max).unwrap_or(true);
+            if overlaps {
+                return Err(format!("zone {} overlaps another zone", i + 1));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(zones: &[Zone]) -> Vec<(f64, Option<f64>)> {
+        zones.iter().map(|z| (z.min, z.max)).collect()
+    }
+
+    fn zones(bounds: &[(f64, Option<f64>)]) -> Vec<Zone> {
+        bounds.iter().map(|&(min, max)| Zone { min: min, max: max }).collect()
+    }
+
+    fn heart_rate(time: f64, heart_rate: Option<f64>) -> Sample {
+        Sample { time: time, heart_rate: heart_rate, ..Default::default() }
+    }
+
+    #[test]
+    fn heart_rate_zones_from_threshold_heart_rate() {
+        let physiology = Physiology {
+            threshold_hr: Some(170.0),
+            max_hr: Some(190.0),
+            ..Default::default()
+        };
+        assert_eq!(bounds(&automatic(HEART_RATE, &physiology)), vec![
+            (0.0, Some(116.0)),
+            (116.0, Some(141.0)),
+            (141.0, Some(160.0)),
+            (160.0, Some(179.0)),
+            (179.0, None),
+        ]);
+    }
+
+    #[test]
+    fn heart_rate_zones_from_maximum_heart_rate() {
+        let physiology = Physiology { max_hr: Some(190.0), ..Default::default() };
+        assert_eq!(bounds(&automatic(HEART_RATE, &physiology)), vec![
+            (0.0, Some(114.0)),
+            (114.0, Some(133.0)),
+            (133.0, Some(152.0)),
+            (152.0, Some(171.0)),
+            (171.0, None),
+        ]);
+    }
+
+    #[test]
+    fn power_zones_from_ftp() {
+        let physiology = Physiology { ftp_watts: Some(250.0), ..Default::default() };
+        assert_eq!(bounds(&automatic(POWER, &physiology)), vec![
+            (0.0, Some(138.0)),
+            (138.0, Some(188.0)),
+            (188.0, Some(225.0)),
+            (225.0, Some(263.0)),
+            (263.0, Some(300.0)),
+            (300.0, Some(375.0)),
+            (375.0, None),
+        ]);
+    }
+
+    #[test]
+    fn pace_zones_run_from_slowest() {
+        let physiology = Physiology { threshold_pace: Some(300.0), ..Default::default() };
+        assert_eq!(bounds(&automatic(PACE, &physiology)), vec![
+            (387.0, None),
+            (342.0, Some(387.0)),
+            (318.0, Some(342.0)),
+            (297.0, Some(318.0)),
+            (0.0, Some(297.0)),
+        ]);
+    }
+
+    #[test]
+    fn no_zones_without_values() {
+        let physiology = Physiology::default();
+        for kind in KINDS.iter() {
+            assert!(automatic(kind, &physiology).is_empty());
+        }
+    }
+
+    #[test]
+    fn histogram_caps_gaps() {
+        let physiology = Physiology { threshold_hr: Some(170.0), ..Default::default() };
+        let samples = vec![
+            heart_rate(0.0, Some(100.0)),
+            heart_rate(5.0, Some(150.0)),
+            heart_rate(10.0, Some(120.0)),
+            // 30 seconds without a sample count as MAX_SAMPLE_GAP
+            heart_rate(40.0, Some(170.0)),
+            heart_rate(45.0, None),
+            heart_rate(50.0, Some(130.0)),
+        ];
+        let histogram = histogram(HEART_RATE, &automatic(HEART_RATE, &physiology), &samples);
+        assert_eq!(histogram.kind, HEART_RATE);
+        let seconds: Vec<(i64, f64)> = histogram.zones.iter().map(|z| (z.zone, z.seconds)).collect();
+        assert_eq!(seconds, vec![(1, 5.0), (2, MAX_SAMPLE_GAP), (3, 5.0), (4, 5.0), (5, 0.0)]);
+    }
+
+    #[test]
+    fn check_accepts_adjacent_zones_in_any_order() {
+        assert!(check(&zones(&[(0.0, Some(120.0)), (120.0, Some(150.0)), (150.0, None)])).is_ok());
+        assert!(check(&zones(&[(150.0, None), (0.0, Some(150.0))])).is_ok());
+    }
+
+    #[test]
+    fn check_rejects_overlapping_zones() {
+        assert_eq!(check(&zones(&[(0.0, Some(120.0)), (110.0, None)])),
+                   Err("zone 1 overlaps another zone".to_string()));
+        assert_eq!(check(&zones(&[(100.0, None), (150.0, None)])),
+                   Err("zone 1 overlaps another zone".to_string()));
+        let nested = zones(&[(0.0, Some(100.0)), (120.0, Some(150.0)), (130.0, Some(140.0))]);
+        assert_eq!(check(&nested),
+                   Err("zone 2 overlaps another zone".to_string()));
+    }
+
+    #[test]
+    fn check_rejects_empty_and_inverted_zones() {
+        assert!(check(&[]).is_err());
+        assert_eq!(check(&zones(&[(150.0, Some(120.0))])),
+                   Err("zone 1 must have min at least 0 and below max".to_string()));
+        assert!(check(&zones(&[(-1.0, None)])).is_err());
+    }
+}